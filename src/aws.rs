@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+//Everything except the unreserved characters is encoded in the canonical query string
+const QUERY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(Clone, Debug)]
+pub(crate) struct Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+}
+
+impl Credentials {
+    // Reads credentials from the standard `AWS_*` environment variables
+    pub(crate) fn from_env() -> Result<Credentials, Error> {
+        let access_key = std::env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| anyhow!("AWS_ACCESS_KEY_ID is not set"))?;
+        let secret_key = std::env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| anyhow!("AWS_SECRET_ACCESS_KEY is not set"))?;
+
+        Ok(Credentials {
+            access_key,
+            secret_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    // Reads credentials for a profile from the shared credentials file, i.e, `~/.aws/credentials`
+    pub(crate) fn from_profile(profile: &str) -> Result<Credentials, Error> {
+        let path = match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?)
+                .join(".aws/credentials"),
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Could not read `{}`: {}", path.display(), err))?;
+
+        parse_profile(&contents, profile).ok_or_else(|| {
+            anyhow!(
+                "Profile `{}` with an access key was not found in `{}`",
+                profile,
+                path.display()
+            )
+        })
+    }
+
+    // Reads credentials from a named profile, or else the environment, falling back to the `AWS_PROFILE` (or `default`) profile
+    pub(crate) fn load(profile: Option<&str>) -> Result<Credentials, Error> {
+        if let Some(profile) = profile {
+            return Credentials::from_profile(profile);
+        }
+
+        Credentials::from_env().or_else(|err| {
+            let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".into());
+            Credentials::from_profile(&profile).map_err(|_| err)
+        })
+    }
+}
+
+// Finds the keys of a `[profile]` section in a credentials file
+fn parse_profile(contents: &str, profile: &str) -> Option<Credentials> {
+    let mut in_profile = false;
+    let mut values = HashMap::new();
+
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            in_profile = line[1..line.len() - 1].trim() == profile;
+        } else if in_profile {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim(), value.trim());
+            }
+        }
+    }
+
+    Some(Credentials {
+        access_key: values.get("aws_access_key_id")?.to_string(),
+        secret_key: values.get("aws_secret_access_key")?.to_string(),
+        session_token: values
+            .get("aws_session_token")
+            .map(|token| token.to_string()),
+    })
+}
+
+// The region from the environment, falling back to `us-east-1`
+pub(crate) fn region_from_env() -> String {
+    std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".into())
+}
+
+pub(crate) fn sha256_hex(input: &[u8]) -> String {
+    hex(&openssl::sha::sha256(input))
+}
+
+fn hex(input: &[u8]) -> String {
+    input.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data.as_bytes())?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+// Signs requests to one service in one region
+#[derive(Clone, Debug)]
+pub(crate) struct SigV4 {
+    pub region: String,
+    pub service: String,
+    pub credentials: Credentials,
+}
+
+impl SigV4 {
+    // Adds the `Authorization` header (and the `x-amz-*` headers it covers) to a request
+    pub(crate) fn sign(
+        &self,
+        method: &str,
+        url: &Url,
+        headers: &mut HeaderMap,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let (region, service, credentials) = (&self.region, &self.service, &self.credentials);
+
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let payload_hash = sha256_hex(body);
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(anyhow!("`{}` has no host to sign", url)),
+        };
+
+        headers.insert("host", HeaderValue::from_str(&host)?);
+        headers.insert("x-amz-date", HeaderValue::from_str(&amz_date)?);
+
+        //S3 won't accept a request without the payload hash
+        if service == "s3" {
+            headers.insert(
+                "x-amz-content-sha256",
+                HeaderValue::from_str(&payload_hash)?,
+            );
+        }
+
+        if let Some(ref token) = credentials.session_token {
+            headers.insert("x-amz-security-token", HeaderValue::from_str(token)?);
+        }
+
+        let mut canonical_headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_lowercase(),
+                    value.to_str().unwrap_or_default().trim().to_string(),
+                )
+            })
+            .collect();
+        canonical_headers.sort();
+
+        let signed_headers = canonical_headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let mut query: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                (
+                    utf8_percent_encode(&key, QUERY).to_string(),
+                    utf8_percent_encode(&value, QUERY).to_string(),
+                )
+            })
+            .collect();
+        query.sort();
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            url.path(),
+            query
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join("&"),
+            canonical_headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+
+        let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+
+        let key = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), &date)?;
+        let key = hmac(&key, region)?;
+        let key = hmac(&key, service)?;
+        let key = hmac(&key, "aws4_request")?;
+
+        let signature = hex(&hmac(&key, &string_to_sign)?);
+
+        headers.insert(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_str(&format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key, scope, signed_headers, signature
+            ))?,
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_profile() {
+        let contents = "[default]\n\
+                        aws_access_key_id = AKIDDEFAULT\n\
+                        aws_secret_access_key = default-secret\n\
+                        \n\
+                        [monitoring]\n\
+                        aws_access_key_id=AKIDMONITORING\n\
+                        aws_secret_access_key=monitoring-secret\n\
+                        aws_session_token=token\n";
+
+        let credentials = parse_profile(contents, "monitoring").unwrap();
+
+        assert_eq!(credentials.access_key, "AKIDMONITORING");
+        assert_eq!(credentials.secret_key, "monitoring-secret");
+        assert_eq!(credentials.session_token.as_deref(), Some("token"));
+
+        assert_eq!(
+            parse_profile(contents, "default").unwrap().session_token,
+            None
+        );
+        assert!(parse_profile(contents, "missing").is_none());
+    }
+
+    #[test]
+    fn signs_get_vanilla() {
+        //The `get-vanilla` case from the AWS SigV4 test suite
+        let credentials = Credentials {
+            access_key: "AKIDEXAMPLE".into(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+        };
+
+        let signer = SigV4 {
+            region: "us-east-1".into(),
+            service: "service".into(),
+            credentials,
+        };
+
+        let mut headers = HeaderMap::new();
+
+        signer
+            .sign(
+                "GET",
+                &Url::parse("https://example.amazonaws.com/").unwrap(),
+                &mut headers,
+                b"",
+                DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc),
+            )
+            .unwrap();
+
+        assert_eq!(
+            headers["authorization"],
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Error};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::step::ErrorCode;
+use crate::submitter::StepResult;
+
+// A percentage, i.e, `20%` or `20`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Percent(pub f32);
+
+impl FromStr for Percent {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        val.trim()
+            .trim_end_matches('%')
+            .parse::<f32>()
+            .ok()
+            .filter(|percent| *percent >= 0.0)
+            .map(Percent)
+            .ok_or_else(|| format!("Invalid percentage `{}`, expected i.e, `20%`", val))
+    }
+}
+
+// Step durations from a previous run, to compare the current run against
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Baseline {
+    durations: HashMap<String, f32>,
+    //Steps slower than this fail, otherwise slowdowns are only noted
+    pub max_slowdown: Option<Percent>,
+    //Slowdowns smaller than this are noise, however large the percentage
+    pub min_slowdown_ms: f32,
+}
+
+impl Baseline {
+    // Loads a baseline from a `--json` report, a webhook payload or a `--history-file`, using the latest run
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        let contents = read_to_string(path)
+            .map_err(|err| anyhow!("Could not read baseline `{}`: {}", path.display(), err))?;
+
+        let value: Value = match serde_json::from_str(&contents) {
+            Ok(value) => value,
+            //History files have a run on each line
+            Err(_) => contents
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .and_then(|line| serde_json::from_str(line).ok())
+                .ok_or_else(|| anyhow!("Baseline `{}` is not valid json", path.display()))?,
+        };
+
+        let results = match value {
+            Value::Array(results) => results,
+            mut value => match value["tests"].take() {
+                Value::Array(results) => results,
+                _ => match value["results"].take() {
+                    Value::Array(results) => results,
+                    _ => return Err(anyhow!("Baseline `{}` has no step results", path.display())),
+                },
+            },
+        };
+
+        //Only passing steps have a duration worth comparing against
+        let durations = results
+            .iter()
+            .filter(|result| result["pass"].as_bool().unwrap_or(false))
+            .filter_map(|result| {
+                Some((
+                    result["name"].as_str()?.to_string(),
+                    result["duration"].as_f64()? as f32,
+                ))
+            })
+            .collect();
+
+        Ok(Baseline {
+            durations,
+            ..Default::default()
+        })
+    }
+
+    // Notes how the step compares to the baseline in its meta, failing it if it's slowed down too much
+    pub fn check(&self, result: &mut StepResult) {
+        if !result.pass || result.skipped.is_some() {
+            return;
+        }
+
+        let baseline = match self.durations.get(&result.name) {
+            Some(baseline) if *baseline > 0.0 => *baseline,
+            _ => return,
+        };
+
+        let slower_ms = result.duration - baseline;
+        let slowdown = slower_ms / baseline * 100.0;
+
+        result
+            .meta
+            .insert("baseline_ms".into(), baseline.to_string());
+        result
+            .meta
+            .insert("slowdown".into(), format!("{:+.1}%", slowdown));
+
+        if let Some(Percent(max)) = self.max_slowdown {
+            if slowdown > max && slower_ms >= self.min_slowdown_ms {
+                result.pass = false;
+                result.error = Some(format!(
+                    "Took {}ms, {:.1}% slower than the baseline of {}ms, more than the maximum of {}%",
+                    result.duration, slowdown, baseline, max
+                ));
+                result.code = Some(ErrorCode::SlowerThanBaseline);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submitter::test_result;
+
+    #[test]
+    fn fails_steps_slower_than_baseline() {
+        let baseline = Baseline {
+            durations: vec![("fast".to_string(), 100.0), ("tiny".to_string(), 1.0)]
+                .into_iter()
+                .collect(),
+            max_slowdown: Some("20%".parse().unwrap()),
+            min_slowdown_ms: 10.0,
+        };
+
+        let result = |name: &str, duration: f32| StepResult {
+            duration,
+            ..test_result(name, true)
+        };
+
+        let mut ok = result("fast", 110.0);
+        baseline.check(&mut ok);
+        assert!(ok.pass);
+        assert_eq!(ok.meta.get("slowdown").unwrap(), "+10.0%");
+
+        let mut slow = result("fast", 150.0);
+        baseline.check(&mut slow);
+        assert!(!slow.pass);
+        assert_eq!(slow.code, Some(ErrorCode::SlowerThanBaseline));
+
+        //Tripling a 1ms step is still under the minimum
+        let mut noisy = result("tiny", 3.0);
+        baseline.check(&mut noisy);
+        assert!(noisy.pass);
+    }
+}
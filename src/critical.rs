@@ -0,0 +1,70 @@
+use crate::submitter::{DurationFormat, StepResult};
+
+// Finds the chain of dependencies that limited the total run time, starting with the first step in the chain.
+// This walks back from the last step to finish, following whichever dependency finished last
+pub fn critical_path(results: &[StepResult]) -> Vec<&StepResult> {
+    let mut current = match results.iter().max_by_key(|result| result.timing.finished) {
+        Some(result) => result,
+        None => return vec![],
+    };
+
+    let mut path = vec![current];
+
+    loop {
+        let dependencies = results.iter().filter(|other| {
+            current.require.contains(&other.name) || other.required_by.contains(&current.name)
+        });
+
+        let previous = dependencies
+            .filter(|other| !path.iter().any(|step| step.name == other.name))
+            .max_by_key(|other| other.timing.finished);
+
+        match previous {
+            Some(previous) => {
+                path.push(previous);
+                current = previous;
+            }
+            None => break,
+        }
+    }
+
+    path.reverse();
+    path
+}
+
+pub fn print_critical_path(results: &[StepResult], format: &DurationFormat) {
+    let path = critical_path(results);
+
+    let total = match path.last() {
+        Some(last) => last.timing.finished,
+        None => return,
+    };
+
+    println!(
+        "critical path: {}",
+        format.format(total.as_secs_f32() * 1000.0)
+    );
+
+    for result in path {
+        let timing = result.timing;
+        let duration = timing.finished.saturating_sub(timing.started);
+        let lock_wait = timing.started.saturating_sub(timing.ready);
+
+        let mut line = format!(
+            "  - {}: {}",
+            result.name,
+            format.format(duration.as_secs_f32() * 1000.0)
+        );
+
+        if !lock_wait.is_zero() {
+            line.push_str(&format!(
+                " (waited {} for lock)",
+                format.format(lock_wait.as_secs_f32() * 1000.0)
+            ));
+        }
+
+        println!("{}", line);
+    }
+
+    println!();
+}
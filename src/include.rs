@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Error};
+use log::{debug, warn};
+use reqwest::Url;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default)]
+pub struct IncludeOptions {
+    //Only use cached copies of remote includes
+    pub offline: bool,
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl IncludeOptions {
+    // Where remote includes are cached.  There's no cache without a home directory, as a shared one could be written to by other users
+    fn cache_dir(&self) -> Option<PathBuf> {
+        if let Some(ref dir) = self.cache_dir {
+            return Some(dir.clone());
+        }
+
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+
+        Some(base.join("lorikeet").join("includes"))
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum IncludeList {
+    One(Include),
+    Many(Vec<Include>),
+}
+
+impl IncludeList {
+    pub fn into_vec(self) -> Vec<Include> {
+        match self {
+            IncludeList::One(include) => vec![include],
+            IncludeList::Many(includes) => includes,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Include {
+    Location(String),
+    Pinned { url: String, sha256: String },
+}
+
+impl Include {
+    fn location(&self) -> &str {
+        match self {
+            Include::Location(location) => location,
+            Include::Pinned { url, .. } => url,
+        }
+    }
+
+    fn sha256(&self) -> Option<&str> {
+        match self {
+            Include::Location(_) => None,
+            Include::Pinned { sha256, .. } => Some(sha256),
+        }
+    }
+
+    // Resolves where the include is, relative to the file that included it
+    pub fn source(&self, parent: Option<&Source>) -> Result<Source, Error> {
+        let location = self.location();
+
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Url::parse(location)
+                .map(Source::Remote)
+                .map_err(|err| anyhow!("Invalid include url `{}`: {}", location, err));
+        }
+
+        match parent {
+            Some(Source::Remote(url)) => url
+                .join(location)
+                .map(Source::Remote)
+                .map_err(|err| anyhow!("Invalid include url `{}`: {}", location, err)),
+            Some(Source::Local(path)) => Ok(Source::Local(
+                path.parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(location),
+            )),
+            None => Ok(Source::Local(PathBuf::from(location))),
+        }
+    }
+
+    // Loads the contents of the include, verifying the checksum if it is pinned
+    pub fn load(&self, source: &Source, options: &IncludeOptions) -> Result<String, Error> {
+        let contents = match source {
+            Source::Local(path) => std::fs::read(path)
+                .map_err(|err| anyhow!("Could not read include {:?}: {}", path, err))?,
+            Source::Remote(url) => load_remote(url, self.sha256(), options)?,
+        };
+
+        if let Some(expected) = self.sha256() {
+            let actual = sha256(&contents);
+
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(anyhow!(
+                    "Checksum mismatch for include `{}`: expected {}, got {}",
+                    source,
+                    expected,
+                    actual
+                ));
+            }
+        }
+
+        String::from_utf8(contents)
+            .map_err(|err| anyhow!("Include `{}` is not utf8: {}", source, err))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Local(PathBuf),
+    Remote(Url),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Local(path) => write!(f, "{}", path.display()),
+            Source::Remote(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+fn load_remote(
+    url: &Url,
+    pinned: Option<&str>,
+    options: &IncludeOptions,
+) -> Result<Vec<u8>, Error> {
+    let cache_path = options
+        .cache_dir()
+        .map(|dir| dir.join(format!("{}.yml", sha256(url.as_str().as_bytes()))));
+
+    let cached = cache_path
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok());
+
+    //A pinned include can't change, so the cached copy can be used as is
+    if let (Some(contents), Some(expected)) = (&cached, pinned) {
+        if sha256(contents).eq_ignore_ascii_case(expected) {
+            debug!("Using cached copy of `{}`", url);
+            return Ok(contents.clone());
+        }
+    }
+
+    if options.offline {
+        return cached.ok_or_else(|| {
+            anyhow!(
+                "Include `{}` has not been cached, and can't be downloaded when offline",
+                url
+            )
+        });
+    }
+
+    match fetch(url) {
+        Ok(contents) => {
+            if let Some(ref cache_path) = cache_path {
+                let written = cache_path
+                    .parent()
+                    .map(std::fs::create_dir_all)
+                    .unwrap_or(Ok(()))
+                    .and_then(|_| std::fs::write(cache_path, &contents));
+
+                if let Err(err) = written {
+                    debug!("Could not cache include `{}`: {}", url, err);
+                }
+            }
+
+            Ok(contents)
+        }
+        Err(err) => match cached {
+            Some(contents) => {
+                warn!("Could not download `{}`, using cached copy: {}", url, err);
+                Ok(contents)
+            }
+            None => Err(err),
+        },
+    }
+}
+
+// Steps are loaded outside of the runtime, so includes are fetched on their own thread
+fn fetch(url: &Url) -> Result<Vec<u8>, Error> {
+    let url = url.clone();
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        runtime.block_on(async {
+            let response = reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|err| anyhow!("Could not download include `{}`: {}", url, err))?;
+
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Could not download include `{}`: status code `{}`",
+                    url,
+                    response.status()
+                ));
+            }
+
+            Ok(response.bytes().await?.to_vec())
+        })
+    })
+    .join()
+    .map_err(|_| anyhow!("Include download thread panicked"))?
+}
+
+fn sha256(contents: &[u8]) -> String {
+    openssl::sha::sha256(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
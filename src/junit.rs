@@ -1,17 +1,25 @@
 use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
 use quick_xml::Writer;
 use std::fs::File;
+use std::io::Write;
 use std::path::Path;
 
-use crate::submitter::StepResult;
+use crate::step::ErrorCode;
+use crate::submitter::{total_retries, DurationFormat, StepResult};
+use crate::timezone::format_time;
 
 use anyhow::Error;
+use chrono::{DateTime, Utc};
+use linked_hash_map::LinkedHashMap;
 use std::fs::create_dir_all;
 
 pub fn create_junit(
     results: &[StepResult],
     file_path: &Path,
     hostname: Option<&str>,
+    meta: &LinkedHashMap<String, String>,
+    format: &DurationFormat,
+    started: DateTime<Utc>,
 ) -> Result<(), Error> {
     if let Some(parent) = file_path.parent() {
         create_dir_all(parent)?;
@@ -32,14 +40,15 @@ pub fn create_junit(
             if let Some(ref output) = step.error {
                 return output == "Dependency Not Met";
             }
-            false
+            step.skipped.is_some()
         })
         .count();
-    let failure_num = results.iter().filter(|step| !step.pass).count() - skip_num;
-
-    let time = results
+    let failure_num = results
         .iter()
-        .fold(0f32, |sum, step| sum + (step.duration / 1000f32));
+        .filter(|step| !step.pass && step.code != Some(ErrorCode::DepNotMet))
+        .count();
+
+    let time = results.iter().fold(0f32, |sum, step| sum + step.duration);
 
     let hostname = match hostname {
         Some(hostname) => String::from(hostname),
@@ -52,14 +61,24 @@ pub fn create_junit(
 
     testsuite.push_attribute(("name", "lorikeet"));
     testsuite.push_attribute(("hostname", &*hostname));
+    testsuite.push_attribute(("timestamp", &*format_time(&started)));
 
     testsuite.push_attribute(("tests", &*test_num.to_string()));
     testsuite.push_attribute(("failures", &*failure_num.to_string()));
     testsuite.push_attribute(("skipped", &*skip_num.to_string()));
-    testsuite.push_attribute(("time", &*time.to_string()));
+    testsuite.push_attribute(("time", &*format.format_secs(time)));
 
     writer.write_event(Event::Start(testsuite))?;
 
+    let total_retries = total_retries(results).to_string();
+    let mut properties: Vec<(&str, &str)> = meta
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    properties.push(("total_retries", &total_retries));
+
+    write_properties(&mut writer, properties)?;
+
     for result in results.iter() {
         let mut testcase = BytesStart::borrowed(b"testcase", b"testcase".len());
 
@@ -71,25 +90,59 @@ pub fn create_junit(
             testcase.push_attribute(("classname", ""));
         }
 
-        testcase.push_attribute(("time", &*(result.duration / 1000f32).to_string()));
+        testcase.push_attribute(("time", &*format.format_secs(result.duration)));
 
         writer.write_event(Event::Start(testcase))?;
 
+        let attempts = result.attempts_display();
+        let mut properties: Vec<(&str, &str)> = result
+            .meta
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+
+        if result.max_attempts > 1 {
+            properties.push(("attempts", &attempts));
+        }
+
+        write_properties(&mut writer, properties)?;
+
         writer.write_event(Event::Start(BytesStart::borrowed(
             b"system-out",
             b"system-out".len(),
         )))?;
 
+        let mut system_out = match result.preview {
+            Some(ref preview) => format!("{}\n{}", result.output, preview),
+            None => result.output.clone(),
+        };
+
+        //Saved attachments are referenced the way the jenkins attachments plugin expects
+        for path in result
+            .attachments
+            .iter()
+            .filter_map(|attachment| attachment.path.as_ref())
+        {
+            system_out.push_str(&format!("\n[[ATTACHMENT|{}]]", path.display()));
+        }
+
         writer.write_event(Event::Text(BytesText::from_plain_str(
-            &filter_invalid_chars(&result.output),
+            &filter_invalid_chars(&system_out),
         )))?;
 
         writer.write_event(Event::End(BytesEnd::borrowed(b"system-out")))?;
 
+        if let Some(ref reason) = result.skipped {
+            let mut skipped = BytesStart::borrowed(b"skipped", b"skipped".len());
+            skipped.push_attribute(("message", &*filter_invalid_chars(reason)));
+
+            writer.write_event(Event::Empty(skipped))?;
+        }
+
         if !result.pass {
             let error_text = result.error.as_deref().unwrap_or("");
 
-            if error_text == "Dependency Not Met" {
+            if result.code == Some(ErrorCode::DepNotMet) {
                 let mut skipped = BytesStart::borrowed(b"skipped", b"skipped".len());
                 skipped.push_attribute(("message", "Dependency Not Met"));
 
@@ -100,6 +153,10 @@ pub fn create_junit(
                 let mut failure = BytesStart::borrowed(b"failure", b"failure".len());
                 failure.push_attribute(("message", "Step failed to finish"));
 
+                if let Some(ref code) = result.code {
+                    failure.push_attribute(("type", &*code.to_string()));
+                }
+
                 writer.write_event(Event::Start(failure))?;
                 writer.write_event(Event::Text(BytesText::from_plain_str(
                     &filter_invalid_chars(error_text),
@@ -116,6 +173,31 @@ pub fn create_junit(
     Ok(())
 }
 
+fn write_properties<W: Write>(
+    writer: &mut Writer<W>,
+    properties: Vec<(&str, &str)>,
+) -> Result<(), Error> {
+    if properties.is_empty() {
+        return Ok(());
+    }
+
+    writer.write_event(Event::Start(BytesStart::borrowed(
+        b"properties",
+        b"properties".len(),
+    )))?;
+
+    for (key, value) in properties {
+        let mut property = BytesStart::borrowed(b"property", b"property".len());
+        property.push_attribute(("name", &*filter_invalid_chars(key)));
+        property.push_attribute(("value", &*filter_invalid_chars(value)));
+        writer.write_event(Event::Empty(property))?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::borrowed(b"properties")))?;
+
+    Ok(())
+}
+
 fn filter_invalid_chars(input: &str) -> String {
     let mut output = String::new();
 
@@ -1,6 +1,25 @@
+pub mod audit_log;
+pub mod aws;
+pub mod baseline;
+pub mod critical;
 pub mod graph;
+pub mod http_log;
+pub mod include;
 pub mod junit;
+pub mod meta;
+pub mod nagios;
+pub mod notify;
+pub mod openapi;
+pub mod reporter;
 pub mod runner;
+pub mod sample;
+pub mod serve;
+pub mod signing;
+pub mod sql;
+pub mod statuspage;
 pub mod step;
 pub mod submitter;
+pub mod syslog;
+pub mod timezone;
+pub mod trace;
 pub mod yaml;
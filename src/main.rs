@@ -1,23 +1,57 @@
-use futures::StreamExt;
 use structopt::StructOpt;
 
-use std::path::{Path, PathBuf};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use linked_hash_map::LinkedHashMap;
+use tokio::sync::Notify;
 
 use anyhow::Error;
 
+use chrono::Utc;
 use log::{debug, trace};
 
-use lorikeet::runner::run_steps;
-use lorikeet::step::{ExpectType, Outcome, RetryPolicy, RunType, Step};
-use lorikeet::submitter::StepResult;
-use lorikeet::yaml::get_steps;
+use lorikeet::baseline::{Baseline, Percent};
+use lorikeet::critical::print_critical_path;
+use lorikeet::include::IncludeOptions;
+use lorikeet::meta::{parse_meta, run_meta};
+use lorikeet::nagios::import_nagios;
+use lorikeet::notify::{Notifier, NotifyRules};
+use lorikeet::openapi::generate_steps;
+use lorikeet::reporter::{
+    new_run_id, JsonReporter, JunitReporter, Reporters, RunInfo, SentryReporter, SlackReporter,
+    SqlReporter, SummaryReporter, SyslogReporter, TerminalReporter, WebhookReporter,
+};
+use lorikeet::runner::{dir_name, LoadLimit, RunOptions};
+use lorikeet::sample::{sample_steps, SampleOptions};
+use lorikeet::serve::{parse_plan, serve, History, RunRecord, ServedPlan};
+use lorikeet::signing::Signing;
+use lorikeet::sql::{SqlDsn, SqlTables};
+use lorikeet::statuspage::{publish, render, Target};
+use lorikeet::step::{
+    load_cookie_jars, save_cookie_jars, set_non_interactive, set_remote_approval, ErrorCode,
+    ExpectType, Outcome, RetryPolicy, RunType, Step,
+};
+use lorikeet::submitter::{
+    has_gated_failures, save_attachments, Compression, DurationFormat, DurationUnit, SentryDsn,
+    StepResult, WebhookCompression,
+};
+use lorikeet::timezone::{set_timezone, Timezone};
+use lorikeet::trace::create_trace;
+use lorikeet::yaml::{get_steps_with_options, plan_schema};
+use lorikeet::{audit_log, http_log};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "lorikeet", about = "a parallel test runner for devops")]
 struct Arguments {
-    #[structopt(short = "q", long = "quiet", help = "Don't output results to console")]
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        help = "Don't output results to console, only a json summary line"
+    )]
     quiet: bool,
 
     #[structopt(short = "c", long = "config", help = "Configuration File")]
@@ -39,6 +73,25 @@ struct Arguments {
     )]
     webhook: Vec<String>,
 
+    #[structopt(
+        long = "webhook-compression",
+        help = "Compress webhook payloads with `gzip` or `zstd`"
+    )]
+    webhook_compression: Option<Compression>,
+
+    #[structopt(
+        long = "webhook-compression-min-bytes",
+        help = "Only compress webhook payloads at least this many bytes long",
+        default_value = "1024"
+    )]
+    webhook_compression_min_bytes: usize,
+
+    #[structopt(
+        long = "webhook-attachments",
+        help = "Upload step attachments with webhooks, as a multipart form"
+    )]
+    webhook_attachments: bool,
+
     #[structopt(
         short = "s",
         long = "slack",
@@ -46,6 +99,38 @@ struct Arguments {
     )]
     slack: Vec<String>,
 
+    #[structopt(
+        long = "sentry",
+        help = "Report failing steps to this Sentry DSN, defaults to the SENTRY_DSN environment variable"
+    )]
+    sentry: Option<SentryDsn>,
+
+    #[structopt(
+        long = "sql",
+        help = "Insert run & step results into this database (`postgres://` or `mysql://` url)"
+    )]
+    sql: Option<SqlDsn>,
+
+    #[structopt(
+        long = "sql-runs-table",
+        help = "Table to insert runs into",
+        default_value = "lorikeet_runs"
+    )]
+    sql_runs_table: String,
+
+    #[structopt(
+        long = "sql-steps-table",
+        help = "Table to insert step results into",
+        default_value = "lorikeet_steps"
+    )]
+    sql_steps_table: String,
+
+    #[structopt(
+        long = "syslog",
+        help = "Send each step result to journald or the local syslog, with structured fields"
+    )]
+    syslog: bool,
+
     #[structopt(
         short = "j",
         long = "junit",
@@ -53,6 +138,286 @@ struct Arguments {
         parse(from_os_str)
     )]
     junit: Option<PathBuf>,
+
+    #[structopt(
+        long = "json",
+        help = "Output the results as json to this file, in the same shape as the webhook payload",
+        parse(from_os_str)
+    )]
+    json: Option<PathBuf>,
+
+    #[structopt(
+        long = "baseline",
+        help = "Compare step durations against the results of a previous run, from a json report or history file",
+        parse(from_os_str)
+    )]
+    baseline: Option<PathBuf>,
+
+    #[structopt(
+        long = "max-slowdown",
+        help = "Fail steps that are slower than the baseline by more than this percentage, i.e, `20%`"
+    )]
+    max_slowdown: Option<Percent>,
+
+    #[structopt(
+        long = "min-slowdown-ms",
+        help = "Ignore slowdowns of less than this many milliseconds, so quick steps don't fail on noise",
+        default_value = "10"
+    )]
+    min_slowdown_ms: f32,
+
+    #[structopt(
+        long = "trace",
+        help = "Output a chrome://tracing file of step timings to this file",
+        parse(from_os_str)
+    )]
+    trace: Option<PathBuf>,
+
+    #[structopt(
+        long = "critical-path",
+        help = "Print the chain of dependent steps that limited the total run time"
+    )]
+    critical_path: bool,
+
+    #[structopt(
+        long = "keep-artifacts",
+        help = "Don't remove the step workdirs after the run has finished"
+    )]
+    keep_artifacts: bool,
+
+    #[structopt(
+        long = "artifacts-dir",
+        help = "Create step workdirs in this directory rather than a temporary one",
+        parse(from_os_str)
+    )]
+    artifacts_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "attachments-dir",
+        help = "Save step attachments to this directory, so junit reports can reference them",
+        parse(from_os_str)
+    )]
+    attachments_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "max-load",
+        help = "Wait before starting bash & system steps while the 1 minute load average is above this"
+    )]
+    max_load: Option<f64>,
+
+    #[structopt(
+        long = "max-memory-percent",
+        help = "Wait before starting bash & system steps while memory usage is above this percentage"
+    )]
+    max_memory_percent: Option<f64>,
+
+    #[structopt(
+        long = "max-load-wait",
+        help = "Maximum seconds to wait for the load or memory usage to drop",
+        default_value = "60"
+    )]
+    max_load_wait: u64,
+
+    #[structopt(
+        long = "duration-unit",
+        help = "Unit to display durations in (ms or s)",
+        default_value = "ms"
+    )]
+    duration_unit: DurationUnit,
+
+    #[structopt(long = "precision", help = "Number of decimal places for durations")]
+    precision: Option<usize>,
+
+    #[structopt(
+        long = "thousands-separator",
+        help = "Group the digits of durations with a thousands separator"
+    )]
+    thousands_separator: bool,
+
+    #[structopt(
+        long = "gate-tags",
+        help = "Only fail the run (exit code) on failed steps with one of these tags",
+        use_delimiter = true,
+        number_of_values = 1
+    )]
+    gate_tags: Vec<String>,
+
+    #[structopt(
+        long = "max-output-lines",
+        help = "Only show this many lines of each step's output in the terminal, reports still get all of it"
+    )]
+    max_output_lines: Option<usize>,
+
+    #[structopt(
+        long = "timezone",
+        help = "Timezone for timestamps in reports & logs (`utc`, `local` or a name like `Australia/Brisbane`)",
+        default_value = "utc"
+    )]
+    timezone: Timezone,
+
+    #[structopt(
+        long = "meta",
+        help = "Add run metadata to reports as `key=value` (multiple values allowed)",
+        parse(try_from_str = parse_meta)
+    )]
+    meta: Vec<(String, String)>,
+
+    #[structopt(
+        long = "http-log",
+        help = "Record every http request made during the run to a file (NDJSON)",
+        parse(from_os_str)
+    )]
+    http_log: Option<PathBuf>,
+
+    #[structopt(
+        long = "audit-log",
+        help = "Append the commands & urls run by steps to a file, with secrets redacted (NDJSON)",
+        parse(from_os_str)
+    )]
+    audit_log: Option<PathBuf>,
+
+    #[structopt(
+        long = "cookie-file",
+        help = "Load saved cookies from this file before running, & save them back after each run",
+        parse(from_os_str)
+    )]
+    cookie_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "non-interactive",
+        help = "Fail prompt steps instead of asking for input"
+    )]
+    non_interactive: bool,
+
+    #[structopt(
+        long = "offline",
+        help = "Skip steps that need network access, & only use cached copies of remote includes"
+    )]
+    offline: bool,
+
+    #[structopt(
+        long = "dedup",
+        help = "Run steps with identical run types & dependencies once, sharing the output"
+    )]
+    dedup: bool,
+
+    #[structopt(
+        long = "serve",
+        help = "Run the plan on an interval, serving the results over http on this address"
+    )]
+    serve: Option<SocketAddr>,
+
+    #[structopt(
+        long = "plan",
+        help = "Run a named plan as `name=path` instead of the test plan, with --serve or --interval (multiple values allowed)",
+        parse(try_from_str = parse_plan),
+        number_of_values = 1
+    )]
+    plans: Vec<(String, String)>,
+
+    #[structopt(
+        long = "serve-token",
+        env = "LORIKEET_SERVE_TOKEN",
+        hide_env_values = true,
+        help = "Bearer token needed to approve or reject manual steps over http with --serve"
+    )]
+    serve_token: Option<String>,
+
+    #[structopt(
+        long = "interval",
+        help = "Run the plan every this many seconds, defaults to 60 with --serve"
+    )]
+    interval: Option<u64>,
+
+    #[structopt(
+        long = "history",
+        help = "Number of runs to keep when running on an interval",
+        default_value = "100"
+    )]
+    history: usize,
+
+    #[structopt(
+        long = "history-file",
+        help = "Keep the history of runs in this file, so it survives a restart or builds up across single runs",
+        parse(from_os_str)
+    )]
+    history_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "status-page",
+        help = "Write a status page (index.html & status.json) to this directory or `s3://bucket/prefix` after each run"
+    )]
+    status_page: Option<Target>,
+
+    #[structopt(
+        long = "status-page-group",
+        help = "Group steps on the status page by this meta key",
+        default_value = "service"
+    )]
+    status_page_group: String,
+
+    #[structopt(long = "sample", help = "Only run this many randomly chosen steps")]
+    sample: Option<usize>,
+
+    #[structopt(
+        long = "sample-percent",
+        help = "Only run this percentage of randomly chosen steps"
+    )]
+    sample_percent: Option<f64>,
+
+    #[structopt(long = "sample-seed", help = "Seed used to choose the sampled steps")]
+    sample_seed: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "openapi",
+        about = "Generate a test plan from the GET endpoints of an OpenAPI spec"
+    )]
+    OpenApi {
+        #[structopt(help = "OpenAPI spec (yaml or json)", parse(from_os_str))]
+        spec: PathBuf,
+
+        #[structopt(
+            short = "b",
+            long = "base-url",
+            help = "Base URL to use instead of the one in the spec"
+        )]
+        base_url: Option<String>,
+    },
+    #[structopt(
+        name = "schema",
+        about = "Print the JSON schema of the test plan format, for editors & validation"
+    )]
+    Schema,
+    #[structopt(
+        name = "import",
+        about = "Generate a test plan from another monitoring tool's config"
+    )]
+    Import {
+        #[structopt(subcommand)]
+        source: ImportSource,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ImportSource {
+    #[structopt(
+        name = "nagios",
+        about = "Convert the check_http, check_tcp & check_disk checks of Nagios or Icinga object definitions"
+    )]
+    Nagios {
+        #[structopt(
+            help = "Object config files, i.e, commands.cfg & services.cfg",
+            parse(from_os_str),
+            required = true
+        )]
+        configs: Vec<PathBuf>,
+    },
 }
 
 #[tokio::main]
@@ -61,103 +426,625 @@ async fn main() {
 
     env_logger::init();
 
+    if let Some(command) = opt.command {
+        run_command(command);
+        return;
+    }
+
     debug!("Loading Steps from `{}`", opt.test_plan);
 
     let colours = atty::is(atty::Stream::Stdout) || opt.term;
 
-    let results = run_steps_or_error(&opt.test_plan, &opt.config, opt.quiet, colours).await;
+    set_non_interactive(opt.non_interactive);
+    set_timezone(opt.timezone);
+
+    if let Some(ref path) = opt.http_log {
+        debug!("Recording http requests to `{}`", path.display());
+        if let Err(err) = http_log::open(path) {
+            eprintln!("Could not create http log: {}", err);
+        }
+    }
+
+    if let Some(ref path) = opt.audit_log {
+        debug!("Recording commands & requests to `{}`", path.display());
+        //Change control may depend on the audit log, so don't run without it
+        if let Err(err) = audit_log::open(path) {
+            eprintln!("Could not open audit log: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(ref path) = opt.cookie_file {
+        debug!("Loading cookies from `{}`", path.display());
+        if let Err(err) = load_cookie_jars(path) {
+            eprintln!("Could not load cookie file: {}", err);
+        }
+    }
+
+    let mut run_options = RunOptions {
+        artifacts_dir: opt.artifacts_dir.clone(),
+        keep_artifacts: opt.keep_artifacts,
+        load_limit: LoadLimit {
+            max_load: opt.max_load,
+            max_memory_percent: opt.max_memory_percent,
+            max_wait: Duration::from_secs(opt.max_load_wait),
+        },
+        dedup: opt.dedup,
+        offline: opt.offline,
+        last_runs: Default::default(),
+        plan: None,
+        started: None,
+    };
+
+    //If we're keeping artifacts, let the user know where they are
+    if opt.keep_artifacts && run_options.artifacts_dir.is_none() {
+        match tempfile::Builder::new().prefix("lorikeet").tempdir() {
+            Ok(dir) => {
+                let path = dir.into_path();
+                if !opt.quiet {
+                    eprintln!("Step workdirs will be kept in `{}`", path.display());
+                }
+                run_options.artifacts_dir = Some(path);
+            }
+            Err(err) => eprintln!("Could not create artifacts directory: {}", err),
+        }
+    }
+
+    let format = DurationFormat {
+        unit: opt.duration_unit,
+        precision: opt.precision,
+        thousands_separator: opt.thousands_separator,
+    };
+
+    if opt.serve.is_some() || opt.interval.is_some() {
+        run_on_interval(&opt, run_options, colours, &format).await;
+        return;
+    }
+
+    if !opt.plans.is_empty() {
+        eprintln!("Named plans can only be run with `--serve` or `--interval`");
+        std::process::exit(1);
+    }
+
+    //Single runs only keep a history when there is somewhere for it to go
+    let history = match opt.status_page.is_some() || opt.history_file.is_some() {
+        true => Some(Mutex::new(History::new(
+            opt.history,
+            opt.history_file.clone(),
+        ))),
+        false => None,
+    };
+
+    let previous = history
+        .as_ref()
+        .and_then(|history| previous_results(&history.lock().unwrap()))
+        .unwrap_or_default();
+
+    let started = Utc::now();
+    let start = Instant::now();
+
+    let results = run_plan(
+        &opt,
+        &opt.test_plan,
+        run_options,
+        colours,
+        &format,
+        &previous,
+    )
+    .await;
 
     let has_errors = results.iter().any(|val| !val.pass);
+    let gate_failed = has_gated_failures(&results, &opt.gate_tags);
+
+    if let Some(history) = history {
+        let run = RunRecord {
+            started,
+            duration_ms: start.elapsed().as_secs_f32() * 1000.0,
+            has_errors,
+            results,
+        };
+
+        record_run(&opt, &history, run).await;
+    }
+
+    if gate_failed {
+        std::process::exit(1)
+    }
+}
+
+// Runs the plan, reporting the results, and returns the results that are reported
+async fn run_plan(
+    opt: &Arguments,
+    path: &str,
+    run_options: RunOptions,
+    colours: bool,
+    format: &DurationFormat,
+    previous: &serde_json::Value,
+) -> Vec<StepResult> {
+    let started = Utc::now();
+    let run_id = new_run_id();
+
+    audit_log::start_run(&run_id, path);
+
+    let mut meta = run_meta(&opt.meta);
+
+    if let Some(ref plan) = run_options.plan {
+        meta.insert("plan".into(), plan.clone());
+    }
+
+    let mut sample = SampleOptions {
+        count: opt.sample,
+        percent: opt.sample_percent,
+        seed: opt.sample_seed,
+    };
+
+    //Choose the seed up front so it can be reported & the sample reproduced
+    if sample.is_enabled() {
+        let seed = sample.seed.unwrap_or_else(rand::random);
+        sample.seed = Some(seed);
+        meta.insert("sample_seed".into(), seed.to_string());
+    }
+
+    let include_options = IncludeOptions {
+        offline: opt.offline,
+        cache_dir: None,
+    };
+
+    let steps = get_steps_with_options(path, &opt.config, &include_options, previous);
+
+    let reporters = reporters(opt, colours, format);
+
+    let plan = run_options.plan.clone();
+
+    let results = run_steps_or_error(steps, &sample, run_options, &reporters).await;
+
+    if let Some(ref path) = opt.cookie_file {
+        debug!("Saving cookies to `{}`", path.display());
+        if let Err(err) = save_cookie_jars(path) {
+            eprintln!("Could not save cookie file: {}", err);
+        }
+    }
+
+    if let Some(ref path) = opt.trace {
+        debug!("Creating trace file at `{}`", path.display());
+        if let Err(err) = create_trace(&results, path) {
+            eprintln!("Could not create trace file: {}", err);
+        }
+    }
+
+    if opt.critical_path && !opt.quiet {
+        print_critical_path(&results, format);
+    }
+
+    let mut results: Vec<StepResult> = results
+        .into_iter()
+        .filter(|result| {
+            if !result.is_reported() {
+                debug!("Not reporting step `{}`", result.name);
+            }
+            result.is_reported()
+        })
+        .collect();
+
+    if let Some(ref dir) = opt.attachments_dir {
+        //Each run has its own directory, so runs on an interval or of other plans don't overwrite each other
+        let dir = match plan {
+            Some(ref plan) => dir.join(dir_name(plan)).join(&run_id),
+            None => dir.join(&run_id),
+        };
+
+        debug!("Saving attachments to `{}`", dir.display());
+        if let Err(err) = save_attachments(&mut results, &dir) {
+            eprintln!("Could not save attachments: {}", err);
+        }
+    }
 
     debug!("Steps finished!");
 
-    if !opt.webhook.is_empty() {
-        let hostname = opt.hostname.clone().unwrap_or_else(|| {
-            hostname::get()
-                .map(|val| val.to_string_lossy().to_string())
-                .unwrap_or_else(|_| "".into())
-        });
+    let run = RunInfo {
+        id: run_id,
+        hostname: hostname(opt),
+        meta,
+        started,
+    };
+
+    for err in reporters.run_finished(&results, &run).await {
+        eprintln!("{}", err);
+    }
 
-        for url in opt.webhook {
-            debug!("Sending webhook to: {}", url);
-            lorikeet::submitter::submit_webhook(&results, &url, &hostname)
-                .await
-                .expect("Could not send webhook")
+    results
+}
+
+// The reporters chosen on the command line, in the order they're run
+fn reporters(opt: &Arguments, colours: bool, format: &DurationFormat) -> Reporters {
+    let mut reporters = Reporters::new(*format);
+
+    if let Some(ref path) = opt.baseline {
+        //There's nothing to compare against on the first run, so this isn't fatal
+        match Baseline::load(path) {
+            Ok(mut baseline) => {
+                baseline.max_slowdown = opt.max_slowdown;
+                baseline.min_slowdown_ms = opt.min_slowdown_ms;
+                reporters = reporters.with_baseline(baseline);
+            }
+            Err(err) => eprintln!("Not comparing against a baseline: {}", err),
         }
     }
 
-    if !opt.slack.is_empty() {
-        let hostname = opt.hostname.unwrap_or_else(|| {
-            hostname::get()
-                .map(|val| val.to_string_lossy().to_string())
-                .unwrap_or_else(|_| "".into())
+    if !opt.quiet {
+        reporters.add(TerminalReporter {
+            colours,
+            format: *format,
+            max_output_lines: opt.max_output_lines,
         });
+    } else {
+        reporters.add(SummaryReporter);
+    }
 
-        for url in opt.slack {
-            debug!("Sending slack webhook to: {}", url);
-            lorikeet::submitter::submit_slack(&results, &url, &hostname)
-                .await
-                .expect("Could not send webhook")
-        }
+    if opt.syslog {
+        reporters.add(SyslogReporter);
     }
 
-    if let Some(path) = opt.junit {
-        debug!("Creating junit file at `{}`", path.display());
-        lorikeet::junit::create_junit(&results, &path, None).expect("Coult not create junit file");
+    //A broken rule shouldn't stop failures being sent, so notifiers fall back to always being sent them
+    let rules = NotifyRules::load(opt.config.as_ref()).unwrap_or_else(|err| {
+        eprintln!("Ignoring notify rules: {}", err);
+        NotifyRules::default()
+    });
+
+    //Unsigned results will be rejected by collectors that verify them, so this isn't fatal either
+    let signing = Signing::load(opt.config.as_ref()).unwrap_or_else(|err| {
+        eprintln!("Not signing results: {}", err);
+        None
+    });
+
+    let compression = opt
+        .webhook_compression
+        .map(|compression| WebhookCompression {
+            compression,
+            min_bytes: opt.webhook_compression_min_bytes,
+        });
+
+    for url in opt.webhook.iter() {
+        reporters.add(Notifier {
+            name: "webhook",
+            rule: rules.webhook.clone(),
+            reporter: WebhookReporter {
+                url: url.clone(),
+                signing: signing.clone(),
+                compression,
+                attachments: opt.webhook_attachments,
+            },
+        });
     }
 
-    if has_errors {
-        std::process::exit(1)
+    for url in opt.slack.iter() {
+        reporters.add(Notifier {
+            name: "slack",
+            rule: rules.slack.clone(),
+            reporter: SlackReporter {
+                url: url.clone(),
+                format: *format,
+            },
+        });
     }
+
+    let sentry = match opt.sentry {
+        Some(ref dsn) => Some(dsn.clone()),
+        None => std::env::var("SENTRY_DSN")
+            .ok()
+            .filter(|dsn| !dsn.is_empty())
+            .and_then(|dsn| match dsn.parse::<SentryDsn>() {
+                Ok(dsn) => Some(dsn),
+                Err(err) => {
+                    eprintln!("Ignoring SENTRY_DSN: {}", err);
+                    None
+                }
+            }),
+    };
+
+    if let Some(dsn) = sentry {
+        reporters.add(Notifier {
+            name: "sentry",
+            rule: rules.sentry.clone(),
+            reporter: SentryReporter { dsn },
+        });
+    }
+
+    if let Some(ref dsn) = opt.sql {
+        reporters.add(SqlReporter {
+            dsn: dsn.clone(),
+            tables: SqlTables {
+                runs: opt.sql_runs_table.clone(),
+                steps: opt.sql_steps_table.clone(),
+            },
+        });
+    }
+
+    if let Some(ref path) = opt.json {
+        reporters.add(JsonReporter {
+            path: path.clone(),
+            signing: signing.clone(),
+        });
+    }
+
+    if let Some(ref path) = opt.junit {
+        reporters.add(JunitReporter {
+            path: path.clone(),
+            format: *format,
+        });
+    }
+
+    reporters
 }
 
-// Runs the steps, or if there is an issue running the steps, then return the error as a step
-async fn run_steps_or_error<P: AsRef<Path>, Q: AsRef<Path>>(
-    file_path: P,
-    config_path: &Option<Q>,
-    quiet: bool,
+// The hostname given on the command line, or the hostname of this machine
+fn hostname(opt: &Arguments) -> String {
+    opt.hostname.clone().unwrap_or_else(|| {
+        hostname::get()
+            .map(|val| val.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "".into())
+    })
+}
+
+// Runs the plan (or each named plan) over & over, keeping the results of recent runs & optionally serving them
+async fn run_on_interval(
+    opt: &Arguments,
+    run_options: RunOptions,
     colours: bool,
-) -> Vec<StepResult> {
-    let steps = match get_steps(file_path, config_path) {
-        Ok(steps) => steps,
-        Err(err) => return vec![step_from_error(err, quiet, colours)],
+    format: &DurationFormat,
+) {
+    if opt.plans.len() > 1 && opt.status_page.is_some() {
+        eprintln!("A status page can only be published for a single plan");
+        std::process::exit(1);
+    }
+
+    //Without named plans, the test plan is served as `default`
+    let named = !opt.plans.is_empty();
+
+    let paths: Vec<(String, String)> = match named {
+        true => opt.plans.clone(),
+        false => vec![("default".into(), opt.test_plan.clone())],
     };
 
-    trace!("Steps:{:?}", steps);
+    let mut plans = LinkedHashMap::new();
+
+    for (name, _) in paths.iter() {
+        //Each plan keeps its history in its own file, i.e, `history.payments.ndjson`
+        let history_file = match named {
+            true => opt
+                .history_file
+                .as_ref()
+                .map(|path| plan_history_file(path, name)),
+            false => opt.history_file.clone(),
+        };
+
+        if plans.contains_key(name) {
+            eprintln!("The plan `{}` is given more than once", name);
+            std::process::exit(1);
+        }
+
+        plans.insert(
+            name.clone(),
+            ServedPlan {
+                scope: match named {
+                    true => name.clone(),
+                    false => String::new(),
+                },
+                history: Arc::new(Mutex::new(History::new(opt.history, history_file))),
+                trigger: Arc::new(Notify::new()),
+            },
+        );
+    }
+
+    let plans = Arc::new(plans);
+
+    if let Some(addr) = opt.serve {
+        //Manual steps can be approved over http, rather than only on the terminal, if there's a token to check
+        set_remote_approval(opt.serve_token.is_some());
+
+        let plans = plans.clone();
+        let token = opt.serve_token.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve(addr, plans, token).await {
+                eprintln!("Could not serve results on `{}`: {}", addr, err);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    let interval = Duration::from_secs(opt.interval.unwrap_or(60));
+
+    let loops = paths.iter().map(|(name, path)| {
+        let served = plans[name].clone();
+
+        //Each plan has its own step outputs & `every` outcomes
+        let run_options = RunOptions {
+            plan: match named {
+                true => Some(name.clone()),
+                false => None,
+            },
+            last_runs: Default::default(),
+            ..run_options.clone()
+        };
+
+        async move {
+            loop {
+                let started = Utc::now();
+                let start = Instant::now();
+
+                let previous =
+                    previous_results(&served.history.lock().unwrap()).unwrap_or_default();
 
-    match run_steps(steps) {
-        Ok(mut stream) => {
-            let mut results = Vec::new();
+                let results =
+                    run_plan(opt, path, run_options.clone(), colours, format, &previous).await;
 
-            while let Some(step) = stream.next().await {
-                let result: StepResult = step.into();
+                let run = RunRecord {
+                    started,
+                    duration_ms: start.elapsed().as_secs_f32() * 1000.0,
+                    has_errors: results.iter().any(|val| !val.pass),
+                    results,
+                };
 
-                if !quiet {
-                    result.terminal_print(&colours);
+                record_run(opt, &served.history, run).await;
+
+                debug!(
+                    "Sleeping for {}s until the next run of `{}`",
+                    interval.as_secs(),
+                    name
+                );
+
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => (),
+                    _ = served.trigger.notified() => debug!("Running `{}` now, as it was triggered", name),
                 }
+            }
+        }
+    });
+
+    futures::future::join_all(loops).await;
+}
+
+// The history file for a named plan, with the name before the extension
+fn plan_history_file(path: &std::path::Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, name),
+    };
+
+    path.with_file_name(file_name)
+}
+
+// The results of the latest run in the history, by step name
+fn previous_results(history: &History) -> Option<serde_json::Value> {
+    history.latest().map(RunRecord::results_by_name)
+}
+
+// Adds the run to the history, then updates the status page from it
+async fn record_run(opt: &Arguments, history: &Mutex<History>, run: RunRecord) {
+    let status = {
+        let mut history = history.lock().unwrap();
+        history.push(run);
+
+        opt.status_page
+            .as_ref()
+            .map(|_| render(&history, &opt.status_page_group))
+    };
+
+    if let (Some(target), Some(status)) = (&opt.status_page, status) {
+        debug!("Publishing status page to {:?}", target);
+        if let Err(err) = publish(target, &status).await {
+            eprintln!("Could not publish status page: {}", err);
+        }
+    }
+}
 
-                results.push(result);
+fn run_command(command: Command) {
+    match command {
+        Command::OpenApi { spec, base_url } => {
+            let generated = std::fs::read_to_string(&spec)
+                .map_err(Error::from)
+                .and_then(|contents| generate_steps(&contents, base_url.as_deref()));
+
+            match generated {
+                Ok(plan) => print!("{}", plan),
+                Err(err) => {
+                    eprintln!("Could not generate steps from {:?}: {}", spec, err);
+                    std::process::exit(1)
+                }
             }
+        }
+        Command::Import {
+            source: ImportSource::Nagios { configs },
+        } => {
+            //Objects are usually spread across a few files, i.e, commands in one & services in another
+            let imported = configs
+                .iter()
+                .map(|path| {
+                    std::fs::read_to_string(path)
+                        .map_err(|err| anyhow::anyhow!("Could not read {:?}: {}", path, err))
+                })
+                .collect::<Result<Vec<String>, Error>>()
+                .and_then(|contents| import_nagios(&contents.join("\n")));
 
-            results
+            match imported {
+                Ok(imported) => {
+                    for skipped in imported.skipped.iter() {
+                        eprintln!("Skipped {}", skipped);
+                    }
+                    print!("{}", imported.plan)
+                }
+                Err(err) => {
+                    eprintln!("Could not import nagios config: {}", err);
+                    std::process::exit(1)
+                }
+            }
         }
-        Err(err) => vec![step_from_error(err, quiet, colours)],
+        Command::Schema => match serde_json::to_string_pretty(&plan_schema()) {
+            Ok(schema) => println!("{}", schema),
+            Err(err) => {
+                eprintln!("Could not generate schema: {}", err);
+                std::process::exit(1)
+            }
+        },
     }
 }
 
-fn step_from_error(err: Error, quiet: bool, colours: bool) -> StepResult {
+// Runs the steps, or if there is an issue loading or running the steps, then return the error as a step
+async fn run_steps_or_error(
+    steps: Result<Vec<Step>, Error>,
+    sample: &SampleOptions,
+    run_options: RunOptions,
+    reporters: &Reporters,
+) -> Vec<StepResult> {
+    let steps = match steps {
+        Ok(steps) => steps,
+        Err(err) => return vec![step_from_error(err, reporters)],
+    };
+
+    let steps = if sample.is_enabled() {
+        sample_steps(steps, sample)
+    } else {
+        steps
+    };
+
+    trace!("Steps:{:?}", steps);
+
+    match reporters.run_steps(steps, run_options).await {
+        Ok(results) => results,
+        Err(err) => vec![step_from_error(err, reporters)],
+    }
+}
+
+fn step_from_error(err: Error, reporters: &Reporters) -> StepResult {
     let outcome = Outcome {
         output: None,
         error: Some(err.to_string()),
         duration: Duration::default(),
         on_fail_output: None,
         on_fail_error: None,
+        attempts: 0,
+        max_attempts: 0,
+        skipped: None,
+        timing: Default::default(),
+        preview: None,
+        bytes: None,
+        code: Some(ErrorCode::PlanError),
+        attachments: vec![],
+        last_run: None,
     };
 
     let result: StepResult = Step {
         name: "lorikeet".into(),
         run: RunType::Value(String::new()),
         do_output: true,
+        sensitive: false,
+        binary: false,
+        report: true,
+        lock: None,
+        guard: Default::default(),
         expect: ExpectType::Anything,
         on_fail: None,
         description: Some(
@@ -167,14 +1054,18 @@ fn step_from_error(err: Error, quiet: bool, colours: bool) -> StepResult {
         filters: vec![],
         require: vec![],
         required_by: vec![],
+        meta: Default::default(),
+        tags: vec![],
+        attachments: Default::default(),
+        every: None,
+        setup: false,
+        source: None,
         retry: RetryPolicy::default(),
         outcome: Some(outcome),
     }
     .into();
 
-    if !quiet {
-        result.terminal_print(&colours);
-    }
+    reporters.step_finished(&result);
 
     result
 }
@@ -0,0 +1,158 @@
+use linked_hash_map::LinkedHashMap;
+use std::env;
+
+//The environment variables used by each CI provider, in the order of (provider, detect, commit, branch, build url)
+const PROVIDERS: &[(&str, &str, &str, &str, &str)] = &[
+    (
+        "gitlab",
+        "GITLAB_CI",
+        "CI_COMMIT_SHA",
+        "CI_COMMIT_REF_NAME",
+        "CI_JOB_URL",
+    ),
+    (
+        "jenkins",
+        "JENKINS_URL",
+        "GIT_COMMIT",
+        "BRANCH_NAME",
+        "BUILD_URL",
+    ),
+    (
+        "circleci",
+        "CIRCLECI",
+        "CIRCLE_SHA1",
+        "CIRCLE_BRANCH",
+        "CIRCLE_BUILD_URL",
+    ),
+    (
+        "buildkite",
+        "BUILDKITE",
+        "BUILDKITE_COMMIT",
+        "BUILDKITE_BRANCH",
+        "BUILDKITE_BUILD_URL",
+    ),
+    (
+        "travis",
+        "TRAVIS",
+        "TRAVIS_COMMIT",
+        "TRAVIS_BRANCH",
+        "TRAVIS_BUILD_WEB_URL",
+    ),
+];
+
+// Returns metadata about the current run, detected from common CI environment variables, with any overrides applied
+pub fn run_meta(overrides: &[(String, String)]) -> LinkedHashMap<String, String> {
+    let mut meta = ci_meta(|key| env::var(key).ok().filter(|val| !val.is_empty()));
+
+    for (key, value) in overrides {
+        meta.insert(key.clone(), value.clone());
+    }
+
+    meta
+}
+
+fn ci_meta<F: Fn(&str) -> Option<String>>(var: F) -> LinkedHashMap<String, String> {
+    let mut meta = LinkedHashMap::new();
+
+    if var("GITHUB_ACTIONS").is_some() {
+        meta.insert("ci".into(), "github".into());
+        insert(&mut meta, "commit", var("GITHUB_SHA"));
+        insert(&mut meta, "branch", var("GITHUB_REF_NAME"));
+
+        if let (Some(server), Some(repo), Some(run_id)) = (
+            var("GITHUB_SERVER_URL"),
+            var("GITHUB_REPOSITORY"),
+            var("GITHUB_RUN_ID"),
+        ) {
+            meta.insert(
+                "build_url".into(),
+                format!("{}/{}/actions/runs/{}", server, repo, run_id),
+            );
+        }
+
+        return meta;
+    }
+
+    if var("TF_BUILD").is_some() {
+        meta.insert("ci".into(), "azure".into());
+        insert(&mut meta, "commit", var("BUILD_SOURCEVERSION"));
+        insert(&mut meta, "branch", var("BUILD_SOURCEBRANCHNAME"));
+
+        if let (Some(collection), Some(project), Some(build_id)) = (
+            var("SYSTEM_COLLECTIONURI"),
+            var("SYSTEM_TEAMPROJECT"),
+            var("BUILD_BUILDID"),
+        ) {
+            meta.insert(
+                "build_url".into(),
+                format!(
+                    "{}{}/_build/results?buildId={}",
+                    collection, project, build_id
+                ),
+            );
+        }
+
+        return meta;
+    }
+
+    for (provider, detect, commit, branch, build_url) in PROVIDERS {
+        if var(detect).is_some() {
+            meta.insert("ci".into(), provider.to_string());
+            insert(&mut meta, "commit", var(commit));
+            //Jenkins only sets `BRANCH_NAME` for multibranch pipelines
+            insert(
+                &mut meta,
+                "branch",
+                var(branch).or_else(|| var("GIT_BRANCH")),
+            );
+            insert(&mut meta, "build_url", var(build_url));
+            return meta;
+        }
+    }
+
+    meta
+}
+
+fn insert(meta: &mut LinkedHashMap<String, String>, key: &str, value: Option<String>) {
+    if let Some(value) = value {
+        meta.insert(key.into(), value);
+    }
+}
+
+// Parses a `key=value` pair from the command line
+pub fn parse_meta(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected `key=value`, got `{}`", input)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn detect_github_actions() {
+        let vars: HashMap<&str, &str> = vec![
+            ("GITHUB_ACTIONS", "true"),
+            ("GITHUB_SHA", "abc123"),
+            ("GITHUB_REF_NAME", "main"),
+            ("GITHUB_SERVER_URL", "https://github.com"),
+            ("GITHUB_REPOSITORY", "cetra3/lorikeet"),
+            ("GITHUB_RUN_ID", "42"),
+        ]
+        .into_iter()
+        .collect();
+
+        let meta = ci_meta(|key| vars.get(key).map(|val| val.to_string()));
+
+        assert_eq!(meta.get("ci").map(String::as_str), Some("github"));
+        assert_eq!(meta.get("commit").map(String::as_str), Some("abc123"));
+        assert_eq!(meta.get("branch").map(String::as_str), Some("main"));
+        assert_eq!(
+            meta.get("build_url").map(String::as_str),
+            Some("https://github.com/cetra3/lorikeet/actions/runs/42")
+        );
+    }
+}
@@ -0,0 +1,703 @@
+use anyhow::{anyhow, Error};
+use linked_hash_map::LinkedHashMap;
+use serde_yaml::{Mapping, Value};
+
+use std::collections::HashSet;
+
+// The plan converted from Nagios/Icinga object definitions, along with why anything couldn't be converted
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub plan: String,
+    pub skipped: Vec<String>,
+}
+
+// A `define <kind> { ... }` block
+#[derive(Debug)]
+struct Object {
+    kind: String,
+    fields: LinkedHashMap<String, String>,
+}
+
+impl Object {
+    fn get(&self, field: &str) -> Option<&str> {
+        self.fields.get(field).map(String::as_str)
+    }
+}
+
+// An option a plugin takes, by its short & long name, and whether it's followed by a value
+type PluginOption = (char, &'static str, bool);
+
+const CHECK_HTTP: &[PluginOption] = &[
+    ('H', "hostname", true),
+    ('I', "IP-address", true),
+    ('u', "url", true),
+    ('p', "port", true),
+    ('S', "ssl", false),
+    ('e', "expect", true),
+    ('s', "string", true),
+    ('r', "regex", true),
+    ('R', "eregi", true),
+    ('t', "timeout", true),
+    ('w', "warning", true),
+    ('c', "critical", true),
+    ('k', "header", true),
+    ('a', "authorization", true),
+    ('f', "onredirect", true),
+    ('j', "method", true),
+    ('P', "post", true),
+    ('T', "content-type", true),
+    ('A', "useragent", true),
+    //These don't change what's checked
+    ('N', "no-body", false),
+    (' ', "sni", false),
+    ('4', "use-ipv4", false),
+    ('6', "use-ipv6", false),
+    ('v', "verbose", false),
+];
+
+const CHECK_TCP: &[PluginOption] = &[
+    ('H', "hostname", true),
+    ('p', "port", true),
+    ('t', "timeout", true),
+    ('w', "warning", true),
+    ('c', "critical", true),
+    ('4', "use-ipv4", false),
+    ('6', "use-ipv6", false),
+    ('v', "verbose", false),
+];
+
+const CHECK_DISK: &[PluginOption] = &[
+    ('w', "warning", true),
+    ('c', "critical", true),
+    ('p', "path", true),
+    ('u', "units", true),
+    ('v', "verbose", false),
+];
+
+// Converts the `check_http`, `check_tcp` & `check_disk` services (and commands no service uses) into a test plan.
+// Each service becomes a step, with the `$ARGn$` & `$HOSTADDRESS$` macros of its command filled in
+pub fn import_nagios(config: &str) -> Result<Imported, Error> {
+    let objects = parse_objects(config)?;
+
+    let commands: LinkedHashMap<&str, &Object> = objects
+        .iter()
+        .filter(|object| object.kind == "command")
+        .filter_map(|object| object.get("command_name").map(|name| (name, object)))
+        .collect();
+
+    let hosts: LinkedHashMap<&str, &str> = objects
+        .iter()
+        .filter(|object| object.kind == "host")
+        .filter_map(|object| {
+            let name = object.get("host_name")?;
+            Some((name, object.get("address").unwrap_or(name)))
+        })
+        .collect();
+
+    let mut imported = Imported::default();
+    let mut steps = Mapping::new();
+    let mut used = HashSet::new();
+
+    for service in objects.iter().filter(|object| object.kind == "service") {
+        //Templates aren't checked themselves
+        if service.get("register") == Some("0") {
+            continue;
+        }
+
+        let description = service.get("service_description").unwrap_or_default();
+
+        let check_command = match service.get("check_command") {
+            Some(check_command) => check_command,
+            None => continue,
+        };
+
+        //Arguments are separated by `!`, i.e, `check_http!/health`
+        let mut args = check_command.split('!');
+        let command_name = args.next().unwrap_or_default();
+        let args: Vec<&str> = args.collect();
+
+        used.insert(command_name);
+
+        let command = match commands.get(command_name) {
+            Some(command) => command,
+            None => {
+                imported.skipped.push(format!(
+                    "Service `{}`: The command `{}` isn't defined",
+                    description, command_name
+                ));
+                continue;
+            }
+        };
+
+        let host_names = match service.get("host_name") {
+            Some(host_names) => host_names,
+            None => {
+                imported.skipped.push(format!(
+                    "Service `{}`: Only services with a `host_name` are supported",
+                    description
+                ));
+                continue;
+            }
+        };
+
+        for host_name in host_names.split(',').map(str::trim) {
+            let address = hosts.get(host_name).copied().unwrap_or(host_name);
+
+            let command_line = command
+                .get("command_line")
+                .unwrap_or_default()
+                .replace("$HOSTADDRESS$", address)
+                .replace("$HOSTNAME$", host_name)
+                .replace("$SERVICEDESC$", description);
+
+            //Later arguments first, so `$ARG1$` doesn't replace the start of `$ARG10$`
+            let command_line = args
+                .iter()
+                .enumerate()
+                .rev()
+                .fold(command_line, |line, (index, arg)| {
+                    line.replace(&format!("$ARG{}$", index + 1), arg)
+                });
+
+            let name = format!("{}_{}", host_name, description);
+
+            match convert(&command_line) {
+                Ok(step) => insert_step(&mut steps, &name, description, step),
+                Err(err) => imported.skipped.push(format!(
+                    "Service `{}` on `{}`: {}",
+                    description, host_name, err
+                )),
+            }
+        }
+    }
+
+    //Commands can also be checked on their own, if they don't need anything from a service
+    for (name, command) in commands.iter() {
+        if used.contains(name) {
+            continue;
+        }
+
+        match convert(command.get("command_line").unwrap_or_default()) {
+            Ok(step) => insert_step(&mut steps, name, name, step),
+            Err(err) => imported
+                .skipped
+                .push(format!("Command `{}`: {}", name, err)),
+        }
+    }
+
+    if steps.is_empty() {
+        return Err(anyhow!(
+            "No check_http, check_tcp or check_disk checks could be converted"
+        ));
+    }
+
+    imported.plan = serde_yaml::to_string(&Value::Mapping(steps))?;
+
+    Ok(imported)
+}
+
+fn insert_step(steps: &mut Mapping, name: &str, description: &str, step: Mapping) {
+    let slug = step_name(name);
+    let mut name = slug.clone();
+
+    //Names can clash once they're slugified
+    let mut suffix = 1;
+    while steps.contains_key(&Value::from(name.as_str())) {
+        suffix += 1;
+        name = format!("{}_{}", slug, suffix);
+    }
+
+    let mut with_description = Mapping::new();
+    with_description.insert("description".into(), description.into());
+
+    //The description goes first, so it reads like a hand written plan
+    for (key, value) in step {
+        with_description.insert(key, value);
+    }
+
+    steps.insert(name.into(), Value::Mapping(with_description));
+}
+
+// Parses the `define <kind> { <field> <value> }` blocks, ignoring comments
+fn parse_objects(config: &str) -> Result<Vec<Object>, Error> {
+    let mut objects = Vec::new();
+    let mut current: Option<Object> = None;
+
+    for (index, line) in config.lines().enumerate() {
+        let line = strip_comment(line);
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        match current {
+            None => {
+                let kind = line
+                    .strip_prefix("define")
+                    .and_then(|rest| rest.trim().strip_suffix('{'))
+                    .map(str::trim)
+                    .filter(|kind| !kind.is_empty())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Expected `define <type> {{` on line {}, found `{}`",
+                            index + 1,
+                            line
+                        )
+                    })?;
+
+                current = Some(Object {
+                    kind: kind.to_string(),
+                    fields: LinkedHashMap::new(),
+                });
+            }
+            Some(ref mut object) => {
+                if line == "}" {
+                    objects.extend(current.take());
+                    continue;
+                }
+
+                let (field, value) = match line.find(char::is_whitespace) {
+                    Some(split) => (&line[..split], line[split..].trim()),
+                    None => (line, ""),
+                };
+
+                object.fields.insert(field.to_string(), value.to_string());
+            }
+        }
+    }
+
+    if let Some(object) = current {
+        return Err(anyhow!("The `{}` definition isn't closed", object.kind));
+    }
+
+    Ok(objects)
+}
+
+// Removes `#` comment lines & trailing `;` comments.  A `\;` is a literal semicolon
+fn strip_comment(line: &str) -> String {
+    if line.trim_start().starts_with('#') {
+        return String::new();
+    }
+
+    let mut stripped = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if chars.peek() == Some(&';') => {
+                stripped.push(';');
+                chars.next();
+            }
+            ';' => break,
+            _ => stripped.push(ch),
+        }
+    }
+
+    stripped
+}
+
+// Converts a command line to a step, if the plugin & all its options are supported
+fn convert(command_line: &str) -> Result<Mapping, String> {
+    let words = shlex::split(command_line)
+        .ok_or_else(|| "The command line has unclosed quotes".to_string())?;
+
+    if let Some(word) = words.iter().find(|word| {
+        word.contains('$')
+            && !word
+                .trim_start_matches("$USER")
+                .trim_start_matches(|ch: char| ch.is_ascii_digit())
+                .starts_with('$')
+    }) {
+        return Err(format!("`{}` uses a macro that isn't known here", word));
+    }
+
+    let (plugin, args) = words
+        .split_first()
+        .ok_or_else(|| "The command line is empty".to_string())?;
+
+    //Plugins are usually run from `$USER1$`, i.e, `$USER1$/check_http`
+    match plugin.rsplit('/').next().unwrap_or_default() {
+        "check_http" => check_http(&parse_args(args, CHECK_HTTP)?),
+        "check_tcp" => check_tcp(&parse_args(args, CHECK_TCP)?),
+        "check_disk" => check_disk(&parse_args(args, CHECK_DISK)?),
+        plugin => Err(format!("`{}` can't be converted", plugin)),
+    }
+}
+
+// Parses the plugin's options, in any of the `-H host`, `-Hhost`, `--hostname host` or `--hostname=host` forms
+fn parse_args(args: &[String], options: &[PluginOption]) -> Result<Vec<(char, String)>, String> {
+    let mut parsed = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (option, attached) = if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.find('=') {
+                Some(split) => (&long[..split], Some(long[split + 1..].to_string())),
+                None => (long, None),
+            };
+
+            let option = options.iter().find(|(_, long, _)| *long == name);
+            (option, value)
+        } else if let Some(short) = arg.strip_prefix('-') {
+            let mut chars = short.chars();
+            let name = chars.next();
+            let value: String = chars.collect();
+
+            let option = options
+                .iter()
+                .find(|(short, _, _)| Some(*short) == name && *short != ' ');
+            (option, Some(value).filter(|value| !value.is_empty()))
+        } else {
+            return Err(format!("Unexpected argument `{}`", arg));
+        };
+
+        let (short, _, takes_value) =
+            option.ok_or_else(|| format!("The option `{}` isn't supported", arg))?;
+
+        let value = match (takes_value, attached) {
+            (true, Some(value)) => value,
+            (true, None) => args
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("The option `{}` needs a value", arg))?,
+            (false, value) => value.unwrap_or_default(),
+        };
+
+        parsed.push((*short, value));
+    }
+
+    Ok(parsed)
+}
+
+// The last value of an option
+fn arg(args: &[(char, String)], option: char) -> Option<&str> {
+    args.iter()
+        .rev()
+        .find(|(short, _)| *short == option)
+        .map(|(_, value)| value.as_str())
+}
+
+fn seconds_to_ms(option: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse::<f64>()
+        .map(|seconds| (seconds * 1000.0).round() as u64)
+        .map_err(|_| format!("The {} `{}` isn't a number of seconds", option, value))
+}
+
+fn check_http(args: &[(char, String)]) -> Result<Mapping, String> {
+    let host = arg(args, 'H')
+        .or_else(|| arg(args, 'I'))
+        .ok_or_else(|| "check_http needs a `-H` or `-I`".to_string())?;
+
+    let scheme = match arg(args, 'S') {
+        Some(_) => "https",
+        None => "http",
+    };
+
+    let port = match arg(args, 'p') {
+        Some(port) => format!(":{}", port),
+        None => String::new(),
+    };
+
+    let path = arg(args, 'u').unwrap_or("/");
+
+    let mut http = Mapping::new();
+    http.insert(
+        "url".into(),
+        format!("{}://{}{}{}", scheme, host, port, path).into(),
+    );
+
+    if let Some(method) = arg(args, 'j') {
+        http.insert("method".into(), method.into());
+    }
+
+    if let Some(expect) = arg(args, 'e') {
+        //i.e, `HTTP/1.1 200,HTTP/1.0 200` or `200,204`
+        let mut statuses: Vec<u16> = Vec::new();
+
+        for status in expect
+            .split(',')
+            .filter_map(|status| status.split_whitespace().last())
+            .filter_map(|status| status.parse().ok())
+        {
+            if !statuses.contains(&status) {
+                statuses.push(status);
+            }
+        }
+
+        match statuses.len() {
+            0 => return Err(format!("Could not read the expected status `{}`", expect)),
+            1 => {
+                http.insert("status".into(), statuses[0].into());
+            }
+            _ => {
+                let list = statuses.into_iter().map(Value::from).collect();
+                http.insert("status".into(), Value::Sequence(list));
+            }
+        }
+    }
+
+    let mut headers = Mapping::new();
+
+    for (short, value) in args.iter() {
+        match short {
+            'k' => {
+                let (name, value) = value
+                    .split_once(':')
+                    .ok_or_else(|| format!("The header `{}` has no value", value))?;
+                headers.insert(name.trim().into(), value.trim().into());
+            }
+            'T' => {
+                headers.insert("Content-Type".into(), value.as_str().into());
+            }
+            'A' => {
+                headers.insert("User-Agent".into(), value.as_str().into());
+            }
+            _ => (),
+        }
+    }
+
+    if !headers.is_empty() {
+        http.insert("headers".into(), Value::Mapping(headers));
+    }
+
+    if let Some(auth) = arg(args, 'a') {
+        let (user, pass) = auth.split_once(':').unwrap_or((auth, ""));
+        http.insert("user".into(), user.into());
+        http.insert("pass".into(), pass.into());
+    }
+
+    if let Some(body) = arg(args, 'P') {
+        http.insert("body".into(), body.into());
+    }
+
+    if let Some(timeout) = arg(args, 't') {
+        http.insert(
+            "timeout_ms".into(),
+            seconds_to_ms("timeout", timeout)?.into(),
+        );
+    }
+
+    //Only the critical response time fails a step, as there are no warnings
+    if let Some(critical) = arg(args, 'c') {
+        http.insert(
+            "max_time_ms".into(),
+            seconds_to_ms("critical response time", critical)?.into(),
+        );
+    }
+
+    if let Some("follow" | "sticky" | "stickyport") = arg(args, 'f') {
+        http.insert("follow_redirects".into(), true.into());
+    }
+
+    let mut step = Mapping::new();
+    step.insert("http".into(), Value::Mapping(http));
+
+    let content_checks: Vec<(char, &str)> = args
+        .iter()
+        .filter(|(short, _)| matches!(short, 's' | 'r' | 'R'))
+        .map(|(short, value)| (*short, value.as_str()))
+        .collect();
+
+    match content_checks[..] {
+        [] => (),
+        [(check, value)] => {
+            let regex = match check {
+                's' => regex::escape(value),
+                'R' => format!("(?i){}", value),
+                _ => value.to_string(),
+            };
+            step.insert("matches".into(), regex.into());
+        }
+        _ => return Err("Only one of `-s`, `-r` or `-R` is supported".into()),
+    }
+
+    Ok(step)
+}
+
+fn check_tcp(args: &[(char, String)]) -> Result<Mapping, String> {
+    let host = arg(args, 'H').ok_or_else(|| "check_tcp needs a `-H`".to_string())?;
+
+    let port: u16 = arg(args, 'p')
+        .ok_or_else(|| "check_tcp needs a `-p`".to_string())?
+        .parse()
+        .map_err(|_| "The port isn't a number".to_string())?;
+
+    //The host ends up in a bash command, so is kept to what a hostname or ip can contain
+    if host.is_empty()
+        || !host
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '-' | ':'))
+    {
+        return Err(format!("The host `{}` isn't a hostname or ip", host));
+    }
+
+    let timeout = match arg(args, 't') {
+        Some(timeout) => timeout
+            .parse::<u64>()
+            .map_err(|_| format!("The timeout `{}` isn't a number of seconds", timeout))?,
+        None => 10,
+    };
+
+    let mut step = Mapping::new();
+    step.insert(
+        "bash".into(),
+        format!("timeout {} bash -c '</dev/tcp/{}/{}'", timeout, host, port).into(),
+    );
+
+    Ok(step)
+}
+
+fn check_disk(args: &[(char, String)]) -> Result<Mapping, String> {
+    let paths: Vec<&str> = args
+        .iter()
+        .filter(|(short, _)| *short == 'p')
+        .map(|(_, value)| value.as_str())
+        .collect();
+
+    let mount = match paths[..] {
+        [mount] => mount,
+        [] => return Err("check_disk needs a `-p` path, as all disks can't be checked".into()),
+        _ => return Err("Only one `-p` path is supported".into()),
+    };
+
+    let mut disk = Mapping::new();
+    disk.insert("mount".into(), mount.into());
+    disk.insert("type".into(), "free".into());
+
+    //Only the critical threshold fails a step, as there are no warnings
+    let threshold = match arg(args, 'c') {
+        Some(critical) if critical.ends_with('%') => {
+            disk.insert("output_type".into(), "percent".into());
+            Some(critical.trim_end_matches('%').to_string())
+        }
+        Some(critical) => {
+            let units = arg(args, 'u').unwrap_or("MB");
+
+            let multiplier: u64 = match units.to_uppercase().as_str() {
+                "BYTES" | "B" => 1,
+                "KB" | "KIB" => 1 << 10,
+                "MB" | "MIB" => 1 << 20,
+                "GB" | "GIB" => 1 << 30,
+                "TB" | "TIB" => 1 << 40,
+                _ => return Err(format!("The units `{}` aren't supported", units)),
+            };
+
+            let critical: u64 = critical
+                .parse()
+                .map_err(|_| format!("The critical threshold `{}` isn't supported", critical))?;
+
+            Some((critical * multiplier).to_string())
+        }
+        None => None,
+    };
+
+    let mut step = Mapping::new();
+    step.insert("disk".into(), Value::Mapping(disk));
+
+    if let Some(threshold) = threshold {
+        step.insert("greater_than".into(), threshold.into());
+    }
+
+    Ok(step)
+}
+
+fn step_name(name: &str) -> String {
+    let slug: String = name
+        .to_lowercase()
+        .chars()
+        .map(|ch| if ch.is_alphanumeric() { ch } else { '_' })
+        .collect();
+
+    slug.split('_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yaml::get_steps_raw;
+    use std::collections::HashMap;
+
+    #[test]
+    fn imports_nagios_checks() {
+        let config = r#"
+# Commands
+define command {
+    command_name    check_http_health
+    command_line    $USER1$/check_http -H $HOSTADDRESS$ -S -u $ARG1$ -e "HTTP/1.1 200" -s "ok" -c 2.5
+}
+
+define command{
+    command_name    check_ssh
+    command_line    $USER1$/check_tcp -H $HOSTADDRESS$ -p 22 -t 5 ; inline comment
+}
+
+define command {
+    command_name    check_root
+    command_line    /usr/lib/nagios/plugins/check_disk -w 20% -c 10% -p /
+}
+
+define command {
+    command_name    check_load
+    command_line    $USER1$/check_load -w 5 -c 10
+}
+
+define host {
+    host_name       web01
+    address         10.0.0.1
+}
+
+define service {
+    host_name               web01
+    service_description     Health Check
+    check_command           check_http_health!/health
+}
+
+define service {
+    host_name               web01,web02
+    service_description     SSH
+    check_command           check_ssh
+}
+"#;
+
+        let imported = import_nagios(config).unwrap();
+
+        assert_eq!(
+            imported.skipped,
+            vec!["Command `check_load`: `check_load` can't be converted"]
+        );
+
+        let steps = get_steps_raw(&imported.plan, &HashMap::<String, String>::new()).unwrap();
+
+        assert_eq!(
+            steps.iter().map(|step| &*step.name).collect::<Vec<_>>(),
+            vec!["web01_health_check", "web01_ssh", "web02_ssh", "check_root"]
+        );
+
+        let plan: Value = serde_yaml::from_str(&imported.plan).unwrap();
+
+        assert_eq!(
+            plan["web01_health_check"]["http"]["url"],
+            Value::from("https://10.0.0.1/health")
+        );
+        assert_eq!(
+            plan["web01_health_check"]["http"]["status"],
+            Value::from(200)
+        );
+        assert_eq!(
+            plan["web01_health_check"]["http"]["max_time_ms"],
+            Value::from(2500)
+        );
+        assert_eq!(
+            plan["web02_ssh"]["bash"],
+            Value::from("timeout 5 bash -c '</dev/tcp/web02/22'")
+        );
+        assert_eq!(plan["check_root"]["greater_than"], Value::from("10"));
+
+        assert!(convert("check_http -H example.com --no-such-option").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use log::debug;
+use serde::Deserialize;
+
+use std::path::Path;
+
+use crate::reporter::{Reporter, RunInfo};
+use crate::step::ErrorCode;
+use crate::submitter::StepResult;
+use crate::yaml::config_section;
+
+// When each notifier is sent the results, from the `notify` section of the config.  Notifiers without a rule are always sent them
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyRules {
+    pub slack: Option<NotifyRule>,
+    pub webhook: Option<NotifyRule>,
+    pub sentry: Option<NotifyRule>,
+}
+
+// Notify when at least `min_failures` steps fail, or a step with one of the `tags` fails.  With neither, any failure notifies
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyRule {
+    #[serde(default)]
+    pub min_failures: Option<usize>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl NotifyRules {
+    // Loads the rules from the config file, if there is one
+    pub fn load<P: AsRef<Path>>(config_path: Option<P>) -> Result<Self, Error> {
+        match config_section(config_path, "notify")? {
+            Some(notify) => serde_yaml::from_value(notify)
+                .map_err(|err| anyhow!("Could not parse `notify` in config: {}", err)),
+            None => Ok(NotifyRules::default()),
+        }
+    }
+}
+
+impl NotifyRule {
+    pub fn matches(&self, results: &[StepResult]) -> bool {
+        let failed: Vec<&StepResult> = results.iter().filter(|result| !result.pass).collect();
+
+        if failed.is_empty() {
+            return false;
+        }
+
+        let enough_failed = self
+            .min_failures
+            .map(|min| failed.len() >= min)
+            .unwrap_or(false);
+
+        let tag_failed = failed
+            .iter()
+            .any(|result| result.tags.iter().any(|tag| self.tags.contains(tag)));
+
+        //Like gating, errors loading the plan always count
+        let plan_failed = failed
+            .iter()
+            .any(|result| result.code == Some(ErrorCode::PlanError));
+
+        match (self.min_failures, self.tags.is_empty()) {
+            (None, true) => true,
+            _ => enough_failed || tag_failed || plan_failed,
+        }
+    }
+}
+
+// A reporter that is only sent the results of the run when its rule matches
+pub struct Notifier<R> {
+    pub name: &'static str,
+    pub rule: Option<NotifyRule>,
+    pub reporter: R,
+}
+
+#[async_trait]
+impl<R: Reporter> Reporter for Notifier<R> {
+    fn step_started(&self, name: &str) {
+        self.reporter.step_started(name);
+    }
+
+    fn step_finished(&self, result: &StepResult) {
+        self.reporter.step_finished(result);
+    }
+
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        match self.rule {
+            Some(ref rule) if !rule.matches(results) => {
+                debug!(
+                    "Not notifying {}, as the `notify` rule didn't match",
+                    self.name
+                );
+                Ok(())
+            }
+            _ => self.reporter.run_finished(results, run).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submitter::test_result;
+
+    #[test]
+    fn rules_match_failures() {
+        let results: Vec<StepResult> = vec![
+            ("checkout", false, vec!["critical"]),
+            ("blog", false, vec!["marketing"]),
+            ("home", true, vec!["critical"]),
+        ]
+        .into_iter()
+        .map(|(name, pass, tags)| StepResult {
+            tags: tags.into_iter().map(String::from).collect(),
+            ..test_result(name, pass)
+        })
+        .collect();
+
+        let rule = |min_failures: Option<usize>, tags: &[&str]| NotifyRule {
+            min_failures,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+
+        assert!(rule(None, &[]).matches(&results));
+        assert!(rule(Some(2), &[]).matches(&results));
+        assert!(!rule(Some(3), &[]).matches(&results));
+        assert!(rule(Some(3), &["critical"]).matches(&results));
+        assert!(!rule(None, &["security"]).matches(&results));
+        assert!(!rule(None, &[]).matches(&results[2..]));
+    }
+}
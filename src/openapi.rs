@@ -0,0 +1,264 @@
+use anyhow::{anyhow, Error};
+use reqwest::Url;
+use serde_yaml::{Mapping, Value};
+
+// Generates a test plan with an http step for each `GET` endpoint in an OpenAPI (v2 or v3) spec.
+// Endpoints with path parameters are skipped, as there is no way to know what values to use
+pub fn generate_steps(spec: &str, base_url: Option<&str>) -> Result<String, Error> {
+    let spec: Value =
+        serde_yaml::from_str(spec).map_err(|err| anyhow!("Could not parse spec: {}", err))?;
+
+    let base_url = match base_url {
+        Some(url) => url.to_string(),
+        None => spec_base_url(&spec)?,
+    };
+
+    let paths = spec
+        .get("paths")
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| anyhow!("Spec does not contain any `paths`"))?;
+
+    let mut steps = Mapping::new();
+
+    for (path, item) in paths.iter() {
+        let path = match path.as_str() {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let operation = match item.get("get") {
+            Some(operation) => operation,
+            None => continue,
+        };
+
+        if path.contains('{') {
+            continue;
+        }
+
+        let name = operation
+            .get("operationId")
+            .and_then(Value::as_str)
+            .map(String::from)
+            .unwrap_or_else(|| step_name(path));
+
+        //Paths like `/users-list` & `/users_list` have the same name, so number the later ones
+        let name = unique_name(&steps, name);
+
+        let mut http = Mapping::new();
+        http.insert(
+            "url".into(),
+            format!("{}{}", base_url.trim_end_matches('/'), path).into(),
+        );
+        http.insert("status".into(), expected_status(operation).into());
+
+        let mut step = Mapping::new();
+
+        if let Some(summary) = operation.get("summary").and_then(Value::as_str) {
+            step.insert("description".into(), summary.into());
+        }
+
+        step.insert("http".into(), Value::Mapping(http));
+
+        steps.insert(name.into(), Value::Mapping(step));
+    }
+
+    if steps.is_empty() {
+        return Err(anyhow!(
+            "No GET endpoints without path parameters were found"
+        ));
+    }
+
+    Ok(serde_yaml::to_string(&Value::Mapping(steps))?)
+}
+
+fn spec_base_url(spec: &Value) -> Result<String, Error> {
+    //OpenAPI v3
+    if let Some(url) = spec
+        .get("servers")
+        .and_then(|servers| servers.get(0))
+        .and_then(|server| server.get("url"))
+        .and_then(Value::as_str)
+    {
+        //Relative server urls are relative to wherever the spec was served from, which isn't known here
+        return match Url::parse(url) {
+            Ok(_) => Ok(url.to_string()),
+            Err(_) => Err(anyhow!(
+                "The spec's server url `{}` is not absolute, please provide a base url",
+                url
+            )),
+        };
+    }
+
+    //Swagger v2
+    if let Some(host) = spec.get("host").and_then(Value::as_str) {
+        let scheme = spec
+            .get("schemes")
+            .and_then(|schemes| schemes.get(0))
+            .and_then(Value::as_str)
+            .unwrap_or("https");
+
+        let base_path = spec.get("basePath").and_then(Value::as_str).unwrap_or("");
+
+        return Ok(format!("{}://{}{}", scheme, host, base_path));
+    }
+
+    Err(anyhow!(
+        "Could not determine the base url from the spec, please provide one"
+    ))
+}
+
+// Uses the lowest 2xx response defined, falling back to `200`
+fn expected_status(operation: &Value) -> u64 {
+    operation
+        .get("responses")
+        .and_then(Value::as_mapping)
+        .map(|responses| {
+            responses
+                .iter()
+                .filter_map(|(code, _)| match code {
+                    Value::Number(num) => num.as_u64(),
+                    Value::String(val) => val.parse().ok(),
+                    _ => None,
+                })
+                .filter(|code| (200..300).contains(code))
+                .min()
+                .unwrap_or(200)
+        })
+        .unwrap_or(200)
+}
+
+fn unique_name(steps: &Mapping, name: String) -> String {
+    if !steps.contains_key(&Value::String(name.clone())) {
+        return name;
+    }
+
+    (2..)
+        .map(|suffix| format!("{}_{}", name, suffix))
+        .find(|name| !steps.contains_key(&Value::String(name.clone())))
+        .unwrap()
+}
+
+fn step_name(path: &str) -> String {
+    let slug: String = path
+        .chars()
+        .map(|ch| if ch.is_alphanumeric() { ch } else { '_' })
+        .collect();
+
+    let slug = slug.trim_matches('_');
+
+    if slug.is_empty() {
+        "get_root".into()
+    } else {
+        format!("get_{}", slug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(spec: &str, base_url: Option<&str>) -> Value {
+        serde_yaml::from_str(&generate_steps(spec, base_url).unwrap()).unwrap()
+    }
+
+    fn url(steps: &Value, name: &str) -> String {
+        steps[name]["http"]["url"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn uses_the_v2_base_url() {
+        let spec = "swagger: '2.0'\n\
+                    host: api.example.com\n\
+                    basePath: /v1\n\
+                    schemes: [http]\n\
+                    paths:\n  /users:\n    get: {}\n";
+
+        assert_eq!(
+            url(&generate(spec, None), "get_users"),
+            "http://api.example.com/v1/users"
+        );
+    }
+
+    #[test]
+    fn uses_the_v3_base_url() {
+        let spec = "openapi: 3.0.0\n\
+                    servers:\n  - url: https://api.example.com/v2/\n\
+                    paths:\n  /users:\n    get: {}\n";
+
+        assert_eq!(
+            url(&generate(spec, None), "get_users"),
+            "https://api.example.com/v2/users"
+        );
+
+        assert_eq!(
+            url(&generate(spec, Some("http://localhost:8080")), "get_users"),
+            "http://localhost:8080/users"
+        );
+    }
+
+    #[test]
+    fn rejects_relative_v3_base_urls() {
+        let spec = "openapi: 3.0.0\n\
+                    servers:\n  - url: /api\n\
+                    paths:\n  /users:\n    get: {}\n";
+
+        assert!(generate_steps(spec, None).is_err());
+
+        assert_eq!(
+            url(
+                &generate(spec, Some("https://example.com/api")),
+                "get_users"
+            ),
+            "https://example.com/api/users"
+        );
+    }
+
+    #[test]
+    fn skips_endpoints_with_path_parameters() {
+        let spec = "openapi: 3.0.0\n\
+                    paths:\n  /users:\n    get: {}\n  /users/{id}:\n    get: {}\n  /login:\n    post: {}\n";
+
+        let steps = generate(spec, Some("https://example.com"));
+
+        assert_eq!(steps.as_mapping().unwrap().len(), 1);
+        assert!(steps.get("get_users").is_some());
+
+        let only_parameters = "openapi: 3.0.0\npaths:\n  /users/{id}:\n    get: {}\n";
+
+        assert!(generate_steps(only_parameters, Some("https://example.com")).is_err());
+    }
+
+    #[test]
+    fn expects_the_lowest_success_status() {
+        let spec = "openapi: 3.0.0\n\
+                    paths:\n  \
+                      /jobs:\n    get:\n      responses:\n        '404': {}\n        '204': {}\n        '202': {}\n  \
+                      /users:\n    get:\n      responses:\n        default: {}\n";
+
+        let steps = generate(spec, Some("https://example.com"));
+
+        assert_eq!(steps["get_jobs"]["http"]["status"], Value::from(202));
+        assert_eq!(steps["get_users"]["http"]["status"], Value::from(200));
+    }
+
+    #[test]
+    fn numbers_steps_with_the_same_name() {
+        let spec = "openapi: 3.0.0\n\
+                    paths:\n  /users-list:\n    get: {}\n  /users_list:\n    get: {}\n  /users.list:\n    get: {}\n";
+
+        let steps = generate(spec, Some("https://example.com"));
+
+        assert_eq!(
+            url(&steps, "get_users_list"),
+            "https://example.com/users-list"
+        );
+        assert_eq!(
+            url(&steps, "get_users_list_2"),
+            "https://example.com/users_list"
+        );
+        assert_eq!(
+            url(&steps, "get_users_list_3"),
+            "https://example.com/users.list"
+        );
+    }
+}
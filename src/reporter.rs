@@ -0,0 +1,473 @@
+use anyhow::{anyhow, Error};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
+use linked_hash_map::LinkedHashMap;
+use serde::Serialize;
+use tokio::sync::mpsc::unbounded_channel;
+
+use std::fs::write;
+use std::path::{Path, PathBuf};
+
+use crate::baseline::Baseline;
+use crate::junit::create_junit;
+use crate::runner::{run_steps_with_options, RunOptions};
+use crate::signing::Signing;
+use crate::sql::{submit_sql, SqlDsn, SqlTables};
+use crate::step::Step;
+use crate::submitter::{
+    submit_sentry, submit_slack, submit_webhook, total_retries, webhook_payload, DurationFormat,
+    SentryDsn, StepResult, WebhookCompression,
+};
+use crate::syslog::submit_syslog;
+
+// Details of the run as a whole, for reporters that send the results somewhere
+#[derive(Clone, Debug, Default)]
+pub struct RunInfo {
+    pub id: String,
+    pub hostname: String,
+    pub meta: LinkedHashMap<String, String>,
+    pub started: DateTime<Utc>,
+}
+
+// A random id for a run, so the results of the run can be tied together
+pub fn new_run_id() -> String {
+    (0..16)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+// Something that is told about steps as they run, & the results once the run has finished
+#[async_trait]
+pub trait Reporter: Send + Sync {
+    fn step_started(&self, _name: &str) {}
+
+    // Called as each step finishes, including steps that aren't reported
+    fn step_finished(&self, _result: &StepResult) {}
+
+    // Called once every step has finished, with only the results that are reported
+    async fn run_finished(&self, _results: &[StepResult], _run: &RunInfo) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// The reporters for a run, which are told about each step in turn
+#[derive(Default)]
+pub struct Reporters {
+    //Durations are rounded before any reporter sees them, so they all show the same value
+    format: DurationFormat,
+    //Likewise, steps are compared against the baseline before they're reported
+    baseline: Option<Baseline>,
+    reporters: Vec<Box<dyn Reporter>>,
+}
+
+impl Reporters {
+    pub fn new(format: DurationFormat) -> Self {
+        Reporters {
+            format,
+            baseline: None,
+            reporters: Vec::new(),
+        }
+    }
+
+    pub fn with_baseline(mut self, baseline: Baseline) -> Self {
+        self.baseline = Some(baseline);
+        self
+    }
+
+    pub fn add<R: Reporter + 'static>(&mut self, reporter: R) {
+        self.reporters.push(Box::new(reporter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reporters.is_empty()
+    }
+
+    pub fn step_started(&self, name: &str) {
+        for reporter in self.reporters.iter() {
+            reporter.step_started(name);
+        }
+    }
+
+    pub fn step_finished(&self, result: &StepResult) {
+        for reporter in self.reporters.iter() {
+            reporter.step_finished(result);
+        }
+    }
+
+    // Tells every reporter the run has finished, returning the errors of any that failed
+    pub async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Vec<Error> {
+        let mut errors = Vec::new();
+
+        for reporter in self.reporters.iter() {
+            if let Err(err) = reporter.run_finished(results, run).await {
+                errors.push(err);
+            }
+        }
+
+        errors
+    }
+
+    // Runs the steps, telling the reporters as each one starts & finishes, & returns every result
+    pub async fn run_steps(
+        &self,
+        steps: Vec<Step>,
+        mut options: RunOptions,
+    ) -> Result<Vec<StepResult>, Error> {
+        let (tx_started, mut rx_started) = unbounded_channel();
+        options.started = Some(tx_started);
+
+        let mut stream = run_steps_with_options(steps, options)?;
+
+        let mut results = Vec::new();
+
+        loop {
+            //A step is always sent as started before it finishes, so checking for started steps first keeps them in order
+            tokio::select! {
+                biased;
+
+                Some(name) = rx_started.recv() => self.step_started(&name),
+                step = stream.next() => match step {
+                    Some(step) => {
+                        let mut result: StepResult = step.into();
+                        result.duration = self.format.round(result.duration);
+
+                        if let Some(ref baseline) = self.baseline {
+                            baseline.check(&mut result);
+                        }
+
+                        self.step_finished(&result);
+                        results.push(result);
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+// Prints each step as it finishes
+pub struct TerminalReporter {
+    pub colours: bool,
+    pub format: DurationFormat,
+    pub max_output_lines: Option<usize>,
+}
+
+#[async_trait]
+impl Reporter for TerminalReporter {
+    fn step_finished(&self, result: &StepResult) {
+        if result.is_reported() {
+            result.terminal_print(&self.colours, &self.format, self.max_output_lines);
+        }
+    }
+
+    async fn run_finished(&self, results: &[StepResult], _run: &RunInfo) -> Result<(), Error> {
+        let total_retries = total_retries(results);
+
+        if total_retries > 0 {
+            let retried = results.iter().filter(|val| val.attempts > 1).count();
+            println!(
+                "{} retr{} used across {} step{}",
+                total_retries,
+                if total_retries == 1 { "y" } else { "ies" },
+                retried,
+                if retried == 1 { "" } else { "s" }
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Prints a single json line summarising the run, so wrapper scripts get the totals when the terminal output is off
+pub struct SummaryReporter;
+
+#[derive(Debug, PartialEq, Serialize)]
+struct RunSummary<'a> {
+    run_id: &'a str,
+    hostname: &'a str,
+    total: usize,
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+    retries: usize,
+    duration_ms: i64,
+    has_errors: bool,
+}
+
+impl<'a> RunSummary<'a> {
+    fn new(results: &[StepResult], run: &'a RunInfo, finished: DateTime<Utc>) -> Self {
+        let failed = results.iter().filter(|result| !result.pass).count();
+        let skipped = results
+            .iter()
+            .filter(|result| result.skipped.is_some())
+            .count();
+
+        RunSummary {
+            run_id: &run.id,
+            hostname: &run.hostname,
+            total: results.len(),
+            passed: results.len() - failed - skipped,
+            failed,
+            skipped,
+            retries: total_retries(results),
+            duration_ms: (finished - run.started).num_milliseconds(),
+            has_errors: failed > 0,
+        }
+    }
+}
+
+#[async_trait]
+impl Reporter for SummaryReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        println!(
+            "{}",
+            serde_json::to_string(&RunSummary::new(results, run, Utc::now()))?
+        );
+
+        Ok(())
+    }
+}
+
+pub struct JunitReporter {
+    pub path: PathBuf,
+    pub format: DurationFormat,
+}
+
+#[async_trait]
+impl Reporter for JunitReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        create_junit(
+            results,
+            &self.path,
+            None,
+            &run.meta,
+            &self.format,
+            run.started,
+        )
+        .map_err(|err| {
+            anyhow!(
+                "Could not create junit file `{}`: {}",
+                self.path.display(),
+                err
+            )
+        })
+    }
+}
+
+// Writes the results to a file, in the same shape as the webhook payload
+pub struct JsonReporter {
+    pub path: PathBuf,
+    //Writes the signature to `<path>.sig`
+    pub signing: Option<Signing>,
+}
+
+#[async_trait]
+impl Reporter for JsonReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        let payload = webhook_payload(results, &run.hostname, &run.meta);
+
+        serde_json::to_string_pretty(&payload)
+            .map_err(Error::from)
+            .and_then(|json| {
+                if let Some(ref signing) = self.signing {
+                    write(
+                        signature_path(&self.path),
+                        signing.sign(json.as_bytes())? + "\n",
+                    )?;
+                }
+                write(&self.path, json).map_err(Error::from)
+            })
+            .map_err(|err| {
+                anyhow!(
+                    "Could not create json file `{}`: {}",
+                    self.path.display(),
+                    err
+                )
+            })
+    }
+}
+
+// The detached signature of a json file, i.e, `results.json.sig`
+fn signature_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".sig");
+    path.with_file_name(file_name)
+}
+
+pub struct WebhookReporter {
+    pub url: String,
+    pub signing: Option<Signing>,
+    pub compression: Option<WebhookCompression>,
+    pub attachments: bool,
+}
+
+#[async_trait]
+impl Reporter for WebhookReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        submit_webhook(
+            results,
+            &self.url,
+            &run.hostname,
+            &run.meta,
+            self.signing.as_ref(),
+            self.compression,
+            self.attachments,
+        )
+        .await
+        .map_err(|err| anyhow!("Could not send webhook: {}", err))
+    }
+}
+
+pub struct SlackReporter {
+    pub url: String,
+    pub format: DurationFormat,
+}
+
+#[async_trait]
+impl Reporter for SlackReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        submit_slack(results, &self.url, &run.hostname, &run.meta, &self.format)
+            .await
+            .map_err(|err| anyhow!("Could not send slack webhook: {}", err))
+    }
+}
+
+pub struct SentryReporter {
+    pub dsn: SentryDsn,
+}
+
+#[async_trait]
+impl Reporter for SentryReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        submit_sentry(results, &self.dsn, &run.hostname, &run.meta)
+            .await
+            .map_err(|err| anyhow!("Could not send sentry events: {}", err))
+    }
+}
+
+pub struct SqlReporter {
+    pub dsn: SqlDsn,
+    pub tables: SqlTables,
+}
+
+#[async_trait]
+impl Reporter for SqlReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        submit_sql(
+            results,
+            &self.dsn,
+            &self.tables,
+            &run.id,
+            &run.hostname,
+            &run.meta,
+            run.started,
+        )
+        .await
+        .map_err(|err| anyhow!("Could not insert results into the database: {}", err))
+    }
+}
+
+pub struct SyslogReporter;
+
+#[async_trait]
+impl Reporter for SyslogReporter {
+    async fn run_finished(&self, results: &[StepResult], run: &RunInfo) -> Result<(), Error> {
+        submit_syslog(results, &run.meta)
+            .map_err(|err| anyhow!("Could not send results to syslog: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submitter::test_result;
+    use crate::yaml::get_steps_raw;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Reporter for Recorder {
+        fn step_started(&self, name: &str) {
+            self.0.lock().unwrap().push(format!("started {}", name));
+        }
+
+        fn step_finished(&self, result: &StepResult) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("finished {}", result.name));
+        }
+    }
+
+    #[tokio::test]
+    async fn reporters_are_told_about_each_step() {
+        let steps = get_steps_raw(
+            "first:\n  value: a\nsecond:\n  value: b\n  require: first\n",
+            &HashMap::<String, String>::new(),
+        )
+        .unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let mut reporters = Reporters::default();
+        reporters.add(Recorder(events.clone()));
+
+        let results = reporters
+            .run_steps(steps, RunOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 2);
+
+        let events = events.lock().unwrap();
+        let position = |event: &str| events.iter().position(|val| val == event).unwrap();
+
+        assert_eq!(events.len(), 4);
+        assert!(position("started first") < position("finished first"));
+        assert!(position("finished first") < position("finished second"));
+        assert!(position("started second") < position("finished second"));
+    }
+
+    #[test]
+    fn summarises_the_run() {
+        let result = |name: &str, pass: bool, skipped: Option<&str>| StepResult {
+            duration: 1.0,
+            skipped: skipped.map(String::from),
+            ..test_result(name, pass)
+        };
+
+        let results = vec![
+            result("up", true, None),
+            result("down", false, None),
+            result("guarded", true, Some("Needs network access")),
+        ];
+
+        let run = RunInfo {
+            id: "abc123".into(),
+            hostname: "web01".into(),
+            ..Default::default()
+        };
+
+        let summary = RunSummary::new(&results, &run, run.started + chrono::Duration::seconds(2));
+
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "run_id": "abc123",
+                "hostname": "web01",
+                "total": 3,
+                "passed": 1,
+                "failed": 1,
+                "skipped": 1,
+                "retries": 0,
+                "duration_ms": 2000,
+                "has_errors": true
+            })
+        );
+    }
+}
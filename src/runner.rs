@@ -1,21 +1,37 @@
 use crate::step::FilterType;
 
 use futures::stream::Stream;
-use std::collections::HashMap;
+use futures::FutureExt;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, metadata, read, remove_dir};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
 
-use crate::step::{ExpectType, Outcome, RetryPolicy, RunType, Step, STEP_OUTPUT};
+use crate::step::{
+    host_pressure, register_session, with_plan, with_step, Attachment, ErrorCode, ExpectType,
+    HostGuard, Outcome, RetryPolicy, RunType, SharedRun, Step, Timing, STEP_BYTES, STEP_OUTPUT,
+    WORKDIR_PLACEHOLDER,
+};
 
+use crate::audit_log;
 use crate::graph::{create_graph, Require};
+use crate::yaml::with_workdir;
 use petgraph::prelude::GraphMap;
 use petgraph::{Directed, Direction};
 
+use linked_hash_map::LinkedHashMap;
 use log::*;
 
 use anyhow::Error;
+use chrono::{DateTime, Local, Utc};
 
 pub struct StepRunner {
     pub name: String,
@@ -25,30 +41,342 @@ pub struct StepRunner {
     pub expect: ExpectType,
     pub retry: RetryPolicy,
     pub filters: Vec<FilterType>,
+    pub binary: bool,
+    pub sensitive: bool,
+    pub shared: Option<SharedRun>,
+    pub setup: Option<SetupRun>,
+    pub workdir: Option<PathBuf>,
+    pub source: Option<serde_yaml::Value>,
+    pub attachments: LinkedHashMap<String, String>,
+    pub lock: Option<String>,
+    pub guard: HostGuard,
+    pub offline: bool,
+    pub every: Option<Duration>,
+    pub last_runs: LastRuns,
+    pub plan: Option<String>,
+    pub load_limit: LoadLimit,
+    pub run_start: Instant,
+    //When the dependencies of this step were met
+    pub ready: Option<Duration>,
     pub notify: UnboundedSender<(usize, Outcome)>,
+    pub started: Option<UnboundedSender<String>>,
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    //Directory to create step workdirs in.  If `None` a temporary directory is used
+    pub artifacts_dir: Option<PathBuf>,
+    //Keep the temporary directory after the run has finished
+    pub keep_artifacts: bool,
+    //Hold off starting bash & system steps while the host is busy
+    pub load_limit: LoadLimit,
+    //Run steps with identical run types once, sharing the output
+    pub dedup: bool,
+    //Skip steps that need network access
+    pub offline: bool,
+    //The last outcomes of steps that don't run every time, shared between runs on an interval
+    pub last_runs: LastRuns,
+    //The name of the plan when serving several, which keeps its step outputs apart from the others
+    pub plan: Option<String>,
+    //Sent the name of each step as it starts running
+    pub started: Option<UnboundedSender<String>>,
+}
+
+// The outcome of a step with `every`, along with when it ran
+type LastRun = (Instant, DateTime<Utc>, Outcome);
+
+#[derive(Clone, Debug, Default)]
+pub struct LastRuns(Arc<Mutex<HashMap<String, LastRun>>>);
+
+impl LastRuns {
+    // The last outcome of the step, if it ran less than `every` ago
+    fn get(&self, name: &str, every: Duration) -> Option<Outcome> {
+        let last_runs = self.0.lock().unwrap();
+
+        match last_runs.get(name) {
+            Some((ran, ran_at, outcome)) if ran.elapsed() < every => Some(Outcome {
+                last_run: Some(*ran_at),
+                ..outcome.clone()
+            }),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, name: &str, outcome: &Outcome) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(name.into(), (Instant::now(), Utc::now(), outcome.clone()));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadLimit {
+    pub max_load: Option<f64>,
+    pub max_memory_percent: Option<f64>,
+    //Run the step anyway after waiting this long, so a busy host doesn't stall the run
+    pub max_wait: Duration,
+}
+
+impl LoadLimit {
+    pub fn is_enabled(&self) -> bool {
+        self.max_load.is_some() || self.max_memory_percent.is_some()
+    }
+
+    // Waits until the load & memory usage are below the limits, or `max_wait` has passed
+    async fn wait(&self, name: &str) {
+        let start = Instant::now();
+
+        loop {
+            let (load, mem_used) = match host_pressure().await {
+                Ok(pressure) => pressure,
+                Err(err) => {
+                    warn!("Not waiting for load to drop: {}", err);
+                    return;
+                }
+            };
+
+            let over_load = self.max_load.map(|max| load > max).unwrap_or(false);
+            let over_memory = self
+                .max_memory_percent
+                .map(|max| mem_used > max)
+                .unwrap_or(false);
+
+            if !over_load && !over_memory {
+                return;
+            }
+
+            if start.elapsed() >= self.max_wait {
+                warn!(
+                    "Starting `{}` after waiting {:?}, load: {:.2}, memory used: {:.1}%",
+                    name, self.max_wait, load, mem_used
+                );
+                return;
+            }
+
+            debug!(
+                "Holding off `{}`, load: {:.2}, memory used: {:.1}%",
+                name, load, mem_used
+            );
+
+            sleep(LOAD_POLL_INTERVAL).await;
+        }
+    }
+}
+
+const LOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 //Spawns into a background task so we can poll the rest
 impl StepRunner {
     pub fn poll(self) {
         debug!("Running: {}", self.name);
 
-        tokio::spawn(async move {
-            let outcome = self
-                .run
-                .execute(self.expect, self.filters, self.retry, self.on_fail)
-                .await;
+        let plan = self.plan.clone();
+
+        //The runner is lost if the step panics, so these are kept to still report it as failed
+        let name = self.name.clone();
+        let index = self.index;
+        let notify = self.notify.clone();
+        let run_start = self.run_start;
+        let ready = self.ready;
+
+        tokio::spawn(with_plan(plan, async move {
+            if let Err(panic) = AssertUnwindSafe(self.run_step()).catch_unwind().await {
+                let message = panic_message(panic.as_ref());
+
+                error!("Step `{}` panicked: {}", name, message);
+
+                let finished = run_start.elapsed();
+
+                let outcome = Outcome {
+                    output: None,
+                    error: Some(format!("Step panicked: {}", message)),
+                    duration: Duration::from_secs(0),
+                    on_fail_output: None,
+                    on_fail_error: None,
+                    attempts: 1,
+                    max_attempts: 1,
+                    skipped: None,
+                    timing: Timing {
+                        ready: ready.unwrap_or(finished),
+                        started: ready.unwrap_or(finished),
+                        finished,
+                    },
+                    preview: None,
+                    bytes: None,
+                    code: Some(ErrorCode::Panicked),
+                    attachments: vec![],
+                    last_run: None,
+                };
+
+                //Without this the scheduler would wait on the step forever
+                if let Err(err) = notify.send((index, outcome)) {
+                    error!("Could not notify executor:{}", err);
+                }
+            }
+        }));
+    }
+
+    // Runs the step & notifies the scheduler of the outcome
+    async fn run_step(self) {
+        let started = self.run_start.elapsed();
+
+        if let Some(reason) = self.guard.check() {
+            debug!("Skipping `{}`: {}", self.name, reason);
+            self.skip(reason);
+            return;
+        }
+
+        if self.offline && self.run.needs_network() {
+            debug!("Skipping `{}` as it needs network access", self.name);
+            self.skip("Needs network access, but lorikeet is running `--offline`".into());
+            return;
+        }
+
+        if let Some(every) = self.every {
+            if let Some(outcome) = self.last_runs.get(&self.name, every) {
+                debug!("Carrying forward the last outcome of `{}`", self.name);
 
-            if let Some(ref output) = outcome.output {
-                STEP_OUTPUT.insert(self.name.clone(), output.clone());
+                if let Some(ref output) = outcome.output {
+                    STEP_OUTPUT.insert(self.name.clone(), output.clone());
+                }
+
+                self.finish(outcome, started);
+                return;
             }
+        }
 
-            if let Err(err) = self.notify.send((self.index, outcome)) {
-                error!("Could not notify executor:{}", err);
+        if self.load_limit.is_enabled() && matches!(self.run, RunType::Bash(_) | RunType::System(_))
+        {
+            self.load_limit.wait(&self.name).await;
+        }
+
+        let (run, on_fail) = match self.workdir {
+            Some(ref workdir) => {
+                match prepare_workdir(&self.run, &self.on_fail, self.source.as_ref(), workdir) {
+                    Ok(prepared) => prepared,
+                    Err(err) => {
+                        let outcome = Outcome {
+                            output: None,
+                            error: Some(err),
+                            duration: Duration::from_secs(0),
+                            on_fail_output: None,
+                            on_fail_error: None,
+                            attempts: 0,
+                            max_attempts: 0,
+                            skipped: None,
+                            timing: Timing::default(),
+                            preview: None,
+                            bytes: None,
+                            code: Some(ErrorCode::WorkdirFailed),
+                            attachments: vec![],
+                            last_run: None,
+                        };
+
+                        self.finish(outcome, started);
+                        return;
+                    }
+                }
             }
+            None => (self.run.clone(), self.on_fail.clone()),
+        };
 
-            debug!("Completed: {}", self.name);
-        });
+        if let Some(ref started) = self.started {
+            //Nobody may be listening anymore, which is fine
+            let _ = started.send(self.name.clone());
+        }
+
+        let execute = with_step(
+            self.name.clone(),
+            run.execute(
+                self.expect.clone(),
+                self.filters.clone(),
+                self.retry,
+                on_fail,
+                self.binary,
+                self.shared.clone(),
+                self.sensitive,
+            ),
+        );
+
+        //The first setup step to start runs, & the others wait for its outcome
+        let mut outcome = match self.setup {
+            Some(ref setup) => setup.0.get_or_init(|| execute).await.clone(),
+            None => execute.await,
+        };
+
+        outcome.attachments = collect_attachments(&self.attachments, self.workdir.as_deref());
+
+        if let Some(ref output) = outcome.output {
+            if self.sensitive {
+                audit_log::add_secret(output);
+            }
+
+            STEP_OUTPUT.insert(self.name.clone(), output.clone());
+        }
+
+        if let Some(bytes) = outcome.bytes.take() {
+            STEP_BYTES.insert(self.name.clone(), bytes);
+        }
+
+        if self.every.is_some() {
+            self.last_runs.insert(&self.name, &outcome);
+        }
+
+        //Any successful step can be used as a session by http steps
+        if outcome.error.is_none() {
+            register_session(&self.name, run, self.filters.clone());
+        }
+
+        self.finish(outcome, started);
+
+        debug!("Completed: {}", self.name);
+    }
+
+    fn skip(self, reason: String) {
+        let started = self.run_start.elapsed();
+
+        let outcome = Outcome {
+            output: None,
+            error: None,
+            duration: Duration::from_secs(0),
+            on_fail_output: None,
+            on_fail_error: None,
+            attempts: 0,
+            max_attempts: 0,
+            skipped: Some(reason),
+            timing: Timing::default(),
+            preview: None,
+            bytes: None,
+            code: None,
+            attachments: vec![],
+            last_run: None,
+        };
+
+        self.finish(outcome, started);
+    }
+
+    fn finish(&self, mut outcome: Outcome, started: Duration) {
+        outcome.timing = Timing {
+            ready: self.ready.unwrap_or(started),
+            started,
+            finished: self.run_start.elapsed(),
+        };
+
+        if let Err(err) = self.notify.send((self.index, outcome)) {
+            error!("Could not notify executor:{}", err);
+        }
+    }
+}
+
+// The message a step panicked with, which is almost always a string
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".into()
     }
 }
 
@@ -56,9 +384,18 @@ impl StepRunner {
 enum Status {
     Awaiting,
     Completed,
+    Skipped,
     Error,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+enum Readiness {
+    Wait,
+    Start,
+    //A dependency was skipped, so this step is skipped too
+    Skip(String),
+}
+
 pub struct StepStream {
     channel: UnboundedReceiver<Step>,
 }
@@ -71,11 +408,233 @@ impl Stream for StepStream {
     }
 }
 
+// Substitutes the workdir into the run types, creating the directory only if it's used
+fn prepare_workdir(
+    run: &RunType,
+    on_fail: &Option<RunType>,
+    source: Option<&serde_yaml::Value>,
+    workdir: &Path,
+) -> Result<(RunType, Option<RunType>), String> {
+    let (mut new_run, mut new_on_fail) = match source {
+        Some(source) => with_workdir(source, workdir)?,
+        None => (run.clone(), on_fail.clone()),
+    };
+
+    let mut used = new_run != *run || new_on_fail != *on_fail;
+
+    used |= new_run.default_workdir(workdir);
+
+    if let Some(ref mut on_fail) = new_on_fail {
+        used |= on_fail.default_workdir(workdir);
+    }
+
+    if used {
+        create_dir_all(workdir)
+            .map_err(|err| format!("Could not create workdir `{}`: {}", workdir.display(), err))?;
+    }
+
+    Ok((new_run, new_on_fail))
+}
+
+//Attachments are held in memory until they are reported, so large files are left out
+const MAX_ATTACHMENT_BYTES: u64 = 10 * 1024 * 1024;
+
+// Reads the files attached to a step, substituting the workdir into their paths.  Missing files are left out, as a failed step may not have created them
+fn collect_attachments(
+    attachments: &LinkedHashMap<String, String>,
+    workdir: Option<&Path>,
+) -> Vec<Attachment> {
+    let mut collected = Vec::new();
+
+    for (name, path) in attachments.iter() {
+        let path = PathBuf::from(match workdir {
+            Some(workdir) => path.replace(WORKDIR_PLACEHOLDER, &workdir.to_string_lossy()),
+            None => path.clone(),
+        });
+
+        match metadata(&path) {
+            Ok(meta) if meta.len() > MAX_ATTACHMENT_BYTES => {
+                warn!(
+                    "Not attaching `{}`, `{}` is larger than {} bytes",
+                    name,
+                    path.display(),
+                    MAX_ATTACHMENT_BYTES
+                );
+                continue;
+            }
+            Ok(_) => (),
+            Err(err) => {
+                debug!(
+                    "Not attaching `{}` from `{}`: {}",
+                    name,
+                    path.display(),
+                    err
+                );
+                continue;
+            }
+        }
+
+        match read(&path) {
+            Ok(contents) => collected.push(Attachment {
+                name: name.clone(),
+                file_name: path
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| name.clone()),
+                size: contents.len(),
+                path: None,
+                part: None,
+                contents,
+            }),
+            Err(err) => warn!("Could not attach `{}`: {}", path.display(), err),
+        }
+    }
+
+    collected
+}
+
+//The outcome of identical setup steps, which is shared between them
+#[derive(Clone, Default)]
+pub struct SetupRun(Arc<OnceCell<Outcome>>);
+
+// Groups setup steps that are the same apart from their name, so they run once
+fn setup_runs(steps: &HashMap<usize, Step>) -> HashMap<usize, SetupRun> {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for (idx, step) in steps.iter().filter(|(_, step)| step.setup) {
+        let mut require = step.require.clone();
+        require.sort();
+
+        let key = match serde_json::to_string(&(
+            &step.run,
+            &step.on_fail,
+            &step.filters,
+            &step.expect,
+            step.retry,
+            step.binary,
+            require,
+        )) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        groups.entry(key).or_default().push(*idx);
+    }
+
+    let mut setup = HashMap::new();
+
+    for indexes in groups.into_values().filter(|indexes| indexes.len() > 1) {
+        debug!("Setup steps {:?} run once", indexes);
+
+        let run = SetupRun::default();
+
+        for idx in indexes {
+            setup.insert(idx, run.clone());
+        }
+    }
+
+    setup
+}
+
+// Groups steps that have the same run type & dependencies, so they can share a single run
+fn shared_runs(steps: &HashMap<usize, Step>) -> HashMap<usize, SharedRun> {
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for (idx, step) in steps.iter() {
+        let mut require = step.require.clone();
+        require.sort();
+
+        //Each step has its own workdir, so these runs are never the same
+        if step.source.is_some() {
+            continue;
+        }
+
+        let key = match serde_json::to_string(&(&step.run, step.binary, require)) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        groups.entry(key).or_default().push(*idx);
+    }
+
+    let mut shared = HashMap::new();
+
+    for indexes in groups.into_values().filter(|indexes| indexes.len() > 1) {
+        debug!("Steps {:?} share the same run", indexes);
+
+        let run = SharedRun::default();
+
+        for idx in indexes {
+            shared.insert(idx, run.clone());
+        }
+    }
+
+    shared
+}
+
+// The directory for a step, prefixed by its index so steps with similar names don't share one
+pub(crate) fn workdir_name(index: usize, name: &str) -> String {
+    format!("{}-{}", index, dir_name(name))
+}
+
+// Step & plan names can contain anything, so only keep characters that are safe for a directory name
+pub fn dir_name(name: &str) -> String {
+    name.chars()
+        .map(|ch| {
+            if ch.is_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Creates a directory for the workdirs of this run within `dir`, so they are kept apart from other plans & runs
+fn run_dir(dir: &Path, plan: Option<&str>) -> std::io::Result<PathBuf> {
+    let dir = match plan {
+        Some(plan) => dir.join(dir_name(plan)),
+        None => dir.to_path_buf(),
+    };
+
+    create_dir_all(&dir)?;
+
+    let prefix = format!("{}-", Local::now().format("%Y%m%dT%H%M%S"));
+
+    Ok(tempfile::Builder::new()
+        .prefix(&prefix)
+        .tempdir_in(&dir)?
+        .into_path())
+}
+
 pub fn run_steps(steps: Vec<Step>) -> Result<StepStream, Error> {
+    run_steps_with_options(steps, RunOptions::default())
+}
+
+pub fn run_steps_with_options(steps: Vec<Step>, options: RunOptions) -> Result<StepStream, Error> {
     let graph = create_graph(&steps)?;
 
+    let run_start = Instant::now();
+
+    //The temporary directory is removed when dropped at the end of the run
+    let (temp_dir, base_dir) = match options.artifacts_dir {
+        Some(ref dir) => (None, run_dir(dir, options.plan.as_deref())?),
+        None => {
+            let temp_dir = tempfile::Builder::new().prefix("lorikeet").tempdir()?;
+            let base_dir = temp_dir.path().to_path_buf();
+            (Some(temp_dir), base_dir)
+        }
+    };
+
     let mut step_map = steps.into_iter().enumerate().collect::<HashMap<_, _>>();
 
+    let mut shared_runs = match options.dedup {
+        true => shared_runs(&step_map),
+        false => HashMap::new(),
+    };
+
+    let mut setup_runs = setup_runs(&step_map);
+
     let (tx_steps, rx_steps) = unbounded_channel();
 
     let step_stream = StepStream { channel: rx_steps };
@@ -97,27 +656,39 @@ pub fn run_steps(steps: Vec<Step>) -> Result<StepStream, Error> {
                     expect: step.expect.clone(),
                     retry: step.retry,
                     filters: step.filters.clone(),
+                    binary: step.binary,
+                    sensitive: step.sensitive,
+                    shared: shared_runs.remove(i),
+                    setup: setup_runs.remove(i),
                     name: step.name.clone(),
                     index: *i,
+                    workdir: Some(base_dir.join(workdir_name(*i, &step.name))),
+                    source: step.source.clone(),
+                    attachments: step.attachments.clone(),
+                    lock: step.lock.clone(),
+                    guard: step.guard.clone(),
+                    offline: options.offline,
+                    every: step.every,
+                    last_runs: options.last_runs.clone(),
+                    plan: options.plan.clone(),
+                    load_limit: options.load_limit,
+                    run_start,
+                    ready: None,
                     notify: tx.clone(),
+                    started: options.started.clone(),
                 };
 
                 runners.push(future);
             }
 
-            //We want to start all the ones that don't have any outgoing neighbors
-            let (to_start, waiting) = runners
-                .into_iter()
-                .partition::<Vec<StepRunner>, _>(|job| can_start(job.index, &statuses, &graph));
+            //Keep plan order so locked steps take turns in the order they're defined
+            runners.sort_by_key(|runner| runner.index);
 
-            runners = waiting;
+            //Named locks that are held by a running step
+            let mut held_locks = HashSet::new();
 
-            let mut active = 0;
-
-            for runner in to_start.into_iter() {
-                runner.poll();
-                active += 1;
-            }
+            //We want to start all the ones that don't have any outgoing neighbors
+            let mut active = start_ready(&mut runners, &statuses, &graph, &mut held_locks);
 
             while active > 0 {
                 debug!(
@@ -127,31 +698,26 @@ pub fn run_steps(steps: Vec<Step>) -> Result<StepStream, Error> {
                 );
                 if let Some((idx, outcome)) = rx.recv().await {
                     active -= 1;
-                    let has_error = outcome.error.is_some();
-
-                    statuses[idx] = if has_error {
+                    statuses[idx] = if outcome.skipped.is_some() {
+                        Status::Skipped
+                    } else if outcome.error.is_some() {
                         Status::Error
                     } else {
                         Status::Completed
                     };
 
                     if let Some(mut step) = step_map.remove(&idx) {
+                        if let Some(ref lock) = step.lock {
+                            held_locks.remove(lock);
+                        }
+
                         step.outcome = Some(outcome);
                         if tx_steps.send(step).is_err() {
                             error!("Error sending step!");
                         }
                     }
 
-                    for neighbor in graph.neighbors_directed(idx, Direction::Outgoing) {
-                        if let Some(job_idx) = runners.iter().position(|job| job.index == neighbor)
-                        {
-                            if !has_error && can_start(runners[job_idx].index, &statuses, &graph) {
-                                let runner = runners.swap_remove(job_idx);
-                                runner.poll();
-                                active += 1;
-                            }
-                        }
-                    }
+                    active += start_ready(&mut runners, &statuses, &graph, &mut held_locks);
                 }
             }
         }
@@ -164,6 +730,15 @@ pub fn run_steps(steps: Vec<Step>) -> Result<StepStream, Error> {
                     duration: Duration::from_secs(0),
                     on_fail_output: None,
                     on_fail_error: None,
+                    attempts: 0,
+                    max_attempts: 0,
+                    skipped: None,
+                    timing: Timing::default(),
+                    preview: None,
+                    bytes: None,
+                    code: Some(ErrorCode::DepNotMet),
+                    attachments: vec![],
+                    last_run: None,
                 });
 
                 if tx_steps.send(step).is_err() {
@@ -171,29 +746,258 @@ pub fn run_steps(steps: Vec<Step>) -> Result<StepStream, Error> {
                 }
             }
         }
+
+        match temp_dir {
+            Some(temp_dir) if options.keep_artifacts => info!(
+                "Keeping step workdirs in `{}`",
+                temp_dir.into_path().display()
+            ),
+            Some(temp_dir) => {
+                if let Err(err) = temp_dir.close() {
+                    error!("Could not remove step workdirs: {}", err);
+                }
+            }
+            //Runs that didn't use a workdir don't leave an empty directory behind
+            None => {
+                if remove_dir(&base_dir).is_err() {
+                    info!("Keeping step workdirs in `{}`", base_dir.display());
+                }
+            }
+        }
     });
 
     Ok(step_stream)
 }
 
-fn can_start(idx: usize, statuses: &[Status], graph: &GraphMap<usize, Require, Directed>) -> bool {
+// Starts any waiting runners that have their dependencies met & whose lock is free, returning how many were started
+fn start_ready(
+    runners: &mut Vec<StepRunner>,
+    statuses: &[Status],
+    graph: &GraphMap<usize, Require, Directed>,
+    held_locks: &mut HashSet<String>,
+) -> usize {
+    let mut started = 0;
+    let mut idx = 0;
+
+    while idx < runners.len() {
+        let runner = &mut runners[idx];
+
+        let lock_free = match runner.lock {
+            Some(ref lock) => !held_locks.contains(lock),
+            None => true,
+        };
+
+        match can_start(runner.index, statuses, graph) {
+            Readiness::Start if !lock_free => {
+                //Record when the dependencies were met, so lock waits can be told apart
+                if runner.ready.is_none() {
+                    runner.ready = Some(runner.run_start.elapsed());
+                }
+                idx += 1;
+            }
+            Readiness::Start => {
+                let runner = runners.remove(idx);
+
+                if let Some(ref lock) = runner.lock {
+                    debug!("Step `{}` acquired lock `{}`", runner.name, lock);
+                    held_locks.insert(lock.clone());
+                }
+
+                runner.poll();
+                started += 1;
+            }
+            Readiness::Skip(reason) => {
+                runners.remove(idx).skip(reason);
+                started += 1;
+            }
+            _ => idx += 1,
+        }
+    }
+
+    started
+}
+
+fn can_start(
+    idx: usize,
+    statuses: &[Status],
+    graph: &GraphMap<usize, Require, Directed>,
+) -> Readiness {
     debug!("Checking if we can start for {}", idx);
 
+    let mut skipped = false;
+
     for neighbor in graph.neighbors_directed(idx, Direction::Incoming) {
         match statuses[neighbor] {
             Status::Awaiting => {
                 debug!("Neighbour {} Not Completed", neighbor);
-                return false;
+                return Readiness::Wait;
             }
             Status::Completed => {
                 debug!("Neighbour {} Completed", neighbor);
             }
+            Status::Skipped => {
+                debug!("Neighbour {} Skipped", neighbor);
+                skipped = true;
+            }
             Status::Error => {
                 debug!("Neighbour {} Has Error", neighbor);
-                return false;
+                return Readiness::Wait;
             }
         }
     }
 
-    true
+    if skipped {
+        Readiness::Skip("A dependency was skipped".into())
+    } else {
+        Readiness::Start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yaml::get_steps_raw;
+    use futures::StreamExt;
+
+    async fn run_plan(yaml: &str, options: RunOptions) -> HashMap<String, Outcome> {
+        let steps = get_steps_raw(yaml, &HashMap::<String, String>::new()).unwrap();
+
+        let finished = tokio::time::timeout(
+            Duration::from_secs(30),
+            run_steps_with_options(steps, options)
+                .unwrap()
+                .collect::<Vec<Step>>(),
+        )
+        .await
+        .expect("The run did not finish");
+
+        finished
+            .into_iter()
+            .map(|step| (step.name, step.outcome.unwrap()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn steps_with_similar_names_have_their_own_workdirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let artifacts_dir = dir.path().join("with spaces");
+
+        let yaml = "\"db.check\":\n  bash: cd {{workdir}} && pwd\n\
+                    \"db check\":\n  bash: cd {{workdir}} && pwd\n\
+                    db_check:\n  bash: cd {{workdir}} && pwd\n\
+                    unused:\n  value: nothing\n";
+
+        let outcomes = run_plan(
+            yaml,
+            RunOptions {
+                artifacts_dir: Some(artifacts_dir.clone()),
+                plan: Some("plan/one".into()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let workdirs: HashSet<String> = ["db.check", "db check", "db_check"]
+            .iter()
+            .map(|name| outcomes[*name].output.clone().unwrap())
+            .collect();
+
+        assert_eq!(workdirs.len(), 3, "{:?}", outcomes);
+
+        //Each run is kept in its own directory within the plan's
+        let plan_dir = artifacts_dir.join("plan_one");
+
+        let runs: Vec<PathBuf> = std::fs::read_dir(&plan_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+
+        assert_eq!(runs.len(), 1);
+
+        for workdir in workdirs {
+            assert_eq!(Path::new(workdir.trim()).parent(), Some(runs[0].as_path()));
+        }
+
+        assert!(!runs[0].join("3-unused").exists());
+    }
+
+    #[tokio::test]
+    async fn identical_setup_steps_run_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("runs.log");
+
+        let login = format!("echo login >> {}; echo token", log.display());
+        let other = format!("echo other >> {}; echo token", log.display());
+
+        let yaml = format!(
+            "login_a:\n  bash: {login}\n  setup: true\n\
+             login_b:\n  bash: {login}\n  setup: true\n\
+             login_c:\n  bash: {login}\n  setup: true\n\
+             login_other:\n  bash: {other}\n  setup: true\n",
+            login = serde_json::to_string(&login).unwrap(),
+            other = serde_json::to_string(&other).unwrap(),
+        );
+
+        let outcomes = run_plan(
+            &yaml,
+            RunOptions {
+                plan: Some("identical_setup_steps_run_once".into()),
+                ..RunOptions::default()
+            },
+        )
+        .await;
+
+        for name in ["login_a", "login_b", "login_c", "login_other"] {
+            assert_eq!(
+                outcomes[name].output.as_deref(),
+                Some("token\n"),
+                "{}",
+                name
+            );
+        }
+
+        let mut runs: Vec<String> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        runs.sort();
+
+        assert_eq!(runs, vec!["login", "other"]);
+    }
+
+    #[tokio::test]
+    async fn panicking_steps_fail_without_blocking_the_run() {
+        //A poisoned lock makes looking up the last run of an `every` step panic
+        let last_runs = LastRuns::default();
+        let poisoned = last_runs.clone();
+
+        std::thread::spawn(move || {
+            let _lock = poisoned.0.lock().unwrap();
+            panic!("poisoning the last runs");
+        })
+        .join()
+        .unwrap_err();
+
+        let outcomes = run_plan(
+            "expensive:\n  value: hi\n  every: 10m\n\
+             after_expensive:\n  value: after\n  require: expensive\n",
+            RunOptions {
+                last_runs,
+                plan: Some("panicking_steps_fail_without_blocking_the_run".into()),
+                ..RunOptions::default()
+            },
+        )
+        .await;
+
+        assert_eq!(outcomes["expensive"].code, Some(ErrorCode::Panicked));
+        assert!(outcomes["expensive"]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("Step panicked"));
+
+        assert_eq!(outcomes["after_expensive"].code, Some(ErrorCode::DepNotMet));
+        assert!(outcomes["after_expensive"].output.as_deref() != Some("after"));
+    }
 }
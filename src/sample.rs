@@ -0,0 +1,128 @@
+use crate::step::Step;
+
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+
+use log::debug;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleOptions {
+    //Run this many steps
+    pub count: Option<usize>,
+    //Run this percentage of steps
+    pub percent: Option<f64>,
+    //Seed to use so a sample can be reproduced.  A random one is chosen if not set
+    pub seed: Option<u64>,
+}
+
+impl SampleOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.count.is_some() || self.percent.is_some()
+    }
+}
+
+// Picks a random subset of steps to run, along with anything they depend on
+pub fn sample_steps(steps: Vec<Step>, options: &SampleOptions) -> Vec<Step> {
+    let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen());
+
+    let total = steps.len();
+
+    let amount = match (options.count, options.percent) {
+        (Some(count), _) => count,
+        (None, Some(percent)) => (total as f64 * percent / 100.0).ceil() as usize,
+        (None, None) => total,
+    }
+    .min(total);
+
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut selected: HashSet<&str> = index::sample(&mut rng, total, amount)
+        .into_iter()
+        .map(|idx| steps[idx].name.as_str())
+        .collect();
+
+    debug!("Sampled {} of {} steps with seed {}", amount, total, seed);
+
+    //Include dependencies, otherwise sampled steps would fail with `Dependency Not Met`
+    let mut pending: Vec<&str> = selected.iter().copied().collect();
+
+    while let Some(name) = pending.pop() {
+        let dependencies = steps.iter().filter(|step| {
+            step.required_by.iter().any(|req| req == name)
+                || steps
+                    .iter()
+                    .any(|other| other.name == name && other.require.contains(&step.name))
+        });
+
+        for dependency in dependencies {
+            if selected.insert(dependency.name.as_str()) {
+                pending.push(dependency.name.as_str());
+            }
+        }
+    }
+
+    let selected: HashSet<String> = selected.into_iter().map(String::from).collect();
+
+    steps
+        .into_iter()
+        .filter(|step| selected.contains(&step.name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step::{ExpectType, RetryPolicy, RunType};
+
+    fn step(name: &str, require: Vec<&str>) -> Step {
+        Step {
+            name: name.into(),
+            description: None,
+            run: RunType::Value(String::new()),
+            on_fail: None,
+            filters: vec![],
+            expect: ExpectType::Anything,
+            do_output: true,
+            sensitive: false,
+            binary: false,
+            report: true,
+            lock: None,
+            guard: Default::default(),
+            outcome: None,
+            retry: RetryPolicy::default(),
+            require: require.into_iter().map(String::from).collect(),
+            required_by: vec![],
+            meta: Default::default(),
+            tags: vec![],
+            attachments: Default::default(),
+            every: None,
+            setup: false,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn sample_includes_dependencies() {
+        let mut steps: Vec<Step> = (0..100)
+            .map(|idx| step(&format!("check_{}", idx), vec!["login"]))
+            .collect();
+        steps.push(step("login", vec![]));
+
+        let options = SampleOptions {
+            count: Some(5),
+            percent: None,
+            seed: Some(42),
+        };
+
+        let sampled = sample_steps(steps.clone(), &options);
+
+        assert_eq!(sampled.len(), 6);
+        assert!(sampled.iter().any(|step| step.name == "login"));
+
+        //The same seed gives the same sample
+        let again = sample_steps(steps, &options);
+        assert_eq!(sampled, again);
+    }
+}
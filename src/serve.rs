@@ -0,0 +1,538 @@
+use crate::step::{pending_gates, resolve_gate};
+use crate::submitter::StepResult;
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use linked_hash_map::LinkedHashMap;
+use log::{debug, error, warn};
+use openssl::memcmp;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub started: DateTime<Utc>,
+    pub duration_ms: f32,
+    pub has_errors: bool,
+    pub results: Vec<StepResult>,
+}
+
+impl RunRecord {
+    // The results by step name, which plans can use as `previous` in templates
+    pub fn results_by_name(&self) -> Value {
+        Value::Object(
+            self.results
+                .iter()
+                .filter_map(|result| {
+                    Some((result.name.clone(), serde_json::to_value(result).ok()?))
+                })
+                .collect(),
+        )
+    }
+}
+
+// The most recent runs, oldest first.  Older runs are pruned once there are more than `limit`
+#[derive(Debug)]
+pub struct History {
+    runs: VecDeque<RunRecord>,
+    limit: usize,
+    //Runs are also kept here, one json object per line, so they survive a restart
+    file: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new(limit: usize, file: Option<PathBuf>) -> History {
+        let mut history = History {
+            runs: VecDeque::new(),
+            limit: limit.max(1),
+            file,
+        };
+
+        if let Some(ref path) = history.file {
+            match std::fs::File::open(path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines().map_while(Result::ok) {
+                        match serde_json::from_str(&line) {
+                            Ok(run) => history.runs.push_back(run),
+                            Err(err) => warn!("Skipping invalid history entry: {}", err),
+                        }
+                    }
+                    history.prune();
+                }
+                Err(err) => debug!("No history loaded from `{}`: {}", path.display(), err),
+            }
+        }
+
+        history
+    }
+
+    pub fn push(&mut self, run: RunRecord) {
+        self.runs.push_back(run);
+        self.prune();
+
+        if let Err(err) = self.save() {
+            error!("Could not save history: {}", err);
+        }
+    }
+
+    pub fn latest(&self) -> Option<&RunRecord> {
+        self.runs.back()
+    }
+
+    // The runs that are kept, oldest first
+    pub fn runs(&self) -> impl DoubleEndedIterator<Item = &RunRecord> {
+        self.runs.iter()
+    }
+
+    fn prune(&mut self) {
+        while self.runs.len() > self.limit {
+            self.runs.pop_front();
+        }
+    }
+
+    // Rewrites the history file with the runs that are kept
+    fn save(&self) -> Result<(), Error> {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let temp_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp_path)?;
+
+        for run in self.runs.iter() {
+            writeln!(file, "{}", serde_json::to_string(run)?)?;
+        }
+
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+
+        Ok(())
+    }
+
+    // The percentage of runs that passed overall, and for each step
+    pub fn uptime(&self) -> Value {
+        let mut steps: LinkedHashMap<String, (usize, usize)> = LinkedHashMap::new();
+
+        for run in self.runs.iter() {
+            for result in run.results.iter() {
+                let entry = steps.entry(result.name.clone()).or_insert((0, 0));
+                entry.0 += 1;
+                if result.pass {
+                    entry.1 += 1;
+                }
+            }
+        }
+
+        let passed = self.runs.iter().filter(|run| !run.has_errors).count();
+
+        let steps: LinkedHashMap<String, Value> = steps
+            .into_iter()
+            .map(|(name, (runs, passed))| (name, uptime(runs, passed)))
+            .collect();
+
+        let mut overall = uptime(self.runs.len(), passed);
+        overall["steps"] = json!(steps);
+        overall
+    }
+}
+
+fn uptime(runs: usize, passed: usize) -> Value {
+    let percent = match runs {
+        0 => 100.0,
+        _ => 100.0 * passed as f64 / runs as f64,
+    };
+
+    json!({
+        "runs": runs,
+        "passed": passed,
+        "percent": percent,
+    })
+}
+
+// Parses a `name=path` plan from the command line.  Names are used in urls & file names, so are kept simple
+pub fn parse_plan(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((name, path))
+            if !name.is_empty()
+                && !path.is_empty()
+                && name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_') =>
+        {
+            Ok((name.to_string(), path.to_string()))
+        }
+        _ => Err(format!(
+            "Expected `name=path` with a name of letters, numbers, `-` or `_`, got `{}`",
+            input
+        )),
+    }
+}
+
+// A plan being run on an interval & served, by name
+#[derive(Clone)]
+pub struct ServedPlan {
+    //The plan name its steps are run with, which is empty for the test plan when there are no named plans
+    pub scope: String,
+    pub history: Arc<Mutex<History>>,
+    //Notified to run the plan straight away, rather than waiting for the interval
+    pub trigger: Arc<Notify>,
+}
+
+// Serves the results of each plan's runs, along with any manual gates waiting to be approved
+// Gates can only be approved or rejected with the `token`, as a bearer token
+pub async fn serve(
+    addr: SocketAddr,
+    plans: Arc<LinkedHashMap<String, ServedPlan>>,
+    token: Option<String>,
+) -> Result<(), Error> {
+    let token = Arc::new(token);
+
+    let make_service = make_service_fn(move |_| {
+        let plans = plans.clone();
+        let token = token.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let plans = plans.clone();
+                let token = token.clone();
+                async move { Ok::<_, Infallible>(handle(request, &plans, token.as_deref())) }
+            }))
+        }
+    });
+
+    Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Ok(())
+}
+
+fn handle(
+    request: Request<Body>,
+    plans: &LinkedHashMap<String, ServedPlan>,
+    token: Option<&str>,
+) -> Response<Body> {
+    debug!("{} {}", request.method(), request.uri());
+
+    let segments: Vec<String> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_encoding::percent_decode_str(segment)
+                .decode_utf8_lossy()
+                .to_string()
+        })
+        .collect();
+
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    //With a single plan, its results are also served at the top level
+    let only_plan = match plans.len() {
+        1 => plans.values().next(),
+        _ => None,
+    };
+
+    match (request.method(), segments.as_slice()) {
+        (&Method::GET, ["results", rest @ ..]) => match only_plan {
+            Some(plan) => results(plan, rest),
+            None => json_response(
+                StatusCode::NOT_FOUND,
+                &json!({"error": "There are several plans, use `/plans/<name>/results`"}),
+            ),
+        },
+        (&Method::GET, ["plans"]) => {
+            let plans: Vec<Value> = plans
+                .iter()
+                .map(|(name, plan)| {
+                    let history = plan.history.lock().unwrap();
+                    let latest = history.latest();
+
+                    json!({
+                        "name": name,
+                        "started": latest.map(|run| run.started),
+                        "has_errors": latest.map(|run| run.has_errors),
+                    })
+                })
+                .collect();
+
+            json_response(StatusCode::OK, &json!(plans))
+        }
+        (method, ["plans", name, rest @ ..]) => match (plans.get(*name), method, rest) {
+            (None, _, _) => json_response(
+                StatusCode::NOT_FOUND,
+                &json!({"error": format!("There is no plan named `{}`", name)}),
+            ),
+            (Some(plan), &Method::GET, ["results", rest @ ..]) => results(plan, rest),
+            (Some(plan), &Method::POST, ["run"]) => {
+                if let Some(response) = unauthorized(&request, token) {
+                    return response;
+                }
+
+                plan.trigger.notify_one();
+                json_response(
+                    StatusCode::ACCEPTED,
+                    &json!({"plan": name, "action": "run"}),
+                )
+            }
+            (Some(plan), method, ["gates", rest @ ..]) => {
+                gates(plan, method, rest, &request, token)
+            }
+            _ => json_response(StatusCode::NOT_FOUND, &json!({"error": "Not found"})),
+        },
+        (method, ["gates", rest @ ..]) => match only_plan {
+            Some(plan) => gates(plan, method, rest, &request, token),
+            None => json_response(
+                StatusCode::NOT_FOUND,
+                &json!({"error": "There are several plans, use `/plans/<name>/gates`"}),
+            ),
+        },
+        _ => json_response(StatusCode::NOT_FOUND, &json!({"error": "Not found"})),
+    }
+}
+
+// The gates of a plan that are waiting, or approves/rejects one of them
+fn gates(
+    plan: &ServedPlan,
+    method: &Method,
+    rest: &[&str],
+    request: &Request<Body>,
+    token: Option<&str>,
+) -> Response<Body> {
+    match (method, rest) {
+        (&Method::GET, []) => {
+            let gates: Vec<Value> = pending_gates(&plan.scope)
+                .into_iter()
+                .map(|(step, message)| json!({"step": step, "message": message}))
+                .collect();
+
+            json_response(StatusCode::OK, &json!(gates))
+        }
+        (&Method::POST, [step, action @ ("approve" | "reject")]) => {
+            if let Some(response) = unauthorized(request, token) {
+                return response;
+            }
+
+            match resolve_gate(&plan.scope, step, *action == "approve") {
+                true => json_response(StatusCode::OK, &json!({"step": step, "action": action})),
+                false => json_response(
+                    StatusCode::NOT_FOUND,
+                    &json!({"error": format!("Step `{}` is not waiting to be approved", step)}),
+                ),
+            }
+        }
+        _ => json_response(StatusCode::NOT_FOUND, &json!({"error": "Not found"})),
+    }
+}
+
+// The response to refuse the request with, if it doesn't have the bearer token.  Without a token, nothing can be changed over http
+fn unauthorized(request: &Request<Body>, token: Option<&str>) -> Option<Response<Body>> {
+    let token = match token {
+        Some(token) => token,
+        None => {
+            return Some(json_response(
+                StatusCode::FORBIDDEN,
+                &json!({"error": "Set `--serve-token` to allow changes over http"}),
+            ))
+        }
+    };
+
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    //Compared in constant time, so the token can't be guessed a byte at a time
+    match given.len() == token.len() && memcmp::eq(given.as_bytes(), token.as_bytes()) {
+        true => None,
+        false => Some(json_response(
+            StatusCode::UNAUTHORIZED,
+            &json!({"error": "A valid bearer token is needed"}),
+        )),
+    }
+}
+
+// The latest results of a plan, or with `/history` all the kept runs
+fn results(plan: &ServedPlan, rest: &[&str]) -> Response<Body> {
+    let history = plan.history.lock().unwrap();
+
+    match rest {
+        [] => match history.latest() {
+            Some(run) => json_response(StatusCode::OK, &json!(run)),
+            None => json_response(
+                StatusCode::NOT_FOUND,
+                &json!({"error": "The first run hasn't finished yet"}),
+            ),
+        },
+        ["history"] => json_response(
+            StatusCode::OK,
+            &json!({
+                "limit": history.limit,
+                "uptime": history.uptime(),
+                "runs": history.runs.iter().rev().collect::<Vec<_>>(),
+            }),
+        ),
+        _ => json_response(StatusCode::NOT_FOUND, &json!({"error": "Not found"})),
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::submitter::test_result;
+
+    fn run(pass: &[(&str, bool)]) -> RunRecord {
+        RunRecord {
+            started: Utc::now(),
+            duration_ms: 0.0,
+            has_errors: pass.iter().any(|(_, pass)| !pass),
+            results: pass
+                .iter()
+                .map(|(name, pass)| test_result(name, *pass))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn history_prunes_oldest_runs() {
+        let mut history = History::new(2, None);
+
+        history.push(run(&[("web", false)]));
+        history.push(run(&[("web", true), ("db", true)]));
+        history.push(run(&[("web", true), ("db", false)]));
+
+        let uptime = history.uptime();
+
+        assert_eq!(uptime["runs"], 2);
+        assert_eq!(uptime["passed"], 1);
+        assert_eq!(uptime["steps"]["web"]["percent"], 100.0);
+        assert_eq!(uptime["steps"]["db"]["percent"], 50.0);
+    }
+
+    #[test]
+    fn serves_each_plan() {
+        let plan = |runs: &[RunRecord]| {
+            let mut history = History::new(10, None);
+            for run in runs.iter() {
+                history.push(run.clone());
+            }
+            ServedPlan {
+                scope: String::new(),
+                history: Arc::new(Mutex::new(history)),
+                trigger: Arc::new(Notify::new()),
+            }
+        };
+
+        let mut plans = LinkedHashMap::new();
+        plans.insert("payments".to_string(), plan(&[run(&[("api", false)])]));
+        plans.insert("search".to_string(), plan(&[]));
+
+        let request = |plans: &LinkedHashMap<String, ServedPlan>, method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(AUTHORIZATION, "Bearer secret")
+                .body(Body::empty())
+                .unwrap();
+            handle(request, plans, Some("secret")).status()
+        };
+
+        assert_eq!(request(&plans, Method::GET, "/plans"), StatusCode::OK);
+        assert_eq!(
+            request(&plans, Method::GET, "/plans/payments/results"),
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&plans, Method::GET, "/plans/search/results"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&plans, Method::GET, "/plans/nope/results"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&plans, Method::GET, "/results"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&plans, Method::POST, "/plans/search/run"),
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            request(&plans, Method::GET, "/plans/search/gates"),
+            StatusCode::OK
+        );
+        assert_eq!(
+            request(&plans, Method::POST, "/plans/search/gates/deploy/approve"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            request(&plans, Method::GET, "/gates"),
+            StatusCode::NOT_FOUND
+        );
+
+        plans.remove("search");
+        assert_eq!(
+            request(&plans, Method::GET, "/results/history"),
+            StatusCode::OK
+        );
+        assert_eq!(request(&plans, Method::GET, "/gates"), StatusCode::OK);
+    }
+
+    #[test]
+    fn gates_need_the_token() {
+        let mut plans = LinkedHashMap::new();
+        plans.insert(
+            "default".to_string(),
+            ServedPlan {
+                scope: String::new(),
+                history: Arc::new(Mutex::new(History::new(10, None))),
+                trigger: Arc::new(Notify::new()),
+            },
+        );
+
+        let approve = |token: Option<&str>, given: Option<&str>| {
+            let mut request = Request::builder()
+                .method(Method::POST)
+                .uri("/gates/deploy/approve");
+
+            if let Some(given) = given {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", given));
+            }
+
+            handle(request.body(Body::empty()).unwrap(), &plans, token).status()
+        };
+
+        assert_eq!(approve(None, Some("secret")), StatusCode::FORBIDDEN);
+        assert_eq!(approve(Some("secret"), None), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            approve(Some("secret"), Some("guess")),
+            StatusCode::UNAUTHORIZED
+        );
+        //Authorized, but there's no gate waiting
+        assert_eq!(
+            approve(Some("secret"), Some("secret")),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{anyhow, Error};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde::Deserialize;
+
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+use crate::yaml::config_section;
+
+// Sent with webhooks, with the signature of the body
+pub const SIGNATURE_HEADER: &str = "X-Lorikeet-Signature";
+// Sent with webhooks when the key has an id, so collectors can tell which host's key to verify with
+pub const KEY_ID_HEADER: &str = "X-Lorikeet-Key-Id";
+
+// The `signing` section of the config.  Either a shared `hmac_key`, or an `ed25519_key` PEM file
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SigningConfig {
+    #[serde(default)]
+    hmac_key: Option<String>,
+    #[serde(default)]
+    ed25519_key: Option<PathBuf>,
+    #[serde(default)]
+    key_id: Option<String>,
+}
+
+#[derive(Clone)]
+enum SigningKey {
+    Hmac(PKey<Private>),
+    Ed25519(PKey<Private>),
+}
+
+// Signs the webhook payload & json output, so collectors can verify the results came from this host
+#[derive(Clone)]
+pub struct Signing {
+    key: SigningKey,
+    pub key_id: Option<String>,
+}
+
+impl Signing {
+    // Loads the key from the config file, if there is a `signing` section
+    pub fn load<P: AsRef<Path>>(config_path: Option<P>) -> Result<Option<Self>, Error> {
+        let config: SigningConfig = match config_section(config_path, "signing")? {
+            Some(section) => serde_yaml::from_value(section)
+                .map_err(|err| anyhow!("Could not parse `signing` in config: {}", err))?,
+            None => return Ok(None),
+        };
+
+        let key = match (config.hmac_key, config.ed25519_key) {
+            (Some(secret), None) => {
+                if secret.is_empty() {
+                    return Err(anyhow!("The `hmac_key` is empty"));
+                }
+                SigningKey::Hmac(PKey::hmac(secret.as_bytes())?)
+            }
+            (None, Some(path)) => {
+                let pem =
+                    read(&path).map_err(|err| anyhow!("Could not read key {:?}: {}", path, err))?;
+
+                let key = PKey::private_key_from_pem(&pem)
+                    .map_err(|err| anyhow!("Could not parse key {:?}: {}", path, err))?;
+
+                if key.id() != openssl::pkey::Id::ED25519 {
+                    return Err(anyhow!("The key {:?} is not an ed25519 key", path));
+                }
+
+                SigningKey::Ed25519(key)
+            }
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Specify either a `hmac_key` or `ed25519_key`, not both"
+                ))
+            }
+            (None, None) => return Err(anyhow!("Specify a `hmac_key` or `ed25519_key`")),
+        };
+
+        Ok(Some(Signing {
+            key,
+            key_id: config.key_id,
+        }))
+    }
+
+    // The signature of the exact bytes sent or written, as `<algorithm>=<base64 signature>`
+    pub fn sign(&self, body: &[u8]) -> Result<String, Error> {
+        match self.key {
+            SigningKey::Hmac(ref key) => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+                signer.update(body)?;
+
+                Ok(format!(
+                    "hmac-sha256={}",
+                    base64::encode(signer.sign_to_vec()?)
+                ))
+            }
+            SigningKey::Ed25519(ref key) => {
+                //Ed25519 hashes the message itself, so can only sign in one go
+                let mut signer = Signer::new_without_digest(key)?;
+
+                Ok(format!(
+                    "ed25519={}",
+                    base64::encode(signer.sign_oneshot_to_vec(body)?)
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn signs_payloads() {
+        let hmac = Signing {
+            key: SigningKey::Hmac(PKey::hmac(b"secret").unwrap()),
+            key_id: None,
+        };
+
+        //Matches `echo -n '{"tests":[]}' | openssl dgst -sha256 -hmac secret -binary | base64`
+        assert_eq!(
+            hmac.sign(br#"{"tests":[]}"#).unwrap(),
+            "hmac-sha256=XCfhtqFBLDgbpTzvu8hQVv7oWEvklN+WC87U9QhlLC4="
+        );
+
+        let key = PKey::generate_ed25519().unwrap();
+        let ed25519 = Signing {
+            key: SigningKey::Ed25519(key.clone()),
+            key_id: Some("web-01".into()),
+        };
+
+        let signature = ed25519.sign(br#"{"tests":[]}"#).unwrap();
+        let signature = base64::decode(signature.trim_start_matches("ed25519=")).unwrap();
+
+        let public = PKey::public_key_from_raw_bytes(
+            &key.raw_public_key().unwrap(),
+            openssl::pkey::Id::ED25519,
+        )
+        .unwrap();
+
+        let mut verifier = Verifier::new_without_digest(&public).unwrap();
+        assert!(verifier
+            .verify_oneshot(&signature, br#"{"tests":[]}"#)
+            .unwrap());
+    }
+}
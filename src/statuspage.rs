@@ -0,0 +1,321 @@
+use crate::aws::{region_from_env, Credentials, SigV4};
+use crate::serve::History;
+use crate::timezone::format_time;
+
+use anyhow::{anyhow, Error};
+use chrono::Utc;
+use linked_hash_map::LinkedHashMap;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+//Steps without the grouping meta key end up here
+const DEFAULT_GROUP: &str = "Other";
+
+// Where the status page is written
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Dir(PathBuf),
+    S3 { bucket: String, prefix: String },
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.strip_prefix("s3://") {
+            Some(location) => {
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+
+                if bucket.is_empty() {
+                    return Err(anyhow!("`{}` has no bucket", input));
+                }
+
+                Ok(Target::S3 {
+                    bucket: bucket.to_string(),
+                    prefix: prefix.trim_matches('/').to_string(),
+                })
+            }
+            None => Ok(Target::Dir(PathBuf::from(input))),
+        }
+    }
+}
+
+fn percent(runs: usize, passed: usize) -> f64 {
+    match runs {
+        0 => 100.0,
+        _ => 100.0 * passed as f64 / runs as f64,
+    }
+}
+
+fn status(up: usize, total: usize) -> &'static str {
+    match up {
+        _ if up == total => "operational",
+        0 => "outage",
+        _ => "degraded",
+    }
+}
+
+// The status of each step in the latest run, grouped by the `group_by` meta key, with uptime across the history.
+// Outputs & errors are left out, as the page is meant to be public
+pub fn render(history: &History, group_by: &str) -> Value {
+    let latest = match history.latest() {
+        Some(latest) => latest,
+        None => return json!({"status": "unknown", "groups": []}),
+    };
+
+    let mut groups: LinkedHashMap<String, Vec<Value>> = LinkedHashMap::new();
+
+    for result in latest.results.iter() {
+        let group = result
+            .meta
+            .get(group_by)
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_GROUP.to_string());
+
+        let (runs, passed) = history
+            .runs()
+            .flat_map(|run| run.results.iter())
+            .filter(|other| other.name == result.name)
+            .fold((0, 0), |(runs, passed), other| {
+                (runs + 1, passed + other.pass as usize)
+            });
+
+        groups.entry(group).or_insert_with(Vec::new).push(json!({
+            "name": result.name,
+            "description": result.description,
+            "status": if result.pass { "up" } else { "down" },
+            "uptime": percent(runs, passed),
+        }));
+    }
+
+    let groups: Vec<Value> = groups
+        .into_iter()
+        .map(|(name, steps)| {
+            let up = steps.iter().filter(|step| step["status"] == "up").count();
+
+            //A run counts towards the group's uptime if every step in the group passed
+            let names: Vec<&Value> = steps.iter().map(|step| &step["name"]).collect();
+
+            let (runs, passed) = history
+                .runs()
+                .map(|run| {
+                    run.results
+                        .iter()
+                        .filter(|result| names.iter().any(|name| **name == result.name))
+                        .collect::<Vec<_>>()
+                })
+                .filter(|results| !results.is_empty())
+                .fold((0, 0), |(runs, passed), results| {
+                    (
+                        runs + 1,
+                        passed + results.iter().all(|result| result.pass) as usize,
+                    )
+                });
+
+            json!({
+                "name": name,
+                "status": status(up, steps.len()),
+                "uptime": percent(runs, passed),
+                "steps": steps,
+            })
+        })
+        .collect();
+
+    let steps = latest.results.len();
+    let up = latest.results.iter().filter(|result| result.pass).count();
+
+    let runs = history.runs().count();
+    let passed = history.runs().filter(|run| !run.has_errors).count();
+
+    json!({
+        "updated": format_time(&latest.started),
+        "status": status(up, steps),
+        "uptime": percent(runs, passed),
+        "groups": groups,
+    })
+}
+
+fn escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn text(value: &Value) -> String {
+    escape(value.as_str().unwrap_or_default())
+}
+
+fn uptime(value: &Value) -> String {
+    format!("{:.2}%", value.as_f64().unwrap_or_default())
+}
+
+// Renders the output of `render` as a standalone html page
+pub fn render_html(status: &Value) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Status</title>\n<style>\n\
+         body { font-family: sans-serif; max-width: 50em; margin: 2em auto; color: #333; }\n\
+         table { width: 100%; border-collapse: collapse; margin-bottom: 2em; }\n\
+         th, td { text-align: left; padding: 0.5em; border-bottom: 1px solid #ddd; }\n\
+         .operational, .up { color: #2a2; } .degraded { color: #c80; } .outage, .down { color: #c22; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+
+    html.push_str(&format!(
+        "<h1 class=\"{0}\">{0}</h1>\n<p>Uptime {1}, updated {2}</p>\n",
+        text(&status["status"]),
+        uptime(&status["uptime"]),
+        text(&status["updated"]),
+    ));
+
+    for group in status["groups"].as_array().into_iter().flatten() {
+        html.push_str(&format!(
+            "<h2>{} <small class=\"{}\">{}</small></h2>\n<table>\n",
+            text(&group["name"]),
+            text(&group["status"]),
+            uptime(&group["uptime"]),
+        ));
+
+        for step in group["steps"].as_array().into_iter().flatten() {
+            html.push_str(&format!(
+                "<tr><td title=\"{}\">{}</td><td class=\"{}\">{}</td><td>{}</td></tr>\n",
+                text(&step["description"]),
+                text(&step["name"]),
+                text(&step["status"]),
+                text(&step["status"]),
+                uptime(&step["uptime"]),
+            ));
+        }
+
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+// Writes `index.html` & `status.json` to the target
+pub async fn publish(target: &Target, status: &Value) -> Result<(), Error> {
+    let files = [
+        (
+            "index.html",
+            "text/html; charset=utf-8",
+            render_html(status).into_bytes(),
+        ),
+        (
+            "status.json",
+            "application/json",
+            serde_json::to_vec_pretty(status)?,
+        ),
+    ];
+
+    match target {
+        Target::Dir(dir) => {
+            std::fs::create_dir_all(dir)?;
+
+            for (name, _, contents) in files.iter() {
+                //Write then rename, so the page is never served half written
+                let path = dir.join(name);
+                let temp_path = path.with_extension("tmp");
+                std::fs::write(&temp_path, contents)?;
+                std::fs::rename(&temp_path, &path)?;
+            }
+        }
+        Target::S3 { bucket, prefix } => {
+            let signer = SigV4 {
+                region: region_from_env(),
+                service: "s3".into(),
+                credentials: Credentials::from_env()?,
+            };
+
+            let client = reqwest::Client::new();
+
+            for (name, content_type, contents) in files.iter() {
+                let url = s3_url(bucket, prefix, name, &signer.region)?;
+
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                signer.sign("PUT", &url, &mut headers, contents, Utc::now())?;
+
+                client
+                    .put(url.clone())
+                    .headers(headers)
+                    .body(contents.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Virtual hosted style on AWS, or path style when `AWS_ENDPOINT_URL` points at something like minio
+fn s3_url(bucket: &str, prefix: &str, name: &str, region: &str) -> Result<Url, Error> {
+    let (mut url, bucket) = match std::env::var("AWS_ENDPOINT_URL") {
+        Ok(endpoint) => (Url::parse(&endpoint)?, Some(bucket)),
+        Err(_) => (
+            Url::parse(&format!("https://{}.s3.{}.amazonaws.com", bucket, region))?,
+            None,
+        ),
+    };
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("The S3 endpoint can't have a path"))?
+        .pop_if_empty()
+        .extend(bucket)
+        .extend(prefix.split('/').filter(|segment| !segment.is_empty()))
+        .push(name);
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serve::RunRecord;
+    use crate::submitter::{test_result, StepResult};
+
+    fn run(pass: &[(&str, &str, bool)]) -> RunRecord {
+        RunRecord {
+            started: Utc::now(),
+            duration_ms: 0.0,
+            has_errors: pass.iter().any(|(_, _, pass)| !pass),
+            results: pass
+                .iter()
+                .map(|(service, name, pass)| StepResult {
+                    output: "secret output".into(),
+                    meta: vec![("service".to_string(), service.to_string())]
+                        .into_iter()
+                        .collect(),
+                    ..test_result(name, *pass)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn groups_steps_by_service() {
+        let mut history = History::new(10, None);
+
+        history.push(run(&[("web", "home", true), ("db", "primary", false)]));
+        history.push(run(&[("web", "home", true), ("db", "primary", true)]));
+        history.push(run(&[("web", "home", true), ("db", "primary", false)]));
+
+        let status = render(&history, "service");
+
+        assert_eq!(status["status"], "degraded");
+        assert_eq!(status["groups"][0]["name"], "web");
+        assert_eq!(status["groups"][0]["status"], "operational");
+        assert_eq!(status["groups"][1]["name"], "db");
+        assert_eq!(status["groups"][1]["status"], "outage");
+        assert_eq!(status["groups"][1]["steps"][0]["uptime"], 100.0 / 3.0);
+        assert!(!status.to_string().contains("secret output"));
+    }
+}
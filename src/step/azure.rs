@@ -0,0 +1,178 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::send_json;
+
+use reqwest::Client;
+
+use std::env;
+use std::time::Duration;
+
+pub(crate) const IMDS_URL: &str = "http://169.254.169.254/metadata";
+const MANAGEMENT_URL: &str = "https://management.azure.com";
+
+fn default_audience() -> String {
+    format!("{}/", MANAGEMENT_URL)
+}
+
+fn default_api_version() -> String {
+    "2021-04-01".into()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AzureVariant {
+    CheckOnly(AzureCheck),
+    Options(AzureOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AzureCheck {
+    //The instance metadata service is reachable
+    Metadata,
+    //A token can be obtained from the default credentials
+    Token,
+    //A resource exists & can be read
+    Resource,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AzureOptions {
+    check: AzureCheck,
+    #[serde(default = "default_audience")]
+    audience: String,
+    #[serde(default)]
+    resource_id: Option<String>,
+    #[serde(default = "default_api_version")]
+    api_version: String,
+}
+
+impl AzureVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let azureopts = match *self {
+            AzureVariant::CheckOnly(ref check) => AzureOptions {
+                check: check.clone(),
+                audience: default_audience(),
+                resource_id: None,
+                api_version: default_api_version(),
+            },
+            AzureVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let output = match azureopts.check {
+            AzureCheck::Metadata => {
+                send_json(
+                    client
+                        .get(format!("{}/instance", IMDS_URL))
+                        .query(&[("api-version", "2021-02-01")])
+                        .header("Metadata", "true"),
+                )
+                .await?
+            }
+            AzureCheck::Token => {
+                let token = get_token(&client, &azureopts.audience).await?;
+
+                json!({
+                    "source": token.source,
+                    "token_type": token.token_type,
+                    "expires_in": token.expires_in,
+                })
+            }
+            AzureCheck::Resource => {
+                let resource_id = azureopts
+                    .resource_id
+                    .as_ref()
+                    .ok_or("The `resource` check requires a `resource_id`")?;
+
+                let token = get_token(&client, &default_audience()).await?;
+
+                let resource = send_json(
+                    client
+                        .get(format!("{}{}", MANAGEMENT_URL, resource_id))
+                        .query(&[("api-version", &azureopts.api_version)])
+                        .bearer_auth(&token.access_token),
+                )
+                .await?;
+
+                json!({
+                    "id": resource["id"],
+                    "name": resource["name"],
+                    "type": resource["type"],
+                    "location": resource["location"],
+                    "provisioning_state": resource["properties"]["provisioningState"],
+                })
+            }
+        };
+
+        serde_json::to_string_pretty(&output).map_err(|err| format!("{}", err))
+    }
+}
+
+struct Token {
+    source: &'static str,
+    access_token: String,
+    token_type: Value,
+    expires_in: Value,
+}
+
+// Gets a token using a client secret from the environment, falling back to the managed identity
+async fn get_token(client: &Client, audience: &str) -> Result<Token, String> {
+    let (source, response) = match (
+        env::var("AZURE_TENANT_ID"),
+        env::var("AZURE_CLIENT_ID"),
+        env::var("AZURE_CLIENT_SECRET"),
+    ) {
+        (Ok(tenant), Ok(client_id), Ok(secret)) => {
+            let scope = format!("{}/.default", audience.trim_end_matches('/'));
+
+            let request = client
+                .post(format!(
+                    "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                    tenant
+                ))
+                .form(&[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", &client_id),
+                    ("client_secret", &secret),
+                    ("scope", &scope),
+                ]);
+
+            ("client_secret", send_json(request).await?)
+        }
+        _ => {
+            let mut query = vec![("api-version", "2018-02-01"), ("resource", audience)];
+
+            //User assigned identities need the client id
+            let client_id = env::var("AZURE_CLIENT_ID").ok();
+            if let Some(ref client_id) = client_id {
+                query.push(("client_id", client_id));
+            }
+
+            let request = client
+                .get(format!("{}/identity/oauth2/token", IMDS_URL))
+                .query(&query)
+                .header("Metadata", "true");
+
+            ("managed_identity", send_json(request).await?)
+        }
+    };
+
+    let access_token = response["access_token"]
+        .as_str()
+        .ok_or("No access token was returned")?
+        .to_string();
+
+    Ok(Token {
+        source,
+        access_token,
+        token_type: response["token_type"].clone(),
+        expires_in: response["expires_in"].clone(),
+    })
+}
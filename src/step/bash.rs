@@ -1,48 +1,96 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum BashVariant {
     CmdOnly(String),
     Options(BashOptions),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct BashOptions {
     cmd: String,
+    #[serde(default)]
     full_error: bool,
+    #[serde(default)]
+    encoding: OutputEncoding,
+}
+
+// How the output of the command is decoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum OutputEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "latin-1")]
+    Latin1,
+    //UTF-8 if the output is valid, otherwise latin-1
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl OutputEncoding {
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match self {
+            OutputEncoding::Utf8 => String::from_utf8_lossy(bytes).to_string(),
+            //Each latin-1 byte is the unicode code point of the same value
+            OutputEncoding::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+            OutputEncoding::Auto => match std::str::from_utf8(bytes) {
+                Ok(output) => output.to_string(),
+                Err(_) => OutputEncoding::Latin1.decode(bytes),
+            },
+        }
+    }
 }
 
 use std::process::Command;
 
 use super::output_renderer;
+use crate::audit_log;
 
 impl BashVariant {
-    pub async fn run(&self) -> Result<String, String> {
-        let bashopts = match *self {
+    fn options(&self) -> BashOptions {
+        match *self {
             BashVariant::CmdOnly(ref val) => BashOptions {
                 cmd: val.clone(),
                 full_error: false,
+                encoding: OutputEncoding::default(),
             },
             BashVariant::Options(ref opts) => opts.clone(),
-        };
+        }
+    }
 
-        tokio::task::spawn_blocking(move || {
-            let cmd = output_renderer(&bashopts.cmd)?;
+    pub async fn run(&self) -> Result<String, String> {
+        let encoding = self.options().encoding;
 
+        self.run_bytes()
+            .await
+            .map(|stdout| encoding.decode(&stdout))
+    }
+
+    pub async fn run_bytes(&self) -> Result<Vec<u8>, String> {
+        let bashopts = self.options();
+        let encoding = bashopts.encoding;
+
+        let cmd = output_renderer(&bashopts.cmd)?;
+
+        audit_log::record("bash", &cmd);
+
+        tokio::task::spawn_blocking(move || {
             match Command::new("bash").arg("-c").arg(cmd).output() {
                 Ok(output) => {
                     if output.status.success() {
-                        Ok(format!("{}", String::from_utf8_lossy(&output.stdout)))
+                        Ok(output.stdout)
                     } else if bashopts.full_error {
                         Err(format!(
                             "Status Code:{}\nError:{}\nOutput:{}",
                             output.status.code().unwrap_or(1),
-                            String::from_utf8_lossy(&output.stderr),
-                            String::from_utf8_lossy(&output.stdout)
+                            encoding.decode(&output.stderr),
+                            encoding.decode(&output.stdout)
                         ))
                     } else {
-                        Err(String::from_utf8_lossy(&output.stderr).to_string())
+                        Err(encoding.decode(&output.stderr))
                     }
                 }
                 Err(err) => Err(format!("Err:{:?}", err)),
@@ -52,3 +100,19 @@ impl BashVariant {
         .map_err(|err| format!("{}", err))?
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_output() {
+        let latin1 = b"caf\xe9";
+        let utf8 = "café".as_bytes();
+
+        assert_eq!(OutputEncoding::Utf8.decode(latin1), "caf\u{fffd}");
+        assert_eq!(OutputEncoding::Latin1.decode(latin1), "café");
+        assert_eq!(OutputEncoding::Auto.decode(latin1), "café");
+        assert_eq!(OutputEncoding::Auto.decode(utf8), "café");
+    }
+}
@@ -0,0 +1,126 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use futures::stream::{self, StreamExt};
+
+use super::HttpVariant;
+
+use std::time::Instant;
+
+fn default_requests() -> usize {
+    100
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BenchmarkVariant {
+    //The request to make, with the same options as the http step
+    #[serde(flatten)]
+    http: HttpVariant,
+    #[serde(default = "default_requests")]
+    requests: usize,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+}
+
+impl BenchmarkVariant {
+    pub fn check_status(&self) -> Result<(), String> {
+        self.http.check_status()
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        if self.requests == 0 {
+            return Err("Benchmark requires at least 1 request".into());
+        }
+
+        let httpops = self.http.options();
+
+        //Sharing a client keeps connections alive between requests, like a real client would
+        let client = httpops.client()?;
+
+        let started = Instant::now();
+
+        let results: Vec<Result<f64, String>> = stream::iter(0..self.requests)
+            .map(|_| async {
+                let start = Instant::now();
+
+                let (response, waited) = httpops.send(&client).await.map_err(|(_, err)| err)?;
+
+                //Waiting for the rate limit isn't part of the latency
+                let start = start + waited;
+
+                //Include the time taken to read the body
+                response
+                    .bytes()
+                    .await
+                    .map_err(|err| format!("Could not read response body: {}", err))?;
+
+                Ok(start.elapsed().as_secs_f64() * 1000.0)
+            })
+            .buffer_unordered(self.concurrency.max(1))
+            .collect()
+            .await;
+
+        let total_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        let mut latencies: Vec<f64> = results
+            .iter()
+            .filter_map(|result| result.as_ref().ok().copied())
+            .collect();
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let errors = self.requests - latencies.len();
+
+        if latencies.is_empty() {
+            let error = results
+                .into_iter()
+                .find_map(Result::err)
+                .unwrap_or_default();
+
+            return Err(format!("All {} requests failed: {}", self.requests, error));
+        }
+
+        let mean_ms = latencies.iter().sum::<f64>() / latencies.len() as f64;
+
+        serde_json::to_string_pretty(&json!({
+            "requests": self.requests,
+            "concurrency": self.concurrency,
+            "errors": errors,
+            "error_rate": errors as f64 / self.requests as f64,
+            "requests_per_second": self.requests as f64 / (total_ms / 1000.0),
+            "min_ms": latencies[0],
+            "mean_ms": mean_ms,
+            "p50_ms": percentile(&latencies, 50.0),
+            "p95_ms": percentile(&latencies, 95.0),
+            "p99_ms": percentile(&latencies, 99.0),
+            "max_ms": latencies[latencies.len() - 1],
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+// Nearest rank percentile of sorted values
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: Vec<f64> = (1..=20).map(f64::from).collect();
+
+        assert_eq!(percentile(&sorted, 50.0), 10.0);
+        assert_eq!(percentile(&sorted, 95.0), 19.0);
+        assert_eq!(percentile(&sorted, 99.0), 20.0);
+        assert_eq!(percentile(&[3.0], 50.0), 3.0);
+    }
+}
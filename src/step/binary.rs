@@ -0,0 +1,77 @@
+use serde_json::json;
+
+//How many bytes are shown in the hex preview of a binary output
+const PREVIEW_BYTES: usize = 256;
+const BYTES_PER_LINE: usize = 16;
+
+// Binary outputs are summarised as json, so they can be filtered & checked like any other output
+pub(crate) fn summary(bytes: &[u8]) -> String {
+    json!({
+        "length": bytes.len(),
+        "sha256": sha256(bytes),
+    })
+    .to_string()
+}
+
+// A hex dump of the start of the output, in the same layout as `xxd`
+pub(crate) fn hex_preview(bytes: &[u8]) -> String {
+    let mut preview = String::new();
+
+    for (line, chunk) in bytes
+        .chunks(BYTES_PER_LINE)
+        .take(PREVIEW_BYTES / BYTES_PER_LINE)
+        .enumerate()
+    {
+        let hex: Vec<String> = chunk
+            .chunks(2)
+            .map(|pair| pair.iter().map(|byte| format!("{:02x}", byte)).collect())
+            .collect();
+
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        preview.push_str(&format!(
+            "{:08x}: {:<40} {}\n",
+            line * BYTES_PER_LINE,
+            hex.join(" "),
+            ascii
+        ));
+    }
+
+    if bytes.len() > PREVIEW_BYTES {
+        preview.push_str(&format!("...{} more bytes\n", bytes.len() - PREVIEW_BYTES));
+    }
+
+    preview
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    openssl::sha::sha256(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_preview_matches_xxd() {
+        let bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR\x00";
+
+        assert_eq!(
+            hex_preview(bytes),
+            "00000000: 8950 4e47 0d0a 1a0a 0000 000d 4948 4452  .PNG........IHDR\n\
+             00000010: 00                                       .\n"
+        );
+    }
+}
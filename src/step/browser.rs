@@ -0,0 +1,271 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use reqwest::Client;
+use tokio::time::sleep;
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+//The key used for element references in the W3C WebDriver protocol
+const ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+
+fn default_webdriver() -> String {
+    "http://localhost:4444".into()
+}
+
+fn default_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_screenshot_on_failure() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum BrowserVariant {
+    UrlOnly(String),
+    Options(BrowserOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BrowserOptions {
+    url: String,
+    #[serde(default = "default_webdriver")]
+    webdriver: String,
+    #[serde(default)]
+    browser: Browser,
+    //Css selector to wait for before extracting
+    #[serde(default)]
+    wait_for: Option<String>,
+    //Css selector of the element to output the text of.  Defaults to the whole page
+    #[serde(default)]
+    extract: Option<String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default = "default_screenshot_on_failure")]
+    screenshot_on_failure: bool,
+    //Defaults to the step's workdir
+    #[serde(default)]
+    screenshot_dir: Option<String>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Browser {
+    #[default]
+    Chrome,
+    Firefox,
+}
+
+impl BrowserVariant {
+    fn options(&self) -> BrowserOptions {
+        match *self {
+            BrowserVariant::UrlOnly(ref url) => BrowserOptions {
+                url: url.clone(),
+                webdriver: default_webdriver(),
+                browser: Browser::default(),
+                wait_for: None,
+                extract: None,
+                timeout_ms: default_timeout_ms(),
+                screenshot_on_failure: default_screenshot_on_failure(),
+                screenshot_dir: None,
+            },
+            BrowserVariant::Options(ref opts) => opts.clone(),
+        }
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let browseropts = self.options();
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let session = Session::new(client, &browseropts).await?;
+
+        let result = session.check(&browseropts).await;
+
+        let result = match result {
+            Ok(output) => Ok(output),
+            Err(err) if browseropts.screenshot_on_failure => match browseropts.screenshot_dir {
+                Some(ref dir) => match session.screenshot(dir).await {
+                    Ok(path) => Err(format!("{}\nScreenshot saved to `{}`", err, path.display())),
+                    Err(screenshot_err) => Err(format!(
+                        "{}\nCould not save screenshot: {}",
+                        err, screenshot_err
+                    )),
+                },
+                None => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        //Always close the session so the browser doesn't linger
+        session.close().await;
+
+        result
+    }
+
+    // Saves screenshots to the workdir unless the step says otherwise, returning whether it will
+    pub fn default_screenshot_dir(&mut self, workdir: &Path) -> bool {
+        let mut opts = self.options();
+
+        if !opts.screenshot_on_failure || opts.screenshot_dir.is_some() {
+            return false;
+        }
+
+        opts.screenshot_dir = Some(workdir.to_string_lossy().to_string());
+        *self = BrowserVariant::Options(opts);
+
+        true
+    }
+}
+
+struct Session {
+    client: Client,
+    url: String,
+}
+
+impl Session {
+    async fn new(client: Client, opts: &BrowserOptions) -> Result<Self, String> {
+        let capabilities = match opts.browser {
+            Browser::Chrome => json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": { "args": ["--headless", "--disable-gpu", "--no-sandbox"] }
+            }),
+            Browser::Firefox => json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": { "args": ["-headless"] }
+            }),
+        };
+
+        let base = opts.webdriver.trim_end_matches('/').to_string();
+
+        let response = send(
+            client
+                .post(format!("{}/session", base))
+                .json(&json!({ "capabilities": { "alwaysMatch": capabilities } })),
+        )
+        .await
+        .map_err(|err| format!("Could not start a webdriver session: {}", err))?;
+
+        let session_id = response["sessionId"]
+            .as_str()
+            .ok_or("Webdriver did not return a session id")?;
+
+        Ok(Session {
+            url: format!("{}/session/{}", base, session_id),
+            client,
+        })
+    }
+
+    async fn check(&self, opts: &BrowserOptions) -> Result<String, String> {
+        send(
+            self.client
+                .post(format!("{}/url", self.url))
+                .json(&json!({ "url": opts.url })),
+        )
+        .await
+        .map_err(|err| format!("Could not navigate to `{}`: {}", opts.url, err))?;
+
+        let timeout = Duration::from_millis(opts.timeout_ms);
+
+        if let Some(ref selector) = opts.wait_for {
+            self.wait_for(selector, timeout).await?;
+        }
+
+        let selector = opts.extract.as_deref().unwrap_or("body");
+        let element = self.wait_for(selector, timeout).await?;
+
+        let text = send(
+            self.client
+                .get(format!("{}/element/{}/text", self.url, element)),
+        )
+        .await?;
+
+        Ok(text.as_str().unwrap_or_default().to_string())
+    }
+
+    // Polls for an element matching the css selector, returning its id
+    async fn wait_for(&self, selector: &str, timeout: Duration) -> Result<String, String> {
+        let start = Instant::now();
+
+        loop {
+            let found = send(
+                self.client
+                    .post(format!("{}/element", self.url))
+                    .json(&json!({ "using": "css selector", "value": selector })),
+            )
+            .await;
+
+            if let Ok(element) = found {
+                if let Some(id) = element[ELEMENT_KEY].as_str() {
+                    return Ok(id.to_string());
+                }
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(format!(
+                    "Timed out after {}ms waiting for `{}`",
+                    timeout.as_millis(),
+                    selector
+                ));
+            }
+
+            sleep(Duration::from_millis(250)).await;
+        }
+    }
+
+    async fn screenshot(&self, dir: &str) -> Result<PathBuf, String> {
+        let encoded = send(self.client.get(format!("{}/screenshot", self.url))).await?;
+
+        let png = base64::decode(encoded.as_str().unwrap_or_default())
+            .map_err(|err| format!("Invalid screenshot: {}", err))?;
+
+        let path = PathBuf::from(dir).join("screenshot.png");
+
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|err| format!("{}", err))?;
+        tokio::fs::write(&path, png)
+            .await
+            .map_err(|err| format!("{}", err))?;
+
+        Ok(path)
+    }
+
+    async fn close(&self) {
+        let _ = http_log::send(self.client.delete(&self.url)).await;
+    }
+}
+
+// Sends a webdriver command, returning the `value` of the response
+async fn send(request: reqwest::RequestBuilder) -> Result<Value, String> {
+    let response = http_log::send(request)
+        .await
+        .map_err(|err| format!("Error connecting to webdriver {}", err))?;
+
+    let status = response.status();
+
+    let mut body: Value = response
+        .json()
+        .await
+        .map_err(|err| format!("Could not parse webdriver response: {}", err))?;
+
+    let value = body["value"].take();
+
+    if !status.is_success() {
+        return Err(format!(
+            "{}: {}",
+            value["error"].as_str().unwrap_or("webdriver error"),
+            value["message"].as_str().unwrap_or_default()
+        ));
+    }
+
+    Ok(value)
+}
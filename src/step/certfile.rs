@@ -0,0 +1,336 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+    OcspRevokedStatus,
+};
+use openssl::pkcs12::Pkcs12;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509NameRef, X509};
+
+use reqwest::Client;
+
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum CertFileVariant {
+    PathOnly(PathBuf),
+    Options(CertFileOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CertFileOptions {
+    path: PathBuf,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    ocsp: bool,
+    #[serde(default)]
+    issuer: Option<PathBuf>,
+    #[serde(default)]
+    ocsp_url: Option<String>,
+}
+
+impl CertFileVariant {
+    // Only checking revocation with OCSP needs the network
+    pub(crate) fn needs_network(&self) -> bool {
+        matches!(self, CertFileVariant::Options(opts) if opts.ocsp)
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let certops = match *self {
+            CertFileVariant::PathOnly(ref path) => CertFileOptions {
+                path: path.clone(),
+                password: None,
+                ocsp: false,
+                issuer: None,
+                ocsp_url: None,
+            },
+            CertFileVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let contents = tokio::fs::read(&certops.path)
+            .await
+            .map_err(|err| format!("Could not read `{}`: {}", certops.path.display(), err))?;
+
+        let mut chain = load_chain(&contents, certops.password.as_deref())
+            .map_err(|err| format!("Could not parse `{}`: {}", certops.path.display(), err))?;
+
+        let mut output = describe_certificate(&chain[0])?;
+
+        if certops.ocsp {
+            let issuer = match certops.issuer {
+                Some(ref path) => {
+                    let contents = tokio::fs::read(path).await.map_err(|err| {
+                        format!("Could not read issuer `{}`: {}", path.display(), err)
+                    })?;
+                    load_certificate(&contents, None).map_err(|err| {
+                        format!("Could not parse issuer `{}`: {}", path.display(), err)
+                    })?
+                }
+                None if chain.len() > 1 => chain.remove(1),
+                None => {
+                    return Err(
+                        "An OCSP check needs the issuer certificate, either in the file after the certificate or via `issuer`".into(),
+                    )
+                }
+            };
+
+            output["ocsp"] = ocsp_status(&chain[0], &issuer, certops.ocsp_url.as_deref()).await?;
+        }
+
+        Ok(output.to_string())
+    }
+}
+
+// Loads the leaf certificate from either a PEM, DER or PKCS12 encoded file
+pub(crate) fn load_certificate(contents: &[u8], password: Option<&str>) -> Result<X509, String> {
+    load_chain(contents, password).map(|mut chain| chain.remove(0))
+}
+
+// Loads the leaf certificate followed by any chain certificates included in the file
+fn load_chain(contents: &[u8], password: Option<&str>) -> Result<Vec<X509>, String> {
+    if contents.starts_with(b"-----BEGIN") {
+        let chain = X509::stack_from_pem(contents).map_err(|err| format!("{}", err))?;
+
+        if chain.is_empty() {
+            return Err("No certificates found".into());
+        }
+
+        return Ok(chain);
+    }
+
+    if let Ok(cert) = X509::from_der(contents) {
+        return Ok(vec![cert]);
+    }
+
+    let pkcs12 = Pkcs12::from_der(contents).map_err(|err| format!("{}", err))?;
+
+    let parsed = pkcs12
+        .parse2(password.unwrap_or(""))
+        .map_err(|err| format!("Could not open PKCS12 keystore: {}", err))?;
+
+    let mut chain = vec![parsed.cert.ok_or("PKCS12 keystore has no certificate")?];
+
+    if let Some(ca) = parsed.ca {
+        chain.extend(ca);
+    }
+
+    Ok(chain)
+}
+
+pub(crate) fn describe_certificate(cert: &X509) -> Result<Value, String> {
+    let not_before = asn1_to_datetime(cert.not_before())?;
+    let not_after = asn1_to_datetime(cert.not_after())?;
+
+    let now = Asn1Time::days_from_now(0).map_err(|err| format!("{}", err))?;
+    let days_remaining = now
+        .diff(cert.not_after())
+        .map(|diff| diff.days)
+        .map_err(|err| format!("{}", err))?;
+
+    let sans: Vec<String> = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| {
+                    name.dnsname()
+                        .map(String::from)
+                        .or_else(|| name.ipaddress().map(format_ip))
+                        .or_else(|| name.email().map(String::from))
+                        .or_else(|| name.uri().map(String::from))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let output = json!({
+        "subject": format_name(cert.subject_name()),
+        "issuer": format_name(cert.issuer_name()),
+        "common_name": common_name(cert.subject_name()),
+        "sans": sans,
+        "not_before": not_before.to_rfc3339(),
+        "not_after": not_after.to_rfc3339(),
+        "days_remaining": days_remaining,
+        "expired": days_remaining < 0,
+    });
+
+    Ok(output)
+}
+
+// Queries the certificate's OCSP responder for its revocation status
+async fn ocsp_status(cert: &X509, issuer: &X509, url: Option<&str>) -> Result<Value, String> {
+    let url = match url {
+        Some(url) => url.to_string(),
+        None => cert
+            .ocsp_responders()
+            .ok()
+            .and_then(|responders| responders.iter().next().map(|url| url.to_string()))
+            .ok_or("Certificate has no OCSP responder, set one with `ocsp_url`")?,
+    };
+
+    let cert_id = || {
+        OcspCertId::from_cert(MessageDigest::sha1(), cert, issuer).map_err(|err| format!("{}", err))
+    };
+
+    let mut request = OcspRequest::new().map_err(|err| format!("{}", err))?;
+    request
+        .add_id(cert_id()?)
+        .map_err(|err| format!("{}", err))?;
+    let request = request.to_der().map_err(|err| format!("{}", err))?;
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|err| format!("{}", err))?;
+
+    let response = http_log::send(
+        client
+            .post(&url)
+            .header("Content-Type", "application/ocsp-request")
+            .body(request),
+    )
+    .await
+    .map_err(|err| format!("Error connecting to OCSP responder `{}`: {}", url, err))?;
+
+    if !response.status().is_success() {
+        return Err(format!(
+            "Unexpected status code `{}` from OCSP responder `{}`",
+            response.status(),
+            url
+        ));
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| format!("Could not read OCSP response: {}", err))?;
+
+    let response = OcspResponse::from_der(&body)
+        .map_err(|err| format!("Could not parse OCSP response: {}", err))?;
+
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(format!(
+            "OCSP responder returned an error status `{}`",
+            response.status().as_raw()
+        ));
+    }
+
+    let basic = response.basic().map_err(|err| format!("{}", err))?;
+
+    //The responder is either the issuer or a certificate delegated by the issuer
+    let mut store = X509StoreBuilder::new().map_err(|err| format!("{}", err))?;
+    store
+        .add_cert(issuer.clone())
+        .map_err(|err| format!("{}", err))?;
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(|err| format!("{}", err))?;
+    let store = store.build();
+
+    let mut certs = Stack::new().map_err(|err| format!("{}", err))?;
+    certs
+        .push(issuer.clone())
+        .map_err(|err| format!("{}", err))?;
+
+    basic
+        .verify(&certs, &store, OcspFlag::empty())
+        .map_err(|err| format!("Could not verify OCSP response: {}", err))?;
+
+    let cert_id = cert_id()?;
+
+    let status = basic
+        .find_status(&cert_id)
+        .ok_or("OCSP response did not include the certificate")?;
+
+    status
+        .check_validity(300, None)
+        .map_err(|err| format!("OCSP response is out of date: {}", err))?;
+
+    let status_name = match status.status {
+        OcspCertStatus::GOOD => "good",
+        OcspCertStatus::REVOKED => "revoked",
+        _ => "unknown",
+    };
+
+    let revocation_time = match status.revocation_time {
+        Some(time) => Some(asn1_to_datetime(time)?.to_rfc3339()),
+        None => None,
+    };
+
+    let next_update = match status.next_update() {
+        Some(time) => Some(asn1_to_datetime(time)?.to_rfc3339()),
+        None => None,
+    };
+
+    let revocation_reason = match status.reason {
+        _ if status.status != OcspCertStatus::REVOKED => None,
+        OcspRevokedStatus::KEY_COMPROMISE => Some("key_compromise"),
+        OcspRevokedStatus::CA_COMPROMISE => Some("ca_compromise"),
+        OcspRevokedStatus::AFFILIATION_CHANGED => Some("affiliation_changed"),
+        OcspRevokedStatus::STATUS_SUPERSEDED => Some("superseded"),
+        OcspRevokedStatus::STATUS_CESSATION_OF_OPERATION => Some("cessation_of_operation"),
+        OcspRevokedStatus::STATUS_CERTIFICATE_HOLD => Some("certificate_hold"),
+        OcspRevokedStatus::REMOVE_FROM_CRL => Some("remove_from_crl"),
+        _ => Some("unspecified"),
+    };
+
+    Ok(json!({
+        "responder": url,
+        "status": status_name,
+        "revocation_time": revocation_time,
+        "revocation_reason": revocation_reason,
+        "this_update": asn1_to_datetime(status.this_update)?.to_rfc3339(),
+        "next_update": next_update,
+    }))
+}
+
+fn asn1_to_datetime<T: Display + ?Sized>(time: &T) -> Result<DateTime<Utc>, String> {
+    let formatted = time.to_string();
+
+    NaiveDateTime::parse_from_str(&formatted, "%b %e %H:%M:%S %Y GMT")
+        .map(|naive| Utc.from_utc_datetime(&naive))
+        .map_err(|err| format!("Could not parse time `{}`: {}", formatted, err))
+}
+
+fn common_name(name: &X509NameRef) -> Option<String> {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok())
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_ip(bytes: &[u8]) -> String {
+    match bytes.len() {
+        4 => std::net::Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string(),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(bytes);
+            std::net::Ipv6Addr::from(octets).to_string()
+        }
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,235 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::azure::IMDS_URL;
+use super::gcp::metadata_url;
+use super::http::connection_error;
+use super::send_json;
+
+use futures::future::{select_ok, BoxFuture, FutureExt};
+use reqwest::{Client, RequestBuilder};
+
+use std::env;
+use std::time::Duration;
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudProvider {
+    //Whichever metadata service answers
+    #[default]
+    Auto,
+    Aws,
+    Gcp,
+    Azure,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum CloudMetadataVariant {
+    ProviderOnly(CloudProvider),
+    Options(CloudMetadataOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CloudMetadataOptions {
+    #[serde(default)]
+    provider: CloudProvider,
+    //Kept short, as the metadata service is local & auto detection waits on the ones that aren't there
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+impl CloudMetadataVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let metaopts = match *self {
+            CloudMetadataVariant::ProviderOnly(provider) => CloudMetadataOptions {
+                provider,
+                timeout_ms: default_timeout_ms(),
+            },
+            CloudMetadataVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let client = Client::builder()
+            .timeout(Duration::from_millis(metaopts.timeout_ms))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let output = match metaopts.provider {
+            CloudProvider::Aws => aws(&client).await?,
+            CloudProvider::Gcp => gcp(&client).await?,
+            CloudProvider::Azure => azure(&client).await?,
+            CloudProvider::Auto => {
+                let providers: Vec<BoxFuture<Result<Value, String>>> = vec![
+                    aws(&client).boxed(),
+                    gcp(&client).boxed(),
+                    azure(&client).boxed(),
+                ];
+
+                select_ok(providers)
+                    .await
+                    .map(|(output, _)| output)
+                    .map_err(|_| {
+                        "Could not find an instance metadata service, tried aws, gcp & azure"
+                            .to_string()
+                    })?
+            }
+        };
+
+        serde_json::to_string_pretty(&output).map_err(|err| format!("{}", err))
+    }
+}
+
+// Sends a request, returning the body as text if it was successful
+async fn send_text(request: RequestBuilder) -> Result<String, String> {
+    let response = http_log::send(request)
+        .await
+        .map_err(|err| connection_error(&err))?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(format!(
+            "Request to `{}` returned status {}",
+            response.url().path(),
+            status
+        ));
+    }
+
+    response.text().await.map_err(|err| format!("{}", err))
+}
+
+// The last segment of a path like `projects/123/zones/us-central1-a`
+fn last_segment(value: &Value) -> Value {
+    match value.as_str() {
+        Some(path) => json!(path.rsplit('/').next().unwrap_or(path)),
+        None => Value::Null,
+    }
+}
+
+// Ids can be numbers, but are reported as strings so they can be compared the same way across providers
+fn text(value: &Value) -> Value {
+    match value {
+        Value::Number(number) => json!(number.to_string()),
+        other => other.clone(),
+    }
+}
+
+// Uses IMDSv2, which needs a session token first
+async fn aws(client: &Client) -> Result<Value, String> {
+    let base = env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+        .unwrap_or_else(|_| "http://169.254.169.254".into());
+    let base = base.trim_end_matches('/');
+
+    let token = send_text(
+        client
+            .put(format!("{}/latest/api/token", base))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", "60"),
+    )
+    .await?;
+
+    let get = |path: &str| {
+        client
+            .get(format!("{}/latest/{}", base, path))
+            .header("X-aws-ec2-metadata-token", &token)
+    };
+
+    let document = send_json(get("dynamic/instance-identity/document")).await?;
+
+    //Instances without a role return a 404
+    let role = send_text(get("meta-data/iam/security-credentials/"))
+        .await
+        .ok()
+        .and_then(|roles| roles.lines().next().map(String::from));
+
+    Ok(json!({
+        "provider": "aws",
+        "instance_id": document["instanceId"],
+        "region": document["region"],
+        "zone": document["availabilityZone"],
+        "instance_type": document["instanceType"],
+        "account": document["accountId"],
+        "private_ip": document["privateIp"],
+        "iam_role": role,
+        "raw": document,
+    }))
+}
+
+async fn gcp(client: &Client) -> Result<Value, String> {
+    let get = |path: &str| {
+        client
+            .get(format!("{}/{}", metadata_url(), path))
+            .header("Metadata-Flavor", "Google")
+    };
+
+    let instance = send_json(get("instance/").query(&[("recursive", "true")])).await?;
+    let project = send_text(get("project/project-id")).await.ok();
+
+    let zone = last_segment(&instance["zone"]);
+
+    //The region is the zone without the trailing `-a`
+    let region = zone
+        .as_str()
+        .and_then(|zone| zone.rsplit_once('-'))
+        .map(|(region, _)| json!(region))
+        .unwrap_or(Value::Null);
+
+    Ok(json!({
+        "provider": "gcp",
+        "instance_id": text(&instance["id"]),
+        "region": region,
+        "zone": zone,
+        "instance_type": last_segment(&instance["machineType"]),
+        "account": project,
+        "private_ip": instance["networkInterfaces"][0]["ip"],
+        "iam_role": instance["serviceAccounts"]["default"]["email"],
+        //Custom metadata holds startup scripts & ssh keys
+        "raw": without(instance, "", &["attributes"]),
+    }))
+}
+
+async fn azure(client: &Client) -> Result<Value, String> {
+    let instance = send_json(
+        client
+            .get(format!("{}/instance", IMDS_URL))
+            .query(&[("api-version", "2021-02-01")])
+            .header("Metadata", "true"),
+    )
+    .await?;
+
+    let compute = &instance["compute"];
+
+    let zone = match compute["zone"].as_str() {
+        Some(zone) if !zone.is_empty() => json!(zone),
+        _ => Value::Null,
+    };
+
+    Ok(json!({
+        "provider": "azure",
+        "instance_id": compute["vmId"],
+        "region": compute["location"],
+        "zone": zone,
+        "instance_type": compute["vmSize"],
+        "account": compute["subscriptionId"],
+        "private_ip": instance["network"]["interface"][0]["ipv4"]["ipAddress"][0]["privateIpAddress"],
+        //Managed identities aren't listed in the instance metadata
+        "iam_role": Value::Null,
+        "raw": without(instance, "/compute", &["userData", "customData", "osProfile", "publicKeys"]),
+    }))
+}
+
+// The document without the fields that can hold secrets, so they don't end up in reports
+fn without(mut document: Value, pointer: &str, keys: &[&str]) -> Value {
+    if let Some(object) = document.pointer_mut(pointer).and_then(Value::as_object_mut) {
+        for key in keys {
+            object.remove(*key);
+        }
+    }
+
+    document
+}
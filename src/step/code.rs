@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+// Stable codes for why a step failed, so alerts can be routed & deduplicated without parsing the error message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    //The step itself returned an error
+    RunFailed,
+    Timeout,
+    //Which layer a connection failed at, or `ConnectionFailed` if it's not clear
+    DnsFailed,
+    ConnectionRefused,
+    TlsFailed,
+    ConnectionFailed,
+    HttpStatusMismatch,
+    CookieMismatch,
+    //An http request took longer than its `max_time_ms`, or downloaded slower than its `min_throughput_kbps`
+    SlowResponse,
+    StepNotFound,
+    //A filter couldn't find what it was looking for in the output
+    RegexNoMatch,
+    JmespathNoMatch,
+    InvalidRegex,
+    InvalidJmespath,
+    InvalidJson,
+    //The output didn't meet the expectation
+    MatchFailed,
+    MatchNotFailed,
+    ValueMismatch,
+    ThresholdNotMet,
+    NotANumber,
+    FileMismatch,
+    FileNotFound,
+    //The step took longer than it did in the baseline run
+    SlowerThanBaseline,
+    //The step didn't run
+    DepNotMet,
+    WorkdirFailed,
+    PlanError,
+    //A bug in lorikeet, rather than the step failing
+    Panicked,
+}
+
+impl ErrorCode {
+    // Works out the code from the message of a step type that doesn't give one, such as `bash`, or free text from
+    // the other step types.  Http steps give their code where the error is raised
+    pub(crate) fn from_run_error(error: &str) -> ErrorCode {
+        let lower = error.to_lowercase();
+
+        if error.starts_with("Response took ") || error.starts_with("Downloaded at ") {
+            ErrorCode::SlowResponse
+        } else if lower.contains("timed out") || lower.contains("timeout") {
+            ErrorCode::Timeout
+        } else if error.contains("returned status `") {
+            ErrorCode::HttpStatusMismatch
+        } else if lower.starts_with("dns lookup failed") {
+            ErrorCode::DnsFailed
+        } else if lower.contains("connection refused") {
+            ErrorCode::ConnectionRefused
+        } else if lower.starts_with("tls handshake") {
+            ErrorCode::TlsFailed
+        } else if lower.contains("error connecting")
+            || lower.contains("could not connect")
+            || lower.contains("dns error")
+        {
+            ErrorCode::ConnectionFailed
+        } else if error.starts_with("Cookie `") {
+            ErrorCode::CookieMismatch
+        } else if error.starts_with("Step ") && error.ends_with(" could not be found") {
+            ErrorCode::StepNotFound
+        } else {
+            ErrorCode::RunFailed
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.as_str().map(String::from))
+            .unwrap_or_default();
+
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_run_errors() {
+        assert_eq!(
+            ErrorCode::from_run_error("returned status `500` does not match expected `200`"),
+            ErrorCode::HttpStatusMismatch
+        );
+        assert_eq!(
+            ErrorCode::from_run_error(
+                "Error connecting to url error sending request: operation timed out"
+            ),
+            ErrorCode::Timeout
+        );
+        assert_eq!(
+            ErrorCode::from_run_error("Connection refused by `127.0.0.1`"),
+            ErrorCode::ConnectionRefused
+        );
+        assert_eq!(
+            ErrorCode::from_run_error(
+                "DNS lookup failed for `example.invalid`: Name or service not known"
+            ),
+            ErrorCode::DnsFailed
+        );
+        assert_eq!(
+            ErrorCode::from_run_error("Step login could not be found"),
+            ErrorCode::StepNotFound
+        );
+        assert_eq!(
+            ErrorCode::from_run_error(
+                "Response took 812ms, longer than the `max_time_ms` of 500ms"
+            ),
+            ErrorCode::SlowResponse
+        );
+        assert_eq!(ErrorCode::DepNotMet.to_string(), "DEP_NOT_MET");
+    }
+}
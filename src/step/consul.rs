@@ -0,0 +1,119 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+fn default_consul_url() -> String {
+    "http://127.0.0.1:8500".into()
+}
+
+fn default_passing() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ConsulVariant {
+    KeyOnly(String),
+    Options(ConsulOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ConsulOptions {
+    #[serde(default = "default_consul_url")]
+    url: String,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    service: Option<String>,
+    #[serde(default = "default_passing")]
+    passing: bool,
+    #[serde(default)]
+    datacenter: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+}
+
+impl ConsulVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let consulops = match *self {
+            ConsulVariant::KeyOnly(ref key) => ConsulOptions {
+                url: default_consul_url(),
+                key: Some(key.clone()),
+                service: None,
+                passing: default_passing(),
+                datacenter: None,
+                token: None,
+            },
+            ConsulVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let base = consulops.url.trim_end_matches('/');
+
+        let (url, is_service) = match (&consulops.key, &consulops.service) {
+            (Some(key), None) => (
+                format!("{}/v1/kv/{}", base, key.trim_start_matches('/')),
+                false,
+            ),
+            (None, Some(service)) => (format!("{}/v1/health/service/{}", base, service), true),
+            _ => {
+                return Err("Consul step requires exactly one of `key` or `service`".into());
+            }
+        };
+
+        let mut query = Vec::new();
+
+        if is_service && consulops.passing {
+            query.push(("passing", "true".to_string()));
+        }
+
+        if !is_service {
+            query.push(("raw", "true".to_string()));
+        }
+
+        if let Some(ref dc) = consulops.datacenter {
+            query.push(("dc", dc.clone()));
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let mut request = client.get(&url).query(&query);
+
+        if let Some(ref token) = consulops.token {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let response = http_log::send(request)
+            .await
+            .map_err(|err| format!("Error connecting to consul {}", err))?;
+
+        let status = response.status();
+
+        if status.as_u16() == 404 && !is_service {
+            return Err(format!(
+                "Key `{}` could not be found in consul",
+                consulops.key.unwrap_or_default()
+            ));
+        }
+
+        if !status.is_success() {
+            return Err(format!("Consul returned status `{}`", status.as_u16()));
+        }
+
+        let output = response.text().await.map_err(|err| format!("{:?}", err))?;
+
+        if is_service && output.trim() == "[]" {
+            return Err(format!(
+                "No {}instances found for service `{}`",
+                if consulops.passing { "passing " } else { "" },
+                consulops.service.unwrap_or_default()
+            ));
+        }
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,220 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use futures::stream::{self, StreamExt};
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
+use reqwest::{header::CONTENT_TYPE, redirect::Policy, Client, Url};
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+
+lazy_static! {
+    static ref HREF_REGEX: Regex =
+        Regex::new(r#"(?i)(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    static ref LOC_REGEX: Regex = Regex::new(r"(?s)<loc>\s*(.*?)\s*</loc>").unwrap();
+}
+
+fn default_depth() -> usize {
+    1
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum CrawlVariant {
+    UrlOnly(String),
+    Options(CrawlOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CrawlOptions {
+    url: String,
+    #[serde(default = "default_depth")]
+    depth: usize,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    #[serde(default = "default_concurrency")]
+    concurrency: usize,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+}
+
+struct Page {
+    url: Url,
+    status: Result<u16, String>,
+    links: Vec<Url>,
+}
+
+impl CrawlVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let crawlops = match *self {
+            CrawlVariant::UrlOnly(ref url) => CrawlOptions {
+                url: url.clone(),
+                depth: default_depth(),
+                limit: default_limit(),
+                concurrency: default_concurrency(),
+                verify_ssl: None,
+            },
+            CrawlVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let start = Url::from_str(&crawlops.url)
+            .map_err(|err| format!("Failed to parse url `{}`: {}", crawlops.url, err))?;
+
+        let mut client_builder = Client::builder()
+            .redirect(Policy::limited(10))
+            .timeout(Duration::from_secs(30));
+
+        if let Some(verify_ssl) = crawlops.verify_ssl {
+            client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
+        }
+
+        let client = client_builder.build().map_err(|err| format!("{}", err))?;
+
+        let mut visited = HashSet::new();
+        visited.insert(start.clone());
+
+        let mut current = vec![start.clone()];
+        let mut broken = Vec::new();
+        let mut checked = 0;
+
+        for level in 0..=crawlops.depth {
+            //Only extract links if we are going to follow them
+            let follow = level < crawlops.depth;
+
+            let pages: Vec<Page> = stream::iter(current)
+                .map(|url| fetch(&client, url, follow))
+                .buffer_unordered(crawlops.concurrency.max(1))
+                .collect()
+                .await;
+
+            let mut next = Vec::new();
+
+            for page in pages {
+                checked += 1;
+
+                match page.status {
+                    Ok(status) if (200..300).contains(&status) => (),
+                    Ok(status) => broken.push(json!({"url": page.url.as_str(), "status": status})),
+                    Err(err) => broken.push(json!({"url": page.url.as_str(), "error": err})),
+                }
+
+                for link in page.links {
+                    if link.host_str() == start.host_str()
+                        && visited.len() < crawlops.limit
+                        && visited.insert(link.clone())
+                    {
+                        next.push(link);
+                    }
+                }
+            }
+
+            debug!("Crawled level {}, {} urls to check next", level, next.len());
+
+            current = next;
+
+            if current.is_empty() {
+                break;
+            }
+        }
+
+        if !broken.is_empty() {
+            return Err(format!(
+                "{} of {} urls did not return a 2xx status: {}",
+                broken.len(),
+                checked,
+                serde_json::Value::Array(broken)
+            ));
+        }
+
+        Ok(json!({ "checked": checked }).to_string())
+    }
+}
+
+async fn fetch(client: &Client, url: Url, follow: bool) -> Page {
+    let response = match http_log::send(client.get(url.clone())).await {
+        Ok(response) => response,
+        Err(err) => {
+            return Page {
+                url,
+                status: Err(format!("{}", err)),
+                links: vec![],
+            }
+        }
+    };
+
+    let status = response.status().as_u16();
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|val| val.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let is_html = content_type.contains("html");
+    let is_xml = content_type.contains("xml") || url.path().ends_with(".xml");
+
+    if !follow || !(is_html || is_xml) || !response.status().is_success() {
+        return Page {
+            url,
+            status: Ok(status),
+            links: vec![],
+        };
+    }
+
+    //Use the final url after redirects to resolve relative links
+    let base = response.url().clone();
+
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(err) => {
+            return Page {
+                url,
+                status: Err(format!("{}", err)),
+                links: vec![],
+            }
+        }
+    };
+
+    let links: Vec<Url> = if is_html {
+        HREF_REGEX
+            .captures_iter(&body)
+            .filter_map(|cap| cap.get(1).or_else(|| cap.get(2)))
+            .filter_map(|link| base.join(link.as_str().trim()).ok())
+            .collect()
+    } else {
+        LOC_REGEX
+            .captures_iter(&body)
+            .filter_map(|cap| cap.get(1))
+            .filter_map(|link| Url::from_str(link.as_str()).ok())
+            .collect()
+    };
+
+    let links = links
+        .into_iter()
+        .filter(|link| link.scheme() == "http" || link.scheme() == "https")
+        .map(|mut link| {
+            link.set_fragment(None);
+            link
+        })
+        .collect();
+
+    Page {
+        url,
+        status: Ok(status),
+        links,
+    }
+}
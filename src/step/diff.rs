@@ -0,0 +1,211 @@
+use regex::Regex;
+use serde_json::Value;
+use similar::{ChangeTag, TextDiff};
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+const EXCERPT_CONTEXT: usize = 40;
+const DIFF_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_JSON_DIFFERENCES: usize = 50;
+const UNIFIED_CONTEXT: usize = 3;
+
+// Produces a line diff between expected & actual, prefixing lines with `-` (expected only), `+` (actual only) or ` `
+pub(crate) fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    let mut output = String::new();
+
+    for change in text_diff(&expected, &actual).iter_all_changes() {
+        let prefix = match change.tag() {
+            ChangeTag::Equal => ' ',
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+        };
+
+        output.push_str(&format!("{} {}\n", prefix, change.value()));
+    }
+
+    output
+}
+
+// Produces a unified diff like `diff -u`, with only the changed lines & a few either side
+pub(crate) fn unified_diff(
+    expected: &str,
+    actual: &str,
+    expected_name: &str,
+    actual_name: &str,
+) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    text_diff(&expected, &actual)
+        .unified_diff()
+        .context_radius(UNIFIED_CONTEXT)
+        .header(expected_name, actual_name)
+        .to_string()
+}
+
+// Diffs the lines without their line endings, so a missing newline at the end doesn't count as a change
+fn text_diff<'a, 'b>(expected: &'b [&'a str], actual: &'b [&'a str]) -> TextDiff<'a, 'a, 'b, str> {
+    //Huge outputs can take a long time to diff, so settle for a less minimal diff after a while
+    TextDiff::configure()
+        .timeout(DIFF_TIMEOUT)
+        .newline_terminated(false)
+        .diff_slices(expected, actual)
+}
+
+// Finds the longest prefix of the regex that matches, returning the prefix and an excerpt around where it matched
+pub(crate) fn closest_match(pattern: &str, val: &str) -> Option<(String, String)> {
+    let boundaries: Vec<usize> = pattern
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .skip(1)
+        .chain(std::iter::once(pattern.len()))
+        .collect();
+
+    for end in boundaries.into_iter().rev() {
+        let prefix = &pattern[..end];
+
+        if let Ok(regex) = Regex::new(prefix) {
+            if let Some(found) = regex.find(val) {
+                if found.as_str().is_empty() {
+                    continue;
+                }
+                return Some((prefix.to_string(), excerpt(val, found.start(), found.end())));
+            }
+        }
+    }
+
+    None
+}
+
+// Returns the text around `start..end`, with some context either side
+pub(crate) fn excerpt(val: &str, start: usize, end: usize) -> String {
+    let mut from = start.saturating_sub(EXCERPT_CONTEXT);
+    while !val.is_char_boundary(from) {
+        from -= 1;
+    }
+
+    let mut to = (end + EXCERPT_CONTEXT).min(val.len());
+    while !val.is_char_boundary(to) {
+        to += 1;
+    }
+
+    format!(
+        "{}{}{}",
+        if from > 0 { "..." } else { "" },
+        &val[from..to],
+        if to < val.len() { "..." } else { "" }
+    )
+}
+
+// Flattens json into its leaf values, keyed by path such as `$.items[0].name`
+pub(crate) fn flatten<'a>(path: &str, value: &'a Value, leaves: &mut BTreeMap<String, &'a Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, val) in map {
+                flatten(&format!("{}.{}", path, key), val, leaves);
+            }
+        }
+        Value::Array(list) if !list.is_empty() => {
+            for (idx, val) in list.iter().enumerate() {
+                flatten(&format!("{}[{}]", path, idx), val, leaves);
+            }
+        }
+        _ => {
+            leaves.insert(path.to_string(), value);
+        }
+    }
+}
+
+// The leaf values that differ between two json documents
+#[derive(Debug, PartialEq)]
+pub(crate) struct JsonDiff {
+    //Each differing path with its values, i.e, `$.name: "a" -> "b"`, up to a limit
+    pub differences: Vec<String>,
+    pub changed: usize,
+    //How many paths were compared, which doesn't include those ignored
+    pub compared: usize,
+}
+
+// Compares two json documents leaf by leaf, skipping any paths that match `ignore`.
+// An ignore entry is either a key name, matched anywhere, or a path like `$.items[*].id`
+pub(crate) fn json_diff(
+    expected: &Value,
+    actual: &Value,
+    ignore: &[String],
+) -> Result<JsonDiff, String> {
+    let ignore = ignore
+        .iter()
+        .map(|pattern| ignore_regex(pattern))
+        .collect::<Result<Vec<Regex>, String>>()?;
+
+    let mut expected_leaves = BTreeMap::new();
+    let mut actual_leaves = BTreeMap::new();
+
+    flatten("$", expected, &mut expected_leaves);
+    flatten("$", actual, &mut actual_leaves);
+
+    let mut paths: Vec<&String> = expected_leaves.keys().chain(actual_leaves.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let show = |leaves: &BTreeMap<String, &Value>, path: &String| {
+        leaves
+            .get(path)
+            .map(|val| val.to_string())
+            .unwrap_or_else(|| "(missing)".into())
+    };
+
+    let paths: Vec<&String> = paths
+        .into_iter()
+        .filter(|path| !ignore.iter().any(|regex| regex.is_match(path)))
+        .collect();
+
+    let compared = paths.len();
+
+    let mut differences: Vec<String> = paths
+        .into_iter()
+        .filter(|path| expected_leaves.get(*path) != actual_leaves.get(*path))
+        .map(|path| {
+            format!(
+                "{}: {} -> {}",
+                path,
+                show(&expected_leaves, path),
+                show(&actual_leaves, path)
+            )
+        })
+        .collect();
+
+    let changed = differences.len();
+
+    if changed > MAX_JSON_DIFFERENCES {
+        differences.truncate(MAX_JSON_DIFFERENCES);
+        differences.push(format!("...and {} more", changed - MAX_JSON_DIFFERENCES));
+    }
+
+    Ok(JsonDiff {
+        differences,
+        changed,
+        compared,
+    })
+}
+
+// Matches the ignored path and anything underneath it
+fn ignore_regex(pattern: &str) -> Result<Regex, String> {
+    let prefix = if pattern.starts_with('$') {
+        format!(
+            "^{}",
+            regex::escape(pattern)
+                .replace(r"\[\*\]", r"\[\d+\]")
+                .replace(r"\*", r"[^.\[]+")
+        )
+    } else {
+        format!(r"\.{}", regex::escape(pattern))
+    };
+
+    Regex::new(&format!(r"{}($|[.\[])", prefix))
+        .map_err(|err| format!("Invalid ignore pattern `{}`: {}", pattern, err))
+}
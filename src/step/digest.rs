@@ -0,0 +1,210 @@
+use openssl::hash::{hash, MessageDigest};
+use reqwest::header::WWW_AUTHENTICATE;
+use reqwest::{Response, Url};
+
+use std::collections::HashMap;
+
+// The `Digest` challenge from a `401 Unauthorized` response, answered on each request after it
+pub(crate) struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    qop: Option<String>,
+    algorithm: String,
+    //How many times the nonce has been used, sent as `nc`
+    count: u32,
+}
+
+impl Challenge {
+    pub(crate) fn from_response(response: &Response) -> Result<Challenge, String> {
+        let header = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .flat_map(|val| val.to_str())
+            .find(|val| val.trim_start().to_lowercase().starts_with("digest "))
+            .ok_or("Server returned `401 Unauthorized` without a `Digest` challenge")?;
+
+        Challenge::parse(header)
+    }
+
+    fn parse(header: &str) -> Result<Challenge, String> {
+        let params = parse_params(header.trim_start()["digest ".len()..].trim());
+
+        let param = |name: &str| params.get(name).cloned();
+
+        //Only `auth` is supported, as `auth-int` needs the body hashed too
+        let qop = match param("qop") {
+            Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => Some("auth".into()),
+            Some(qop) => return Err(format!("Digest `qop` of `{}` is not supported", qop)),
+            None => None,
+        };
+
+        let algorithm = param("algorithm").unwrap_or_else(|| "MD5".into());
+
+        //Check the algorithm up front, so a bad one is reported rather than answered wrongly
+        digest_for(&algorithm)?;
+
+        Ok(Challenge {
+            realm: param("realm").unwrap_or_default(),
+            nonce: param("nonce").ok_or("Digest challenge has no `nonce`")?,
+            opaque: param("opaque"),
+            qop,
+            algorithm,
+            count: 0,
+        })
+    }
+
+    // The `Authorization` header answering the challenge for a request
+    pub(crate) fn authorization(
+        &mut self,
+        user: &str,
+        pass: &str,
+        method: &str,
+        url: &Url,
+    ) -> Result<String, String> {
+        let cnonce = format!("{:016x}", rand::random::<u64>());
+
+        self.answer(user, pass, method, url, &cnonce)
+    }
+
+    fn answer(
+        &mut self,
+        user: &str,
+        pass: &str,
+        method: &str,
+        url: &Url,
+        cnonce: &str,
+    ) -> Result<String, String> {
+        self.count += 1;
+
+        let digest = digest_for(&self.algorithm)?;
+        let h = |input: String| hex_hash(digest, &input);
+
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        let nc = format!("{:08x}", self.count);
+
+        let mut ha1 = h(format!("{}:{}:{}", user, self.realm, pass))?;
+
+        if self.algorithm.to_lowercase().ends_with("-sess") {
+            ha1 = h(format!("{}:{}:{}", ha1, self.nonce, cnonce))?;
+        }
+
+        let ha2 = h(format!("{}:{}", method, uri))?;
+
+        let response = match self.qop {
+            Some(ref qop) => h(format!(
+                "{}:{}:{}:{}:{}:{}",
+                ha1, self.nonce, nc, cnonce, qop, ha2
+            ))?,
+            None => h(format!("{}:{}:{}", ha1, self.nonce, ha2))?,
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+            user, self.realm, self.nonce, uri, self.algorithm, response
+        );
+
+        if let Some(ref qop) = self.qop {
+            header.push_str(&format!(", qop={}, nc={}, cnonce=\"{}\"", qop, nc, cnonce));
+        }
+
+        if let Some(ref opaque) = self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+
+        Ok(header)
+    }
+}
+
+fn digest_for(algorithm: &str) -> Result<MessageDigest, String> {
+    match algorithm.to_uppercase().trim_end_matches("-SESS") {
+        "MD5" => Ok(MessageDigest::md5()),
+        "SHA-256" => Ok(MessageDigest::sha256()),
+        _ => Err(format!("Digest algorithm `{}` is not supported", algorithm)),
+    }
+}
+
+fn hex_hash(digest: MessageDigest, input: &str) -> Result<String, String> {
+    let bytes = hash(digest, input.as_bytes()).map_err(|err| format!("{}", err))?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Splits `key=value, key="quoted, value"` into its keys & values
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .skip_while(|ch| *ch == ',' || ch.is_whitespace())
+            .take_while(|ch| *ch != '=')
+            .collect();
+
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+
+            while let Some(ch) = chars.next() {
+                match ch {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    ch => value.push(ch),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|ch| *ch != ',').collect();
+        }
+
+        params.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_rfc_2617_example() {
+        let mut challenge = Challenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+
+        let url = Url::parse("http://www.nowhere.org/dir/index.html").unwrap();
+
+        let header = challenge
+            .answer("Mufasa", "Circle Of Life", "GET", &url, "0a4f113b")
+            .unwrap();
+
+        assert_eq!(
+            header,
+            "Digest username=\"Mufasa\", realm=\"testrealm@host.com\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", uri=\"/dir/index.html\", algorithm=MD5, \
+             response=\"6629fae49393a05397450978507c4ef1\", qop=auth, nc=00000001, cnonce=\"0a4f113b\", \
+             opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""
+        );
+
+        //Without a `qop`, the response only depends on the nonce
+        challenge.qop = None;
+
+        let header = challenge
+            .answer("Mufasa", "Circle Of Life", "GET", &url, "0a4f113b")
+            .unwrap();
+
+        assert!(header.contains(r#"response="670fd8c2df070c60b045671b8b24ff02""#));
+    }
+}
@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 #[cfg(not(target_os = "windows"))]
 use std::cmp;
@@ -7,14 +8,14 @@ use log::*;
 #[cfg(not(target_os = "windows"))]
 use std::{ffi::CString, mem::zeroed};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum DiskVariant {
     MountPointOnly(String),
     Options(DiskOptions),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct DiskOptions {
     mount: String,
     #[serde(default, rename = "type")]
@@ -23,34 +24,24 @@ pub struct DiskOptions {
     output_type: OutputType,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum DiskType {
     Size,
     Used,
+    #[default]
     Free,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum OutputType {
+    #[default]
     Bytes,
     Human,
     Percent,
 }
 
-impl Default for DiskType {
-    fn default() -> Self {
-        DiskType::Free
-    }
-}
-
-impl Default for OutputType {
-    fn default() -> Self {
-        OutputType::Bytes
-    }
-}
-
 impl DiskVariant {
     pub async fn run(&self) -> Result<String, String> {
         let diskops = match *self {
@@ -112,10 +103,10 @@ pub fn get_stats(ops: &DiskOptions) -> Result<String, String> {
                     ops.mount
                 ));
             }
-            return Ok(format!(
+            Ok(format!(
                 "{}%",
                 ((output as f64 / size as f64) * 100.0).round() as usize
-            ));
+            ))
         }
         OutputType::Human => Ok(pretty_bytes(output as f64)),
     }
@@ -141,5 +132,5 @@ pub fn pretty_bytes(num: f64) -> String {
     );
 
     let unit = units[exponent as usize];
-    return format!("{}{:.2}{}", negative, num / delimiter.powi(exponent), unit);
+    format!("{}{:.2}{}", negative, num / delimiter.powi(exponent), unit)
 }
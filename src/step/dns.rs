@@ -0,0 +1,111 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::proto::rr::RecordType as DnsRecordType;
+use trust_dns_resolver::proto::xfer::DnsRequestOptions;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum DnsVariant {
+    NameOnly(String),
+    Options(DnsOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DnsOptions {
+    name: String,
+    #[serde(default)]
+    record_type: RecordType,
+    //The nameserver to query, i.e, `8.8.8.8` or `10.0.0.2:5353`.  Uses the system resolver if not set
+    #[serde(default)]
+    resolver: Option<String>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordType {
+    #[default]
+    A,
+    Aaaa,
+    Cname,
+    Txt,
+    Mx,
+}
+
+impl From<&RecordType> for DnsRecordType {
+    fn from(record_type: &RecordType) -> Self {
+        match record_type {
+            RecordType::A => DnsRecordType::A,
+            RecordType::Aaaa => DnsRecordType::AAAA,
+            RecordType::Cname => DnsRecordType::CNAME,
+            RecordType::Txt => DnsRecordType::TXT,
+            RecordType::Mx => DnsRecordType::MX,
+        }
+    }
+}
+
+impl DnsVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let dnsopts = match *self {
+            DnsVariant::NameOnly(ref name) => DnsOptions {
+                name: name.clone(),
+                record_type: RecordType::A,
+                resolver: None,
+            },
+            DnsVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let resolver = match dnsopts.resolver {
+            Some(ref resolver) => {
+                let addr = parse_resolver(resolver)?;
+
+                let config = ResolverConfig::from_parts(
+                    None,
+                    vec![],
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+                );
+
+                TokioAsyncResolver::tokio(config, ResolverOpts::default())
+            }
+            None => TokioAsyncResolver::tokio_from_system_conf(),
+        }
+        .map_err(|err| format!("Could not create resolver: {}", err))?;
+
+        let record_type = DnsRecordType::from(&dnsopts.record_type);
+
+        let lookup = resolver
+            .lookup(
+                dnsopts.name.as_str(),
+                record_type,
+                DnsRequestOptions::default(),
+            )
+            .await
+            .map_err(|err| format!("Could not resolve `{}`: {}", dnsopts.name, err))?;
+
+        //Lookups can include other records, such as the CNAME chain for an `A` lookup
+        let mut records: Vec<String> = lookup
+            .record_iter()
+            .filter(|record| record.record_type() == record_type)
+            .map(|record| record.rdata().to_string())
+            .collect();
+
+        records.sort();
+
+        serde_json::to_string_pretty(&records).map_err(|err| format!("{}", err))
+    }
+}
+
+fn parse_resolver(resolver: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = SocketAddr::from_str(resolver) {
+        return Ok(addr);
+    }
+
+    IpAddr::from_str(resolver)
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| format!("Resolver `{}` must be an ip address", resolver))
+}
@@ -0,0 +1,89 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use std::time::Duration;
+
+fn default_etcd_url() -> String {
+    "http://127.0.0.1:2379".into()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum EtcdVariant {
+    KeyOnly(String),
+    Options(EtcdOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct EtcdOptions {
+    #[serde(default = "default_etcd_url")]
+    url: String,
+    #[serde(default)]
+    key: Option<String>,
+}
+
+impl EtcdVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let etcdops = match *self {
+            EtcdVariant::KeyOnly(ref key) => EtcdOptions {
+                url: default_etcd_url(),
+                key: Some(key.clone()),
+            },
+            EtcdVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let base = etcdops.url.trim_end_matches('/');
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        match etcdops.key {
+            //Read a key via the v3 JSON gateway, keys and values are base64 encoded
+            Some(key) => {
+                let response = http_log::send(
+                    client
+                        .post(format!("{}/v3/kv/range", base))
+                        .json(&json!({ "key": base64::encode(&key) })),
+                )
+                .await
+                .map_err(|err| format!("Error connecting to etcd {}", err))?;
+
+                if !response.status().is_success() {
+                    return Err(format!(
+                        "etcd returned status `{}`",
+                        response.status().as_u16()
+                    ));
+                }
+
+                let body: Value = response.json().await.map_err(|err| format!("{}", err))?;
+
+                let value = body
+                    .pointer("/kvs/0/value")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| format!("Key `{}` could not be found in etcd", key))?;
+
+                let decoded = base64::decode(value)
+                    .map_err(|err| format!("Could not decode value for `{}`: {}", key, err))?;
+
+                Ok(String::from_utf8_lossy(&decoded).to_string())
+            }
+            //Otherwise check the health endpoint
+            None => {
+                let response = http_log::send(client.get(format!("{}/health", base)))
+                    .await
+                    .map_err(|err| format!("Error connecting to etcd {}", err))?;
+
+                let body: Value = response.json().await.map_err(|err| format!("{}", err))?;
+
+                match body.get("health").and_then(Value::as_str) {
+                    Some("true") => Ok(body.to_string()),
+                    _ => Err(format!("etcd is not healthy: {}", body)),
+                }
+            }
+        }
+    }
+}
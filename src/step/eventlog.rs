@@ -0,0 +1,191 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::wmi::{powershell_json, quote};
+use crate::audit_log;
+
+fn default_within_s() -> u64 {
+    3600
+}
+
+fn default_max_events() -> usize {
+    10
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum EventLogVariant {
+    LogOnly(String),
+    Options(EventLogOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct EventLogOptions {
+    //The log name, i.e, `System` or `Application`
+    #[serde(default)]
+    log: Option<String>,
+    //The event source, i.e, `Service Control Manager`
+    #[serde(default)]
+    provider: Option<String>,
+    //Events at this level or more severe
+    #[serde(default)]
+    level: Option<EventLevel>,
+    #[serde(default)]
+    id: Option<EventIds>,
+    //How far back to look, in seconds
+    #[serde(default = "default_within_s")]
+    within_s: u64,
+    //How many of the most recent events to include messages for
+    #[serde(default = "default_max_events")]
+    max_events: usize,
+    #[serde(default)]
+    computer: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EventLevel {
+    Critical,
+    Error,
+    Warning,
+    Information,
+    Verbose,
+}
+
+impl EventLevel {
+    // The `Level` values of this level & the ones more severe.  Information can also be `0`, for classic event logs
+    fn levels(self) -> &'static str {
+        match self {
+            EventLevel::Critical => "1",
+            EventLevel::Error => "1,2",
+            EventLevel::Warning => "1,2,3",
+            EventLevel::Information => "0,1,2,3,4",
+            EventLevel::Verbose => "0,1,2,3,4,5",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum EventIds {
+    One(u32),
+    Many(Vec<u32>),
+}
+
+impl EventLogVariant {
+    pub(crate) fn needs_network(&self) -> bool {
+        matches!(self, EventLogVariant::Options(opts) if opts.computer.is_some())
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let logopts = match *self {
+            EventLogVariant::LogOnly(ref log) => EventLogOptions {
+                log: Some(log.clone()),
+                provider: None,
+                level: None,
+                id: None,
+                within_s: default_within_s(),
+                max_events: default_max_events(),
+                computer: None,
+            },
+            EventLogVariant::Options(ref opts) => opts.clone(),
+        };
+
+        if !cfg!(windows) {
+            return Err("Not Implemented Yet".into());
+        }
+
+        let script = logopts.script()?;
+
+        audit_log::record("powershell", &script);
+
+        let mut output = tokio::task::spawn_blocking(move || powershell_json(&script))
+            .await
+            .map_err(|err| format!("{}", err))??;
+
+        //A single event is converted to an object rather than a list
+        if output["events"].is_object() {
+            output["events"] = json!([output["events"].take()]);
+        }
+
+        output["log"] = json!(logopts.log.or(logopts.provider));
+
+        serde_json::to_string_pretty(&output).map_err(|err| format!("{}", err))
+    }
+}
+
+impl EventLogOptions {
+    // The powershell to run, which outputs the count & most recent events as json
+    fn script(&self) -> Result<String, String> {
+        let mut filter = vec![format!(
+            "StartTime=(Get-Date).AddSeconds(-{})",
+            self.within_s
+        )];
+
+        if self.log.is_none() && self.provider.is_none() {
+            return Err("Specify a `log` or `provider`".into());
+        }
+
+        if let Some(ref log) = self.log {
+            filter.push(format!("LogName={}", quote(log)));
+        }
+
+        if let Some(ref provider) = self.provider {
+            filter.push(format!("ProviderName={}", quote(provider)));
+        }
+
+        if let Some(level) = self.level {
+            filter.push(format!("Level={}", level.levels()));
+        }
+
+        match self.id {
+            Some(EventIds::One(id)) => filter.push(format!("Id={}", id)),
+            Some(EventIds::Many(ref ids)) if !ids.is_empty() => filter.push(format!(
+                "Id={}",
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+            _ => (),
+        }
+
+        let computer = match self.computer {
+            Some(ref computer) => format!(" -ComputerName {}", quote(computer)),
+            None => String::new(),
+        };
+
+        //Get-WinEvent raises an error when nothing matches, rather than returning nothing
+        Ok(format!(
+            "$events = try {{ @(Get-WinEvent -ErrorAction Stop -FilterHashtable @{{{}}}{}) }} catch {{ if ($_.FullyQualifiedErrorId -like 'NoMatchingEventsFound*') {{ @() }} else {{ throw }} }}; \
+            ConvertTo-Json -Compress -Depth 3 -InputObject @{{count=$events.Count; events=@($events | Select-Object -First {} @{{n='time';e={{$_.TimeCreated.ToUniversalTime().ToString('o')}}}}, @{{n='id';e={{$_.Id}}}}, @{{n='level';e={{$_.LevelDisplayName}}}}, @{{n='provider';e={{$_.ProviderName}}}}, @{{n='message';e={{$_.Message}}}})}}",
+            filter.join("; "),
+            computer,
+            self.max_events
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_event_filter() {
+        let opts: EventLogOptions = serde_yaml::from_str(
+            "provider: Service Control Manager\nlevel: error\nid: [7031, 7034]\nwithin_s: 600",
+        )
+        .unwrap();
+
+        let script = opts.script().unwrap();
+
+        assert!(script.contains(
+            "@{StartTime=(Get-Date).AddSeconds(-600); ProviderName='Service Control Manager'; Level=1,2; Id=7031,7034}"
+        ));
+        assert!(script.contains("Select-Object -First 10"));
+
+        let opts: EventLogOptions = serde_yaml::from_str("level: warning").unwrap();
+        assert!(opts.script().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::http::connection_error;
+use super::send_json;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use reqwest::Client;
+
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+fn default_scope() -> String {
+    DEFAULT_SCOPE.into()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum GcpVariant {
+    CheckOnly(GcpCheck),
+    Options(GcpOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GcpCheck {
+    //The metadata server is reachable
+    Metadata,
+    //A token can be obtained from the default credentials
+    Token,
+    //A pubsub topic exists
+    PubsubTopic,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GcpOptions {
+    check: GcpCheck,
+    #[serde(default = "default_scope")]
+    scope: String,
+    #[serde(default)]
+    project: Option<String>,
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+impl GcpVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let gcpopts = match *self {
+            GcpVariant::CheckOnly(ref check) => GcpOptions {
+                check: check.clone(),
+                scope: default_scope(),
+                project: None,
+                topic: None,
+            },
+            GcpVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let output = match gcpopts.check {
+            GcpCheck::Metadata => {
+                send_json(
+                    client
+                        .get(format!("{}/instance/", metadata_url()))
+                        .query(&[("recursive", "true")])
+                        .header("Metadata-Flavor", "Google"),
+                )
+                .await?
+            }
+            GcpCheck::Token => {
+                let token = get_token(&client, &gcpopts.scope).await?;
+
+                json!({
+                    "source": token.source,
+                    "token_type": token.token_type,
+                    "expires_in": token.expires_in,
+                })
+            }
+            GcpCheck::PubsubTopic => {
+                let topic = gcpopts
+                    .topic
+                    .as_ref()
+                    .ok_or("The `pubsub_topic` check requires a `topic`")?;
+
+                let token = get_token(&client, DEFAULT_SCOPE).await?;
+
+                let project = match gcpopts.project {
+                    Some(ref project) => project.clone(),
+                    None => match token.project_id {
+                        Some(ref project) => project.clone(),
+                        None => get_project(&client).await?,
+                    },
+                };
+
+                let url = format!(
+                    "https://pubsub.googleapis.com/v1/projects/{}/topics/{}",
+                    project, topic
+                );
+
+                let response = http_log::send(client.get(&url).bearer_auth(&token.access_token))
+                    .await
+                    .map_err(|err| connection_error(&err))?;
+
+                if response.status().as_u16() == 404 {
+                    return Err(format!(
+                        "Topic `{}` does not exist in project `{}`",
+                        topic, project
+                    ));
+                }
+
+                if !response.status().is_success() {
+                    return Err(format!(
+                        "Request for topic `{}` returned status {}",
+                        topic,
+                        response.status()
+                    ));
+                }
+
+                response
+                    .json()
+                    .await
+                    .map_err(|err| format!("Could not parse pubsub response: {}", err))?
+            }
+        };
+
+        serde_json::to_string_pretty(&output).map_err(|err| format!("{}", err))
+    }
+}
+
+pub(crate) fn metadata_url() -> String {
+    let host = env::var("GCE_METADATA_HOST").unwrap_or_else(|_| "metadata.google.internal".into());
+    format!("http://{}/computeMetadata/v1", host)
+}
+
+struct Token {
+    source: &'static str,
+    access_token: String,
+    token_type: Value,
+    expires_in: Value,
+    project_id: Option<String>,
+}
+
+// Follows the application default credentials order: `GOOGLE_APPLICATION_CREDENTIALS`,
+// the gcloud user credentials, then the metadata server
+async fn get_token(client: &Client, scope: &str) -> Result<Token, String> {
+    let credentials_file = env::var("GOOGLE_APPLICATION_CREDENTIALS")
+        .map(PathBuf::from)
+        .ok()
+        .or_else(|| {
+            let config = match env::var("CLOUDSDK_CONFIG") {
+                Ok(config) => PathBuf::from(config),
+                Err(_) => PathBuf::from(env::var("HOME").ok()?).join(".config/gcloud"),
+            };
+            Some(config.join("application_default_credentials.json")).filter(|path| path.exists())
+        });
+
+    let (source, response, project_id) = match credentials_file {
+        Some(path) => {
+            let contents = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|err| format!("Could not read credentials {:?}: {}", path, err))?;
+
+            let credentials: Value = serde_json::from_str(&contents)
+                .map_err(|err| format!("Could not parse credentials {:?}: {}", path, err))?;
+
+            let project_id = credentials["project_id"]
+                .as_str()
+                .or_else(|| credentials["quota_project_id"].as_str())
+                .map(String::from);
+
+            match credentials["type"].as_str() {
+                Some("service_account") => {
+                    let assertion = service_account_jwt(&credentials, scope)?;
+
+                    let request = client.post(TOKEN_URL).form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &assertion),
+                    ]);
+
+                    ("service_account", send_json(request).await?, project_id)
+                }
+                Some("authorized_user") => {
+                    let field = |name: &str| credentials[name].as_str().unwrap_or_default();
+
+                    let request = client.post(TOKEN_URL).form(&[
+                        ("grant_type", "refresh_token"),
+                        ("client_id", field("client_id")),
+                        ("client_secret", field("client_secret")),
+                        ("refresh_token", field("refresh_token")),
+                    ]);
+
+                    ("authorized_user", send_json(request).await?, project_id)
+                }
+                other => {
+                    return Err(format!(
+                        "Unsupported credentials type `{}` in {:?}",
+                        other.unwrap_or_default(),
+                        path
+                    ))
+                }
+            }
+        }
+        None => {
+            let request = client
+                .get(format!(
+                    "{}/instance/service-accounts/default/token",
+                    metadata_url()
+                ))
+                .query(&[("scopes", scope)])
+                .header("Metadata-Flavor", "Google");
+
+            ("metadata", send_json(request).await?, None)
+        }
+    };
+
+    let access_token = response["access_token"]
+        .as_str()
+        .ok_or("No access token was returned")?
+        .to_string();
+
+    Ok(Token {
+        source,
+        access_token,
+        token_type: response["token_type"].clone(),
+        expires_in: response["expires_in"].clone(),
+        project_id,
+    })
+}
+
+fn service_account_jwt(credentials: &Value, scope: &str) -> Result<String, String> {
+    let email = credentials["client_email"]
+        .as_str()
+        .ok_or("Service account credentials are missing `client_email`")?;
+
+    let private_key = credentials["private_key"]
+        .as_str()
+        .ok_or("Service account credentials are missing `private_key`")?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("{}", err))?
+        .as_secs();
+
+    let header = json!({"alg": "RS256", "typ": "JWT"});
+    let claims = json!({
+        "iss": email,
+        "scope": scope,
+        "aud": credentials["token_uri"].as_str().unwrap_or(TOKEN_URL),
+        "iat": now,
+        "exp": now + 3600,
+    });
+
+    let unsigned = format!(
+        "{}.{}",
+        base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+        base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+    );
+
+    let key = PKey::private_key_from_pem(private_key.as_bytes())
+        .map_err(|err| format!("Could not load service account key: {}", err))?;
+
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).map_err(|err| format!("{}", err))?;
+    signer
+        .update(unsigned.as_bytes())
+        .map_err(|err| format!("{}", err))?;
+    let signature = signer.sign_to_vec().map_err(|err| format!("{}", err))?;
+
+    Ok(format!(
+        "{}.{}",
+        unsigned,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+async fn get_project(client: &Client) -> Result<String, String> {
+    if let Ok(project) = env::var("GOOGLE_CLOUD_PROJECT") {
+        return Ok(project);
+    }
+
+    let unknown = |err: String| {
+        format!(
+            "Could not determine the project, please provide one: {}",
+            err
+        )
+    };
+
+    let response = http_log::send(
+        client
+            .get(format!("{}/project/project-id", metadata_url()))
+            .header("Metadata-Flavor", "Google"),
+    )
+    .await
+    .map_err(|err| unknown(err.to_string()))?;
+
+    //An error page would otherwise be taken as the project
+    let failed = response.error_for_status_ref().err();
+
+    let body = response
+        .text()
+        .await
+        .map_err(|err| unknown(err.to_string()))?;
+
+    match failed {
+        Some(err) => Err(unknown(format!("{}: {}", err, body.trim()))),
+        None => Ok(body),
+    }
+}
@@ -0,0 +1,101 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use log::debug;
+
+use super::{ExpectType, Failure, FilterType, RunType, STEP_OUTPUT};
+
+// A step within a group, with its own filters & expectation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GroupStep {
+    pub name: String,
+    pub run: RunType,
+    #[serde(default)]
+    pub filters: Vec<FilterType>,
+    #[serde(default)]
+    pub expect: ExpectType,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GroupVariant {
+    pub steps: Vec<GroupStep>,
+}
+
+impl GroupVariant {
+    // Runs each step in order, stopping at the first that fails.  The output is the output of the last step
+    pub(crate) async fn run(&self) -> Result<String, Failure> {
+        let mut output = String::new();
+
+        for step in self.steps.iter() {
+            debug!("Running group step `{}`", step.name);
+
+            //Groups can contain groups, so this needs to be boxed
+            output = Box::pin(step.run.attempt(false))
+                .await
+                .map(|(output, _)| output)
+                .map_err(|(code, err)| (code, format!("`{}` failed: {}", step.name, err)))?;
+
+            for filter in step.filters.iter() {
+                output = filter
+                    .filter(&output)
+                    .map_err(|(code, err)| (code, format!("`{}` failed: {}", step.name, err)))?;
+            }
+
+            step.expect
+                .check(&output, false)
+                .map_err(|(code, err)| (code, format!("`{}` failed: {}", step.name, err)))?;
+
+            //Later steps in the group can use the output, i.e, for a token from a login page
+            STEP_OUTPUT.insert(step.name.clone(), output.clone());
+        }
+
+        Ok(output)
+    }
+
+    pub(crate) fn needs_network(&self) -> bool {
+        self.steps.iter().any(|step| step.run.needs_network())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::step::ErrorCode;
+    use crate::yaml::get_steps_raw;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn stops_at_first_failing_step() {
+        let steps = get_steps_raw(
+            "flow:\n  group:\n    token:\n      value: '{\"token\": \"abc\"}'\n      jmespath: token\n    check:\n      value: two\n      matches: three\n    never:\n      value: three\n",
+            &HashMap::<String, String>::new(),
+        )
+        .unwrap();
+
+        let group = match steps[0].run {
+            RunType::Group(ref group) => group.clone(),
+            _ => panic!("Expected a group"),
+        };
+
+        assert_eq!(
+            group
+                .steps
+                .iter()
+                .map(|step| &*step.name)
+                .collect::<Vec<_>>(),
+            vec!["token", "check", "never"]
+        );
+
+        let (code, err) = group.run().await.unwrap_err();
+        assert_eq!(code, ErrorCode::MatchFailed);
+        assert!(err.starts_with("`check` failed"));
+        assert_eq!(STEP_OUTPUT.get("token"), Some("abc".into()));
+        assert!(STEP_OUTPUT.get("never").is_none());
+
+        assert!(get_steps_raw(
+            "token:\n  value: a\nflow:\n  group:\n    token:\n      value: b\n",
+            &HashMap::<String, String>::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use hyper::body::HttpBody;
+use hyper::client::conn::{Builder, SendRequest};
+use hyper::{Body, HeaderMap, Request, Uri};
+use linked_hash_map::LinkedHashMap;
+use native_tls::TlsConnector;
+use percent_encoding::percent_decode_str;
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MethodDescriptor};
+use prost_types::FileDescriptorProto;
+use tokio::net::TcpStream;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+
+const REFLECTION: [&str; 2] = [
+    "/grpc.reflection.v1.ServerReflection/ServerReflectionInfo",
+    "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+];
+
+//The status returned when a service or method doesn't exist
+const UNIMPLEMENTED: u32 = 12;
+
+fn default_timeout_ms() -> u64 {
+    10000
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum GrpcVariant {
+    UrlOnly(String),
+    Options(GrpcOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GrpcOptions {
+    url: String,
+    //The service to check the health of, defaults to the whole server
+    #[serde(default)]
+    service: String,
+    //A unary method to call instead of the health check, as `package.Service/Method`
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(default)]
+    request: Option<Value>,
+    #[serde(default)]
+    descriptor_set: Option<PathBuf>,
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, String>")]
+    metadata: LinkedHashMap<String, String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HealthCheckRequest {
+    #[prost(string, tag = "1")]
+    service: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct HealthCheckResponse {
+    #[prost(int32, tag = "1")]
+    status: i32,
+}
+
+//The `message_request` & `message_response` oneofs are flattened, as only a few variants are needed
+#[derive(Clone, PartialEq, Message)]
+struct ReflectionRequest {
+    #[prost(string, tag = "1")]
+    host: String,
+    #[prost(string, optional, tag = "3")]
+    file_by_filename: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    file_containing_symbol: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReflectionResponse {
+    #[prost(message, optional, tag = "4")]
+    file_descriptor_response: Option<FileDescriptorResponse>,
+    #[prost(message, optional, tag = "7")]
+    error_response: Option<ReflectionError>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorResponse {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    file_descriptor_proto: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ReflectionError {
+    #[prost(int32, tag = "1")]
+    error_code: i32,
+    #[prost(string, tag = "2")]
+    error_message: String,
+}
+
+impl GrpcVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let grpcops = match *self {
+            GrpcVariant::UrlOnly(ref url) => GrpcOptions {
+                url: url.clone(),
+                service: String::new(),
+                method: None,
+                request: None,
+                descriptor_set: None,
+                metadata: LinkedHashMap::new(),
+                timeout_ms: default_timeout_ms(),
+                verify_ssl: None,
+            },
+            GrpcVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let timeout = Duration::from_millis(grpcops.timeout_ms);
+
+        tokio::time::timeout(timeout, grpcops.check())
+            .await
+            .map_err(|_| format!("Timed out after {}ms", grpcops.timeout_ms))?
+    }
+}
+
+impl GrpcOptions {
+    async fn check(&self) -> Result<String, String> {
+        let mut channel = Channel::connect(self).await?;
+
+        match self.method {
+            Some(ref method) => self.call(&mut channel, method).await,
+            None => self.health_check(&mut channel).await,
+        }
+    }
+
+    async fn health_check(&self, channel: &mut Channel) -> Result<String, String> {
+        let request = HealthCheckRequest {
+            service: self.service.clone(),
+        };
+
+        let response = channel
+            .unary(HEALTH_CHECK, request.encode_to_vec())
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let response = HealthCheckResponse::decode(&*response)
+            .map_err(|err| format!("Could not decode health check response: {}", err))?;
+
+        let status = match response.status {
+            1 => "SERVING",
+            2 => "NOT_SERVING",
+            3 => "SERVICE_UNKNOWN",
+            _ => "UNKNOWN",
+        };
+
+        if status != "SERVING" {
+            return Err(format!("Health check returned `{}`", status));
+        }
+
+        Ok(json!({ "status": status }).to_string())
+    }
+
+    async fn call(&self, channel: &mut Channel, method: &str) -> Result<String, String> {
+        let (service_name, method_name) = method
+            .trim_start_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(|| format!("Method `{}` should be `package.Service/Method`", method))?;
+
+        let pool = match self.descriptor_set {
+            Some(ref path) => {
+                let contents = tokio::fs::read(path)
+                    .await
+                    .map_err(|err| format!("Could not read `{}`: {}", path.display(), err))?;
+
+                DescriptorPool::decode(&*contents)
+                    .map_err(|err| format!("Could not parse `{}`: {}", path.display(), err))?
+            }
+            None => channel.reflect(service_name).await?,
+        };
+
+        let method = find_method(&pool, service_name, method_name)?;
+
+        let request = match self.request {
+            Some(Value::String(ref request)) => serde_json::from_str(request)
+                .map_err(|err| format!("Could not parse request as json: {}", err))?,
+            Some(ref request) => request.clone(),
+            None => json!({}),
+        };
+
+        let request = DynamicMessage::deserialize(method.input(), request).map_err(|err| {
+            format!(
+                "Request does not match `{}`: {}",
+                method.input().full_name(),
+                err
+            )
+        })?;
+
+        let path = format!("/{}/{}", service_name, method_name);
+
+        let response = channel
+            .unary(&path, request.encode_to_vec())
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let response = DynamicMessage::decode(method.output(), &*response)
+            .map_err(|err| format!("Could not decode response: {}", err))?;
+
+        serde_json::to_string_pretty(&response).map_err(|err| format!("{}", err))
+    }
+}
+
+fn find_method(
+    pool: &DescriptorPool,
+    service_name: &str,
+    method_name: &str,
+) -> Result<MethodDescriptor, String> {
+    pool.get_service_by_name(service_name)
+        .ok_or_else(|| format!("Service `{}` could not be found", service_name))?
+        .methods()
+        .find(|method| method.name() == method_name)
+        .ok_or_else(|| {
+            format!(
+                "Method `{}` could not be found on `{}`",
+                method_name, service_name
+            )
+        })
+}
+
+struct Channel {
+    sender: SendRequest<Body>,
+    base: String,
+    host: String,
+    metadata: HeaderMap,
+}
+
+struct GrpcError {
+    code: u32,
+    message: String,
+}
+
+impl std::fmt::Display for GrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Channel {
+    async fn connect(opts: &GrpcOptions) -> Result<Channel, String> {
+        let uri: Uri = opts
+            .url
+            .parse()
+            .map_err(|err| format!("Failed to parse url `{}`: {}", opts.url, err))?;
+
+        let tls = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err("Url must start with `http://` or `https://`".into()),
+        };
+
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("No host could be found for url: {}", opts.url))?
+            .to_string();
+
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        let stream = TcpStream::connect((&*host, port))
+            .await
+            .map_err(|err| format!("Could not connect to `{}:{}`: {}", host, port, err))?;
+
+        let builder = {
+            let mut builder = Builder::new();
+            builder.http2_only(true);
+            builder
+        };
+
+        //The connection is driven in the background until the sender is dropped
+        let sender = if tls {
+            let mut connector = TlsConnector::builder();
+            connector.request_alpns(&["h2"]);
+
+            if let Some(verify_ssl) = opts.verify_ssl {
+                connector.danger_accept_invalid_certs(!verify_ssl);
+            }
+
+            let connector = tokio_native_tls::TlsConnector::from(
+                connector.build().map_err(|err| format!("{}", err))?,
+            );
+
+            let stream = connector
+                .connect(&host, stream)
+                .await
+                .map_err(|err| format!("TLS error: {}", err))?;
+
+            let (sender, connection) = builder
+                .handshake(stream)
+                .await
+                .map_err(|err| format!("HTTP/2 handshake failed: {}", err))?;
+            tokio::spawn(connection);
+            sender
+        } else {
+            let (sender, connection) = builder
+                .handshake(stream)
+                .await
+                .map_err(|err| format!("HTTP/2 handshake failed: {}", err))?;
+            tokio::spawn(connection);
+            sender
+        };
+
+        let mut metadata = HeaderMap::new();
+
+        for (key, value) in opts.metadata.iter() {
+            let key: hyper::header::HeaderName = key
+                .parse()
+                .map_err(|err| format!("Invalid metadata key `{}`: {}", key, err))?;
+            let value = value
+                .parse()
+                .map_err(|err| format!("Invalid metadata value for `{}`: {}", key, err))?;
+            metadata.insert(key, value);
+        }
+
+        Ok(Channel {
+            sender,
+            base: format!("{}://{}:{}", if tls { "https" } else { "http" }, host, port),
+            host,
+            metadata,
+        })
+    }
+
+    // Makes a call with a single request message, returning the single response message
+    async fn unary(&mut self, path: &str, message: Vec<u8>) -> Result<Vec<u8>, GrpcError> {
+        let error = |message: String| GrpcError { code: 0, message };
+
+        let mut body = Vec::with_capacity(message.len() + 5);
+        body.push(0);
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+
+        let mut request = Request::post(format!("{}{}", self.base, path))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(body))
+            .map_err(|err| error(format!("{}", err)))?;
+
+        request.headers_mut().extend(self.metadata.clone());
+
+        futures::future::poll_fn(|cx| self.sender.poll_ready(cx))
+            .await
+            .map_err(|err| error(format!("Connection closed: {}", err)))?;
+
+        let response = self
+            .sender
+            .send_request(request)
+            .await
+            .map_err(|err| error(format!("Request failed: {}", err)))?;
+
+        if !response.status().is_success() {
+            return Err(error(format!(
+                "Unexpected HTTP status `{}`",
+                response.status()
+            )));
+        }
+
+        let headers = response.headers().clone();
+        let mut body = response.into_body();
+        let mut data = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.map_err(|err| error(format!("{}", err)))?);
+        }
+
+        //A response without a message has the status in the headers, rather than the trailers
+        let trailers = body
+            .trailers()
+            .await
+            .map_err(|err| error(format!("{}", err)))?
+            .unwrap_or(headers);
+
+        let code = trailers
+            .get("grpc-status")
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<u32>().ok())
+            .ok_or_else(|| error("Response had no `grpc-status`".into()))?;
+
+        if code != 0 {
+            let message = trailers
+                .get("grpc-message")
+                .and_then(|val| val.to_str().ok())
+                .map(|val| percent_decode_str(val).decode_utf8_lossy().to_string())
+                .unwrap_or_default();
+
+            return Err(GrpcError {
+                code,
+                message: format!("grpc-status {}: {}", code, message),
+            });
+        }
+
+        if data.len() < 5 {
+            return Err(error("Response had no message".into()));
+        }
+
+        if data[0] != 0 {
+            return Err(error("Compressed responses are not supported".into()));
+        }
+
+        let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+
+        data.get(5..5 + len)
+            .map(|message| message.to_vec())
+            .ok_or_else(|| error("Response message was truncated".into()))
+    }
+
+    // Builds up the descriptors for a service using server reflection
+    async fn reflect(&mut self, service_name: &str) -> Result<DescriptorPool, String> {
+        let mut files: HashMap<String, FileDescriptorProto> = HashMap::new();
+
+        let mut pending = self
+            .reflection_request(ReflectionRequest {
+                host: self.host.clone(),
+                file_by_filename: None,
+                file_containing_symbol: Some(service_name.into()),
+            })
+            .await?;
+
+        while let Some(file) = pending.pop() {
+            let name = file.name().to_string();
+
+            if files.contains_key(&name) {
+                continue;
+            }
+
+            for dependency in file.dependency.iter() {
+                if !files.contains_key(dependency)
+                    && !pending.iter().any(|file| file.name() == dependency)
+                {
+                    let mut dependencies = self
+                        .reflection_request(ReflectionRequest {
+                            host: self.host.clone(),
+                            file_by_filename: Some(dependency.clone()),
+                            file_containing_symbol: None,
+                        })
+                        .await?;
+
+                    pending.append(&mut dependencies);
+                }
+            }
+
+            files.insert(name, file);
+        }
+
+        let mut pool = DescriptorPool::new();
+        pool.add_file_descriptor_protos(files.into_values())
+            .map_err(|err| format!("Invalid descriptors from reflection: {}", err))?;
+
+        Ok(pool)
+    }
+
+    async fn reflection_request(
+        &mut self,
+        request: ReflectionRequest,
+    ) -> Result<Vec<FileDescriptorProto>, String> {
+        let message = request.encode_to_vec();
+
+        let mut response = Err("Server reflection is not available".to_string());
+
+        //Older servers only support the `v1alpha` version of reflection
+        for path in REFLECTION.iter() {
+            match self.unary(path, message.clone()).await {
+                Ok(bytes) => {
+                    response = Ok(bytes);
+                    break;
+                }
+                Err(err) if err.code == UNIMPLEMENTED => continue,
+                Err(err) => return Err(format!("Server reflection failed: {}", err)),
+            }
+        }
+
+        let response = ReflectionResponse::decode(&*response?)
+            .map_err(|err| format!("Could not decode reflection response: {}", err))?;
+
+        if let Some(error) = response.error_response {
+            return Err(format!("Server reflection failed: {}", error.error_message));
+        }
+
+        response
+            .file_descriptor_response
+            .map(|files| files.file_descriptor_proto)
+            .unwrap_or_default()
+            .iter()
+            .map(|file| {
+                FileDescriptorProto::decode(&**file)
+                    .map_err(|err| format!("Invalid descriptor from reflection: {}", err))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::Path;
+
+//Conditions the host must meet for a step to run.  Steps on hosts that don't meet them are skipped
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostGuard {
+    pub os: Vec<String>,
+    pub commands: Vec<String>,
+}
+
+impl HostGuard {
+    // Returns the reason the step should be skipped, if any
+    pub fn check(&self) -> Option<String> {
+        if !self.os.is_empty() && !self.os.iter().any(|os| os_matches(os)) {
+            return Some(format!(
+                "Requires os `{}`, but this host is `{}`",
+                self.os.join("|"),
+                env::consts::OS
+            ));
+        }
+
+        for command in self.commands.iter() {
+            if !command_exists(command) {
+                return Some(format!(
+                    "Requires command `{}`, which was not found",
+                    command
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+fn os_matches(os: &str) -> bool {
+    match os.to_lowercase().as_str() {
+        "unix" => cfg!(unix),
+        "osx" | "darwin" | "macos" => env::consts::OS == "macos",
+        other => env::consts::OS == other,
+    }
+}
+
+fn command_exists(command: &str) -> bool {
+    //Absolute or relative paths are checked directly
+    if command.contains(std::path::MAIN_SEPARATOR) {
+        return is_executable(Path::new(command));
+    }
+
+    let paths = match env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return false,
+    };
+
+    env::split_paths(&paths).any(|dir| {
+        if cfg!(windows) {
+            let extensions = env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.BAT;.CMD".into());
+            is_executable(&dir.join(command))
+                || extensions
+                    .split(';')
+                    .any(|ext| is_executable(&dir.join(format!("{}{}", command, ext))))
+        } else {
+            is_executable(&dir.join(command))
+        }
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
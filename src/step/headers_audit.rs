@@ -0,0 +1,112 @@
+use super::http::connection_error;
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use reqwest::{redirect::Policy, Client, Url};
+
+use std::str::FromStr;
+use std::time::Duration;
+
+fn default_headers() -> Vec<String> {
+    vec![
+        "strict-transport-security".into(),
+        "content-security-policy".into(),
+        "x-frame-options".into(),
+        "x-content-type-options".into(),
+        "referrer-policy".into(),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum HeadersAuditVariant {
+    UrlOnly(String),
+    Options(HeadersAuditOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HeadersAuditOptions {
+    url: String,
+    #[serde(default = "default_headers")]
+    headers: Vec<String>,
+    #[serde(default)]
+    robots: bool,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+}
+
+impl HeadersAuditVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let auditops = match *self {
+            HeadersAuditVariant::UrlOnly(ref url) => HeadersAuditOptions {
+                url: url.clone(),
+                headers: default_headers(),
+                robots: false,
+                verify_ssl: None,
+            },
+            HeadersAuditVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let url = Url::from_str(&auditops.url)
+            .map_err(|err| format!("Failed to parse url `{}`: {}", auditops.url, err))?;
+
+        let mut client_builder = Client::builder()
+            .redirect(Policy::limited(10))
+            .timeout(Duration::from_secs(30));
+
+        if let Some(verify_ssl) = auditops.verify_ssl {
+            client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
+        }
+
+        let client = client_builder.build().map_err(|err| format!("{}", err))?;
+
+        let response = http_log::send(client.get(url.clone()))
+            .await
+            .map_err(|err| connection_error(&err))?;
+
+        let mut present = Map::new();
+        let mut missing = Vec::new();
+
+        for header in auditops.headers.iter() {
+            match response
+                .headers()
+                .get(header.as_str())
+                .and_then(|val| val.to_str().ok())
+            {
+                Some(val) => {
+                    present.insert(header.to_lowercase(), Value::String(val.into()));
+                }
+                None => missing.push(header.to_lowercase()),
+            }
+        }
+
+        let mut output = json!({
+            "missing": missing,
+            "present": present,
+        });
+
+        if auditops.robots {
+            let robots_url = url.join("/robots.txt").map_err(|err| format!("{}", err))?;
+
+            let status = http_log::send(client.get(robots_url))
+                .await
+                .map(|response| response.status().as_u16())
+                .map_err(|err| format!("Error fetching robots.txt {}", err))?;
+
+            output["robots"] = json!(status);
+
+            if !(200..300).contains(&status) {
+                missing.push("robots.txt".into());
+                output["missing"] = json!(missing);
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(format!("Security headers audit failed: {}", output));
+        }
+
+        Ok(output.to_string())
+    }
+}
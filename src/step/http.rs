@@ -1,35 +1,73 @@
-use crate::step::output_renderer;
+use crate::aws::{region_from_env, Credentials, SigV4};
+use crate::http_log;
+use crate::step::{current_plan, output_renderer};
 
-use super::STEP_OUTPUT;
+use super::digest::Challenge;
+use super::session::{refresh_session, session_generation};
+use super::{untyped, ErrorCode, Failure, STEP_BYTES, STEP_OUTPUT};
+use encoding_rs::{Encoding, UTF_8};
 use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use reqwest::{
-    header::{HeaderValue, COOKIE, SET_COOKIE},
+    header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, SET_COOKIE},
     multipart::Form,
     multipart::Part,
     redirect::Policy,
-    Body, Method,
+    Body, Client, Identity, Method, Proxy, Request, RequestBuilder, Response, StatusCode, Url,
 };
 
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+
 use tokio::fs::File;
+use tokio::time::sleep;
+
+use log::debug;
 
+use anyhow::Error;
 use chashmap::CHashMap;
+use chrono::{TimeZone, Utc};
 use lazy_static::lazy_static;
 
 use cookie::{Cookie, CookieJar};
 
 use tokio_util::codec::{BytesCodec, FramedRead};
 
-use std::{collections::HashMap, time::Duration};
-use std::{path::PathBuf, str::FromStr};
+use serde_json::{json, Map, Value};
+
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+//How many shared clients are kept before they're all dropped
+const MAX_CLIENTS: usize = 100;
 
 lazy_static! {
     static ref COOKIES: CHashMap<String, CookieJar> = CHashMap::new();
+    //Steps in a plan that connect the same way share a client, so connections are kept alive between them
+    static ref CLIENTS: CHashMap<String, Client> = CHashMap::new();
+    //When the next request to each rate limited host can be sent
+    static ref NEXT_REQUEST: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    //Plans running on their own loops save the cookie file one at a time
+    static ref COOKIE_FILE_LOCK: Mutex<()> = Mutex::new(());
     static ref REGEX_OUTPUT: Regex = Regex::new("\\$\\{(step_output.[^}]+)\\}").unwrap();
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum HttpVariant {
     UrlOnly(String),
@@ -55,11 +93,104 @@ fn default_cookies() -> bool {
     true
 }
 
-fn default_status() -> u16 {
-    200
+fn default_status() -> ExpectedStatus {
+    ExpectedStatus::Code(200)
+}
+
+// The status a response should have: a code, a class like `2xx`, a range like `200-299`, or a list of any of these
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ExpectedStatus {
+    Code(u16),
+    Pattern(String),
+    List(Vec<ExpectedStatus>),
+}
+
+impl ExpectedStatus {
+    // Fails on any pattern that isn't a valid status, class or range
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            ExpectedStatus::Code(_) => Ok(()),
+            ExpectedStatus::Pattern(ref pattern) => status_range(pattern).map(|_| ()),
+            ExpectedStatus::List(ref list) => list.iter().try_for_each(ExpectedStatus::check),
+        }
+    }
+
+    pub fn matches(&self, status: u16) -> Result<bool, String> {
+        match *self {
+            ExpectedStatus::Code(code) => Ok(code == status),
+            ExpectedStatus::Pattern(ref pattern) => {
+                let (min, max) = status_range(pattern)?;
+                Ok(min <= status && status <= max)
+            }
+            ExpectedStatus::List(ref list) => {
+                for expected in list.iter() {
+                    if expected.matches(status)? {
+                        return Ok(true);
+                    }
+                }
+
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExpectedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ExpectedStatus::Code(code) => write!(f, "{}", code),
+            ExpectedStatus::Pattern(ref pattern) => write!(f, "{}", pattern),
+            ExpectedStatus::List(ref list) => write!(
+                f,
+                "{}",
+                list.iter()
+                    .map(ExpectedStatus::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+// The lowest & highest status of a `2xx` class, a `200-299` range, or a single status as a string
+fn status_range(pattern: &str) -> Result<(u16, u16), String> {
+    let invalid = || {
+        format!(
+            "Invalid status `{}`, expected a status, a class like `2xx` or a range like `200-299`",
+            pattern
+        )
+    };
+
+    let pattern = pattern.trim();
+    let lower = pattern.to_lowercase();
+
+    if let Some(class) = lower.strip_suffix("xx") {
+        let class: u16 = class.parse().map_err(|_| invalid())?;
+
+        if !(1..=5).contains(&class) {
+            return Err(invalid());
+        }
+
+        return Ok((class * 100, class * 100 + 99));
+    }
+
+    let (min, max) = match pattern.split_once('-') {
+        Some((min, max)) => (min.trim(), max.trim()),
+        None => (pattern, pattern),
+    };
+
+    let min: u16 = min.parse().map_err(|_| invalid())?;
+    let max: u16 = max.parse().map_err(|_| invalid())?;
+
+    if min > max {
+        return Err(invalid());
+    }
+
+    Ok((min, max))
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct HttpOptions {
     url: String,
     #[serde(
@@ -67,11 +198,39 @@ pub struct HttpOptions {
         deserialize_with = "string_to_method",
         serialize_with = "method_to_string"
     )]
+    #[schemars(with = "String")]
     method: Method,
     #[serde(default = "default_cookies")]
     save_cookies: bool,
+    //Cookies are kept per host, in the default jar unless one is named
+    #[serde(default)]
+    cookie_jar: Option<String>,
+    //Checks on the cookies set by the response
+    #[serde(default)]
+    cookies: Option<HashMap<String, CookieExpect>>,
+    //Cookies to send, replacing any of the same name from the jar
+    #[serde(default)]
+    send_cookies: Option<HashMap<String, String>>,
+    //Empty the jar before sending the request
+    #[serde(default)]
+    clear_cookies: bool,
+    //Signs the request with AWS SigV4
+    #[serde(default)]
+    aws_auth: Option<AwsAuth>,
+    //Sent as an `Authorization: Bearer` header, and can use `${step_output...}`
+    #[serde(default)]
+    bearer: Option<String>,
+    //Answers the server's digest challenge with this user & password
+    #[serde(default)]
+    digest: Option<DigestAuth>,
+    //Adds the request & response headers to the error if the step fails
+    #[serde(default)]
+    trace: bool,
+    //Spaces out the requests to the host, so checks don't trip rate limits
+    #[serde(default)]
+    max_requests_per_second: Option<f64>,
     #[serde(default = "default_status")]
-    status: u16,
+    status: ExpectedStatus,
     #[serde(default)]
     headers: Option<HashMap<String, String>>,
     #[serde(default)]
@@ -88,13 +247,457 @@ pub struct HttpOptions {
     timeout_ms: Option<u64>,
     #[serde(default)]
     verify_ssl: Option<bool>,
+    //A step that is run again to log back in if this request is unauthorized
+    #[serde(default)]
+    session: Option<String>,
+    #[serde(default)]
+    output: HttpOutput,
+    #[serde(default)]
+    follow_redirects: Option<FollowRedirects>,
+    //A regex the url must match after any redirects, i.e, to check it ended up at the login page
+    #[serde(default)]
+    final_url: Option<String>,
+    //Fail if the response, including the body, takes longer than this
+    #[serde(default)]
+    max_time_ms: Option<u64>,
+    //Statuses that are retried straight away, i.e, `502` from a gateway, rather than failing the step
+    #[serde(default)]
+    retry_on_status: Option<RetryOnStatus>,
+    //A client certificate & key for mutual TLS, either inline PEM or a path to a PEM file
+    #[serde(default)]
+    client_cert: Option<String>,
+    //Not needed if the key is in the `client_cert` PEM
+    #[serde(default)]
+    client_key: Option<String>,
+    //Send the request through a proxy, rather than any set by `HTTP_PROXY` & friends
+    #[serde(default)]
+    proxy: Option<ProxyVariant>,
+    //Connect to these IPs for the host names, rather than looking them up, i.e, to check one backend
+    #[serde(default)]
+    resolve: Option<HashMap<String, String>>,
+    //Streams a file as the body, for payloads too large to put in the plan
+    #[serde(default)]
+    body_file: Option<BodyFileVariant>,
+    //Fail if the body is smaller than this, i.e, a truncated download
+    #[serde(default)]
+    min_bytes: Option<u64>,
+    //Fail if the body downloads slower than this many kilobits per second
+    #[serde(default)]
+    min_throughput_kbps: Option<f64>,
+}
+
+// The body of a response, along with how fast it downloaded
+struct Download {
+    body: Vec<u8>,
+    throughput_kbps: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DigestAuth {
+    user: String,
+    pass: String,
+}
+
+// Signs requests with AWS SigV4, such as for API Gateway, S3 or OpenSearch
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AwsAuth {
+    //The service to sign for, i.e, `execute-api`, `s3` or `es`
+    service: String,
+    //Defaults to `AWS_REGION`, then `AWS_DEFAULT_REGION`, then `us-east-1`
+    #[serde(default)]
+    region: Option<String>,
+    //A profile in `~/.aws/credentials`, otherwise the `AWS_*` environment variables are used
+    #[serde(default)]
+    profile: Option<String>,
+}
+
+impl AwsAuth {
+    // Adds the `Authorization` header, signing the headers & body the request already has
+    fn sign(&self, request: &mut Request) -> Result<(), String> {
+        let body = match request.body() {
+            Some(body) => body
+                .as_bytes()
+                .ok_or("`aws_auth` can't sign a streamed `body_file`")?
+                .to_vec(),
+            None => Vec::new(),
+        };
+
+        let signer = SigV4 {
+            region: self.region.clone().unwrap_or_else(region_from_env),
+            service: self.service.clone(),
+            credentials: Credentials::load(self.profile.as_deref())
+                .map_err(|err| format!("Could not load AWS credentials: {}", err))?,
+        };
+
+        let (method, url) = (request.method().to_string(), request.url().clone());
+
+        signer
+            .sign(&method, &url, request.headers_mut(), &body, Utc::now())
+            .map_err(|err| format!("Could not sign request: {}", err))
+    }
+}
+
+// A proxy url, or the url along with hosts that are connected to directly.  `false` connects directly
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ProxyVariant {
+    Enabled(bool),
+    Url(String),
+    Options(ProxyOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProxyOptions {
+    url: String,
+    //Host names, or domains starting with a `.`, that don't go through the proxy
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+impl ProxyVariant {
+    // The proxy to send requests through, or `None` to connect directly
+    fn proxy(&self) -> Result<Option<Proxy>, String> {
+        let opts = match *self {
+            ProxyVariant::Enabled(false) => return Ok(None),
+            ProxyVariant::Enabled(true) => {
+                return Err("`proxy: true` needs a url to know which proxy to use".into())
+            }
+            ProxyVariant::Url(ref url) => ProxyOptions {
+                url: url.clone(),
+                no_proxy: Vec::new(),
+            },
+            ProxyVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let url = Url::from_str(&opts.url)
+            .map_err(|err| format!("Failed to parse proxy url `{}`: {}", opts.url, err))?;
+
+        //With `socks5h`, host names are resolved by the proxy rather than locally
+        match url.scheme() {
+            "http" | "https" | "socks5" | "socks5h" => (),
+            scheme => {
+                return Err(format!(
+                    "Proxy url `{}` has an unsupported scheme `{}`, only `http`, `https`, `socks5` & `socks5h` proxies are supported",
+                    opts.url, scheme
+                ))
+            }
+        }
+
+        Ok(Some(Proxy::custom(move |target| match target.host_str() {
+            Some(host) if bypasses_proxy(host, &opts.no_proxy) => None,
+            _ => Some(url.clone()),
+        })))
+    }
+}
+
+// Whether the host matches a `no_proxy` entry, either exactly or as a subdomain
+fn bypasses_proxy(host: &str, no_proxy: &[String]) -> bool {
+    let host = host.to_lowercase();
+
+    no_proxy.iter().any(|entry| {
+        let entry = entry.to_lowercase();
+        let domain = entry.trim_start_matches('.');
+
+        entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+fn default_status_retries() -> usize {
+    2
+}
+
+fn default_status_retry_delay_ms() -> u64 {
+    1000
+}
+
+// The statuses to retry, or the statuses along with how many times & how long to wait between
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RetryOnStatus {
+    Statuses(Vec<u16>),
+    Options(RetryOnStatusOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RetryOnStatusOptions {
+    status: Vec<u16>,
+    #[serde(default = "default_status_retries")]
+    count: usize,
+    #[serde(default = "default_status_retry_delay_ms")]
+    delay_ms: u64,
+}
+
+impl RetryOnStatus {
+    fn options(&self) -> RetryOnStatusOptions {
+        match *self {
+            RetryOnStatus::Statuses(ref status) => RetryOnStatusOptions {
+                status: status.clone(),
+                count: default_status_retries(),
+                delay_ms: default_status_retry_delay_ms(),
+            },
+            RetryOnStatus::Options(ref opts) => opts.clone(),
+        }
+    }
+}
+
+// Whether to follow redirects, or the most to follow
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FollowRedirects {
+    Enabled(bool),
+    MaxHops(usize),
+}
+
+impl FollowRedirects {
+    fn policy(self) -> Policy {
+        match self {
+            FollowRedirects::Enabled(true) => Policy::limited(10),
+            FollowRedirects::Enabled(false) | FollowRedirects::MaxHops(0) => Policy::none(),
+            //The limit counts the original url as well as the urls redirected to
+            FollowRedirects::MaxHops(hops) => Policy::limited(hops + 1),
+        }
+    }
+}
+
+// What part of the response the step outputs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpOutput {
+    #[default]
+    Body,
+    //The response headers as a json object
+    Headers,
+    Status,
+    //The status, headers, body & time taken as a json object
+    Envelope,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CookieExpect {
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    secure: Option<bool>,
+    #[serde(default)]
+    http_only: Option<bool>,
+    #[serde(default)]
+    same_site: Option<String>,
+    //How long the cookie lasts, from `Max-Age` or `Expires`.  Session cookies have no lifetime
+    #[serde(default)]
+    min_lifetime_s: Option<i64>,
+    #[serde(default)]
+    max_lifetime_s: Option<i64>,
+}
+
+impl CookieExpect {
+    fn check(&self, cookie: &Cookie) -> Result<(), String> {
+        let name = cookie.name();
+
+        if let Some(ref pattern) = self.value {
+            let regex = Regex::new(pattern)
+                .map_err(|err| format!("Cookie `{}` value regex is invalid: {}", name, err))?;
+
+            if !regex.is_match(cookie.value()) {
+                return Err(format!(
+                    "Cookie `{}` value does not match `{}`",
+                    name, pattern
+                ));
+            }
+        }
+
+        let flags = [
+            ("Secure", self.secure, cookie.secure()),
+            ("HttpOnly", self.http_only, cookie.http_only()),
+        ];
+
+        for (flag, expected, actual) in flags.iter() {
+            if let Some(expected) = expected {
+                if *expected != actual.unwrap_or(false) {
+                    return Err(format!(
+                        "Cookie `{}` {} is `{}` but expected `{}`",
+                        name,
+                        flag,
+                        actual.unwrap_or(false),
+                        expected
+                    ));
+                }
+            }
+        }
+
+        if let Some(ref expected) = self.same_site {
+            let actual = cookie.same_site().map(|val| val.to_string());
+
+            if !actual
+                .as_deref()
+                .map(|actual| actual.eq_ignore_ascii_case(expected))
+                .unwrap_or(false)
+            {
+                return Err(format!(
+                    "Cookie `{}` SameSite is `{}` but expected `{}`",
+                    name,
+                    actual.unwrap_or_else(|| "unset".into()),
+                    expected
+                ));
+            }
+        }
+
+        if self.min_lifetime_s.is_some() || self.max_lifetime_s.is_some() {
+            let lifetime = cookie_lifetime(cookie)
+                .ok_or_else(|| format!("Cookie `{}` is a session cookie with no expiry", name))?;
+
+            if let Some(min) = self.min_lifetime_s {
+                if lifetime < min {
+                    return Err(format!(
+                        "Cookie `{}` expires in {}s, less than {}s",
+                        name, lifetime, min
+                    ));
+                }
+            }
+
+            if let Some(max) = self.max_lifetime_s {
+                if lifetime > max {
+                    return Err(format!(
+                        "Cookie `{}` expires in {}s, more than {}s",
+                        name, lifetime, max
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The key of the jar a request's cookies are kept in, which is per host within the named jar
+fn jar_key(plan: &str, cookie_jar: Option<&str>, hostname: &str) -> String {
+    let jar = match cookie_jar {
+        Some(name) => format!("{}/{}", name, hostname),
+        None => hostname.to_string(),
+    };
+
+    //Plans being served side by side keep their cookies apart too
+    match plan {
+        "" => jar,
+        plan => format!("{}:{}", plan, jar),
+    }
+}
+
+// A cookie as it's kept in the `--cookie-file`
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedCookie {
+    name: String,
+    value: String,
+    //When it expires as a unix timestamp, session cookies don't
+    #[serde(default)]
+    expires: Option<i64>,
+}
+
+// The cookie as it's kept in a jar.  `Max-Age` is turned into when it expires, so the expiry stays the same when it's saved & loaded again
+fn jar_cookie(name: &str, value: &str, expires: Option<i64>) -> Option<Cookie<'static>> {
+    let cookie = match expires {
+        Some(expires) => format!(
+            "{}={}; Expires={}",
+            name,
+            value,
+            Utc.timestamp_opt(expires, 0)
+                .single()?
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+        ),
+        None => format!("{}={}", name, value),
+    };
+
+    Cookie::parse(cookie).ok()
+}
+
+// Loads the cookie jars saved by `save_cookie_jars`, leaving out any cookies that have since expired
+pub fn load_cookie_jars(path: &Path) -> Result<(), Error> {
+    //There's nothing to load before the first run
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let jars: HashMap<String, Vec<SavedCookie>> = serde_json::from_slice(&std::fs::read(path)?)?;
+    let now = Utc::now().timestamp();
+
+    for (name, saved) in jars {
+        let mut cookie_jar = CookieJar::new();
+
+        for cookie in saved {
+            if cookie.expires.map(|expires| expires > now).unwrap_or(true) {
+                if let Some(cookie) = jar_cookie(&cookie.name, &cookie.value, cookie.expires) {
+                    cookie_jar.add(cookie);
+                }
+            }
+        }
+
+        COOKIES.insert(name, cookie_jar);
+    }
+
+    Ok(())
+}
+
+// Saves every cookie jar to a file, so a later run can carry on with the same cookies
+pub fn save_cookie_jars(path: &Path) -> Result<(), Error> {
+    let now = Utc::now().timestamp();
+
+    let jars: BTreeMap<String, Vec<SavedCookie>> = COOKIES
+        .clone()
+        .into_iter()
+        .map(|(name, cookie_jar)| {
+            let saved = cookie_jar
+                .iter()
+                .map(|cookie| SavedCookie {
+                    name: cookie.name().into(),
+                    value: cookie.value().into(),
+                    expires: cookie_lifetime(cookie).map(|lifetime| now + lifetime),
+                })
+                .filter(|cookie| cookie.expires.map(|expires| expires > now).unwrap_or(true))
+                .collect();
+
+            (name, saved)
+        })
+        .collect();
+
+    let _lock = COOKIE_FILE_LOCK.lock().unwrap();
+
+    //Write then rename, so a run never loads a half written file.  Only the owner can read the cookies
+    let temp_path = path.with_extension("tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    //A temp file left by a crash may have been made with other permissions
+    let _ = std::fs::remove_file(&temp_path);
+
+    let mut file = options.open(&temp_path)?;
+
+    file.write_all(&serde_json::to_vec_pretty(&jars)?)?;
+    file.sync_all()?;
+
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+// Seconds until the cookie expires, with `Max-Age` taking precedence over `Expires`
+fn cookie_lifetime(cookie: &Cookie) -> Option<i64> {
+    if let Some(max_age) = cookie.max_age() {
+        return Some(max_age.whole_seconds());
+    }
+
+    cookie
+        .expires()
+        .map(|expires| expires.unix_timestamp() - Utc::now().timestamp())
 }
 
 fn default_timeout() -> Option<u64> {
     Some(30000)
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum MultipartValue {
     Value(String),
@@ -102,18 +705,131 @@ pub enum MultipartValue {
     Step(StepStruct),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+// The path of the file to send as the body, or the path along with its content type
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum BodyFileVariant {
+    Path(PathBuf),
+    Options(BodyFileOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BodyFileOptions {
+    file: PathBuf,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+impl BodyFileVariant {
+    fn options(&self) -> BodyFileOptions {
+        match *self {
+            BodyFileVariant::Path(ref file) => BodyFileOptions {
+                file: file.clone(),
+                content_type: None,
+            },
+            BodyFileVariant::Options(ref opts) => opts.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct PathStruct {
     file: PathBuf,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct StepStruct {
     step: String,
 }
 
 impl HttpVariant {
-    pub async fn run(&self) -> Result<String, String> {
+    pub fn check_status(&self) -> Result<(), String> {
+        match *self {
+            HttpVariant::UrlOnly(_) => Ok(()),
+            HttpVariant::Options(ref opts) => opts.status.check(),
+        }
+    }
+
+    pub async fn run(&self) -> Result<String, Failure> {
+        let httpops = self.options();
+        let client = untyped(httpops.client())?;
+
+        let start = Instant::now();
+        let (response, waited) = httpops.send(&client).await?;
+
+        //Waiting for the rate limit doesn't count towards the response time
+        let start = start + waited;
+
+        let status = response.status().as_u16();
+        let url = response.url().to_string();
+        let headers = headers_json(&response);
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .map(String::from);
+
+        //The body isn't read for the status or headers, unless its size or speed is checked
+        let download = match httpops.output {
+            HttpOutput::Body | HttpOutput::Envelope => Some(httpops.download(response).await?),
+            _ if httpops.checks_download() => Some(httpops.download(response).await?),
+            _ => None,
+        };
+
+        let body = download
+            .as_ref()
+            .map(|download| decode_text(content_type.as_deref(), &download.body))
+            .unwrap_or_default();
+
+        let output = untyped(match httpops.output {
+            HttpOutput::Body => Ok(body),
+            HttpOutput::Status => Ok(status.to_string()),
+            HttpOutput::Headers => {
+                serde_json::to_string_pretty(&headers).map_err(|err| format!("{}", err))
+            }
+            HttpOutput::Envelope => {
+                //Json bodies are nested as is, so they can be filtered with `jmespath`
+                let body = serde_json::from_str(&body).unwrap_or(Value::String(body));
+
+                serde_json::to_string_pretty(&json!({
+                    "status": status,
+                    "url": url,
+                    "headers": headers,
+                    "body": body,
+                    "time_ms": (start.elapsed().as_secs_f64() * 1_000_000.0).round() / 1000.0,
+                    "bytes": download.as_ref().map(|download| download.body.len()),
+                    "throughput_kbps": download.as_ref().map(|download| download.throughput_kbps),
+                }))
+                .map_err(|err| format!("{}", err))
+            }
+        })?;
+
+        httpops.check_time(start)?;
+
+        Ok(output)
+    }
+
+    pub async fn run_bytes(&self) -> Result<Vec<u8>, Failure> {
+        //Only the body can be binary
+        if self.options().output != HttpOutput::Body {
+            return self.run().await.map(String::into_bytes);
+        }
+
+        let httpops = self.options();
+        let client = untyped(httpops.client())?;
+
+        let start = Instant::now();
+
+        let (response, waited) = httpops.send(&client).await?;
+        let start = start + waited;
+        let download = httpops.download(response).await?;
+
+        httpops.check_time(start)?;
+
+        Ok(download.body)
+    }
+
+    pub(crate) fn options(&self) -> HttpOptions {
         let mut httpops = match *self {
             HttpVariant::UrlOnly(ref val) => HttpOptions {
                 url: val.clone(),
@@ -121,6 +837,15 @@ impl HttpVariant {
                 status: default_status(),
                 headers: None,
                 save_cookies: default_cookies(),
+                cookie_jar: None,
+                cookies: None,
+                send_cookies: None,
+                clear_cookies: false,
+                aws_auth: None,
+                bearer: None,
+                digest: None,
+                trace: false,
+                max_requests_per_second: None,
                 user: None,
                 pass: None,
                 body: None,
@@ -128,122 +853,937 @@ impl HttpVariant {
                 multipart: None,
                 timeout_ms: default_timeout(),
                 verify_ssl: None,
+                session: None,
+                output: HttpOutput::default(),
+                follow_redirects: None,
+                final_url: None,
+                max_time_ms: None,
+                retry_on_status: None,
+                client_cert: None,
+                client_key: None,
+                proxy: None,
+                resolve: None,
+                body_file: None,
+                min_bytes: None,
+                min_throughput_kbps: None,
             },
             HttpVariant::Options(ref opts) => *opts.clone(),
         };
 
-        let mut client_builder = reqwest::ClientBuilder::new().redirect(Policy::none());
+        if (httpops.form.is_some() || httpops.multipart.is_some() || httpops.body.is_some())
+            && httpops.method == Method::GET
+        {
+            httpops.method = Method::POST;
+        }
+
+        httpops
+    }
+}
+
+impl HttpOptions {
+    pub(crate) fn client(&self) -> Result<Client, String> {
+        let fingerprint = self.client_fingerprint()?;
+
+        if let Some(client) = CLIENTS.get(&fingerprint) {
+            return Ok(client.clone());
+        }
+
+        let client = self.build_client()?;
+
+        //Renewed certificates & changed plans leave old clients behind, so long running servers start afresh now & then
+        if CLIENTS.len() >= MAX_CLIENTS {
+            debug!("Dropping {} shared http clients", CLIENTS.len());
+            CLIENTS.clear();
+        }
+
+        CLIENTS.insert(fingerprint, client.clone());
+
+        Ok(client)
+    }
+
+    // Identifies the options a client is built with, so steps that would build the same client share one instead
+    fn client_fingerprint(&self) -> Result<String, String> {
+        //Certificates are read rather than going by path, so a renewed certificate gets a new client.  Only their
+        //hashes are used, so private keys aren't kept around in the fingerprint
+        let pem = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|value| {
+                    pem_or_file(value).map(|pem| {
+                        openssl::sha::sha256(&pem)
+                            .iter()
+                            .map(|byte| format!("{:02x}", byte))
+                            .collect::<String>()
+                    })
+                })
+                .transpose()
+        };
+
+        let resolve: Option<BTreeMap<&String, &String>> = self
+            .resolve
+            .as_ref()
+            .map(|resolve| resolve.iter().collect());
+
+        serde_json::to_string(&json!({
+            "plan": current_plan(),
+            "follow_redirects": self.follow_redirects,
+            "timeout_ms": self.timeout_ms,
+            "verify_ssl": self.verify_ssl,
+            "client_cert": pem(&self.client_cert)?,
+            "client_key": pem(&self.client_key)?,
+            "proxy": self.proxy,
+            "resolve": resolve,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+
+    fn build_client(&self) -> Result<Client, String> {
+        //Redirects aren't followed unless asked, so they can be checked with `status`
+        let policy = self
+            .follow_redirects
+            .map(FollowRedirects::policy)
+            .unwrap_or_else(Policy::none);
 
-        if let Some(timeout) = httpops.timeout_ms {
+        let mut client_builder = reqwest::ClientBuilder::new().redirect(policy);
+
+        if let Some(timeout) = self.timeout_ms {
             client_builder = client_builder.timeout(Duration::from_millis(timeout));
         }
 
-        if let Some(verify_ssl) = httpops.verify_ssl {
+        if let Some(verify_ssl) = self.verify_ssl {
             client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
         }
 
-        let client = client_builder.build().map_err(|err| format!("{}", err))?;
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), key) => {
+                let cert = pem_or_file(cert)?;
 
-        let url = reqwest::Url::from_str(&httpops.url)
-            .map_err(|err| format!("Failed to parse url `{}`: {}", httpops.url, err))?;
+                let key = match key {
+                    Some(key) => pem_or_file(key)?,
+                    None => cert.clone(),
+                };
 
-        let hostname: String = url
-            .host_str()
-            .map(String::from)
-            .ok_or_else(|| format!("No host could be found for url: {}", url))?;
+                let identity = Identity::from_pkcs12_der(&identity(&cert, &key)?, "")
+                    .map_err(|err| format!("Could not load client certificate: {}", err))?;
 
-        if (httpops.form.is_some() || httpops.multipart.is_some() || httpops.body.is_some())
-            && httpops.method == Method::GET
-        {
-            httpops.method = Method::POST;
+                client_builder = client_builder.identity(identity);
+            }
+            (None, Some(_)) => return Err("A `client_key` needs a `client_cert`".into()),
+            (None, None) => (),
         }
 
-        let mut request = client.request(httpops.method, url);
+        if let Some(ref proxy) = self.proxy {
+            client_builder = match proxy.proxy()? {
+                Some(proxy) => client_builder.proxy(proxy),
+                //Ignores the config's proxy, along with any in the environment
+                None => client_builder.no_proxy(),
+            };
+        }
 
-        if let Some(user) = httpops.user {
-            request = request.basic_auth(user, httpops.pass)
+        //The url is unchanged, so the `Host` header & SNI are still the host name
+        for (host, ip) in self.resolve.iter().flatten() {
+            let ip: IpAddr = ip
+                .parse()
+                .map_err(|err| format!("Could not resolve `{}` to `{}`: {}", host, ip, err))?;
+
+            //The port is taken from the url
+            client_builder = client_builder.resolve(host, SocketAddr::new(ip, 0));
         }
 
-        if let Some(form) = httpops.form {
-            request = request.form(&form)
+        client_builder.build().map_err(|err| format!("{}", err))
+    }
+
+    // Waits until the host can be sent another request under `max_requests_per_second`, returning how long it waited
+    async fn wait_for_rate_limit(&self, url: &Url) -> Result<Duration, String> {
+        let max_requests_per_second = match self.max_requests_per_second {
+            Some(max) if max > 0.0 => max,
+            Some(max) => {
+                return Err(format!(
+                    "`max_requests_per_second` must be more than 0, not {}",
+                    max
+                ))
+            }
+            None => return Ok(Duration::ZERO),
+        };
+
+        let host = format!("{}:{}", current_plan(), url.host_str().unwrap_or_default());
+
+        let wait = reserve_slot(
+            &mut NEXT_REQUEST.lock().unwrap(),
+            host,
+            Instant::now(),
+            Duration::from_secs_f64(1.0 / max_requests_per_second),
+        );
+
+        if !wait.is_zero() {
+            debug!("Waiting {:?} for the rate limit", wait);
+            sleep(wait).await;
         }
 
-        if let Some(multipart) = httpops.multipart {
-            let mut form = Form::new();
+        Ok(wait)
+    }
 
-            for (key, val) in multipart.into_iter() {
-                form = match val {
-                    MultipartValue::Value(string) => form.text(key, string),
-                    MultipartValue::Path(path_struct) => {
-                        let file_name = path_struct
-                            .file
-                            .file_name()
-                            .map(|val| val.to_string_lossy().to_string())
-                            .unwrap_or_default();
+    fn checks_download(&self) -> bool {
+        self.min_bytes.is_some() || self.min_throughput_kbps.is_some()
+    }
 
-                        let file = File::open(&path_struct.file)
-                            .await
-                            .map_err(|err| format!("{:?}", err))?;
-                        let reader = Body::wrap_stream(FramedRead::new(file, BytesCodec::new()));
-                        form.part(key, Part::stream(reader).file_name(file_name))
-                    }
-                    MultipartValue::Step(step) => match STEP_OUTPUT.get(&step.step) {
-                        Some(val) => form.text(key, val.to_string()),
-                        None => return Err(format!("Step {} could not be found", &step.step)),
-                    },
-                }
+    // Reads the body, failing if it's smaller or downloaded slower than expected
+    async fn download(&self, response: Response) -> Result<Download, Failure> {
+        //Timed from when the headers arrived, so it's the transfer rather than how long the server took to respond
+        let start = Instant::now();
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(|err| connection_failure(&err))?
+            .to_vec();
+
+        //Tiny bodies can arrive in the same instant as the headers
+        let secs = start.elapsed().as_secs_f64().max(0.000_001);
+        let throughput_kbps = ((body.len() as f64 * 8.0 / 1000.0) / secs * 10.0).round() / 10.0;
+
+        if let Some(min_bytes) = self.min_bytes {
+            if (body.len() as u64) < min_bytes {
+                return Err((
+                    ErrorCode::RunFailed,
+                    format!(
+                        "Response was {} bytes, less than the `min_bytes` of {}",
+                        body.len(),
+                        min_bytes
+                    ),
+                ));
             }
+        }
 
-            request = request.multipart(form)
+        if let Some(min_throughput_kbps) = self.min_throughput_kbps {
+            if throughput_kbps < min_throughput_kbps {
+                return Err((
+                    ErrorCode::SlowResponse,
+                    format!(
+                        "Downloaded at {}kbps, slower than the `min_throughput_kbps` of {}kbps",
+                        throughput_kbps, min_throughput_kbps
+                    ),
+                ));
+            }
         }
 
-        if let Some(body) = httpops.body {
-            request = request.body(output_renderer(&body)?);
+        Ok(Download {
+            body,
+            throughput_kbps,
+        })
+    }
+
+    // Fails if the request took longer than `max_time_ms`, even though it otherwise succeeded
+    fn check_time(&self, start: Instant) -> Result<(), Failure> {
+        let elapsed = start.elapsed().as_millis();
+
+        match self.max_time_ms {
+            Some(max_time_ms) if elapsed > max_time_ms as u128 => Err((
+                ErrorCode::SlowResponse,
+                format!(
+                    "Response took {}ms, longer than the `max_time_ms` of {}ms",
+                    elapsed, max_time_ms
+                ),
+            )),
+            _ => Ok(()),
         }
+    }
+
+    // Builds & sends the request, answering the digest challenge if there is one.  Every request waits for the
+    // rate limit, adding the time it waited to `waited`
+    async fn execute(
+        &self,
+        client: &Client,
+        url: &Url,
+        jar: &str,
+        challenge: &mut Option<Challenge>,
+        waited: &mut Duration,
+    ) -> Result<Response, Failure> {
+        *waited += untyped(self.wait_for_rate_limit(url).await)?;
+
+        let mut request = untyped(build_request(client, self, url, jar).await)?;
 
-        if let Some(cookie_jar) = COOKIES.get(&hostname) {
-            let cookie_strings: Vec<String> = cookie_jar.iter().map(Cookie::to_string).collect();
-            request = request.header(COOKIE, cookie_strings.join("; "))
+        if let (Some(digest), Some(challenge)) = (&self.digest, challenge) {
+            let authorization = untyped(challenge.authorization(
+                &digest.user,
+                &digest.pass,
+                request.method().as_str(),
+                request.url(),
+            ))?;
+
+            request.headers_mut().insert(
+                AUTHORIZATION,
+                untyped(HeaderValue::from_str(&authorization).map_err(|err| format!("{}", err)))?,
+            );
         }
 
-        if let Some(headers) = httpops.headers {
-            for (key, val) in headers.into_iter() {
-                request = request.header(&*key, &*val);
-            }
+        if self.trace {
+            http_log::trace_request(&request);
         }
 
-        let response = client
-            .execute(request.build().map_err(|err| format!("{:?}", err))?)
+        let response = http_log::execute(client, request)
             .await
-            .map_err(|err| format!("Error connecting to url {}", err))?;
+            .map_err(|err| connection_failure(&err))?;
+
+        if self.trace {
+            http_log::trace_response(&response);
+        }
+
+        Ok(response)
+    }
+
+    // Sends the request, along with any retries, returning the response & how long was spent waiting for the rate limit
+    pub(crate) async fn send(&self, client: &Client) -> Result<(Response, Duration), Failure> {
+        let url = untyped(
+            reqwest::Url::from_str(&self.url)
+                .map_err(|err| format!("Failed to parse url `{}`: {}", self.url, err)),
+        )?;
+
+        let hostname: String = untyped(
+            url.host_str()
+                .map(String::from)
+                .ok_or_else(|| format!("No host could be found for url: {}", url)),
+        )?;
+
+        let jar = jar_key(&current_plan(), self.cookie_jar.as_deref(), &hostname);
+
+        if self.clear_cookies {
+            COOKIES.remove(&jar);
+        }
 
-        if response.status().as_u16() != httpops.status {
-            return Err(format!(
-                "returned status `{}` does not match expected `{}`",
-                response.status().as_u16(),
-                httpops.status
+        let generation = match self.session {
+            Some(ref session) => session_generation(session).await,
+            None => None,
+        };
+
+        let mut challenge = None;
+        let mut waited = Duration::ZERO;
+
+        let mut response = self
+            .execute(client, &url, &jar, &mut challenge, &mut waited)
+            .await?;
+
+        //Digest auth needs the server's challenge first, so the request is sent again with the answer
+        if self.digest.is_some() && response.status() == StatusCode::UNAUTHORIZED {
+            challenge = Some(untyped(Challenge::from_response(&response))?);
+
+            response = self
+                .execute(client, &url, &jar, &mut challenge, &mut waited)
+                .await?;
+        }
+
+        //If the session has expired, log in again & retry with the new session
+        if let (Some(session), Some(generation)) = (&self.session, generation) {
+            if response.status() == StatusCode::UNAUTHORIZED {
+                debug!("Unauthorized, refreshing session `{}`", session);
+
+                untyped(refresh_session(session, generation).await)?;
+
+                response = self
+                    .execute(client, &url, &jar, &mut challenge, &mut waited)
+                    .await?;
+            }
+        }
+
+        if let Some(ref retry) = self.retry_on_status {
+            let retry = retry.options();
+            let mut retries = 0;
+
+            while retries < retry.count && retry.status.contains(&response.status().as_u16()) {
+                retries += 1;
+
+                debug!(
+                    "Returned status `{}`, retrying {}/{}",
+                    response.status().as_u16(),
+                    retries,
+                    retry.count
+                );
+
+                sleep(Duration::from_millis(retry.delay_ms)).await;
+
+                response = self
+                    .execute(client, &url, &jar, &mut challenge, &mut waited)
+                    .await?;
+            }
+        }
+
+        if !untyped(self.status.matches(response.status().as_u16()))? {
+            return Err((
+                ErrorCode::HttpStatusMismatch,
+                format!(
+                    "returned status `{}` does not match expected `{}`",
+                    response.status().as_u16(),
+                    self.status
+                ),
             ));
         }
 
-        if httpops.save_cookies {
-            let new_cookies = response.headers().get_all(SET_COOKIE);
+        if let Some(ref final_url) = self.final_url {
+            let regex = Regex::new(final_url).map_err(|err| {
+                (
+                    ErrorCode::InvalidRegex,
+                    format!("Final url regex `{}` is invalid: {}", final_url, err),
+                )
+            })?;
 
-            COOKIES.alter(hostname, |value| {
-                let mut cookie_jar = value.unwrap_or_default();
-                for cookie in new_cookies
+            if !regex.is_match(response.url().as_str()) {
+                return Err((
+                    ErrorCode::RunFailed,
+                    format!(
+                        "Final url `{}` does not match `{}`",
+                        response.url(),
+                        final_url
+                    ),
+                ));
+            }
+        }
+
+        let new_cookies: Vec<Cookie<'static>> = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .flat_map(HeaderValue::to_str)
+            .map(String::from)
+            .flat_map(Cookie::parse)
+            .collect();
+
+        if let Some(ref cookies) = self.cookies {
+            for (name, expect) in cookies.iter() {
+                let cookie = new_cookies
                     .iter()
-                    .flat_map(HeaderValue::to_str)
-                    .map(String::from)
-                    .flat_map(Cookie::parse)
-                {
-                    cookie_jar.add(cookie);
+                    .find(|cookie| cookie.name() == name)
+                    .ok_or_else(|| {
+                        (
+                            ErrorCode::CookieMismatch,
+                            format!("Cookie `{}` was not set", name),
+                        )
+                    })?;
+
+                expect
+                    .check(cookie)
+                    .map_err(|err| (ErrorCode::CookieMismatch, err))?;
+            }
+        }
+
+        if self.save_cookies {
+            let now = Utc::now().timestamp();
+
+            COOKIES.alter(jar, |value| {
+                let mut cookie_jar = value.unwrap_or_default();
+                for cookie in new_cookies {
+                    let expires = cookie_lifetime(&cookie).map(|lifetime| now + lifetime);
+                    if let Some(cookie) = jar_cookie(cookie.name(), cookie.value(), expires) {
+                        cookie_jar.add(cookie);
+                    }
                 }
                 Some(cookie_jar)
             });
         }
 
-        let output = response.text().await.map_err(|err| format!("{:?}", err))?;
+        Ok((response, waited))
+    }
+}
+
+// Reserves the next slot to send a request to the host, returning how long to wait for it.  Slots are handed out in
+// turn, so requests are evenly spaced rather than sent in bursts
+fn reserve_slot(
+    next_request: &mut HashMap<String, Instant>,
+    host: String,
+    now: Instant,
+    interval: Duration,
+) -> Duration {
+    let slot = match next_request.get(&host) {
+        Some(slot) if *slot > now => *slot,
+        _ => now,
+    };
 
-        Ok(output)
+    next_request.insert(host, slot + interval);
+
+    slot - now
+}
+
+// Inline PEM, or the contents of a PEM file
+fn pem_or_file(value: &str) -> Result<Vec<u8>, String> {
+    if value.contains("-----BEGIN") {
+        return Ok(value.as_bytes().to_vec());
+    }
+
+    std::fs::read(value).map_err(|err| format!("Could not read `{}`: {}", value, err))
+}
+
+// Converts a PEM client certificate & key to PKCS12.  Any certificates after the first are included as the chain
+pub(crate) fn identity(cert: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    let mut certs =
+        X509::stack_from_pem(cert).map_err(|err| format!("Invalid client certificate: {}", err))?;
+
+    if certs.is_empty() {
+        return Err("No client certificate was found in the PEM".into());
+    }
+
+    let cert = certs.remove(0);
+
+    let key =
+        PKey::private_key_from_pem(key).map_err(|err| format!("Invalid client key: {}", err))?;
+
+    let mut chain = Stack::new().map_err(|err| format!("{}", err))?;
+
+    for cert in certs {
+        chain.push(cert).map_err(|err| format!("{}", err))?;
+    }
+
+    Pkcs12::builder()
+        .name("lorikeet")
+        .pkey(&key)
+        .cert(&cert)
+        .ca(chain)
+        .build2("")
+        .and_then(|pkcs12| pkcs12.to_der())
+        .map_err(|err| format!("Could not load client certificate: {}", err))
+}
+
+// Decodes the body with the charset of the content type, defaulting to UTF-8 like `Response::text`
+fn decode_text(content_type: Option<&str>, body: &[u8]) -> String {
+    let encoding = content_type
+        .and_then(|content_type| {
+            content_type.split(';').skip(1).find_map(|param| {
+                let (key, val) = param.split_once('=')?;
+
+                match key.trim().eq_ignore_ascii_case("charset") {
+                    true => Encoding::for_label(val.trim().trim_matches('"').as_bytes()),
+                    false => None,
+                }
+            })
+        })
+        .unwrap_or(UTF_8);
+
+    encoding.decode(body).0.into_owned()
+}
+
+// The response headers, by lowercase name.  Repeated headers are joined with `, `
+fn headers_json(response: &Response) -> Value {
+    let mut headers = Map::new();
+
+    for (name, value) in response.headers().iter() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+
+        match headers.get_mut(name.as_str()) {
+            Some(Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            _ => {
+                headers.insert(name.as_str().into(), Value::String(value));
+            }
+        }
+    }
+
+    Value::Object(headers)
+}
+
+// Describes which layer a request failed at, i.e, DNS, TCP, TLS or a timeout, so the error code says what broke
+pub(crate) fn connection_error(err: &reqwest::Error) -> String {
+    connection_failure(err).1
+}
+
+// The code & message for why a request failed, by the layer it failed at
+pub(crate) fn connection_failure(err: &reqwest::Error) -> Failure {
+    let host = err
+        .url()
+        .and_then(|url| url.host_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if err.is_timeout() {
+        return (
+            ErrorCode::Timeout,
+            format!("Request to `{}` timed out", host),
+        );
+    }
+
+    let mut causes: Vec<&(dyn std::error::Error + 'static)> = Vec::new();
+    let mut source = std::error::Error::source(err);
+
+    while let Some(cause) = source {
+        causes.push(cause);
+        source = cause.source();
+    }
+
+    //The innermost error is the most specific, i.e, the resolver or OS error
+    let detail = causes
+        .last()
+        .map(|cause| cause.to_string())
+        .unwrap_or_else(|| err.to_string());
+
+    let is_refused = causes.iter().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .map(|io| io.kind() == std::io::ErrorKind::ConnectionRefused)
+            .unwrap_or(false)
+    });
+
+    let is_tls = causes.iter().any(|cause| {
+        cause.is::<native_tls::Error>()
+            || cause.is::<openssl::ssl::Error>()
+            || cause.is::<openssl::error::ErrorStack>()
+    });
+
+    let is_dns = causes
+        .iter()
+        .any(|cause| cause.to_string().starts_with("dns error"));
+
+    if is_dns {
+        (
+            ErrorCode::DnsFailed,
+            format!("DNS lookup failed for `{}`: {}", host, detail),
+        )
+    } else if is_refused {
+        (
+            ErrorCode::ConnectionRefused,
+            format!("Connection refused by `{}`", host),
+        )
+    } else if is_tls {
+        (
+            ErrorCode::TlsFailed,
+            format!("TLS handshake with `{}` failed: {}", host, detail),
+        )
+    } else {
+        (
+            ErrorCode::ConnectionFailed,
+            format!("Error connecting to url {}", err),
+        )
+    }
+}
+
+// Builds the request, rendering any step outputs so it can be rebuilt after a session refresh
+async fn build_request(
+    client: &Client,
+    httpops: &HttpOptions,
+    url: &Url,
+    jar: &str,
+) -> Result<reqwest::Request, String> {
+    let mut request: RequestBuilder = client.request(httpops.method.clone(), url.clone());
+
+    let auth_options = [
+        httpops.user.is_some(),
+        httpops.bearer.is_some(),
+        httpops.digest.is_some(),
+        httpops.aws_auth.is_some(),
+    ];
+
+    if auth_options.iter().filter(|is_set| **is_set).count() > 1 {
+        return Err("Only one of `user`, `bearer`, `digest` or `aws_auth` can be set".into());
+    }
+
+    if let Some(ref token) = httpops.bearer {
+        request = request.bearer_auth(output_renderer(token)?);
+    }
+
+    if let Some(ref user) = httpops.user {
+        request = request.basic_auth(user, httpops.pass.as_ref())
+    }
+
+    if let Some(ref form) = httpops.form {
+        request = request.form(form)
+    }
+
+    if let Some(ref multipart) = httpops.multipart {
+        let mut form = Form::new();
+
+        for (key, val) in multipart.iter() {
+            let key = key.clone();
+
+            form = match val {
+                MultipartValue::Value(string) => form.text(key, string.clone()),
+                MultipartValue::Path(path_struct) => {
+                    let file_name = path_struct
+                        .file
+                        .file_name()
+                        .map(|val| val.to_string_lossy().to_string())
+                        .unwrap_or_default();
+
+                    let file = File::open(&path_struct.file)
+                        .await
+                        .map_err(|err| format!("{:?}", err))?;
+                    let reader = Body::wrap_stream(FramedRead::new(file, BytesCodec::new()));
+                    form.part(key, Part::stream(reader).file_name(file_name))
+                }
+                MultipartValue::Step(step) => match STEP_BYTES.get(&step.step) {
+                    Some(bytes) => form.part(key, Part::bytes(bytes)),
+                    None => match STEP_OUTPUT.get(&step.step) {
+                        Some(val) => form.text(key, val),
+                        None => return Err(format!("Step {} could not be found", &step.step)),
+                    },
+                },
+            }
+        }
+
+        request = request.multipart(form)
+    }
+
+    if let Some(ref body) = httpops.body {
+        request = request.body(output_renderer(body)?);
+    }
+
+    if let Some(ref body_file) = httpops.body_file {
+        if httpops.body.is_some() {
+            return Err("Only one of `body` & `body_file` can be set".into());
+        }
+
+        let body_file = body_file.options();
+
+        //The file is opened for each attempt, as the stream can only be sent once
+        let file = File::open(&body_file.file).await.map_err(|err| {
+            format!(
+                "Could not open body file `{}`: {}",
+                body_file.file.display(),
+                err
+            )
+        })?;
+
+        let len = file
+            .metadata()
+            .await
+            .map_err(|err| format!("Could not read body file metadata: {}", err))?
+            .len();
+
+        //Sending the length up front means servers that don't accept chunked uploads still work
+        request = request
+            .header(CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(FramedRead::new(file, BytesCodec::new())));
+
+        if let Some(ref content_type) = body_file.content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+    }
+
+    //Only the name & value are sent back, not the attributes
+    let mut cookies: Vec<(String, String)> = COOKIES
+        .get(jar)
+        .map(|cookie_jar| {
+            cookie_jar
+                .iter()
+                .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    for (name, value) in httpops.send_cookies.iter().flatten() {
+        let value = output_renderer(value)?;
+
+        match cookies.iter_mut().find(|(existing, _)| existing == name) {
+            Some(cookie) => cookie.1 = value,
+            None => cookies.push((name.clone(), value)),
+        }
+    }
+
+    if !cookies.is_empty() {
+        let cookie_strings: Vec<String> = cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        request = request.header(COOKIE, cookie_strings.join("; "))
+    }
+
+    if let Some(ref headers) = httpops.headers {
+        for (key, val) in headers.iter() {
+            request = request.header(&**key, &*output_renderer(val)?);
+        }
+    }
+
+    let mut request = request.build().map_err(|err| format!("{:?}", err))?;
+
+    //Signed last, as the signature covers the headers & body
+    if let Some(ref aws_auth) = httpops.aws_auth {
+        aws_auth.sign(&mut request)?;
+    }
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_out_requests_to_each_host() {
+        let mut next_request = HashMap::new();
+        let now = Instant::now();
+        let interval = Duration::from_millis(100);
+
+        let mut reserve = |host: &str| reserve_slot(&mut next_request, host.into(), now, interval);
+
+        assert_eq!(reserve("api"), Duration::ZERO);
+        assert_eq!(reserve("api"), interval);
+        assert_eq!(reserve("api"), interval * 2);
+        assert_eq!(reserve("cdn"), Duration::ZERO);
+
+        //Once the slots have passed, requests go straight away again
+        let later = now + interval * 5;
+        assert_eq!(
+            reserve_slot(&mut next_request, "api".into(), later, interval),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_with_the_code_for_why() {
+        let refused = HttpVariant::UrlOnly("http://127.0.0.1:1/timeout".into());
+
+        //The code comes from where it failed, not the words in the message or url
+        assert_eq!(
+            refused.run().await.unwrap_err().0,
+            ErrorCode::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn decodes_text_with_its_charset() {
+        assert_eq!(
+            decode_text(Some("text/plain; charset=\"ISO-8859-1\""), b"caf\xe9"),
+            "café"
+        );
+        assert_eq!(decode_text(Some("text/plain"), "café".as_bytes()), "café");
+        assert_eq!(decode_text(None, b"caf\xe9"), "caf\u{fffd}");
+    }
+
+    #[tokio::test]
+    async fn fails_downloads_under_min_bytes() {
+        let opts: HttpOptions = serde_json::from_value(json!({
+            "url": "http://example.com",
+            "min_bytes": 100,
+        }))
+        .unwrap();
+
+        let response = |len| Response::from(hyper::Response::new(vec![0u8; len]));
+
+        let (code, err) = match opts.download(response(99)).await {
+            Err(failure) => failure,
+            Ok(_) => panic!("A short body should fail"),
+        };
+        assert_eq!(code, ErrorCode::RunFailed);
+        assert_eq!(
+            err,
+            "Response was 99 bytes, less than the `min_bytes` of 100"
+        );
+
+        assert_eq!(opts.download(response(100)).await.unwrap().body.len(), 100);
+    }
+
+    #[test]
+    fn rejects_invalid_resolve_ips() {
+        let opts: HttpOptions = serde_json::from_value(json!({
+            "url": "https://example.com",
+            "resolve": {"example.com": "10.0.0.300"},
+        }))
+        .unwrap();
+
+        let err = opts.build_client().unwrap_err();
+
+        assert!(
+            err.starts_with("Could not resolve `example.com` to `10.0.0.300`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn named_jars_are_kept_apart() {
+        let admin = jar_key("", Some("admin"), "app.example.com");
+        let user = jar_key("", Some("user"), "app.example.com");
+
+        assert_ne!(admin, user);
+        assert_ne!(admin, jar_key("", None, "app.example.com"));
+        assert_ne!(admin, jar_key("payments", Some("admin"), "app.example.com"));
+        assert_eq!(admin, jar_key("", Some("admin"), "app.example.com"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saves_cookies_for_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("lorikeet-cookies-{}.json", std::process::id()));
+
+        let mut cookie_jar = CookieJar::new();
+        cookie_jar.add(Cookie::new("session", "abc123"));
+        COOKIES.insert("saves_cookies_for_the_owner_only".into(), cookie_jar);
+
+        save_cookie_jars(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let saved = std::fs::read_to_string(&path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(saved.contains("abc123"));
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn checks_cookie_attributes() {
+        let cookie =
+            Cookie::parse("session=abc123; Secure; HttpOnly; SameSite=Lax; Max-Age=3600").unwrap();
+
+        let expect = CookieExpect {
+            secure: Some(true),
+            http_only: Some(true),
+            same_site: Some("lax".into()),
+            min_lifetime_s: Some(600),
+            max_lifetime_s: Some(86400),
+            ..Default::default()
+        };
+
+        assert_eq!(expect.check(&cookie), Ok(()));
+
+        let insecure = Cookie::parse("session=abc123").unwrap();
+
+        assert_eq!(
+            expect.check(&insecure),
+            Err("Cookie `session` Secure is `false` but expected `true`".into())
+        );
+
+        let short = CookieExpect {
+            min_lifetime_s: Some(7200),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            short.check(&cookie),
+            Err("Cookie `session` expires in 3600s, less than 7200s".into())
+        );
+    }
+
+    #[test]
+    fn matches_no_proxy() {
+        let no_proxy = vec![".corp.example.com".to_string(), "localhost".to_string()];
+
+        assert!(bypasses_proxy("wiki.corp.example.com", &no_proxy));
+        assert!(bypasses_proxy("corp.example.com", &no_proxy));
+        assert!(bypasses_proxy("LOCALHOST", &no_proxy));
+        assert!(!bypasses_proxy("example.com", &no_proxy));
+        assert!(!bypasses_proxy("notcorp.example.com", &no_proxy));
+        assert!(bypasses_proxy("example.com", &["*".to_string()]));
+    }
+
+    #[test]
+    fn matches_expected_statuses() {
+        let expected: ExpectedStatus = serde_yaml::from_str("[200, 204, 3xx]").unwrap();
+
+        assert_eq!(expected.matches(204), Ok(true));
+        assert_eq!(expected.matches(302), Ok(true));
+        assert_eq!(expected.matches(404), Ok(false));
+        assert_eq!(expected.to_string(), "200, 204, 3xx");
+
+        let range = ExpectedStatus::Pattern("200-299".into());
+        assert_eq!(range.matches(299), Ok(true));
+        assert_eq!(range.matches(300), Ok(false));
+
+        assert!(ExpectedStatus::Pattern("2yy".into()).matches(200).is_err());
+
+        let typo: ExpectedStatus = serde_yaml::from_str("[200, 2yy]").unwrap();
+        assert!(typo.check().is_err());
+        assert_eq!(expected.check(), Ok(()));
     }
 }
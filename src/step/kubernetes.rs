@@ -0,0 +1,409 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::http::identity;
+use reqwest::{Certificate, Client, Identity};
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+fn default_timeout_ms() -> u64 {
+    10000
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum KubernetesVariant {
+    //A resource as `kind/name`, i.e, `deployment/web`
+    ResourceOnly(String),
+    Options(KubernetesOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct KubernetesOptions {
+    #[serde(default)]
+    kind: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    namespace: Option<String>,
+    //An API path to get instead of a known kind, i.e, `/apis/networking.k8s.io/v1/namespaces/default/ingresses/web`
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    kubeconfig: Option<PathBuf>,
+    #[serde(default)]
+    context: Option<String>,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+impl KubernetesVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let kubeopts = match *self {
+            KubernetesVariant::ResourceOnly(ref resource) => {
+                let (kind, name) = resource.split_once('/').ok_or_else(|| {
+                    format!("Resource `{}` should be in the form `kind/name`", resource)
+                })?;
+
+                KubernetesOptions {
+                    kind: Some(kind.into()),
+                    name: Some(name.into()),
+                    namespace: None,
+                    path: None,
+                    kubeconfig: None,
+                    context: None,
+                    verify_ssl: None,
+                    timeout_ms: default_timeout_ms(),
+                }
+            }
+            KubernetesVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let cluster = tokio::task::spawn_blocking({
+            let kubeopts = kubeopts.clone();
+            move || Cluster::load(&kubeopts)
+        })
+        .await
+        .map_err(|err| format!("{}", err))??;
+
+        let namespace = kubeopts
+            .namespace
+            .clone()
+            .or_else(|| cluster.namespace.clone())
+            .unwrap_or_else(|| "default".into());
+
+        let path = match (&kubeopts.path, &kubeopts.kind, &kubeopts.name) {
+            (Some(path), _, _) => path.clone(),
+            (None, Some(kind), Some(name)) => {
+                resource_path(&kind.to_lowercase(), name, &namespace)?
+            }
+            _ => return Err("Kubernetes requires either `kind` & `name`, or a `path`".into()),
+        };
+
+        let mut client_builder =
+            Client::builder().timeout(Duration::from_millis(kubeopts.timeout_ms));
+
+        if let Some(ref ca) = cluster.ca {
+            client_builder = client_builder.add_root_certificate(ca.clone());
+        }
+
+        if let Some(ref pkcs12) = cluster.identity {
+            let identity =
+                Identity::from_pkcs12_der(pkcs12, "").map_err(|err| format!("{}", err))?;
+            client_builder = client_builder.identity(identity);
+        }
+
+        if let Some(verify_ssl) = kubeopts.verify_ssl.or(cluster.verify_ssl) {
+            client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
+        }
+
+        let client = client_builder.build().map_err(|err| format!("{}", err))?;
+
+        let url = format!("{}{}", cluster.server.trim_end_matches('/'), path);
+
+        let mut request = client.get(&url);
+
+        match cluster.auth {
+            Auth::None => (),
+            Auth::Token(ref token) => request = request.bearer_auth(token),
+            Auth::Basic(ref user, ref pass) => request = request.basic_auth(user, Some(pass)),
+        }
+
+        let response = http_log::send(request)
+            .await
+            .map_err(|err| format!("Error connecting to the API server {}", err))?;
+
+        let status = response.status();
+
+        let resource: Value = response
+            .json()
+            .await
+            .map_err(|err| format!("Could not parse response from `{}`: {}", path, err))?;
+
+        if !status.is_success() {
+            return Err(format!(
+                "API server returned status {}: {}",
+                status,
+                resource["message"].as_str().unwrap_or_default()
+            ));
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "kind": resource["kind"],
+            "name": resource["metadata"]["name"],
+            "namespace": resource["metadata"]["namespace"],
+            "ready": is_ready(&resource),
+            "status": resource["status"],
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+// The API path for the common resource kinds
+fn resource_path(kind: &str, name: &str, namespace: &str) -> Result<String, String> {
+    let (group, plural, namespaced) = match kind.trim_end_matches('s') {
+        "pod" => ("/api/v1", "pods", true),
+        "service" => ("/api/v1", "services", true),
+        "node" => ("/api/v1", "nodes", false),
+        "deployment" => ("/apis/apps/v1", "deployments", true),
+        "statefulset" => ("/apis/apps/v1", "statefulsets", true),
+        "daemonset" => ("/apis/apps/v1", "daemonsets", true),
+        "replicaset" => ("/apis/apps/v1", "replicasets", true),
+        "job" => ("/apis/batch/v1", "jobs", true),
+        "cronjob" => ("/apis/batch/v1", "cronjobs", true),
+        _ => {
+            return Err(format!(
+                "Unknown kind `{}`, use `path` to get other resources",
+                kind
+            ))
+        }
+    };
+
+    Ok(match namespaced {
+        true => format!("{}/namespaces/{}/{}/{}", group, namespace, plural, name),
+        false => format!("{}/{}/{}", group, plural, name),
+    })
+}
+
+// Whether the resource has finished rolling out, or `None` if readiness isn't known for the kind
+fn is_ready(resource: &Value) -> Option<bool> {
+    let kind = resource["kind"].as_str().unwrap_or_default().to_lowercase();
+    let status = &resource["status"];
+    let spec = &resource["spec"];
+
+    let count = |val: &Value| val.as_u64().unwrap_or(0);
+
+    let desired = spec["replicas"].as_u64().unwrap_or(1);
+
+    match kind.as_str() {
+        "deployment" => Some(
+            count(&status["readyReplicas"]) >= desired
+                && count(&status["updatedReplicas"]) >= desired
+                && count(&status["observedGeneration"])
+                    >= count(&resource["metadata"]["generation"]),
+        ),
+        "statefulset" | "replicaset" => Some(count(&status["readyReplicas"]) >= desired),
+        "daemonset" => Some(
+            count(&status["numberReady"]) >= count(&status["desiredNumberScheduled"])
+                && count(&status["updatedNumberScheduled"])
+                    >= count(&status["desiredNumberScheduled"]),
+        ),
+        "pod" => Some(
+            status["phase"] == "Succeeded"
+                || (status["phase"] == "Running" && has_condition(status, "Ready")),
+        ),
+        "job" => Some(
+            has_condition(status, "Complete")
+                || count(&status["succeeded"]) >= spec["completions"].as_u64().unwrap_or(1),
+        ),
+        "node" => Some(has_condition(status, "Ready")),
+        _ => None,
+    }
+}
+
+fn has_condition(status: &Value, condition: &str) -> bool {
+    status["conditions"]
+        .as_array()
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|cond| cond["type"] == condition && cond["status"] == "True")
+        })
+        .unwrap_or(false)
+}
+
+enum Auth {
+    None,
+    Token(String),
+    Basic(String, String),
+}
+
+struct Cluster {
+    server: String,
+    ca: Option<Certificate>,
+    //The client certificate & key as PKCS12, as that's all native TLS accepts
+    identity: Option<Vec<u8>>,
+    verify_ssl: Option<bool>,
+    auth: Auth,
+    namespace: Option<String>,
+}
+
+impl Cluster {
+    // Uses the kubeconfig if there is one, otherwise the service account when running in a pod
+    fn load(opts: &KubernetesOptions) -> Result<Cluster, String> {
+        if let Some(ref path) = opts.kubeconfig {
+            return Cluster::from_kubeconfig(path, opts.context.as_deref());
+        }
+
+        if let Some(path) = default_kubeconfig() {
+            return Cluster::from_kubeconfig(&path, opts.context.as_deref());
+        }
+
+        if std::env::var_os("KUBERNETES_SERVICE_HOST").is_some() {
+            return Cluster::in_cluster();
+        }
+
+        Err("Could not find a kubeconfig, and not running in a cluster".into())
+    }
+
+    fn in_cluster() -> Result<Cluster, String> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").unwrap_or_default();
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+
+        let token = read_string(&dir.join("token"))?;
+        let ca = read_bytes(&dir.join("ca.crt"))?;
+        let namespace = read_string(&dir.join("namespace")).ok();
+
+        //IPv6 addresses need brackets within a url
+        let host = match host.contains(':') {
+            true => format!("[{}]", host),
+            false => host,
+        };
+
+        Ok(Cluster {
+            server: format!("https://{}:{}", host, port),
+            ca: Some(Certificate::from_pem(&ca).map_err(|err| format!("{}", err))?),
+            identity: None,
+            verify_ssl: None,
+            auth: Auth::Token(token.trim().into()),
+            namespace: namespace.map(|namespace| namespace.trim().into()),
+        })
+    }
+
+    fn from_kubeconfig(path: &Path, context: Option<&str>) -> Result<Cluster, String> {
+        let config: Value = serde_yaml::from_str(&read_string(path)?)
+            .map_err(|err| format!("Could not parse kubeconfig `{}`: {}", path.display(), err))?;
+
+        //Relative file paths are relative to the kubeconfig
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let context_name = match context {
+            Some(context) => context,
+            None => config["current-context"]
+                .as_str()
+                .ok_or("The kubeconfig has no `current-context`")?,
+        };
+
+        let context = named(&config, "contexts", context_name)?;
+
+        let cluster = named(
+            &config,
+            "clusters",
+            context["cluster"].as_str().unwrap_or_default(),
+        )?;
+
+        let server = cluster["server"]
+            .as_str()
+            .ok_or_else(|| format!("Cluster for context `{}` has no server", context_name))?
+            .to_string();
+
+        let ca = match data_or_file(cluster, "certificate-authority", dir)? {
+            Some(pem) => Some(Certificate::from_pem(&pem).map_err(|err| format!("{}", err))?),
+            None => None,
+        };
+
+        let verify_ssl = cluster["insecure-skip-tls-verify"]
+            .as_bool()
+            .map(|insecure| !insecure);
+
+        let user = match context["user"].as_str() {
+            Some(user) => named(&config, "users", user)?,
+            None => &Value::Null,
+        };
+
+        if !user["exec"].is_null() || !user["auth-provider"].is_null() {
+            return Err(format!(
+                "Context `{}` uses an exec or auth provider plugin, which isn't supported.  Use a token or client certificate instead",
+                context_name
+            ));
+        }
+
+        let identity = match (
+            data_or_file(user, "client-certificate", dir)?,
+            data_or_file(user, "client-key", dir)?,
+        ) {
+            (Some(cert), Some(key)) => Some(identity(&cert, &key)?),
+            _ => None,
+        };
+
+        let auth = if let Some(token) = user["token"].as_str() {
+            Auth::Token(token.into())
+        } else if let Some(file) = user["tokenFile"].as_str() {
+            Auth::Token(read_string(&dir.join(file))?.trim().into())
+        } else if let (Some(user), Some(pass)) =
+            (user["username"].as_str(), user["password"].as_str())
+        {
+            Auth::Basic(user.into(), pass.into())
+        } else {
+            Auth::None
+        };
+
+        Ok(Cluster {
+            server,
+            ca,
+            identity,
+            verify_ssl,
+            auth,
+            namespace: context["namespace"].as_str().map(String::from),
+        })
+    }
+}
+
+// The first kubeconfig in `KUBECONFIG` or `~/.kube/config` that exists
+fn default_kubeconfig() -> Option<PathBuf> {
+    if let Some(paths) = std::env::var_os("KUBECONFIG") {
+        return std::env::split_paths(&paths).find(|path| path.exists());
+    }
+
+    let path = Path::new(&std::env::var_os("HOME")?)
+        .join(".kube")
+        .join("config");
+
+    match path.exists() {
+        true => Some(path),
+        false => None,
+    }
+}
+
+// Finds an entry in one of the kubeconfig lists, such as `clusters`, returning its inner value
+fn named<'a>(config: &'a Value, list: &str, name: &str) -> Result<&'a Value, String> {
+    let field = list.trim_end_matches('s');
+
+    config[list]
+        .as_array()
+        .and_then(|entries| entries.iter().find(|entry| entry["name"] == name))
+        .map(|entry| &entry[field])
+        .ok_or_else(|| format!("Could not find {} `{}` in the kubeconfig", field, name))
+}
+
+// Kubeconfig values can be inline as `<key>-data`, or a path to a file
+fn data_or_file(section: &Value, key: &str, dir: &Path) -> Result<Option<Vec<u8>>, String> {
+    if let Some(data) = section[format!("{}-data", key)].as_str() {
+        return base64::decode(data)
+            .map(Some)
+            .map_err(|err| format!("Could not decode `{}-data`: {}", key, err));
+    }
+
+    match section[key].as_str() {
+        Some(file) => read_bytes(&dir.join(file)).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn read_string(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read `{}`: {}", path.display(), err))
+}
+
+fn read_bytes(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("Could not read `{}`: {}", path.display(), err))
+}
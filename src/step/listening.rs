@@ -0,0 +1,235 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+use serde_json::json;
+#[cfg(target_os = "linux")]
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ListeningVariant {
+    PortOnly(u16),
+    Options(ListeningOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ListeningOptions {
+    port: u16,
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default)]
+    process: Option<String>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl ListeningVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let listenops = match *self {
+            ListeningVariant::PortOnly(port) => ListeningOptions {
+                port,
+                protocol: Protocol::Tcp,
+                process: None,
+            },
+            ListeningVariant::Options(ref opts) => opts.clone(),
+        };
+
+        tokio::task::spawn_blocking(move || get_listeners(&listenops))
+            .await
+            .map_err(|err| format!("{}", err))?
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Socket {
+    address: IpAddr,
+    port: u16,
+    inode: u64,
+}
+
+#[cfg(target_os = "linux")]
+fn get_listeners(ops: &ListeningOptions) -> Result<String, String> {
+    let (tables, listen_state) = match ops.protocol {
+        Protocol::Tcp => (["/proc/net/tcp", "/proc/net/tcp6"], "0A"),
+        Protocol::Udp => (["/proc/net/udp", "/proc/net/udp6"], "07"),
+    };
+
+    let mut sockets = Vec::new();
+
+    for table in tables.iter() {
+        //The ipv6 table may not exist if ipv6 is disabled
+        if let Ok(contents) = fs::read_to_string(table) {
+            sockets.extend(parse_table(&contents, listen_state));
+        }
+    }
+
+    sockets.retain(|socket| socket.port == ops.port);
+
+    if sockets.is_empty() {
+        return Err(format!("Nothing is listening on port {}", ops.port));
+    }
+
+    let processes = socket_processes();
+
+    let listeners: Vec<_> = sockets
+        .iter()
+        .map(|socket| {
+            let (pid, name) = processes
+                .get(&socket.inode)
+                .map(|(pid, name)| (Some(*pid), Some(name.clone())))
+                .unwrap_or((None, None));
+
+            (socket, pid, name)
+        })
+        .filter(|(_, _, name)| match ops.process {
+            Some(ref process) => name.as_deref() == Some(process.as_str()),
+            None => true,
+        })
+        .map(|(socket, pid, name)| {
+            json!({
+                "address": socket.address.to_string(),
+                "port": socket.port,
+                "pid": pid,
+                "process": name,
+            })
+        })
+        .collect();
+
+    if listeners.is_empty() {
+        return Err(format!(
+            "Port {} is not bound by process `{}`",
+            ops.port,
+            ops.process.as_deref().unwrap_or_default()
+        ));
+    }
+
+    Ok(serde_json::Value::Array(listeners).to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn parse_table(contents: &str, listen_state: &str) -> Vec<Socket> {
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields.len() < 10 || fields[3] != listen_state {
+                return None;
+            }
+
+            let (address, port) = parse_address(fields[1])?;
+            let inode = fields[9].parse().ok()?;
+
+            Some(Socket {
+                address,
+                port,
+                inode,
+            })
+        })
+        .collect()
+}
+
+// Addresses are hex encoded, with each 32 bit word in host (little endian) order
+#[cfg(target_os = "linux")]
+fn parse_address(input: &str) -> Option<(IpAddr, u16)> {
+    let mut parts = input.split(':');
+    let address = parts.next()?;
+    let port = u16::from_str_radix(parts.next()?, 16).ok()?;
+
+    let mut words = Vec::new();
+    for chunk in address.as_bytes().chunks(8) {
+        let word = u32::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+        words.push(word.to_le_bytes());
+    }
+
+    let address = match words.len() {
+        1 => IpAddr::V4(Ipv4Addr::from(words[0])),
+        4 => {
+            let mut octets = [0u8; 16];
+            for (i, word) in words.iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(word);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some((address, port))
+}
+
+// Maps socket inodes to the pid & name of the process holding them open.
+// Processes we don't have permission to inspect are skipped
+#[cfg(target_os = "linux")]
+fn socket_processes() -> HashMap<u64, (u32, String)> {
+    let mut processes = HashMap::new();
+
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return processes,
+    };
+
+    for entry in entries.flatten() {
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+
+        let name = fs::read_to_string(entry.path().join("comm"))
+            .map(|comm| comm.trim().to_string())
+            .unwrap_or_default();
+
+        for fd in fds.flatten() {
+            if let Ok(link) = fs::read_link(fd.path()) {
+                let link = link.to_string_lossy();
+                if let Some(inode) = link
+                    .strip_prefix("socket:[")
+                    .and_then(|val| val.strip_suffix(']'))
+                    .and_then(|val| val.parse().ok())
+                {
+                    processes.insert(inode, (pid, name.clone()));
+                }
+            }
+        }
+    }
+
+    processes
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_listeners(_ops: &ListeningOptions) -> Result<String, String> {
+    Err("Not Implemented Yet".into())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_addresses() {
+        assert_eq!(
+            parse_address("0100007F:1F90"),
+            Some((IpAddr::V4(Ipv4Addr::LOCALHOST), 8080))
+        );
+        assert_eq!(
+            parse_address("00000000000000000000000001000000:0016"),
+            Some((IpAddr::V6(Ipv6Addr::LOCALHOST), 22))
+        );
+    }
+}
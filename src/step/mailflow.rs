@@ -0,0 +1,412 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use native_tls::TlsConnector;
+use reqwest::Url;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_mailbox() -> String {
+    "INBOX".into()
+}
+
+fn default_timeout_secs() -> u64 {
+    120
+}
+
+fn default_poll_interval_secs() -> u64 {
+    5
+}
+
+fn default_delete() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MailflowVariant {
+    //`smtp://` (using STARTTLS if offered) or `smtps://` url, with credentials
+    smtp: String,
+    //`imap://` or `imaps://` url, with credentials
+    imap: String,
+    from: String,
+    to: String,
+    #[serde(default = "default_mailbox")]
+    mailbox: String,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+    #[serde(default = "default_poll_interval_secs")]
+    poll_interval_secs: u64,
+    //Delete the probe once it has arrived
+    #[serde(default = "default_delete")]
+    delete: bool,
+}
+
+impl MailflowVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let smtp_url = Url::from_str(&self.smtp)
+            .map_err(|err| format!("Failed to parse smtp url `{}`: {}", self.smtp, err))?;
+        let imap_url = Url::from_str(&self.imap)
+            .map_err(|err| format!("Failed to parse imap url `{}`: {}", self.imap, err))?;
+
+        let probe_id = format!("{:016x}", rand::random::<u64>());
+
+        send_probe(&smtp_url, self, &probe_id).await?;
+
+        let sent = Instant::now();
+
+        let found = wait_for_probe(&imap_url, self, &probe_id, sent).await?;
+
+        let latency = match found {
+            Some(latency) => latency,
+            None => {
+                return Err(format!(
+                    "Probe `{}` did not arrive in `{}` within {}s",
+                    probe_id, self.mailbox, self.timeout_secs
+                ))
+            }
+        };
+
+        serde_json::to_string_pretty(&json!({
+            "probe_id": probe_id,
+            "latency_ms": latency.as_secs_f64() * 1000.0,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+async fn connect(url: &Url, default_port: u16) -> Result<TcpStream, String> {
+    let host = url.host_str().ok_or("Url must have a host")?;
+    let port = url.port().unwrap_or(default_port);
+
+    timeout(IO_TIMEOUT, TcpStream::connect((host, port)))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}:{}", host, port))?
+        .map_err(|err| format!("Could not connect to {}:{}: {}", host, port, err))
+}
+
+async fn tls<S: AsyncRead + AsyncWrite + Unpin>(
+    url: &Url,
+    stream: S,
+) -> Result<tokio_native_tls::TlsStream<S>, String> {
+    let connector = TlsConnector::new().map_err(|err| format!("{}", err))?;
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+
+    connector
+        .connect(url.host_str().unwrap_or_default(), stream)
+        .await
+        .map_err(|err| format!("TLS handshake failed: {}", err))
+}
+
+fn credentials(url: &Url) -> Option<(String, String)> {
+    match url.username() {
+        "" => None,
+        user => Some((decode(user), decode(url.password().unwrap_or_default()))),
+    }
+}
+
+fn decode(val: &str) -> String {
+    percent_encoding::percent_decode_str(val)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+async fn read_line<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+) -> Result<String, String> {
+    let mut line = String::new();
+
+    let read = timeout(IO_TIMEOUT, stream.read_line(&mut line))
+        .await
+        .map_err(|_| "Timed out waiting for the server".to_string())?
+        .map_err(|err| format!("{}", err))?;
+
+    if read == 0 {
+        return Err("Server closed the connection".into());
+    }
+
+    Ok(line.trim_end().to_string())
+}
+
+async fn write_line<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    line: &str,
+) -> Result<(), String> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|err| format!("{}", err))
+}
+
+// Reads a (possibly multi-line) smtp reply, returning the code & all lines
+async fn smtp_reply<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+) -> Result<(u16, Vec<String>), String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let line = read_line(stream).await?;
+        let done = line.as_bytes().get(3) != Some(&b'-');
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("Invalid smtp reply: {}", line))?;
+
+        lines.push(line);
+
+        if done {
+            return Ok((code, lines));
+        }
+    }
+}
+
+async fn smtp_command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    command: &str,
+    expected: u16,
+) -> Result<Vec<String>, String> {
+    write_line(stream, command).await?;
+
+    let (code, lines) = smtp_reply(stream).await?;
+
+    if code != expected {
+        //Don't leak credentials into the error
+        let command = if command.starts_with("AUTH") {
+            "AUTH"
+        } else {
+            command
+        };
+        return Err(format!(
+            "Smtp command `{}` failed: {}",
+            command,
+            lines.join(" ")
+        ));
+    }
+
+    Ok(lines)
+}
+
+async fn send_probe(url: &Url, opts: &MailflowVariant, probe_id: &str) -> Result<(), String> {
+    let stream = connect(url, if url.scheme() == "smtps" { 465 } else { 587 }).await?;
+
+    match url.scheme() {
+        "smtps" => smtp_session(BufReader::new(tls(url, stream).await?), url, opts, probe_id).await,
+        "smtp" => {
+            let mut stream = BufReader::new(stream);
+
+            let (code, lines) = smtp_reply(&mut stream).await?;
+            if code != 220 {
+                return Err(format!("Unexpected smtp greeting: {}", lines.join(" ")));
+            }
+
+            let capabilities = smtp_command(&mut stream, "EHLO lorikeet", 250).await?;
+
+            if capabilities
+                .iter()
+                .any(|line| line.to_uppercase().contains("STARTTLS"))
+            {
+                smtp_command(&mut stream, "STARTTLS", 220).await?;
+                let stream = tls(url, stream.into_inner()).await?;
+                smtp_session(BufReader::new(stream), url, opts, probe_id).await
+            } else if credentials(url).is_some() {
+                //Never send the password in the clear
+                Err(format!(
+                    "`{}` doesn't offer STARTTLS, so the credentials can't be sent safely",
+                    url.host_str().unwrap_or_default()
+                ))
+            } else {
+                smtp_deliver(stream, url, opts, probe_id).await
+            }
+        }
+        other => Err(format!(
+            "Unsupported scheme `{}`, expected `smtp` or `smtps`",
+            other
+        )),
+    }
+}
+
+// Starts a new smtp session, for implicit TLS or after STARTTLS
+async fn smtp_session<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: BufReader<S>,
+    url: &Url,
+    opts: &MailflowVariant,
+    probe_id: &str,
+) -> Result<(), String> {
+    if url.scheme() == "smtps" {
+        let (code, lines) = smtp_reply(&mut stream).await?;
+        if code != 220 {
+            return Err(format!("Unexpected smtp greeting: {}", lines.join(" ")));
+        }
+    }
+
+    smtp_command(&mut stream, "EHLO lorikeet", 250).await?;
+
+    smtp_deliver(stream, url, opts, probe_id).await
+}
+
+async fn smtp_deliver<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: BufReader<S>,
+    url: &Url,
+    opts: &MailflowVariant,
+    probe_id: &str,
+) -> Result<(), String> {
+    if let Some((user, pass)) = credentials(url) {
+        let token = base64::encode(format!("\0{}\0{}", user, pass));
+        smtp_command(&mut stream, &format!("AUTH PLAIN {}", token), 235).await?;
+    }
+
+    smtp_command(&mut stream, &format!("MAIL FROM:<{}>", opts.from), 250).await?;
+    smtp_command(&mut stream, &format!("RCPT TO:<{}>", opts.to), 250).await?;
+    smtp_command(&mut stream, "DATA", 354).await?;
+
+    let message = format!(
+        "From: <{from}>\r\nTo: <{to}>\r\nSubject: lorikeet mailflow probe {id}\r\nDate: {date}\r\nMessage-ID: <{id}@lorikeet>\r\nX-Lorikeet-Probe: {id}\r\n\r\nThis is an automated message to check mail delivery, and can be deleted.\r\n.",
+        from = opts.from,
+        to = opts.to,
+        id = probe_id,
+        date = chrono::Local::now().to_rfc2822(),
+    );
+
+    smtp_command(&mut stream, &message, 250).await?;
+
+    let _ = smtp_command(&mut stream, "QUIT", 221).await;
+
+    Ok(())
+}
+
+struct Imap<S> {
+    stream: BufReader<S>,
+    tag: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Imap<S> {
+    // Sends a tagged command, returning the untagged responses
+    async fn command(&mut self, command: &str) -> Result<Vec<String>, String> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+
+        write_line(&mut self.stream, &format!("{} {}", tag, command)).await?;
+
+        let mut untagged = Vec::new();
+
+        loop {
+            let line = read_line(&mut self.stream).await?;
+
+            if let Some(status) = line.strip_prefix(&format!("{} ", tag)) {
+                if status.to_uppercase().starts_with("OK") {
+                    return Ok(untagged);
+                }
+
+                let command = if command.starts_with("LOGIN") {
+                    "LOGIN"
+                } else {
+                    command
+                };
+                return Err(format!("Imap command `{}` failed: {}", command, status));
+            }
+
+            untagged.push(line);
+        }
+    }
+}
+
+fn quote(val: &str) -> String {
+    format!("\"{}\"", val.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+async fn wait_for_probe(
+    url: &Url,
+    opts: &MailflowVariant,
+    probe_id: &str,
+    sent: Instant,
+) -> Result<Option<Duration>, String> {
+    let stream = connect(url, if url.scheme() == "imaps" { 993 } else { 143 }).await?;
+
+    match url.scheme() {
+        "imaps" => {
+            poll_mailbox(
+                BufReader::new(tls(url, stream).await?),
+                url,
+                opts,
+                probe_id,
+                sent,
+            )
+            .await
+        }
+        "imap" => poll_mailbox(BufReader::new(stream), url, opts, probe_id, sent).await,
+        other => Err(format!(
+            "Unsupported scheme `{}`, expected `imap` or `imaps`",
+            other
+        )),
+    }
+}
+
+async fn poll_mailbox<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: BufReader<S>,
+    url: &Url,
+    opts: &MailflowVariant,
+    probe_id: &str,
+    sent: Instant,
+) -> Result<Option<Duration>, String> {
+    let greeting = read_line(&mut stream).await?;
+    if !greeting.to_uppercase().starts_with("* OK") {
+        return Err(format!("Unexpected imap greeting: {}", greeting));
+    }
+
+    let mut imap = Imap { stream, tag: 0 };
+
+    let (user, pass) = credentials(url).ok_or("The imap url requires credentials")?;
+
+    imap.command(&format!("LOGIN {} {}", quote(&user), quote(&pass)))
+        .await?;
+    imap.command(&format!("SELECT {}", quote(&opts.mailbox)))
+        .await?;
+
+    let deadline = Duration::from_secs(opts.timeout_secs);
+
+    loop {
+        //Ask the server to report any new messages before searching
+        imap.command("NOOP").await?;
+
+        let responses = imap
+            .command(&format!(
+                "SEARCH HEADER X-Lorikeet-Probe {}",
+                quote(probe_id)
+            ))
+            .await?;
+
+        let found: Vec<String> = responses
+            .iter()
+            .filter_map(|line| line.strip_prefix("* SEARCH"))
+            .flat_map(|ids| ids.split_whitespace().map(String::from))
+            .collect();
+
+        if !found.is_empty() {
+            let latency = sent.elapsed();
+
+            if opts.delete {
+                imap.command(&format!("STORE {} +FLAGS (\\Deleted)", found.join(",")))
+                    .await?;
+                imap.command("EXPUNGE").await?;
+            }
+
+            let _ = imap.command("LOGOUT").await;
+
+            return Ok(Some(latency));
+        }
+
+        if sent.elapsed() >= deadline {
+            let _ = imap.command("LOGOUT").await;
+            return Ok(None);
+        }
+
+        sleep(Duration::from_secs(opts.poll_interval_secs)).await;
+    }
+}
@@ -0,0 +1,246 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::prompt::{is_non_interactive, PROMPT_LOCK};
+use super::{current_plan, current_step};
+
+use lazy_static::lazy_static;
+use log::debug;
+use tokio::sync::oneshot;
+
+#[cfg(unix)]
+use tokio::io::{unix::AsyncFd, Interest};
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static REMOTE_APPROVAL: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    //Gates waiting on an operator, by plan & step name, so plans with the same step names don't clash
+    static ref GATES: Mutex<HashMap<(String, String), Gate>> = Mutex::new(HashMap::new());
+}
+
+struct Gate {
+    message: String,
+    approve: oneshot::Sender<bool>,
+}
+
+// Allows gates to be approved with `resolve_gate`, i.e, from the http endpoint in serve mode
+pub fn set_remote_approval(enabled: bool) {
+    REMOTE_APPROVAL.store(enabled, Ordering::SeqCst);
+}
+
+// The steps of a plan that are waiting to be approved, along with their messages
+pub fn pending_gates(plan: &str) -> Vec<(String, String)> {
+    let gates = GATES.lock().unwrap();
+
+    let mut pending: Vec<(String, String)> = gates
+        .iter()
+        .filter(|((gate_plan, _), _)| gate_plan == plan)
+        .map(|((_, name), gate)| (name.clone(), gate.message.clone()))
+        .collect();
+
+    pending.sort();
+    pending
+}
+
+// Approves or rejects a waiting gate, returning `false` if there is no gate for the plan's step
+pub fn resolve_gate(plan: &str, name: &str, approved: bool) -> bool {
+    match GATES
+        .lock()
+        .unwrap()
+        .remove(&(plan.to_string(), name.to_string()))
+    {
+        Some(gate) => gate.approve.send(approved).is_ok(),
+        None => false,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ManualVariant {
+    MessageOnly(String),
+    Options(ManualOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ManualOptions {
+    message: String,
+    //Fail if nobody has confirmed by then, otherwise wait forever
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl ManualVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let manualopts = match *self {
+            ManualVariant::MessageOnly(ref message) => ManualOptions {
+                message: message.clone(),
+                timeout_ms: None,
+            },
+            ManualVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let terminal = !is_non_interactive() && atty::is(atty::Stream::Stdin);
+        let remote = REMOTE_APPROVAL.load(Ordering::SeqCst);
+
+        if !terminal && !remote {
+            return Err(format!(
+                "Can't confirm `{}` without a terminal",
+                manualopts.message
+            ));
+        }
+
+        let wait = async {
+            match remote {
+                true => wait_for_approval(&manualopts.message, terminal).await,
+                false => confirm_on_terminal(&manualopts.message).await,
+            }
+        };
+
+        let approved = match manualopts.timeout_ms {
+            Some(timeout_ms) => tokio::time::timeout(Duration::from_millis(timeout_ms), wait)
+                .await
+                .map_err(|_| {
+                    format!(
+                        "`{}` was not confirmed within {}ms",
+                        manualopts.message, timeout_ms
+                    )
+                })?,
+            None => wait.await,
+        }?;
+
+        match approved {
+            true => Ok(format!("Confirmed: {}", manualopts.message)),
+            false => Err(format!("Rejected: {}", manualopts.message)),
+        }
+    }
+}
+
+// Waits on either the terminal or `resolve_gate`, whichever answers first
+async fn wait_for_approval(message: &str, terminal: bool) -> Result<bool, String> {
+    let key = (
+        current_plan(),
+        current_step().unwrap_or_else(|| message.to_string()),
+    );
+
+    let (approve, approved) = oneshot::channel();
+
+    GATES.lock().unwrap().insert(
+        key.clone(),
+        Gate {
+            message: message.to_string(),
+            approve,
+        },
+    );
+
+    debug!("Waiting for gate `{}` to be approved", key.1);
+
+    //Make sure the gate isn't left behind if the step times out
+    let _cleanup = GateCleanup(&key);
+
+    match terminal {
+        true => tokio::select! {
+            remote = approved => Ok(remote.unwrap_or(false)),
+            local = confirm_on_terminal(message) => local,
+        },
+        false => Ok(approved.await.unwrap_or(false)),
+    }
+}
+
+struct GateCleanup<'a>(&'a (String, String));
+
+impl Drop for GateCleanup<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut gates) = GATES.lock() {
+            gates.remove(self.0);
+        }
+    }
+}
+
+async fn confirm_on_terminal(message: &str) -> Result<bool, String> {
+    let _lock = PROMPT_LOCK.lock().await;
+
+    let mut stderr = std::io::stderr();
+
+    write!(stderr, "{} [y/N]: ", message)
+        .and_then(|_| stderr.flush())
+        .map_err(|err| format!("{}", err))?;
+
+    let answer = read_answer().await?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+// Reads a line once one has been typed.  Waiting is cancelled if the gate times out or is approved over http,
+// so there's no read left behind to swallow the answer to the next prompt
+#[cfg(unix)]
+async fn read_answer() -> Result<String, String> {
+    let stdin = AsyncFd::with_interest(std::io::stdin(), Interest::READABLE)
+        .map_err(|err| format!("Could not wait for answer: {}", err))?;
+
+    let _ready = stdin
+        .readable()
+        .await
+        .map_err(|err| format!("Could not wait for answer: {}", err))?;
+
+    let mut answer = String::new();
+
+    stdin
+        .get_ref()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| format!("Could not read answer: {}", err))?;
+
+    Ok(answer)
+}
+
+#[cfg(not(unix))]
+async fn read_answer() -> Result<String, String> {
+    tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+
+        std::io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .map_err(|err| format!("Could not read answer: {}", err))?;
+
+        Ok(answer)
+    })
+    .await
+    .map_err(|err| format!("{}", err))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{with_plan, with_step};
+    use super::*;
+
+    #[tokio::test]
+    async fn gates_are_kept_per_plan() {
+        let gate = |plan: &str| {
+            tokio::spawn(with_plan(
+                Some(plan.into()),
+                with_step("deploy".into(), wait_for_approval("Deploy?", false)),
+            ))
+        };
+
+        let payments = gate("payments");
+        let search = gate("search");
+
+        while pending_gates("payments").is_empty() || pending_gates("search").is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(resolve_gate("payments", "deploy", true));
+        assert!(!resolve_gate("payments", "deploy", true));
+        assert!(resolve_gate("search", "deploy", false));
+
+        assert_eq!(payments.await.unwrap(), Ok(true));
+        assert_eq!(search.await.unwrap(), Ok(false));
+    }
+}
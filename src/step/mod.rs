@@ -1,22 +1,107 @@
+mod azure;
 mod bash;
+mod benchmark;
+mod binary;
+mod browser;
+mod certfile;
+mod cloud_metadata;
+mod code;
+mod consul;
+mod crawl;
+mod diff;
+mod digest;
 mod disk;
+mod dns;
+mod etcd;
+mod eventlog;
+mod gcp;
+mod group;
+mod grpc;
+mod guard;
+mod headers_audit;
 mod http;
+mod kubernetes;
+mod listening;
+mod mailflow;
+mod manual;
+mod oauth2;
+mod perm;
+mod prompt;
+mod proxy;
+mod redfish;
+mod saas_status;
+mod session;
+mod snapshot;
 mod system;
+mod time;
+mod traceroute;
+mod transfer;
+mod wasm;
+mod wmi;
 
+pub use azure::AzureVariant;
 pub use bash::BashVariant;
+pub use benchmark::BenchmarkVariant;
+pub use browser::BrowserVariant;
+pub use certfile::CertFileVariant;
+pub use cloud_metadata::CloudMetadataVariant;
+pub use code::ErrorCode;
+pub use consul::ConsulVariant;
+pub use crawl::CrawlVariant;
 pub use disk::DiskVariant;
-pub use http::HttpVariant;
+pub use dns::DnsVariant;
+pub use etcd::EtcdVariant;
+pub use eventlog::EventLogVariant;
+pub use gcp::GcpVariant;
+pub use group::{GroupStep, GroupVariant};
+pub use grpc::GrpcVariant;
+pub use guard::HostGuard;
+pub use headers_audit::HeadersAuditVariant;
+pub use http::{load_cookie_jars, save_cookie_jars, HttpVariant};
+pub use kubernetes::KubernetesVariant;
+pub use listening::ListeningVariant;
+pub use mailflow::MailflowVariant;
+pub use manual::{pending_gates, resolve_gate, set_remote_approval, ManualVariant};
+pub use oauth2::OAuth2Variant;
+pub use perm::PermVariant;
+pub use prompt::{set_non_interactive, PromptVariant};
+pub use proxy::ProxyVariant;
+pub use redfish::RedfishVariant;
+pub use saas_status::AwsHealthVariant;
+pub use saas_status::GithubStatusVariant;
+pub use saas_status::StatuspageVariant;
+pub use snapshot::SnapshotVariant;
 pub use system::SystemVariant;
+pub use time::TimeVariant;
+pub use traceroute::TracerouteVariant;
+pub use transfer::TransferVariant;
+pub use wasm::WasmVariant;
+pub use wmi::WmiVariant;
 
+pub(crate) use session::register_session;
+pub(crate) use system::host_pressure;
+
+use crate::timezone::register_templates;
+use crate::{audit_log, http_log};
+use http::connection_error;
 use regex::Regex;
 
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
 use tokio::time::sleep;
 
 use tera::{Context, Tera};
 
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use jmespath::{self, Variable};
 
@@ -24,6 +109,9 @@ use lazy_static::lazy_static;
 use log::debug;
 
 use chashmap::CHashMap;
+use linked_hash_map::LinkedHashMap;
+use reqwest::RequestBuilder;
+use serde_json::Value;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Outcome {
@@ -32,6 +120,46 @@ pub struct Outcome {
     pub on_fail_output: Option<String>,
     pub on_fail_error: Option<String>,
     pub duration: Duration,
+    //Number of attempts used, out of the maximum allowed by the retry policy
+    pub attempts: usize,
+    pub max_attempts: usize,
+    //The reason the step was skipped rather than run
+    pub skipped: Option<String>,
+    pub timing: Timing,
+    //A hex dump of the start of a binary output
+    pub preview: Option<String>,
+    #[serde(skip)]
+    pub bytes: Option<Vec<u8>>,
+    //Why the step failed
+    pub code: Option<ErrorCode>,
+    pub attachments: Vec<Attachment>,
+    //When the run this outcome was carried forward from was, if it wasn't run this time
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+//A file attached to a step's outcome, i.e, a screenshot or response body
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub file_name: String,
+    pub size: usize,
+    //Where the attachment was saved, if it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    //The multipart form part it was uploaded in, for webhooks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub part: Option<String>,
+    #[serde(skip)]
+    pub contents: Vec<u8>,
+}
+
+//When a step was ready & ran, as offsets from the start of the run
+#[derive(Default, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timing {
+    //All dependencies had finished, but the step may still be waiting on a lock
+    pub ready: Duration,
+    pub started: Duration,
+    pub finished: Duration,
 }
 
 #[derive(Default, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -50,13 +178,30 @@ pub struct Step {
     pub filters: Vec<FilterType>,
     pub expect: ExpectType,
     pub do_output: bool,
+    pub sensitive: bool,
+    //The output is raw bytes, which are summarised rather than converted to a string
+    pub binary: bool,
+    //Whether a successful run is included in the results
+    pub report: bool,
+    //Steps sharing the same lock never run at the same time
+    pub lock: Option<String>,
+    pub guard: HostGuard,
     pub outcome: Option<Outcome>,
     pub retry: RetryPolicy,
     pub require: Vec<String>,
     pub required_by: Vec<String>,
+    pub meta: LinkedHashMap<String, String>,
+    pub tags: Vec<String>,
+    //Files to attach to the outcome, by name
+    pub attachments: LinkedHashMap<String, String>,
+    //When running on an interval, run at most this often & carry the last outcome forward in between
+    pub every: Option<Duration>,
+    //Identical setup steps, i.e, from a loop in the plan, run once & share the outcome
+    pub setup: bool, //The step as written in the plan when it uses `{{workdir}}`, so it can be parsed again with its own workdir
+    pub source: Option<serde_yaml::Value>,
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Requirement {
     Some(String),
@@ -72,7 +217,7 @@ impl Requirement {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum RunType {
     Step(String),
@@ -81,20 +226,137 @@ pub enum RunType {
     Http(HttpVariant),
     System(SystemVariant),
     Disk(DiskVariant),
+    Consul(ConsulVariant),
+    Etcd(EtcdVariant),
+    CertFile(CertFileVariant),
+    Listening(ListeningVariant),
+    Time(TimeVariant),
+    Perm(PermVariant),
+    Crawl(CrawlVariant),
+    #[serde(rename = "headers_audit")]
+    HeadersAudit(HeadersAuditVariant),
+    Wmi(WmiVariant),
+    Redfish(RedfishVariant),
+    Azure(AzureVariant),
+    Gcp(GcpVariant),
+    Transfer(TransferVariant),
+    Dns(DnsVariant),
+    Mailflow(MailflowVariant),
+    Browser(BrowserVariant),
+    Snapshot(SnapshotVariant),
+    Wasm(WasmVariant),
+    Grpc(GrpcVariant),
+    Kubernetes(KubernetesVariant),
+    Prompt(PromptVariant),
+    Manual(ManualVariant),
+    Benchmark(BenchmarkVariant),
+    Proxy(ProxyVariant),
+    Traceroute(TracerouteVariant),
+    CloudMetadata(CloudMetadataVariant),
+    Statuspage(StatuspageVariant),
+    GithubStatus(GithubStatusVariant),
+    AwsHealth(AwsHealthVariant),
+    EventLog(EventLogVariant),
+    OAuth2(OAuth2Variant),
+    Group(GroupVariant),
 }
 
 lazy_static! {
-    pub static ref STEP_OUTPUT: CHashMap<String, String> = CHashMap::new();
+    pub static ref STEP_OUTPUT: PerPlan<String> = PerPlan::default();
+    pub static ref STEP_BYTES: PerPlan<Vec<u8>> = PerPlan::default();
     static ref REGEX_OUTPUT: Regex = Regex::new("\\$\\{(step_output.[^}]+)\\}").unwrap();
 }
 
+tokio::task_local! {
+    //The step being run, so requests & gates can be attributed to it
+    static CURRENT_STEP: String;
+    //The plan being run, when serving several, so they can't see each other's step outputs
+    static CURRENT_PLAN: String;
+}
+
+// Values by step name, kept apart for each plan being run
+pub struct PerPlan<T>(CHashMap<(String, String), T>);
+
+impl<T> Default for PerPlan<T> {
+    fn default() -> Self {
+        PerPlan(CHashMap::new())
+    }
+}
+
+impl<T: Clone> PerPlan<T> {
+    pub fn get(&self, name: &str) -> Option<T> {
+        self.0
+            .get(&(current_plan(), name.to_string()))
+            .map(|value| value.clone())
+    }
+
+    pub fn insert(&self, name: String, value: T) {
+        self.0.insert((current_plan(), name), value);
+    }
+
+    // The values of the current plan, by step name
+    pub fn current(&self) -> HashMap<String, T> {
+        let plan = current_plan();
+
+        self.0
+            .clone()
+            .into_iter()
+            .filter(|((value_plan, _), _)| *value_plan == plan)
+            .map(|((_, name), value)| (name, value))
+            .collect()
+    }
+}
+
+// Runs a plan's steps, so they only see the outputs of steps in the same plan
+pub(crate) async fn with_plan<F: Future>(plan: Option<String>, future: F) -> F::Output {
+    CURRENT_PLAN.scope(plan.unwrap_or_default(), future).await
+}
+
+// The name of the plan being run, or empty if there's only the one
+pub(crate) fn current_plan() -> String {
+    CURRENT_PLAN
+        .try_with(|plan| plan.clone())
+        .unwrap_or_default()
+}
+
+// Runs a step's future, so the name of the step is available to it
+pub(crate) async fn with_step<F: Future>(name: String, future: F) -> F::Output {
+    CURRENT_STEP.scope(name, future).await
+}
+
+pub(crate) fn current_step() -> Option<String> {
+    CURRENT_STEP.try_with(|step| step.clone()).ok()
+}
+
+//A failed filter or expectation, with the code for why it failed
+type Failure = (ErrorCode, String);
+
+// A step type's error that only has a message, such as from `bash`, so its code is worked out from the message
+fn untyped<T>(result: Result<T, String>) -> Result<T, Failure> {
+    result.map_err(|err| (ErrorCode::from_run_error(&err), err))
+}
+
+//The output of a single attempt, along with the raw bytes if it's binary
+type Attempt = Result<(String, Option<Vec<u8>>), Failure>;
+
+//The result of a run that is shared between steps with identical run types
+#[derive(Clone, Default)]
+pub struct SharedRun(Arc<OnceCell<Attempt>>);
+
+//Placeholder for the per-step working directory, substituted when the step is run
+pub const WORKDIR_PLACEHOLDER: &str = "${workdir}";
+
 impl RunType {
+    #[allow(clippy::too_many_arguments)]
     pub async fn execute(
         &self,
         expect: ExpectType,
         filters: Vec<FilterType>,
         retry: RetryPolicy,
         on_fail: Option<RunType>,
+        binary: bool,
+        shared: Option<SharedRun>,
+        sensitive: bool,
     ) -> Outcome {
         let start = Instant::now();
 
@@ -107,12 +369,17 @@ impl RunType {
         let try_count = retry.retry_count + 1;
 
         let mut output = String::new();
+        let mut bytes = None;
         let mut error = String::new();
+        let mut code = None;
         let mut on_fail_output = None;
         let mut on_fail_error = None;
         let mut successful = false;
+        let mut attempts = 0;
 
         'retry: for count in 0..try_count {
+            attempts = count + 1;
+
             //If this is a retry, sleep first before trying again
             if count > 0 {
                 debug!("Retry {} of {}", count + 1, try_count - 1);
@@ -125,17 +392,31 @@ impl RunType {
             }
 
             output = String::new();
+            bytes = None;
             error = String::new();
+            code = None;
             on_fail_output = None;
             on_fail_error = None;
 
-            //Run the runner first
-            match self.run().await {
-                Ok(run_out) => {
+            //Run the runner first.  A shared run is only used for the first attempt, retries run again
+            let (result, trace) = http_log::capture_trace(async {
+                match shared {
+                    Some(ref shared) if count == 0 => {
+                        shared.0.get_or_init(|| self.attempt(binary)).await.clone()
+                    }
+                    _ => self.attempt(binary).await,
+                }
+            })
+            .await;
+
+            match result {
+                Ok((run_out, run_bytes)) => {
                     output = run_out;
+                    bytes = run_bytes;
                     successful = true;
                 }
-                Err(run_err) => {
+                Err((run_code, run_err)) => {
+                    code = Some(run_code);
                     error = run_err;
                     successful = false;
                 }
@@ -148,7 +429,8 @@ impl RunType {
                         Ok(filter_out) => {
                             output = filter_out;
                         }
-                        Err(filter_err) => {
+                        Err((filter_code, filter_err)) => {
+                            code = Some(filter_code);
                             error = filter_err;
                             successful = false;
                             break 'filter;
@@ -159,7 +441,8 @@ impl RunType {
 
             //If it's still successful, do the check
             if successful {
-                if let Err(check_err) = expect.check(&output) {
+                if let Err((check_code, check_err)) = expect.check(&output, sensitive) {
+                    code = Some(check_code);
                     error = check_err;
                     successful = false;
                 } else {
@@ -168,12 +451,17 @@ impl RunType {
             }
 
             if !successful {
+                //Requests traced with `trace` help explain why it failed
+                if !trace.is_empty() {
+                    error = format!("{}\n\nHttp trace:\n{}", error, trace.join("\n\n"));
+                }
+
                 if let Some(ref on_fail_runner) = on_fail {
-                    match on_fail_runner.run().await {
+                    match audit_log::on_fail(on_fail_runner.run()).await {
                         Ok(val) => {
                             on_fail_output = Some(val);
                         }
-                        Err(val) => on_fail_error = Some(val),
+                        Err((_, val)) => on_fail_error = Some(val),
                     }
                 }
             }
@@ -184,9 +472,9 @@ impl RunType {
             _ => Some(output),
         };
 
-        let error_opt = match successful {
-            true => None,
-            false => Some(error),
+        let (error_opt, code) = match successful {
+            true => (None, None),
+            false => (Some(error), code),
         };
 
         //Default Return
@@ -196,20 +484,150 @@ impl RunType {
             duration: start.elapsed(),
             on_fail_output,
             on_fail_error,
+            attempts,
+            max_attempts: try_count,
+            skipped: None,
+            timing: Timing::default(),
+            preview: bytes.as_deref().map(binary::hex_preview),
+            bytes,
+            code,
+            attachments: vec![],
+            last_run: None,
+        }
+    }
+
+    // Whether the step talks to other hosts, so can't run with `--offline`.  Bash steps might, but there's no telling
+    pub fn needs_network(&self) -> bool {
+        match self {
+            RunType::Http(_)
+            | RunType::Consul(_)
+            | RunType::Etcd(_)
+            | RunType::Crawl(_)
+            | RunType::HeadersAudit(_)
+            | RunType::Redfish(_)
+            | RunType::Azure(_)
+            | RunType::Gcp(_)
+            | RunType::Transfer(_)
+            | RunType::Dns(_)
+            | RunType::Mailflow(_)
+            | RunType::Browser(_)
+            | RunType::Grpc(_)
+            | RunType::Kubernetes(_)
+            | RunType::Benchmark(_)
+            | RunType::Proxy(_)
+            | RunType::Traceroute(_)
+            | RunType::CloudMetadata(_)
+            | RunType::Statuspage(_)
+            | RunType::GithubStatus(_)
+            | RunType::AwsHealth(_)
+            | RunType::OAuth2(_) => true,
+            RunType::Wmi(variant) => variant.needs_network(),
+            RunType::EventLog(variant) => variant.needs_network(),
+            RunType::Snapshot(variant) => variant.needs_network(),
+            RunType::Group(variant) => variant.needs_network(),
+            RunType::CertFile(variant) => variant.needs_network(),
+            RunType::Step(_)
+            | RunType::Value(_)
+            | RunType::Bash(_)
+            | RunType::System(_)
+            | RunType::Disk(_)
+            | RunType::Listening(_)
+            | RunType::Time(_)
+            | RunType::Perm(_)
+            | RunType::Wasm(_)
+            | RunType::Prompt(_)
+            | RunType::Manual(_) => false,
+        }
+    }
+
+    // Fills in the workdir for browser steps that save screenshots to it by default, returning whether any did
+    pub fn default_workdir(&mut self, workdir: &Path) -> bool {
+        match *self {
+            RunType::Browser(ref mut browser) => browser.default_screenshot_dir(workdir),
+            RunType::Group(ref mut group) => {
+                let mut used = false;
+
+                for step in group.steps.iter_mut() {
+                    used |= step.run.default_workdir(workdir);
+                }
+
+                used
+            }
+            _ => false,
         }
     }
 
-    async fn run(&self) -> Result<String, String> {
+    // Runs once, returning the output along with the raw bytes if it's binary
+    async fn attempt(&self, binary: bool) -> Attempt {
+        match binary {
+            true => self
+                .run_bytes()
+                .await
+                .map(|bytes| (binary::summary(&bytes), Some(bytes))),
+            false => self.run().await.map(|output| (output, None)),
+        }
+    }
+
+    // Runs, keeping the raw bytes of the output for the step types that can produce binary
+    async fn run_bytes(&self) -> Result<Vec<u8>, Failure> {
+        match *self {
+            RunType::Step(ref val) => match STEP_BYTES.get(val) {
+                Some(val) => Ok(val),
+                None => self.run().await.map(String::into_bytes),
+            },
+            RunType::Bash(ref val) => untyped(val.run_bytes().await),
+            RunType::Http(ref val) => val.run_bytes().await,
+            _ => self.run().await.map(String::into_bytes),
+        }
+    }
+
+    // Runs, failing with the code for why.  Http steps & groups know their code, the rest go by their message
+    async fn run(&self) -> Result<String, Failure> {
         match *self {
             RunType::Step(ref val) => match STEP_OUTPUT.get(val) {
-                Some(val) => Ok(val.to_string()),
-                None => return Err(format!("Step {} could not be found", val)),
+                Some(val) => Ok(val),
+                None => Err((
+                    ErrorCode::StepNotFound,
+                    format!("Step {} could not be found", val),
+                )),
             },
             RunType::Value(ref val) => Ok(val.clone()),
-            RunType::Bash(ref val) => val.run().await,
+            RunType::Bash(ref val) => untyped(val.run().await),
             RunType::Http(ref val) => val.run().await,
-            RunType::System(ref val) => val.run().await,
-            RunType::Disk(ref val) => val.run().await,
+            RunType::System(ref val) => untyped(val.run().await),
+            RunType::Disk(ref val) => untyped(val.run().await),
+            RunType::Consul(ref val) => untyped(val.run().await),
+            RunType::Etcd(ref val) => untyped(val.run().await),
+            RunType::CertFile(ref val) => untyped(val.run().await),
+            RunType::Listening(ref val) => untyped(val.run().await),
+            RunType::Time(ref val) => untyped(val.run().await),
+            RunType::Perm(ref val) => untyped(val.run().await),
+            RunType::Crawl(ref val) => untyped(val.run().await),
+            RunType::HeadersAudit(ref val) => untyped(val.run().await),
+            RunType::Wmi(ref val) => untyped(val.run().await),
+            RunType::Redfish(ref val) => untyped(val.run().await),
+            RunType::Azure(ref val) => untyped(val.run().await),
+            RunType::Gcp(ref val) => untyped(val.run().await),
+            RunType::Transfer(ref val) => untyped(val.run().await),
+            RunType::Dns(ref val) => untyped(val.run().await),
+            RunType::Mailflow(ref val) => untyped(val.run().await),
+            RunType::Browser(ref val) => untyped(val.run().await),
+            RunType::Snapshot(ref val) => untyped(val.run().await),
+            RunType::Wasm(ref val) => untyped(val.run().await),
+            RunType::Grpc(ref val) => untyped(val.run().await),
+            RunType::Kubernetes(ref val) => untyped(val.run().await),
+            RunType::Prompt(ref val) => untyped(val.run().await),
+            RunType::Manual(ref val) => untyped(val.run().await),
+            RunType::Benchmark(ref val) => untyped(val.run().await),
+            RunType::Proxy(ref val) => untyped(val.run().await),
+            RunType::Traceroute(ref val) => untyped(val.run().await),
+            RunType::CloudMetadata(ref val) => untyped(val.run().await),
+            RunType::Statuspage(ref val) => untyped(val.run().await),
+            RunType::GithubStatus(ref val) => untyped(val.run().await),
+            RunType::AwsHealth(ref val) => untyped(val.run().await),
+            RunType::EventLog(ref val) => untyped(val.run().await),
+            RunType::OAuth2(ref val) => untyped(val.run().await),
+            RunType::Group(ref val) => val.run().await,
         }
     }
 }
@@ -226,7 +644,7 @@ impl Step {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum FilterType {
     NoOutput,
@@ -234,43 +652,102 @@ pub enum FilterType {
     JmesPath(String),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(untagged)]
 pub enum RegexVariant {
     MatchOnly(String),
     Options(RegexOptions),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct RegexOptions {
     matches: String,
     group: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum ExpectType {
+    #[default]
     Anything,
     Matches(String),
     MatchesNot(String),
+    Equals(String),
     GreaterThan(f64),
     LessThan(f64),
+    MatchesFile(MatchesFileVariant),
+    CompareTo(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum MatchesFileVariant {
+    FileOnly(String),
+    Options(MatchesFileOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MatchesFileOptions {
+    file: String,
+    //Volatile fields, such as timestamps, that aren't compared
+    #[serde(default)]
+    ignore: Vec<String>,
+}
+
+impl MatchesFileVariant {
+    fn options(&self) -> MatchesFileOptions {
+        match self {
+            MatchesFileVariant::FileOnly(file) => MatchesFileOptions {
+                file: file.clone(),
+                ignore: Vec::new(),
+            },
+            MatchesFileVariant::Options(opts) => opts.clone(),
+        }
+    }
+
+    // Makes a relative file path relative to `dir` instead, such as the directory of the test plan
+    pub fn relative_to(&mut self, dir: &Path) {
+        let file = match self {
+            MatchesFileVariant::FileOnly(file) => file,
+            MatchesFileVariant::Options(opts) => &mut opts.file,
+        };
+
+        relative_to(file, dir);
+    }
+}
+
+// Makes a relative file path relative to `dir` instead
+pub fn relative_to(file: &mut String, dir: &Path) {
+    if Path::new(file.as_str()).is_relative() {
+        *file = dir.join(&file).to_string_lossy().to_string();
+    }
 }
 
 impl FilterType {
-    fn filter(&self, val: &str) -> Result<String, String> {
+    pub(crate) fn filter(&self, val: &str) -> Result<String, Failure> {
         match *self {
             FilterType::NoOutput => Ok(String::from("")),
             FilterType::JmesPath(ref jmes) => {
-                let expr = jmespath::compile(jmes)
-                    .map_err(|err| format!("Could not compile jmespath:{}", err))?;
+                let expr = jmespath::compile(jmes).map_err(|err| {
+                    (
+                        ErrorCode::InvalidJmespath,
+                        format!("Could not compile jmespath:{}", err),
+                    )
+                })?;
 
-                let data = Variable::from_json(val)
-                    .map_err(|err| format!("Could not format as json:{}", err))?;
+                let data = Variable::from_json(val).map_err(|err| {
+                    (
+                        ErrorCode::InvalidJson,
+                        format!("Could not format as json:{}", err),
+                    )
+                })?;
 
-                let result = expr
-                    .search(data)
-                    .map_err(|err| format!("Could not find jmes expression:{}", err))?;
+                let result = expr.search(data).map_err(|err| {
+                    (
+                        ErrorCode::JmespathNoMatch,
+                        format!("Could not find jmes expression:{}", err),
+                    )
+                })?;
 
                 let output = match &*result {
                     Variable::String(val) => val.clone(),
@@ -280,9 +757,9 @@ impl FilterType {
                 if output != "null" {
                     Ok(output)
                 } else {
-                    Err(format!(
-                        "Could not find jmespath expression `{}` in output",
-                        expr
+                    Err((
+                        ErrorCode::JmespathNoMatch,
+                        format!("Could not find jmespath expression `{}` in output", expr),
                     ))
                 }
             }
@@ -296,40 +773,44 @@ impl FilterType {
                 };
 
                 let regex = Regex::new(&opts.matches).map_err(|err| {
-                    format!(
-                        "Could not create regex from `{}`.  Error is:{:?}",
-                        &opts.matches, err
+                    (
+                        ErrorCode::InvalidRegex,
+                        format!(
+                            "Could not create regex from `{}`.  Error is:{:?}",
+                            &opts.matches, err
+                        ),
                     )
                 })?;
 
-                let captures = regex
-                    .captures(val)
-                    .ok_or_else(|| format!("Could not find `{}` in output", &opts.matches))?;
+                let captures = regex.captures(val).ok_or_else(|| {
+                    (
+                        ErrorCode::RegexNoMatch,
+                        format!("Could not find `{}` in output", &opts.matches),
+                    )
+                })?;
 
-                match opts.group.parse::<usize>() {
-                    Ok(num) => {
-                        return captures
-                            .get(num)
-                            .map(|val| val.as_str().into())
-                            .ok_or_else(|| {
-                                format!(
-                                    "Could not find group number `{}` in regex `{}`",
-                                    opts.group, opts.matches
-                                )
-                            });
-                    }
-                    Err(_) => {
-                        return captures
-                            .name(&opts.group)
-                            .map(|val| val.as_str().into())
-                            .ok_or_else(|| {
-                                format!(
-                                    "Could not find group name `{}` in regex `{}`",
-                                    opts.group, opts.matches
-                                )
-                            });
-                    }
-                }
+                let group = match opts.group.parse::<usize>() {
+                    Ok(num) => captures
+                        .get(num)
+                        .map(|val| val.as_str().into())
+                        .ok_or_else(|| {
+                            format!(
+                                "Could not find group number `{}` in regex `{}`",
+                                opts.group, opts.matches
+                            )
+                        }),
+                    Err(_) => captures
+                        .name(&opts.group)
+                        .map(|val| val.as_str().into())
+                        .ok_or_else(|| {
+                            format!(
+                                "Could not find group name `{}` in regex `{}`",
+                                opts.group, opts.matches
+                            )
+                        }),
+                };
+
+                group.map_err(|err| (ErrorCode::RegexNoMatch, err))
             }
         }
     }
@@ -342,11 +823,12 @@ fn output_renderer(input: &str) -> Result<String, String> {
         Cow::Borrowed(_) => Ok(input.to_string()),
         Cow::Owned(cow_body) => {
             let mut tera = Tera::default();
+            register_templates(&mut tera);
 
             tera.add_raw_template("step_body", &cow_body)
                 .map_err(|err| format!("Template Error: {}", err))?;
 
-            let step_output: HashMap<String, String> = STEP_OUTPUT.clone().into_iter().collect();
+            let step_output = STEP_OUTPUT.current();
 
             let mut context = HashMap::new();
             context.insert("step_output", step_output);
@@ -369,35 +851,87 @@ lazy_static! {
 }
 
 impl ExpectType {
-    fn check(&self, val: &str) -> Result<(), String> {
+    // Checks the output.  Errors for a sensitive step leave out the excerpts, diffs & values that would quote it
+    fn check(&self, val: &str, sensitive: bool) -> Result<(), Failure> {
         match *self {
             ExpectType::Anything => Ok(()),
             ExpectType::MatchesNot(ref match_string) => {
                 let regex = Regex::new(match_string).map_err(|err| {
-                    format!(
-                        "Could not create regex from `{}`.  Error is:{:?}",
-                        match_string, err
+                    (
+                        ErrorCode::InvalidRegex,
+                        format!(
+                            "Could not create regex from `{}`.  Error is:{:?}",
+                            match_string, err
+                        ),
                     )
                 })?;
 
-                if !regex.is_match(val) {
-                    Ok(())
-                } else {
-                    Err(format!("Matched against `{}`", match_string))
+                match regex.find(val) {
+                    None => Ok(()),
+                    Some(_) if sensitive => Err((
+                        ErrorCode::MatchNotFailed,
+                        format!("Matched against `{}`", match_string),
+                    )),
+                    Some(found) => Err((
+                        ErrorCode::MatchNotFailed,
+                        format!(
+                            "Matched against `{}` at: {}",
+                            match_string,
+                            diff::excerpt(val, found.start(), found.end())
+                        ),
+                    )),
                 }
             }
             ExpectType::Matches(ref match_string) => {
                 let regex = Regex::new(match_string).map_err(|err| {
-                    format!(
-                        "Could not create regex from `{}`.  Error is:{:?}",
-                        match_string, err
+                    (
+                        ErrorCode::InvalidRegex,
+                        format!(
+                            "Could not create regex from `{}`.  Error is:{:?}",
+                            match_string, err
+                        ),
                     )
                 })?;
 
                 if regex.is_match(val) {
                     Ok(())
+                } else if sensitive {
+                    Err((
+                        ErrorCode::MatchFailed,
+                        format!("Not matched against `{}`", match_string),
+                    ))
+                } else {
+                    let error = match diff::closest_match(match_string, val) {
+                        Some((prefix, excerpt)) => format!(
+                            "Not matched against `{}`.  Closest partial match `{}` at: {}",
+                            match_string, prefix, excerpt
+                        ),
+                        None => format!(
+                            "Not matched against `{}`.  Output starts with: {}",
+                            match_string,
+                            diff::excerpt(val, 0, 0)
+                        ),
+                    };
+
+                    Err((ErrorCode::MatchFailed, error))
+                }
+            }
+            ExpectType::Equals(ref expected) => {
+                if val.trim_end() == expected.trim_end() {
+                    Ok(())
+                } else if sensitive {
+                    Err((
+                        ErrorCode::ValueMismatch,
+                        "Output does not equal expected value".into(),
+                    ))
                 } else {
-                    Err(format!("Not matched against `{}`", match_string))
+                    Err((
+                        ErrorCode::ValueMismatch,
+                        format!(
+                            "Output does not equal expected value:\n{}",
+                            diff::line_diff(expected, val)
+                        ),
+                    ))
                 }
             }
             ExpectType::GreaterThan(ref num) => {
@@ -406,13 +940,20 @@ impl ExpectType {
                         if compare > *num {
                             Ok(())
                         } else {
-                            Err(format!(
-                                "The value `{}` is not greater than `{}`",
-                                compare, num
+                            Err((
+                                ErrorCode::ThresholdNotMet,
+                                format!(
+                                    "The value `{}` is not greater than `{}`",
+                                    shown(&compare, sensitive),
+                                    num
+                                ),
                             ))
                         }
                     }
-                    Err(_) => Err(format!("Could not parse `{}` as a number", val)),
+                    Err(_) => Err((
+                        ErrorCode::NotANumber,
+                        format!("Could not parse `{}` as a number", shown(val, sensitive)),
+                    )),
                 }
             }
             ExpectType::LessThan(ref num) => {
@@ -421,25 +962,132 @@ impl ExpectType {
                         if compare < *num {
                             Ok(())
                         } else {
-                            Err(format!(
-                                "The value `{}` is not less than `{}`",
-                                compare, num
+                            Err((
+                                ErrorCode::ThresholdNotMet,
+                                format!(
+                                    "The value `{}` is not less than `{}`",
+                                    shown(&compare, sensitive),
+                                    num
+                                ),
                             ))
                         }
                     }
-                    Err(_) => Err(format!("Could not parse `{}` as a number", num)),
+                    Err(_) => Err((
+                        ErrorCode::NotANumber,
+                        format!("Could not parse `{}` as a number", num),
+                    )),
+                }
+            }
+            ExpectType::MatchesFile(ref variant) => {
+                let opts = variant.options();
+
+                let contents = std::fs::read_to_string(&opts.file).map_err(|err| {
+                    (
+                        ErrorCode::FileNotFound,
+                        format!("Could not read `{}`: {}", opts.file, err),
+                    )
+                })?;
+
+                let expected: Value = serde_json::from_str(&contents).map_err(|err| {
+                    (
+                        ErrorCode::InvalidJson,
+                        format!("Could not parse `{}` as json: {}", opts.file, err),
+                    )
+                })?;
+
+                let actual: Value = serde_json::from_str(val).map_err(|err| {
+                    (
+                        ErrorCode::InvalidJson,
+                        format!("Could not parse output as json: {}", err),
+                    )
+                })?;
+
+                let differences = diff::json_diff(&expected, &actual, &opts.ignore)
+                    .map_err(|err| (ErrorCode::InvalidRegex, err))?
+                    .differences;
+
+                if differences.is_empty() {
+                    Ok(())
+                } else if sensitive {
+                    Err((
+                        ErrorCode::FileMismatch,
+                        format!("Output does not match `{}`", opts.file),
+                    ))
+                } else {
+                    Err((
+                        ErrorCode::FileMismatch,
+                        format!(
+                            "Output does not match `{}`:\n{}",
+                            opts.file,
+                            differences.join("\n")
+                        ),
+                    ))
+                }
+            }
+            ExpectType::CompareTo(ref file) => {
+                let expected = std::fs::read_to_string(file).map_err(|err| {
+                    (
+                        ErrorCode::FileNotFound,
+                        format!("Could not read `{}`: {}", file, err),
+                    )
+                })?;
+
+                //Like `equals`, trailing whitespace such as the file's last newline doesn't count
+                if val.trim_end() == expected.trim_end() {
+                    Ok(())
+                } else if sensitive {
+                    Err((
+                        ErrorCode::FileMismatch,
+                        format!("Output does not match `{}`", file),
+                    ))
+                } else {
+                    Err((
+                        ErrorCode::FileMismatch,
+                        format!(
+                            "Output does not match `{}`:\n{}",
+                            file,
+                            diff::unified_diff(&expected, val, file, "output")
+                        ),
+                    ))
                 }
             }
         }
     }
 }
 
-impl Default for ExpectType {
-    fn default() -> Self {
-        ExpectType::Anything
+// A value parsed from the output, hidden if the step is sensitive
+fn shown<T: std::fmt::Display + ?Sized>(val: &T, sensitive: bool) -> String {
+    match sensitive {
+        true => audit_log::REDACTED.into(),
+        false => val.to_string(),
     }
 }
 
+// Sends a request, returning the json body if it was successful
+pub(crate) async fn send_json(request: RequestBuilder) -> Result<Value, String> {
+    let response = http_log::send(request)
+        .await
+        .map_err(|err| connection_error(&err))?;
+
+    let status = response.status();
+    let url = response.url().clone();
+
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!(
+            "Request to `{}` returned status {}: {}",
+            url.path(),
+            status,
+            body
+        ));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|err| format!("Could not parse response from `{}`: {}", url.path(), err))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -447,15 +1095,71 @@ mod tests {
     #[test]
     fn expect_negative_numbers() {
         let expect = ExpectType::LessThan(0.0);
-        assert_eq!(expect.check("-1"), Ok(()));
-        assert_eq!(expect.check("-1.0"), Ok(()));
-        assert_eq!(expect.check("-.01"), Ok(()));
-        assert_eq!(expect.check("-0.01"), Ok(()));
+        assert_eq!(expect.check("-1", false), Ok(()));
+        assert_eq!(expect.check("-1.0", false), Ok(()));
+        assert_eq!(expect.check("-.01", false), Ok(()));
+        assert_eq!(expect.check("-0.01", false), Ok(()));
 
         let expect = ExpectType::GreaterThan(-2.0);
-        assert_eq!(expect.check("-1"), Ok(()));
-        assert_eq!(expect.check("-1.0"), Ok(()));
-        assert_eq!(expect.check("-.01"), Ok(()));
-        assert_eq!(expect.check("-0.01"), Ok(()));
+        assert_eq!(expect.check("-1", false), Ok(()));
+        assert_eq!(expect.check("-1.0", false), Ok(()));
+        assert_eq!(expect.check("-.01", false), Ok(()));
+        assert_eq!(expect.check("-0.01", false), Ok(()));
+    }
+
+    #[test]
+    fn expect_equals_diff() {
+        let expect = ExpectType::Equals("one\ntwo\nthree\n".into());
+        assert_eq!(expect.check("one\ntwo\nthree", false), Ok(()));
+        assert_eq!(
+            expect.check("one\n2\nthree", false),
+            Err((
+                ErrorCode::ValueMismatch,
+                "Output does not equal expected value:\n  one\n- two\n+ 2\n  three\n".into()
+            ))
+        );
+
+        //A sensitive step's output isn't quoted back
+        assert_eq!(
+            expect.check("one\n2\nthree", true),
+            Err((
+                ErrorCode::ValueMismatch,
+                "Output does not equal expected value".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn unified_diff_only_shows_changed_hunks() {
+        let expected = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+        let actual = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+
+        assert_eq!(
+            diff::unified_diff(expected, actual, "expected.txt", "output"),
+            "--- expected.txt\n+++ output\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -10,3 +10,4 @@\n j\n k\n l\n+m\n"
+        );
+    }
+
+    #[test]
+    fn json_diff_ignores_volatile_fields() {
+        let expected = serde_json::json!({
+            "id": 1,
+            "updated_at": "2020-01-01",
+            "items": [{"name": "a", "id": 5}, {"name": "b", "id": 6}]
+        });
+        let actual = serde_json::json!({
+            "id": 1,
+            "updated_at": "2021-06-30",
+            "items": [{"name": "a", "id": 7}, {"name": "c", "id": 8}]
+        });
+
+        let ignore = vec!["updated_at".to_string(), "$.items[*].id".to_string()];
+
+        assert_eq!(
+            diff::json_diff(&expected, &actual, &ignore).map(|diff| diff.differences),
+            Ok(vec![r#"$.items[1].name: "b" -> "c""#.to_string()])
+        );
     }
 }
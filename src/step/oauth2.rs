@@ -0,0 +1,223 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::http::connection_error;
+use super::prompt::{is_non_interactive, PROMPT_LOCK};
+use super::send_json;
+
+use linked_hash_map::LinkedHashMap;
+use reqwest::{Client, RequestBuilder};
+use tokio::time::sleep;
+
+use std::time::{Duration, Instant};
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+fn default_timeout_ms() -> u64 {
+    30000
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantType {
+    #[default]
+    ClientCredentials,
+    Password,
+    //Someone signs in on another device, with a code shown in the terminal
+    DeviceCode,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OAuth2Variant {
+    token_url: String,
+    client_id: String,
+    #[serde(default)]
+    client_secret: Option<String>,
+    #[serde(default)]
+    grant_type: GrantType,
+    #[serde(default)]
+    scope: Option<String>,
+    //For the `password` grant
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    //For the `device_code` grant
+    #[serde(default)]
+    device_url: Option<String>,
+    //Send the client credentials as basic auth rather than in the form
+    #[serde(default)]
+    basic_auth: bool,
+    //Any other form parameters, i.e, `audience`
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, String>")]
+    params: LinkedHashMap<String, String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+impl OAuth2Variant {
+    pub async fn run(&self) -> Result<String, String> {
+        let client = Client::builder()
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .build()
+            .map_err(|err| format!("{}", err))?;
+
+        let response = match self.grant_type {
+            GrantType::ClientCredentials => {
+                send_json(self.token_request(&client, vec![("grant_type", "client_credentials")]))
+                    .await?
+            }
+            GrantType::Password => {
+                let username = self
+                    .username
+                    .as_deref()
+                    .ok_or("The `password` grant needs a `username`")?;
+                let password = self
+                    .password
+                    .as_deref()
+                    .ok_or("The `password` grant needs a `password`")?;
+
+                send_json(self.token_request(
+                    &client,
+                    vec![
+                        ("grant_type", "password"),
+                        ("username", username),
+                        ("password", password),
+                    ],
+                ))
+                .await?
+            }
+            GrantType::DeviceCode => self.device_code(&client).await?,
+        };
+
+        response["access_token"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| format!("No access token was returned from `{}`", self.token_url))
+    }
+
+    // A request to the token endpoint with the client credentials, scope & extra parameters
+    fn token_request(&self, client: &Client, grant: Vec<(&str, &str)>) -> RequestBuilder {
+        let mut form: Vec<(&str, &str)> = grant;
+
+        if let Some(ref scope) = self.scope {
+            form.push(("scope", scope));
+        }
+
+        for (key, value) in self.params.iter() {
+            form.push((key, value));
+        }
+
+        let request = client.post(&self.token_url);
+
+        match self.basic_auth {
+            true => request
+                .basic_auth(&self.client_id, self.client_secret.as_ref())
+                .form(&form),
+            false => {
+                form.push(("client_id", &self.client_id));
+
+                if let Some(ref secret) = self.client_secret {
+                    form.push(("client_secret", secret));
+                }
+
+                request.form(&form)
+            }
+        }
+    }
+
+    // Shows the code to sign in with, then polls the token endpoint until it's been used
+    async fn device_code(&self, client: &Client) -> Result<Value, String> {
+        let device_url = self
+            .device_url
+            .as_deref()
+            .ok_or("The `device_code` grant needs a `device_url`")?;
+
+        if is_non_interactive() {
+            return Err("Can't sign in with a device code when running non-interactively".into());
+        }
+
+        let mut form = vec![("client_id", self.client_id.as_str())];
+
+        if let Some(ref scope) = self.scope {
+            form.push(("scope", scope));
+        }
+
+        let device = send_json(client.post(device_url).form(&form)).await?;
+
+        let device_code = device["device_code"]
+            .as_str()
+            .ok_or_else(|| format!("No device code was returned from `{}`", device_url))?;
+
+        {
+            let _lock = PROMPT_LOCK.lock().await;
+
+            match device["verification_uri_complete"].as_str() {
+                Some(uri) => eprintln!("To sign in, visit {}", uri),
+                None => eprintln!(
+                    "To sign in, visit {} and enter the code {}",
+                    device["verification_uri"].as_str().unwrap_or_default(),
+                    device["user_code"].as_str().unwrap_or_default()
+                ),
+            }
+        }
+
+        let expires = Duration::from_secs(device["expires_in"].as_u64().unwrap_or(300));
+        let mut interval = Duration::from_secs(device["interval"].as_u64().unwrap_or(5));
+
+        let start = Instant::now();
+
+        loop {
+            sleep(interval).await;
+
+            if start.elapsed() > expires {
+                return Err("Timed out waiting for the device code to be used".into());
+            }
+
+            //Pending sign ins are returned as errors, so the response is checked here rather than with `send_json`
+            let response = http_log::send(self.token_request(
+                client,
+                vec![
+                    ("grant_type", DEVICE_CODE_GRANT),
+                    ("device_code", device_code),
+                ],
+            ))
+            .await
+            .map_err(|err| connection_error(&err))?;
+
+            let status = response.status();
+
+            let body: Value = response.json().await.map_err(|err| {
+                format!(
+                    "Could not parse response from `{}`: {}",
+                    self.token_url, err
+                )
+            })?;
+
+            if status.is_success() {
+                return Ok(body);
+            }
+
+            match body["error"].as_str() {
+                Some("authorization_pending") => (),
+                Some("slow_down") => interval += Duration::from_secs(5),
+                Some(error) => {
+                    return Err(format!(
+                        "Device code sign in failed with `{}`: {}",
+                        error,
+                        body["error_description"].as_str().unwrap_or_default()
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "Request to `{}` returned status {}",
+                        self.token_url, status
+                    ))
+                }
+            }
+        }
+    }
+}
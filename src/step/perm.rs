@@ -0,0 +1,136 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+
+#[cfg(unix)]
+use std::{ffi::CStr, mem::zeroed, os::unix::fs::MetadataExt, ptr};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PermVariant {
+    PathOnly(PathBuf),
+    Options(PermOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PermOptions {
+    path: PathBuf,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    mode: Option<String>,
+    #[serde(default)]
+    max_mode: Option<String>,
+}
+
+impl PermVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let permops = match *self {
+            PermVariant::PathOnly(ref path) => PermOptions {
+                path: path.clone(),
+                owner: None,
+                group: None,
+                mode: None,
+                max_mode: None,
+            },
+            PermVariant::Options(ref opts) => opts.clone(),
+        };
+
+        tokio::task::spawn_blocking(move || check_perms(&permops))
+            .await
+            .map_err(|err| format!("{}", err))?
+    }
+}
+
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|err| format!("Could not parse mode `{}` as octal: {}", mode, err))
+}
+
+#[cfg(unix)]
+fn check_perms(ops: &PermOptions) -> Result<String, String> {
+    let metadata = std::fs::symlink_metadata(&ops.path)
+        .map_err(|err| format!("Could not read `{}`: {}", ops.path.display(), err))?;
+
+    let owner = user_name(metadata.uid()).unwrap_or_else(|| metadata.uid().to_string());
+    let group = group_name(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string());
+    let mode = metadata.mode() & 0o7777;
+
+    let output = format!("{}:{} {:04o}", owner, group, mode);
+
+    if let Some(ref expected) = ops.owner {
+        if expected != &owner && expected != &metadata.uid().to_string() {
+            return Err(format!("Owner is `{}` but expected `{}`", owner, expected));
+        }
+    }
+
+    if let Some(ref expected) = ops.group {
+        if expected != &group && expected != &metadata.gid().to_string() {
+            return Err(format!("Group is `{}` but expected `{}`", group, expected));
+        }
+    }
+
+    if let Some(ref expected) = ops.mode {
+        if parse_mode(expected)? != mode {
+            return Err(format!(
+                "Mode is `{:04o}` but expected `{}`",
+                mode, expected
+            ));
+        }
+    }
+
+    if let Some(ref max_mode) = ops.max_mode {
+        let extra = mode & !parse_mode(max_mode)?;
+        if extra != 0 {
+            return Err(format!(
+                "Mode `{:04o}` is more permissive than `{}` (extra bits `{:04o}`)",
+                mode, max_mode, extra
+            ));
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+
+    unsafe {
+        let mut passwd: libc::passwd = zeroed();
+        if libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) != 0
+            || result.is_null()
+        {
+            return None;
+        }
+
+        Some(CStr::from_ptr(passwd.pw_name).to_string_lossy().to_string())
+    }
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = ptr::null_mut();
+
+    unsafe {
+        let mut group: libc::group = zeroed();
+        if libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut result) != 0
+            || result.is_null()
+        {
+            return None;
+        }
+
+        Some(CStr::from_ptr(group.gr_name).to_string_lossy().to_string())
+    }
+}
+
+#[cfg(not(unix))]
+fn check_perms(_ops: &PermOptions) -> Result<String, String> {
+    Err("Not Implemented Yet".into())
+}
@@ -0,0 +1,161 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
+
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    //Only one step can ask the operator at a time
+    pub(crate) static ref PROMPT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// Makes prompt steps fail instead of waiting for input, i.e, when run from cron
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::SeqCst);
+}
+
+pub(crate) fn is_non_interactive() -> bool {
+    NON_INTERACTIVE.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum PromptVariant {
+    MessageOnly(String),
+    Options(PromptOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PromptOptions {
+    message: String,
+    //Don't echo what is typed, for passwords & MFA codes
+    #[serde(default)]
+    secret: bool,
+    //Used if the operator just presses enter
+    #[serde(default)]
+    default: Option<String>,
+}
+
+impl PromptVariant {
+    pub fn is_secret(&self) -> bool {
+        matches!(self, PromptVariant::Options(opts) if opts.secret)
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let promptopts = match *self {
+            PromptVariant::MessageOnly(ref message) => PromptOptions {
+                message: message.clone(),
+                secret: false,
+                default: None,
+            },
+            PromptVariant::Options(ref opts) => opts.clone(),
+        };
+
+        if is_non_interactive() {
+            return Err(format!(
+                "Can't ask `{}` when running non-interactively",
+                promptopts.message
+            ));
+        }
+
+        if !atty::is(atty::Stream::Stdin) {
+            return Err(format!(
+                "Can't ask `{}` as there is no terminal",
+                promptopts.message
+            ));
+        }
+
+        let _lock = PROMPT_LOCK.lock().await;
+
+        let answer = tokio::task::spawn_blocking(move || ask(&promptopts))
+            .await
+            .map_err(|err| format!("{}", err))??;
+
+        Ok(answer)
+    }
+}
+
+fn ask(opts: &PromptOptions) -> Result<String, String> {
+    let mut stderr = std::io::stderr();
+
+    match opts.default {
+        Some(ref default) if !opts.secret => write!(stderr, "{} [{}]: ", opts.message, default),
+        _ => write!(stderr, "{}: ", opts.message),
+    }
+    .and_then(|_| stderr.flush())
+    .map_err(|err| format!("{}", err))?;
+
+    let echo = match opts.secret {
+        true => Some(EchoGuard::disable()),
+        false => None,
+    };
+
+    let mut answer = String::new();
+    let read = std::io::stdin().lock().read_line(&mut answer);
+
+    //Restore echo before anything else is printed
+    drop(echo);
+
+    if opts.secret {
+        eprintln!();
+    }
+
+    if read.map_err(|err| format!("Could not read answer: {}", err))? == 0 {
+        return Err("No answer given".into());
+    }
+
+    let answer = answer.trim_end_matches(&['\r', '\n'][..]).to_string();
+
+    match (answer.is_empty(), &opts.default) {
+        (true, Some(default)) => Ok(default.clone()),
+        _ => Ok(answer),
+    }
+}
+
+// Turns off terminal echo until dropped
+struct EchoGuard {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl EchoGuard {
+    #[cfg(unix)]
+    fn disable() -> EchoGuard {
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return EchoGuard { original: None };
+            }
+
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+
+            EchoGuard {
+                original: Some(original),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn disable() -> EchoGuard {
+        EchoGuard {}
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(ref original) = self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+fn default_status() -> u16 {
+    200
+}
+
+fn default_timeout_ms() -> u64 {
+    10000
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ProxyVariant {
+    //`http://proxy:3128`, with credentials for `Proxy-Authorization` if needed
+    url: String,
+    //The `host:port` to tunnel to
+    target: String,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+impl ProxyVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let url = Url::from_str(&self.url)
+            .map_err(|err| format!("Failed to parse proxy url `{}`: {}", self.url, err))?;
+
+        if url.scheme() != "http" {
+            return Err(format!(
+                "Proxy url `{}` must be `http://`, the tunnel is opened in plain text",
+                self.url
+            ));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("No host could be found for proxy url: {}", url))?;
+        let address = format!("{}:{}", host, url.port().unwrap_or(80));
+
+        let duration = Duration::from_millis(self.timeout_ms);
+
+        let start = Instant::now();
+
+        let mut stream = timeout(duration, TcpStream::connect(&address))
+            .await
+            .map_err(|_| format!("Timed out connecting to proxy `{}`", address))?
+            .map_err(|err| format!("Could not connect to proxy `{}`: {}", address, err))?;
+
+        let connect_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let mut request = format!(
+            "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nUser-Agent: lorikeet\r\n",
+            self.target
+        );
+
+        if !url.username().is_empty() {
+            let user = percent_decode_str(url.username()).decode_utf8_lossy();
+            let pass = percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy();
+
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", user, pass))
+            ));
+        }
+
+        request.push_str("\r\n");
+
+        let status = timeout(duration, async {
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut reader = BufReader::new(&mut stream);
+
+            let mut status_line = String::new();
+            reader.read_line(&mut status_line).await?;
+
+            //Skip the headers, the tunnel starts after the blank line
+            let mut line = String::new();
+            while reader.read_line(&mut line).await? > 2 {
+                line.clear();
+            }
+
+            Ok::<_, std::io::Error>(status_line)
+        })
+        .await
+        .map_err(|_| format!("Timed out waiting for proxy `{}` to connect", address))?
+        .map_err(|err| format!("Error talking to proxy `{}`: {}", address, err))?;
+
+        let tunnel_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let code = status
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| {
+                format!(
+                    "Proxy `{}` sent an invalid response: `{}`",
+                    address,
+                    status.trim()
+                )
+            })?;
+
+        if code != self.status {
+            return Err(format!(
+                "Proxy returned status `{}` for CONNECT to `{}`, expected `{}`",
+                code, self.target, self.status
+            ));
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "proxy": address,
+            "target": self.target,
+            "status": code,
+            "connect_ms": connect_ms,
+            "tunnel_ms": tunnel_ms,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
@@ -0,0 +1,223 @@
+use super::http::connection_error;
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use reqwest::{Client, Url};
+
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RedfishVariant {
+    UrlOnly(String),
+    Options(RedfishOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RedfishOptions {
+    url: String,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    pass: Option<String>,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+}
+
+impl RedfishVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let redfishopts = match *self {
+            RedfishVariant::UrlOnly(ref url) => RedfishOptions {
+                url: url.clone(),
+                user: None,
+                pass: None,
+                verify_ssl: None,
+            },
+            RedfishVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let base = Url::from_str(&redfishopts.url)
+            .map_err(|err| format!("Failed to parse url `{}`: {}", redfishopts.url, err))?;
+
+        let mut client_builder = Client::builder().timeout(Duration::from_secs(30));
+
+        if let Some(verify_ssl) = redfishopts.verify_ssl {
+            client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
+        }
+
+        let client = client_builder.build().map_err(|err| format!("{}", err))?;
+
+        let redfish = Redfish {
+            client,
+            base,
+            opts: redfishopts,
+        };
+
+        let collection = redfish.get("/redfish/v1/Chassis").await?;
+
+        let mut chassis_list = Vec::new();
+        let mut worst = Health::Ok;
+
+        for member in members(&collection) {
+            let chassis = redfish.get(&member).await?;
+
+            let health = health_of(&chassis);
+            worst = worst.max(health);
+
+            let mut temperatures = Vec::new();
+            let mut fans = Vec::new();
+
+            if let Some(thermal) = link(&chassis, "Thermal") {
+                let thermal = redfish.get(&thermal).await?;
+
+                for sensor in present(&thermal, "Temperatures") {
+                    worst = worst.max(health_of(sensor));
+                    temperatures.push(json!({
+                        "name": sensor["Name"],
+                        "reading_celsius": sensor["ReadingCelsius"],
+                        "health": health_of(sensor).as_str(),
+                    }));
+                }
+
+                for fan in present(&thermal, "Fans") {
+                    worst = worst.max(health_of(fan));
+                    fans.push(json!({
+                        "name": fan.get("Name").or_else(|| fan.get("FanName")),
+                        "reading": fan["Reading"],
+                        "units": fan["ReadingUnits"],
+                        "health": health_of(fan).as_str(),
+                    }));
+                }
+            }
+
+            let mut power_supplies = Vec::new();
+            let mut power_consumed_watts = Value::Null;
+
+            if let Some(power) = link(&chassis, "Power") {
+                let power = redfish.get(&power).await?;
+
+                for supply in present(&power, "PowerSupplies") {
+                    worst = worst.max(health_of(supply));
+                    power_supplies.push(json!({
+                        "name": supply["Name"],
+                        "health": health_of(supply).as_str(),
+                    }));
+                }
+
+                power_consumed_watts = power["PowerControl"][0]["PowerConsumedWatts"].clone();
+            }
+
+            chassis_list.push(json!({
+                "id": chassis["Id"],
+                "name": chassis["Name"],
+                "health": health.as_str(),
+                "temperatures": temperatures,
+                "fans": fans,
+                "power_supplies": power_supplies,
+                "power_consumed_watts": power_consumed_watts,
+            }));
+        }
+
+        if chassis_list.is_empty() {
+            return Err("No chassis were found".into());
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "health": worst.as_str(),
+            "chassis": chassis_list,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+struct Redfish {
+    client: Client,
+    base: Url,
+    opts: RedfishOptions,
+}
+
+impl Redfish {
+    async fn get(&self, path: &str) -> Result<Value, String> {
+        let url = self
+            .base
+            .join(path)
+            .map_err(|err| format!("Invalid redfish path `{}`: {}", path, err))?;
+
+        let mut request = self.client.get(url.clone());
+
+        if let Some(ref user) = self.opts.user {
+            request = request.basic_auth(user, self.opts.pass.as_ref());
+        }
+
+        let response = http_log::send(request)
+            .await
+            .map_err(|err| connection_error(&err))?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Request to `{}` returned status {}",
+                url,
+                response.status()
+            ));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|err| format!("Could not parse response from `{}`: {}", url, err))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Health {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl Health {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Health::Ok => "OK",
+            Health::Warning => "Warning",
+            Health::Critical => "Critical",
+        }
+    }
+}
+
+// Resources without a health reported are treated as OK
+fn health_of(resource: &Value) -> Health {
+    match resource["Status"]["Health"].as_str() {
+        Some("Warning") => Health::Warning,
+        Some("Critical") => Health::Critical,
+        _ => Health::Ok,
+    }
+}
+
+fn members(collection: &Value) -> Vec<String> {
+    collection["Members"]
+        .as_array()
+        .map(|members| {
+            members
+                .iter()
+                .filter_map(|member| member["@odata.id"].as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn link(resource: &Value, name: &str) -> Option<String> {
+    resource[name]["@odata.id"].as_str().map(String::from)
+}
+
+// Sensors & supplies that are not fitted are reported as `Absent`, so are skipped
+fn present<'a>(resource: &'a Value, name: &str) -> impl Iterator<Item = &'a Value> {
+    resource[name]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|item| item["Status"]["State"].as_str() != Some("Absent"))
+}
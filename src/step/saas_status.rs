@@ -0,0 +1,427 @@
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::http::connection_error;
+use super::send_json;
+
+use reqwest::Client;
+
+use std::time::Duration;
+
+const GITHUB_STATUS: &str = "https://www.githubstatus.com";
+const AWS_HEALTH: &str = "https://health.aws.amazon.com/public/currentevents";
+
+//Checks everything, rather than a particular component or service
+const ALL: &str = "all";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum StatuspageVariant {
+    PageOnly(String),
+    Options(StatuspageOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StatuspageOptions {
+    //The host of the status page, i.e, `status.example.com`, or its url
+    page: String,
+    //Only these components count, otherwise the page's overall status is used
+    #[serde(default)]
+    components: Vec<String>,
+}
+
+// A component name, a list of them, or `all`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum GithubStatusVariant {
+    Component(String),
+    Components(Vec<String>),
+}
+
+// A service name like `ec2`, or `all`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AwsHealthVariant {
+    Service(String),
+    Options(AwsHealthOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AwsHealthOptions {
+    #[serde(default)]
+    service: Option<String>,
+    //A region code, i.e, `us-east-1`
+    #[serde(default)]
+    region: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Status {
+    Operational,
+    Maintenance,
+    Degraded,
+    Outage,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Operational => "operational",
+            Status::Maintenance => "maintenance",
+            Status::Degraded => "degraded",
+            Status::Outage => "outage",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Status::Outage => "has an outage",
+            _ => "is degraded",
+        }
+    }
+
+    fn from_component(status: &str) -> Status {
+        match status {
+            "operational" => Status::Operational,
+            "under_maintenance" => Status::Maintenance,
+            "major_outage" => Status::Outage,
+            _ => Status::Degraded,
+        }
+    }
+
+    fn from_indicator(indicator: &str) -> Status {
+        match indicator {
+            "none" => Status::Operational,
+            "maintenance" => Status::Maintenance,
+            "minor" => Status::Degraded,
+            _ => Status::Outage,
+        }
+    }
+}
+
+// The status of a provider, the same whichever API it came from
+struct Health {
+    provider: String,
+    status: Status,
+    description: String,
+    //The components or services that aren't operational
+    affected: Vec<(String, Status)>,
+    incidents: Vec<String>,
+}
+
+impl Health {
+    //Maintenance is planned, so only degraded services & outages fail
+    fn into_result(self) -> Result<String, String> {
+        if self.status > Status::Maintenance {
+            let affected: Vec<String> = self
+                .affected
+                .iter()
+                .map(|(name, status)| format!("{} ({})", name, status.name()))
+                .collect();
+
+            return Err(match affected.is_empty() {
+                true => format!(
+                    "`{}` {}: {}",
+                    self.provider,
+                    self.status.describe(),
+                    self.description
+                ),
+                false => format!(
+                    "`{}` {}: {}",
+                    self.provider,
+                    self.status.describe(),
+                    affected.join(", ")
+                ),
+            });
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "provider": self.provider,
+            "status": self.status.name(),
+            "description": self.description,
+            "affected": self
+                .affected
+                .iter()
+                .map(|(name, status)| json!({"name": name, "status": status.name()}))
+                .collect::<Vec<_>>(),
+            "incidents": self.incidents,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+fn client() -> Result<Client, String> {
+    Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|err| format!("{}", err))
+}
+
+impl StatuspageVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let (page, components) = match *self {
+            StatuspageVariant::PageOnly(ref page) => (page.clone(), vec![]),
+            StatuspageVariant::Options(ref opts) => (opts.page.clone(), opts.components.clone()),
+        };
+
+        let base = match page.starts_with("http://") || page.starts_with("https://") {
+            true => page.trim_end_matches('/').to_string(),
+            false => format!("https://{}", page.trim_end_matches('/')),
+        };
+
+        let summary = send_json(client()?.get(format!("{}/api/v2/summary.json", base))).await?;
+
+        statuspage_health(&summary, &components)?.into_result()
+    }
+}
+
+impl GithubStatusVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let components = match *self {
+            GithubStatusVariant::Component(ref component) if component == ALL => vec![],
+            GithubStatusVariant::Component(ref component) => vec![component.clone()],
+            GithubStatusVariant::Components(ref components) => components.clone(),
+        };
+
+        StatuspageVariant::Options(StatuspageOptions {
+            page: GITHUB_STATUS.into(),
+            components,
+        })
+        .run()
+        .await
+    }
+}
+
+impl AwsHealthVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let healthopts = match *self {
+            AwsHealthVariant::Service(ref service) => AwsHealthOptions {
+                service: Some(service.clone()).filter(|service| service != ALL),
+                region: None,
+            },
+            AwsHealthVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let response = http_log::send(client()?.get(AWS_HEALTH))
+            .await
+            .map_err(|err| connection_error(&err))?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Request to `{}` returned status {}",
+                AWS_HEALTH,
+                response.status()
+            ));
+        }
+
+        let bytes = response.bytes().await.map_err(|err| format!("{}", err))?;
+
+        let events: Value = serde_json::from_str(&decode(&bytes))
+            .map_err(|err| format!("Could not parse response from `{}`: {}", AWS_HEALTH, err))?;
+
+        aws_health(&events, &healthopts).into_result()
+    }
+}
+
+// The AWS health feed is sometimes UTF-16 with a byte order mark
+fn decode(bytes: &[u8]) -> String {
+    match bytes {
+        [0xFF, 0xFE, rest @ ..] => String::from_utf16_lossy(
+            &rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        _ => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
+fn statuspage_health(summary: &Value, components: &[String]) -> Result<Health, String> {
+    let provider = summary["page"]["name"]
+        .as_str()
+        .unwrap_or("statuspage")
+        .to_string();
+
+    let all: Vec<(String, Status)> = summary["components"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        //Groups summarise the components within them
+        .filter(|component| !component["group"].as_bool().unwrap_or(false))
+        .map(|component| {
+            (
+                component["name"].as_str().unwrap_or_default().to_string(),
+                Status::from_component(component["status"].as_str().unwrap_or_default()),
+            )
+        })
+        .collect();
+
+    for name in components.iter() {
+        if !all.iter().any(|(component, _)| component == name) {
+            return Err(format!("`{}` has no component `{}`", provider, name));
+        }
+    }
+
+    let selected: Vec<(String, Status)> = all
+        .into_iter()
+        .filter(|(name, _)| components.is_empty() || components.contains(name))
+        .collect();
+
+    let status = match components.is_empty() {
+        true => Status::from_indicator(summary["status"]["indicator"].as_str().unwrap_or("none")),
+        false => {
+            selected
+                .iter()
+                .map(|(_, status)| *status)
+                .fold(Status::Operational, |worst, status| match status > worst {
+                    true => status,
+                    false => worst,
+                })
+        }
+    };
+
+    let description = match components.is_empty() {
+        true => summary["status"]["description"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        false => format!("{} {}", components.join(", "), status.name()),
+    };
+
+    Ok(Health {
+        provider,
+        status,
+        description,
+        affected: selected
+            .into_iter()
+            .filter(|(_, status)| *status != Status::Operational)
+            .collect(),
+        incidents: summary["incidents"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|incident| incident["name"].as_str().map(String::from))
+            .collect(),
+    })
+}
+
+// Each current event is for a service in a region, i.e, `ec2-us-east-1`.  Resolved events can linger in the feed
+fn aws_health(events: &Value, opts: &AwsHealthOptions) -> Health {
+    let current: Vec<&Value> = events
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|event| {
+            !event["summary"]
+                .as_str()
+                .unwrap_or_default()
+                .starts_with("[RESOLVED]")
+        })
+        .filter(|event| {
+            let service = event["service"].as_str().unwrap_or_default();
+
+            let service_matches = opts
+                .service
+                .as_ref()
+                .map(|name| service == name || service.starts_with(&format!("{}-", name)))
+                .unwrap_or(true);
+
+            let region_matches = opts
+                .region
+                .as_ref()
+                .map(|region| service.ends_with(&format!("-{}", region)))
+                .unwrap_or(true);
+
+            service_matches && region_matches
+        })
+        .collect();
+
+    let affected: Vec<(String, Status)> = current
+        .iter()
+        .map(|event| {
+            (
+                event["service"].as_str().unwrap_or_default().to_string(),
+                Status::Degraded,
+            )
+        })
+        .collect();
+
+    Health {
+        provider: "AWS".into(),
+        status: match current.is_empty() {
+            true => Status::Operational,
+            false => Status::Degraded,
+        },
+        description: match current.is_empty() {
+            true => "No current events".into(),
+            false => format!("{} current events", current.len()),
+        },
+        affected,
+        incidents: current
+            .iter()
+            .filter_map(|event| event["summary"].as_str().map(String::from))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalises_statuspage_summary() {
+        let summary = json!({
+            "page": {"name": "GitHub"},
+            "status": {"indicator": "minor", "description": "Partial System Outage"},
+            "components": [
+                {"name": "Git Operations", "status": "operational"},
+                {"name": "Actions", "status": "degraded_performance"},
+                {"name": "Webhooks", "status": "major_outage"},
+            ],
+            "incidents": [{"name": "Delays in Actions"}],
+        });
+
+        let health = statuspage_health(&summary, &[]).unwrap();
+        assert_eq!(health.status, Status::Degraded);
+        assert_eq!(health.incidents, vec!["Delays in Actions".to_string()]);
+
+        let health = statuspage_health(&summary, &["Git Operations".into()]).unwrap();
+        assert!(health.into_result().is_ok());
+
+        let health = statuspage_health(&summary, &["Actions".into(), "Webhooks".into()]).unwrap();
+        assert_eq!(
+            health.into_result(),
+            Err("`GitHub` has an outage: Actions (degraded), Webhooks (outage)".into())
+        );
+
+        assert!(statuspage_health(&summary, &["Pages".into()]).is_err());
+    }
+
+    #[test]
+    fn filters_aws_events_by_service_and_region() {
+        let events = json!([
+            {"service": "ec2-us-east-1", "summary": "Increased API error rates"},
+            {"service": "s3-eu-west-1", "summary": "[RESOLVED] Elevated latency"},
+        ]);
+
+        let opts = |service: Option<&str>, region: Option<&str>| AwsHealthOptions {
+            service: service.map(String::from),
+            region: region.map(String::from),
+        };
+
+        assert_eq!(
+            aws_health(&events, &opts(None, None)).status,
+            Status::Degraded
+        );
+        assert_eq!(
+            aws_health(&events, &opts(Some("ec2"), Some("us-west-2"))).status,
+            Status::Operational
+        );
+        assert_eq!(
+            aws_health(&events, &opts(Some("s3"), None)).status,
+            Status::Operational
+        );
+    }
+}
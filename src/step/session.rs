@@ -0,0 +1,79 @@
+use super::{FilterType, PerPlan, RunType, STEP_OUTPUT};
+
+use lazy_static::lazy_static;
+use log::debug;
+use tokio::sync::Mutex;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+struct Session {
+    run: RunType,
+    filters: Vec<FilterType>,
+    //Incremented each time the session is refreshed
+    generation: Mutex<u64>,
+}
+
+lazy_static! {
+    static ref SESSIONS: PerPlan<Arc<Session>> = PerPlan::default();
+}
+
+// Remembers how a step was run, so it can be run again if it is used as a session
+pub(crate) fn register_session(name: &str, run: RunType, filters: Vec<FilterType>) {
+    SESSIONS.insert(
+        name.to_string(),
+        Arc::new(Session {
+            run,
+            filters,
+            generation: Mutex::new(0),
+        }),
+    );
+}
+
+// The current generation of a session, or `None` if the session step hasn't run yet
+pub(crate) async fn session_generation(name: &str) -> Option<u64> {
+    let session = SESSIONS.get(name)?;
+    let generation = *session.generation.lock().await;
+    Some(generation)
+}
+
+// Runs the session step again, updating its output.  If another step has already refreshed the
+// session since `generation`, this does nothing.  Boxed as a session step may itself be http
+pub(crate) fn refresh_session(
+    name: &str,
+    generation: u64,
+) -> Pin<Box<dyn Future<Output = Result<(), String>> + Send + '_>> {
+    Box::pin(async move {
+        let session = SESSIONS
+            .get(name)
+            .ok_or_else(|| format!("Session step `{}` has not run", name))?;
+
+        let mut current = session.generation.lock().await;
+
+        if *current != generation {
+            debug!("Session `{}` has already been refreshed", name);
+            return Ok(());
+        }
+
+        debug!("Refreshing session `{}`", name);
+
+        let mut output = session
+            .run
+            .run()
+            .await
+            .map_err(|(_, err)| format!("Could not refresh session `{}`: {}", name, err))?;
+
+        for filter in session.filters.iter() {
+            output = filter
+                .filter(&output)
+                .map_err(|(_, err)| format!("Could not refresh session `{}`: {}", name, err))?;
+        }
+
+        STEP_OUTPUT.insert(name.to_string(), output);
+
+        *current += 1;
+
+        Ok(())
+    })
+}
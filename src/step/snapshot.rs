@@ -0,0 +1,273 @@
+use super::diff;
+use super::http::connection_error;
+use crate::http_log;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use reqwest::{redirect::Policy, Client};
+use similar::TextDiff;
+
+use std::path::Path;
+use std::time::Duration;
+
+//How many lines of diff to show when a text snapshot doesn't match
+const MAX_DIFF_LINES: usize = 50;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SnapshotVariant {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    file: Option<String>,
+    baseline: String,
+    #[serde(default)]
+    format: Option<SnapshotFormat>,
+    //The fraction of content that can differ, from 0 to 1
+    #[serde(default)]
+    tolerance: f64,
+    #[serde(default)]
+    update: bool,
+    #[serde(default)]
+    verify_ssl: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    Text,
+    Json,
+    Image,
+}
+
+impl SnapshotFormat {
+    fn from_path(path: &str) -> SnapshotFormat {
+        match Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+        {
+            Some("json") => SnapshotFormat::Json,
+            Some("png") => SnapshotFormat::Image,
+            _ => SnapshotFormat::Text,
+        }
+    }
+}
+
+impl SnapshotVariant {
+    // Snapshots of files are local, urls aren't
+    pub(crate) fn needs_network(&self) -> bool {
+        self.url.is_some()
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let current = self.current().await?;
+
+        let format = self
+            .format
+            .unwrap_or_else(|| SnapshotFormat::from_path(&self.baseline));
+
+        let baseline = Path::new(&self.baseline);
+
+        if self.update || !baseline.exists() {
+            if let Some(parent) = baseline.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|err| format!("Could not create baseline directory: {}", err))?;
+            }
+            tokio::fs::write(baseline, &current)
+                .await
+                .map_err(|err| format!("Could not write baseline `{}`: {}", self.baseline, err))?;
+
+            return Ok(format!("Baseline `{}` updated", self.baseline));
+        }
+
+        let expected = tokio::fs::read(baseline)
+            .await
+            .map_err(|err| format!("Could not read baseline `{}`: {}", self.baseline, err))?;
+
+        let (difference, details) = match format {
+            SnapshotFormat::Text => text_difference(&expected, &current),
+            SnapshotFormat::Json => json_difference(&expected, &current)?,
+            SnapshotFormat::Image => image_difference(&expected, &current)?,
+        };
+
+        if difference > self.tolerance {
+            return Err(format!(
+                "Snapshot differs from baseline `{}` by {:.2}% (tolerance {:.2}%)\n{}",
+                self.baseline,
+                difference * 100.0,
+                self.tolerance * 100.0,
+                details
+            ));
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "baseline": self.baseline,
+            "difference": difference,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+
+    async fn current(&self) -> Result<Vec<u8>, String> {
+        match (&self.url, &self.file) {
+            (Some(url), None) => {
+                let mut client_builder = Client::builder()
+                    .redirect(Policy::limited(10))
+                    .timeout(Duration::from_secs(30));
+
+                if let Some(verify_ssl) = self.verify_ssl {
+                    client_builder = client_builder.danger_accept_invalid_certs(!verify_ssl);
+                }
+
+                let client = client_builder.build().map_err(|err| format!("{}", err))?;
+
+                let response = http_log::send(client.get(url))
+                    .await
+                    .map_err(|err| connection_error(&err))?;
+
+                if !response.status().is_success() {
+                    return Err(format!(
+                        "Unexpected status code `{}` from `{}`",
+                        response.status(),
+                        url
+                    ));
+                }
+
+                response
+                    .bytes()
+                    .await
+                    .map(|bytes| bytes.to_vec())
+                    .map_err(|err| format!("Could not read response body: {}", err))
+            }
+            (None, Some(file)) => tokio::fs::read(file)
+                .await
+                .map_err(|err| format!("Could not read `{}`: {}", file, err)),
+            _ => Err("Snapshot requires exactly one of `url` or `file`".into()),
+        }
+    }
+}
+
+fn text_difference(expected: &[u8], current: &[u8]) -> (f64, String) {
+    let expected = String::from_utf8_lossy(expected);
+    let current = String::from_utf8_lossy(current);
+
+    let diff = TextDiff::from_lines(&*expected, &*current);
+
+    let difference = 1.0 - diff.ratio() as f64;
+
+    let unified = diff
+        .unified_diff()
+        .context_radius(2)
+        .header("baseline", "current")
+        .to_string();
+
+    let mut lines: Vec<&str> = unified.lines().take(MAX_DIFF_LINES + 1).collect();
+
+    if lines.len() > MAX_DIFF_LINES {
+        lines.truncate(MAX_DIFF_LINES);
+        lines.push("...");
+    }
+
+    (difference, lines.join("\n"))
+}
+
+// Compares the leaf values of two json documents, returning the fraction of differing paths
+fn json_difference(expected: &[u8], current: &[u8]) -> Result<(f64, String), String> {
+    let expected: Value = serde_json::from_slice(expected)
+        .map_err(|err| format!("Could not parse baseline as json: {}", err))?;
+    let current: Value = serde_json::from_slice(current)
+        .map_err(|err| format!("Could not parse output as json: {}", err))?;
+
+    let diff = diff::json_diff(&expected, &current, &[])?;
+
+    if diff.compared == 0 {
+        return Ok((0.0, String::new()));
+    }
+
+    Ok((
+        diff.changed as f64 / diff.compared as f64,
+        diff.differences.join("\n"),
+    ))
+}
+
+struct Image {
+    width: u32,
+    height: u32,
+    channels: usize,
+    pixels: Vec<u8>,
+}
+
+// Compares two PNG images, returning the fraction of pixels that differ
+fn image_difference(expected: &[u8], current: &[u8]) -> Result<(f64, String), String> {
+    let expected =
+        decode_png(expected).map_err(|err| format!("Could not decode baseline: {}", err))?;
+    let current = decode_png(current).map_err(|err| format!("Could not decode output: {}", err))?;
+
+    if (expected.width, expected.height) != (current.width, current.height) {
+        return Ok((
+            1.0,
+            format!(
+                "Image dimensions differ: {}x{} -> {}x{}",
+                expected.width, expected.height, current.width, current.height
+            ),
+        ));
+    }
+
+    if expected.channels != current.channels {
+        return Ok((1.0, "Image colour types differ".into()));
+    }
+
+    let pixels = expected.pixels.len() / expected.channels;
+
+    if pixels == 0 {
+        return Ok((0.0, String::new()));
+    }
+
+    let changed = expected
+        .pixels
+        .chunks(expected.channels)
+        .zip(current.pixels.chunks(current.channels))
+        .filter(|(left, right)| left != right)
+        .count();
+
+    Ok((
+        changed as f64 / pixels as f64,
+        format!("{} of {} pixels differ", changed, pixels),
+    ))
+}
+
+// Decodes a PNG to 8 bit samples
+fn decode_png(bytes: &[u8]) -> Result<Image, png::DecodingError> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        channels: info.color_type.samples(),
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_difference_counts_changed_leaves() {
+        let expected = br#"{"name": "lorikeet", "tags": ["a", "b"], "version": 1}"#;
+        let current = br#"{"name": "lorikeet", "tags": ["a", "c"], "version": 1}"#;
+
+        let (difference, details) = json_difference(expected, current).unwrap();
+
+        assert_eq!(difference, 0.25);
+        assert_eq!(details, r#"$.tags[1]: "b" -> "c""#);
+    }
+}
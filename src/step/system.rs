@@ -1,10 +1,12 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use lazy_static::lazy_static;
 use sys_info::{disk_info, loadavg, mem_info};
 use tokio::sync::Mutex;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum SystemVariant {
     MemTotal,
@@ -15,6 +17,8 @@ pub enum SystemVariant {
     LoadAvg15m,
     DiskTotal,
     DiskFree,
+    //Every value at once, as json
+    All,
 }
 
 lazy_static! {
@@ -51,6 +55,38 @@ impl SystemVariant {
             SystemVariant::DiskFree => disk_info()
                 .map(|disk| disk.free.to_string())
                 .map_err(|_| "Could not get disk".to_string()),
+            SystemVariant::All => {
+                let load = loadavg().map_err(|_| "Could not get load".to_string())?;
+                let mem = mem_info().map_err(|_| "Could not get memory".to_string())?;
+                let disk = disk_info().map_err(|_| "Could not get disk".to_string())?;
+
+                serde_json::to_string_pretty(&json!({
+                    "load_avg_1m": load.one,
+                    "load_avg_5m": load.five,
+                    "load_avg_15m": load.fifteen,
+                    "mem_total": mem.total,
+                    "mem_free": mem.free,
+                    "mem_available": mem.avail,
+                    "disk_total": disk.total,
+                    "disk_free": disk.free,
+                }))
+                .map_err(|err| format!("{}", err))
+            }
         }
     }
 }
+
+// Returns the 1 minute load average & the percentage of memory in use
+pub(crate) async fn host_pressure() -> Result<(f64, f64), String> {
+    let _guard = SYS_MUTEX.lock().await;
+
+    let load = loadavg().map_err(|_| "Could not get load".to_string())?;
+    let mem = mem_info().map_err(|_| "Could not get memory".to_string())?;
+
+    let mem_used = match mem.total {
+        0 => 0.0,
+        total => (total.saturating_sub(mem.avail)) as f64 / total as f64 * 100.0,
+    };
+
+    Ok((load.one, mem_used))
+}
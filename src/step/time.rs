@@ -0,0 +1,107 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::timezone::{format_time, Timezone};
+use chrono::{Local, Utc};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum TimeVariant {
+    Value(TimeValue),
+    Options(TimeOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeValue {
+    Timezone,
+    UtcOffset,
+    Local,
+    Utc,
+    //The current time in the `--timezone`
+    Now,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TimeOptions {
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    utc_offset: Option<String>,
+}
+
+impl TimeVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let now = Utc::now();
+
+        match *self {
+            TimeVariant::Value(TimeValue::Timezone) => system_timezone(),
+            TimeVariant::Value(TimeValue::UtcOffset) => Ok(Local::now().offset().to_string()),
+            TimeVariant::Value(TimeValue::Local) => Ok(Timezone::Local.format(&now)),
+            TimeVariant::Value(TimeValue::Utc) => Ok(Timezone::Utc.format(&now)),
+            TimeVariant::Value(TimeValue::Now) => Ok(format_time(&now)),
+            TimeVariant::Options(ref opts) => {
+                let offset = Local::now().offset().to_string();
+
+                if let Some(ref expected) = opts.utc_offset {
+                    if expected != &offset {
+                        return Err(format!(
+                            "UTC offset is `{}` but expected `{}`",
+                            offset, expected
+                        ));
+                    }
+                }
+
+                let timezone = match opts.timezone {
+                    Some(ref expected) => {
+                        let timezone = system_timezone()?;
+                        if expected != &timezone {
+                            return Err(format!(
+                                "Timezone is `{}` but expected `{}`",
+                                timezone, expected
+                            ));
+                        }
+                        Some(timezone)
+                    }
+                    None => system_timezone().ok(),
+                };
+
+                Ok(json!({
+                    "timezone": timezone,
+                    "utc_offset": offset,
+                    "local": Timezone::Local.format(&now),
+                    "utc": Timezone::Utc.format(&now),
+                    "now": format_time(&now),
+                })
+                .to_string())
+            }
+        }
+    }
+}
+
+// Determines the configured timezone from `TZ`, falling back to the system configuration
+fn system_timezone() -> Result<String, String> {
+    if let Ok(tz) = std::env::var("TZ") {
+        let tz = tz.trim_start_matches(':');
+        if !tz.is_empty() {
+            return Ok(tz.to_string());
+        }
+    }
+
+    if let Ok(contents) = std::fs::read_to_string("/etc/timezone") {
+        let tz = contents.trim();
+        if !tz.is_empty() {
+            return Ok(tz.to_string());
+        }
+    }
+
+    if let Ok(link) = std::fs::read_link("/etc/localtime") {
+        let link = link.to_string_lossy();
+        if let Some(idx) = link.find("zoneinfo/") {
+            return Ok(link[idx + "zoneinfo/".len()..].to_string());
+        }
+    }
+
+    Err("Could not determine the system timezone".into())
+}
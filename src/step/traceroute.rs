@@ -0,0 +1,525 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_os = "linux")]
+use serde_json::{json, Value};
+#[cfg(target_os = "linux")]
+use std::{
+    io,
+    mem::{size_of, zeroed},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+fn default_max_hops() -> u8 {
+    30
+}
+
+fn default_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_port() -> u16 {
+    33434
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum TracerouteVariant {
+    HostOnly(String),
+    Options(TracerouteOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TracerouteOptions {
+    host: String,
+    #[serde(default = "default_max_hops")]
+    max_hops: u8,
+    //How long to wait for each hop to answer
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    //Probes go to this port plus the hop number, like `traceroute`
+    #[serde(default = "default_port")]
+    port: u16,
+}
+
+impl TracerouteVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let traceopts = match *self {
+            TracerouteVariant::HostOnly(ref host) => TracerouteOptions {
+                host: host.clone(),
+                max_hops: default_max_hops(),
+                timeout_ms: default_timeout_ms(),
+                port: default_port(),
+            },
+            TracerouteVariant::Options(ref opts) => opts.clone(),
+        };
+
+        tokio::task::spawn_blocking(move || trace(&traceopts))
+            .await
+            .map_err(|err| format!("{}", err))?
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn trace(_opts: &TracerouteOptions) -> Result<String, String> {
+    Err("The traceroute step is only supported on linux".into())
+}
+
+// Sends UDP probes with an increasing TTL, reading the ICMP replies from the socket's error queue.
+// This doesn't need raw sockets, so works without root
+#[cfg(target_os = "linux")]
+fn trace(opts: &TracerouteOptions) -> Result<String, String> {
+    let target = (opts.host.as_str(), 0)
+        .to_socket_addrs()
+        .map_err(|err| format!("DNS lookup failed for `{}`: {}", opts.host, err))?
+        .next()
+        .ok_or_else(|| format!("DNS lookup failed for `{}`: no addresses", opts.host))?;
+
+    let family = Family::of(&target.ip());
+
+    let bind: SocketAddr = match family {
+        Family::V4 => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        Family::V6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(bind).map_err(|err| format!("Could not open socket: {}", err))?;
+    let fd = socket.as_raw_fd();
+
+    set_option(fd, family.level(), family.recverr(), 1)?;
+    //Set the don't fragment bit, but keep sending at the interface MTU so each hop can report a smaller one
+    set_option(
+        fd,
+        family.level(),
+        family.mtu_discover(),
+        libc::IP_PMTUDISC_PROBE,
+    )?;
+
+    let timeout = Duration::from_millis(opts.timeout_ms);
+
+    let mut hops = Vec::new();
+    let mut reached = false;
+    let mut mtu = 0;
+
+    for ttl in 1..=opts.max_hops {
+        let dest = SocketAddr::new(target.ip(), opts.port.wrapping_add(ttl as u16));
+
+        socket
+            .connect(dest)
+            .map_err(|err| format!("Could not connect to `{}`: {}", dest, err))?;
+
+        set_option(fd, family.level(), family.ttl(), ttl as libc::c_int)?;
+
+        mtu = get_option(fd, family.level(), family.mtu())?;
+
+        let mut reply = None;
+
+        //Each `too big` reply shrinks the probe, so only retry a few times
+        for _ in 0..8 {
+            let payload = vec![0u8; (mtu as usize).saturating_sub(family.header_len())];
+
+            //A late reply to an earlier probe would otherwise fail this send
+            drain_errors(fd)?;
+
+            let start = Instant::now();
+
+            if let Err(err) = socket.send(&payload) {
+                match err.raw_os_error() {
+                    Some(libc::EMSGSIZE) => {
+                        mtu = get_option(fd, family.level(), family.mtu())?;
+                        continue;
+                    }
+                    //The hop is reported as not answering, rather than failing the whole trace
+                    Some(libc::ECONNREFUSED)
+                    | Some(libc::EHOSTUNREACH)
+                    | Some(libc::ENETUNREACH)
+                    | Some(libc::EHOSTDOWN)
+                    | Some(libc::ENOBUFS)
+                    | Some(libc::EAGAIN) => break,
+                    _ => return Err(format!("Could not send probe to `{}`: {}", dest, err)),
+                }
+            }
+
+            match wait_for_reply(fd, family, dest.port(), start, timeout)? {
+                Some(Reply {
+                    kind: ReplyKind::TooBig(next),
+                    ..
+                }) if next > 0 && next < mtu => mtu = next,
+                other => {
+                    reply = other;
+                    break;
+                }
+            }
+        }
+
+        let done = match reply {
+            Some(ref reply) => {
+                hops.push(json!({
+                    "hop": ttl,
+                    "address": reply.address.map(|address| address.to_string()),
+                    "latency_ms": reply.latency.as_secs_f64() * 1000.0,
+                }));
+
+                reached = reply.kind == ReplyKind::Reached || reply.address == Some(target.ip());
+
+                reply.kind != ReplyKind::TimeExceeded
+            }
+            None => {
+                hops.push(json!({
+                    "hop": ttl,
+                    "address": Value::Null,
+                    "latency_ms": Value::Null,
+                }));
+                false
+            }
+        };
+
+        if done {
+            break;
+        }
+    }
+
+    serde_json::to_string_pretty(&json!({
+        "host": opts.host,
+        "address": target.ip().to_string(),
+        "reached": reached,
+        "mtu": mtu,
+        "hops": hops,
+    }))
+    .map_err(|err| format!("{}", err))
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Family {
+    V4,
+    V6,
+}
+
+#[cfg(target_os = "linux")]
+impl Family {
+    fn of(address: &IpAddr) -> Family {
+        match address {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    fn level(self) -> libc::c_int {
+        match self {
+            Family::V4 => libc::SOL_IP,
+            Family::V6 => libc::SOL_IPV6,
+        }
+    }
+
+    fn recverr(self) -> libc::c_int {
+        match self {
+            Family::V4 => libc::IP_RECVERR,
+            Family::V6 => libc::IPV6_RECVERR,
+        }
+    }
+
+    fn ttl(self) -> libc::c_int {
+        match self {
+            Family::V4 => libc::IP_TTL,
+            Family::V6 => libc::IPV6_UNICAST_HOPS,
+        }
+    }
+
+    fn mtu_discover(self) -> libc::c_int {
+        match self {
+            Family::V4 => libc::IP_MTU_DISCOVER,
+            Family::V6 => libc::IPV6_MTU_DISCOVER,
+        }
+    }
+
+    fn mtu(self) -> libc::c_int {
+        match self {
+            Family::V4 => libc::IP_MTU,
+            Family::V6 => libc::IPV6_MTU,
+        }
+    }
+
+    //The IP & UDP headers
+    fn header_len(self) -> usize {
+        match self {
+            Family::V4 => 28,
+            Family::V6 => 48,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReplyKind {
+    //A router along the way
+    TimeExceeded,
+    //The target itself, as nothing is listening on the port
+    Reached,
+    TooBig(libc::c_int),
+    //Some other unreachable, i.e, a firewall rejecting the probe
+    Unreachable,
+}
+
+#[cfg(target_os = "linux")]
+struct Reply {
+    address: Option<IpAddr>,
+    latency: Duration,
+    kind: ReplyKind,
+}
+
+#[cfg(target_os = "linux")]
+fn set_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<(), String> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(format!(
+            "Could not set socket option: {}",
+            io::Error::last_os_error()
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_option(fd: RawFd, level: libc::c_int, name: libc::c_int) -> Result<libc::c_int, String> {
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match result {
+        0 => Ok(value),
+        _ => Err(format!(
+            "Could not get socket option: {}",
+            io::Error::last_os_error()
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    unsafe {
+        match (*addr).sa_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = &*(addr as *const libc::sockaddr_in);
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(addr as *const libc::sockaddr_in6);
+                Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr_port(addr: &libc::sockaddr_storage) -> u16 {
+    unsafe {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                u16::from_be((*(addr as *const _ as *const libc::sockaddr_in)).sin_port)
+            }
+            libc::AF_INET6 => {
+                u16::from_be((*(addr as *const _ as *const libc::sockaddr_in6)).sin6_port)
+            }
+            _ => 0,
+        }
+    }
+}
+
+// Throws away any replies still queued on the socket, which also clears its pending error
+#[cfg(target_os = "linux")]
+fn drain_errors(fd: RawFd) -> Result<(), String> {
+    let mut buf = [0u8; 512];
+    let mut control = [0u8; 512];
+
+    loop {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let mut msg: libc::msghdr = unsafe { zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        if unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+            let err = io::Error::last_os_error();
+
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(()),
+                io::ErrorKind::Interrupted => continue,
+                _ => Err(format!("Could not read reply: {}", err)),
+            };
+        }
+    }
+}
+
+// Waits for the ICMP reply to the probe sent to `port`, ignoring late replies to earlier probes
+#[cfg(target_os = "linux")]
+fn wait_for_reply(
+    fd: RawFd,
+    family: Family,
+    port: u16,
+    start: Instant,
+    timeout: Duration,
+) -> Result<Option<Reply>, String> {
+    loop {
+        let remaining = match timeout.checked_sub(start.elapsed()) {
+            Some(remaining) => remaining,
+            None => return Ok(None),
+        };
+
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLERR,
+            revents: 0,
+        };
+
+        let ready = unsafe { libc::poll(&mut poll, 1, remaining.as_millis() as libc::c_int) };
+
+        if ready < 0 {
+            return Err(format!(
+                "Could not poll socket: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        let latency = start.elapsed();
+
+        let mut buf = [0u8; 512];
+        let mut control = [0u8; 512];
+        let mut name: libc::sockaddr_storage = unsafe { zeroed() };
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let mut msg: libc::msghdr = unsafe { zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        if unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+            let err = io::Error::last_os_error();
+
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                _ => return Err(format!("Could not read reply: {}", err)),
+            }
+        }
+
+        if sockaddr_port(&name) != port {
+            continue;
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+
+            if header.cmsg_level == family.level() && header.cmsg_type == family.recverr() {
+                let err = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let extended = unsafe { std::ptr::read_unaligned(err) };
+
+                let address = sockaddr_ip(unsafe { libc::SO_EE_OFFENDER(err) });
+
+                let kind = match (extended.ee_origin, extended.ee_type, extended.ee_code) {
+                    _ if extended.ee_errno == libc::EMSGSIZE as u32 => {
+                        ReplyKind::TooBig(extended.ee_info as libc::c_int)
+                    }
+                    (libc::SO_EE_ORIGIN_ICMP, 11, _) | (libc::SO_EE_ORIGIN_ICMP6, 3, _) => {
+                        ReplyKind::TimeExceeded
+                    }
+                    (libc::SO_EE_ORIGIN_ICMP, 3, 3) | (libc::SO_EE_ORIGIN_ICMP6, 1, 4) => {
+                        ReplyKind::Reached
+                    }
+                    _ => ReplyKind::Unreachable,
+                };
+
+                return Ok(Some(Reply {
+                    address,
+                    latency,
+                    kind,
+                }));
+            }
+
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traces_loopback() {
+        let output = trace(&TracerouteOptions {
+            host: "127.0.0.1".into(),
+            max_hops: 3,
+            timeout_ms: 1000,
+            port: 47000,
+        })
+        .unwrap();
+
+        let output: Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(output["reached"], true);
+        assert_eq!(output["hops"].as_array().unwrap().len(), 1);
+        assert_eq!(output["hops"][0]["address"], "127.0.0.1");
+    }
+
+    #[test]
+    fn drains_late_replies() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let fd = socket.as_raw_fd();
+
+        set_option(fd, libc::SOL_IP, libc::IP_RECVERR, 1).unwrap();
+
+        //Nothing is listening, so the reply is left queued as a pending error
+        socket.connect((Ipv4Addr::LOCALHOST, 47001)).unwrap();
+        socket.send(&[0]).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        drain_errors(fd).unwrap();
+
+        assert!(socket.send(&[0]).is_ok());
+    }
+}
@@ -0,0 +1,391 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use rand::Rng;
+use reqwest::Url;
+use ssh2::Session;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn default_size() -> usize {
+    1024
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum TransferVariant {
+    UrlOnly(String),
+    Options(TransferOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TransferOptions {
+    //`sftp://host/dir` or `ftp://host/dir`
+    url: String,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    pass: Option<String>,
+    //Private key for sftp, with `pass` used as the passphrase
+    #[serde(default)]
+    private_key: Option<PathBuf>,
+    #[serde(default = "default_size")]
+    size: usize,
+}
+
+struct RoundTrip {
+    upload: Duration,
+    download: Duration,
+    downloaded: Vec<u8>,
+}
+
+impl TransferVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let transferopts = match *self {
+            TransferVariant::UrlOnly(ref url) => TransferOptions {
+                url: url.clone(),
+                user: None,
+                pass: None,
+                private_key: None,
+                size: default_size(),
+            },
+            TransferVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let url = Url::from_str(&transferopts.url)
+            .map_err(|err| format!("Failed to parse url `{}`: {}", transferopts.url, err))?;
+
+        let mut contents = vec![0u8; transferopts.size];
+        rand::thread_rng().fill(&mut contents[..]);
+
+        let name = format!(".lorikeet-probe-{:016x}", rand::random::<u64>());
+
+        let path = format!("{}/{}", url.path().trim_end_matches('/'), name);
+
+        let round_trip = match url.scheme() {
+            "sftp" => {
+                let (url, opts, path, contents) = (
+                    url.clone(),
+                    transferopts.clone(),
+                    path.clone(),
+                    contents.clone(),
+                );
+
+                tokio::task::spawn_blocking(move || sftp_round_trip(&url, &opts, &path, &contents))
+                    .await
+                    .map_err(|err| format!("{}", err))??
+            }
+            "ftp" => ftp_round_trip(&url, &transferopts, &path, &contents).await?,
+            other => {
+                return Err(format!(
+                    "Unsupported scheme `{}`, expected `sftp` or `ftp`",
+                    other
+                ))
+            }
+        };
+
+        let expected = hex(&openssl::sha::sha256(&contents));
+        let actual = hex(&openssl::sha::sha256(&round_trip.downloaded));
+
+        if expected != actual {
+            return Err(format!(
+                "Checksum mismatch for `{}`: uploaded {} ({} bytes), downloaded {} ({} bytes)",
+                path,
+                expected,
+                contents.len(),
+                actual,
+                round_trip.downloaded.len()
+            ));
+        }
+
+        serde_json::to_string_pretty(&json!({
+            "protocol": url.scheme(),
+            "path": path,
+            "bytes": contents.len(),
+            "sha256": expected,
+            "upload_ms": round_trip.upload.as_secs_f64() * 1000.0,
+            "download_ms": round_trip.download.as_secs_f64() * 1000.0,
+        }))
+        .map_err(|err| format!("{}", err))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Credentials in the url take precedence over the options
+fn credentials(url: &Url, opts: &TransferOptions, default_user: &str) -> (String, Option<String>) {
+    let user = match url.username() {
+        "" => opts.user.clone().unwrap_or_else(|| default_user.into()),
+        user => user.into(),
+    };
+
+    let pass = url
+        .password()
+        .map(String::from)
+        .or_else(|| opts.pass.clone());
+
+    (user, pass)
+}
+
+fn socket_addr(url: &Url, default_port: u16) -> Result<SocketAddr, String> {
+    let host = url.host_str().ok_or("Url must have a host")?;
+    let port = url.port().unwrap_or(default_port);
+
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|err| format!("Could not resolve `{}`: {}", host, err))?
+        .next()
+        .ok_or_else(|| format!("Could not resolve `{}`", host))
+}
+
+fn sftp_round_trip(
+    url: &Url,
+    opts: &TransferOptions,
+    path: &str,
+    contents: &[u8],
+) -> Result<RoundTrip, String> {
+    let addr = socket_addr(url, 22)?;
+
+    let tcp = std::net::TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|err| format!("Could not connect to {}: {}", addr, err))?;
+
+    let mut session = Session::new().map_err(|err| format!("{}", err))?;
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .map_err(|err| format!("SSH handshake failed: {}", err))?;
+
+    let local_user = std::env::var("USER").unwrap_or_default();
+    let (user, pass) = credentials(url, opts, &local_user);
+
+    let auth = match (&opts.private_key, &pass) {
+        (Some(key), passphrase) => {
+            session.userauth_pubkey_file(&user, None, key, passphrase.as_deref())
+        }
+        (None, Some(pass)) => session.userauth_password(&user, pass),
+        (None, None) => session.userauth_agent(&user),
+    };
+
+    auth.map_err(|err| format!("Authentication failed for `{}`: {}", user, err))?;
+
+    let sftp = session.sftp().map_err(|err| format!("{}", err))?;
+    let remote = Path::new(path);
+
+    let start = Instant::now();
+    let mut file = sftp
+        .create(remote)
+        .map_err(|err| format!("Could not create `{}`: {}", path, err))?;
+    file.write_all(contents)
+        .map_err(|err| format!("Could not upload `{}`: {}", path, err))?;
+    drop(file);
+    let upload = start.elapsed();
+
+    let start = Instant::now();
+    let mut downloaded = Vec::new();
+    let result = sftp
+        .open(remote)
+        .map_err(|err| format!("{}", err))
+        .and_then(|mut file| {
+            file.read_to_end(&mut downloaded)
+                .map_err(|err| format!("{}", err))
+        })
+        .map_err(|err| format!("Could not download `{}`: {}", path, err));
+    let download = start.elapsed();
+
+    //Always try to clean up, even if the download failed
+    let removed = sftp
+        .unlink(remote)
+        .map_err(|err| format!("Could not delete `{}`: {}", path, err));
+
+    result?;
+    removed?;
+
+    Ok(RoundTrip {
+        upload,
+        download,
+        downloaded,
+    })
+}
+
+struct FtpControl {
+    stream: BufReader<TcpStream>,
+    peer: SocketAddr,
+}
+
+impl FtpControl {
+    async fn read_reply(&mut self) -> Result<(u16, String), String> {
+        let mut reply = String::new();
+
+        loop {
+            let mut line = String::new();
+            let read = timeout(TIMEOUT, self.stream.read_line(&mut line))
+                .await
+                .map_err(|_| "Timed out waiting for the ftp server".to_string())?
+                .map_err(|err| format!("{}", err))?;
+
+            if read == 0 {
+                return Err("Ftp server closed the connection".into());
+            }
+
+            reply.push_str(&line);
+
+            //Multi-line replies end with the code followed by a space
+            let bytes = line.as_bytes();
+            if bytes.len() >= 4 && bytes[..3].iter().all(u8::is_ascii_digit) && bytes[3] == b' ' {
+                let code = line[..3].parse().map_err(|err| format!("{}", err))?;
+                return Ok((code, reply.trim_end().to_string()));
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, expected: &[u16]) -> Result<String, String> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await
+            .map_err(|err| format!("{}", err))?;
+
+        let (code, reply) = self.read_reply().await?;
+
+        if !expected.contains(&code) {
+            //Don't leak the password into the error
+            let command = if command.starts_with("PASS ") {
+                "PASS"
+            } else {
+                command
+            };
+            return Err(format!("Ftp command `{}` failed: {}", command, reply));
+        }
+
+        Ok(reply)
+    }
+
+    // Opens a passive data connection, using the control connection address as servers behind NAT often report the wrong one
+    async fn data_connection(&mut self) -> Result<TcpStream, String> {
+        let reply = self.command("PASV", &[227]).await?;
+
+        let numbers: Vec<u16> = reply
+            .split(['(', ')'])
+            .nth(1)
+            .ok_or_else(|| format!("Could not parse passive reply: {}", reply))?
+            .split(',')
+            .filter_map(|num| num.trim().parse().ok())
+            .collect();
+
+        if numbers.len() != 6 {
+            return Err(format!("Could not parse passive reply: {}", reply));
+        }
+
+        let addr = SocketAddr::new(self.peer.ip(), numbers[4] * 256 + numbers[5]);
+
+        timeout(TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("Timed out connecting to {}", addr))?
+            .map_err(|err| format!("Could not connect to {}: {}", addr, err))
+    }
+}
+
+async fn ftp_round_trip(
+    url: &Url,
+    opts: &TransferOptions,
+    path: &str,
+    contents: &[u8],
+) -> Result<RoundTrip, String> {
+    let addr = socket_addr(url, 21)?;
+
+    let stream = timeout(TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", addr))?
+        .map_err(|err| format!("Could not connect to {}: {}", addr, err))?;
+
+    let mut control = FtpControl {
+        stream: BufReader::new(stream),
+        peer: addr,
+    };
+
+    let (code, reply) = control.read_reply().await?;
+    if code != 220 {
+        return Err(format!("Unexpected ftp greeting: {}", reply));
+    }
+
+    let (user, pass) = credentials(url, opts, "anonymous");
+
+    let reply = control
+        .command(&format!("USER {}", user), &[230, 331])
+        .await?;
+    if reply.starts_with("331") {
+        control
+            .command(&format!("PASS {}", pass.unwrap_or_default()), &[230, 202])
+            .await?;
+    }
+
+    control.command("TYPE I", &[200]).await?;
+
+    let start = Instant::now();
+    let mut data = control.data_connection().await?;
+    control
+        .command(&format!("STOR {}", path), &[125, 150])
+        .await?;
+    data.write_all(contents)
+        .await
+        .map_err(|err| format!("Could not upload `{}`: {}", path, err))?;
+    data.shutdown().await.map_err(|err| format!("{}", err))?;
+    drop(data);
+    let (code, reply) = control.read_reply().await?;
+    if code != 226 && code != 250 {
+        return Err(format!("Upload of `{}` failed: {}", path, reply));
+    }
+    let upload = start.elapsed();
+
+    let start = Instant::now();
+    let mut downloaded = Vec::new();
+    let mut data = control.data_connection().await?;
+    let retrieved = match control
+        .command(&format!("RETR {}", path), &[125, 150])
+        .await
+    {
+        Ok(_) => {
+            //Kept rather than returned, so the file is still removed below
+            let read = timeout(TIMEOUT, data.read_to_end(&mut downloaded))
+                .await
+                .map_err(|_| format!("Timed out downloading `{}`", path))
+                .and_then(|read| {
+                    read.map_err(|err| format!("Could not download `{}`: {}", path, err))
+                });
+            drop(data);
+            match read {
+                Ok(_) => control.read_reply().await.map(|_| ()),
+                Err(err) => Err(err),
+            }
+        }
+        Err(err) => Err(err),
+    };
+    let download = start.elapsed();
+
+    //Always try to clean up, even if the download failed
+    let removed = control.command(&format!("DELE {}", path), &[250]).await;
+
+    let _ = control.command("QUIT", &[221]).await;
+
+    retrieved?;
+    removed?;
+
+    Ok(RoundTrip {
+        upload,
+        download,
+        downloaded,
+    })
+}
@@ -0,0 +1,154 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use linked_hash_map::LinkedHashMap;
+use wasmtime::{Config, Engine, Linker, Module, Store};
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+//Maximum bytes captured from stdout & stderr
+const MAX_OUTPUT: usize = 10 * 1024 * 1024;
+
+//How much fuel to burn before yielding back to the runtime, so timeouts can fire
+const YIELD_INTERVAL: u64 = 10_000;
+
+fn default_timeout_ms() -> u64 {
+    30000
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum WasmVariant {
+    ModuleOnly(PathBuf),
+    Options(WasmOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WasmOptions {
+    module: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, String>")]
+    env: LinkedHashMap<String, String>,
+    #[serde(default)]
+    input: Option<String>,
+    //Host directories made available read only to the module, by the same path
+    #[serde(default)]
+    dirs: Vec<String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+}
+
+impl WasmVariant {
+    pub async fn run(&self) -> Result<String, String> {
+        let wasmopts = match *self {
+            WasmVariant::ModuleOnly(ref module) => WasmOptions {
+                module: module.clone(),
+                args: Vec::new(),
+                env: LinkedHashMap::new(),
+                input: None,
+                dirs: Vec::new(),
+                timeout_ms: default_timeout_ms(),
+            },
+            WasmVariant::Options(ref opts) => opts.clone(),
+        };
+
+        let mut config = Config::new();
+        config.async_support(true);
+        config.consume_fuel(true);
+
+        let engine = Engine::new(&config).map_err(|err| format!("{}", err))?;
+
+        //Compiling can take a while for larger modules, so keep it off the runtime
+        let module = {
+            let engine = engine.clone();
+            let path = wasmopts.module.clone();
+            tokio::task::spawn_blocking(move || Module::from_file(&engine, &path))
+                .await
+                .map_err(|err| format!("{}", err))?
+                .map_err(|err| {
+                    format!(
+                        "Could not load module `{}`: {}",
+                        wasmopts.module.display(),
+                        err
+                    )
+                })?
+        };
+
+        let stdout = MemoryOutputPipe::new(MAX_OUTPUT);
+        let stderr = MemoryOutputPipe::new(MAX_OUTPUT);
+
+        let mut builder = WasiCtxBuilder::new();
+
+        //Like a normal command, the first argument is the program name
+        builder
+            .arg(wasmopts.module.to_string_lossy())
+            .args(&wasmopts.args)
+            .stdin(MemoryInputPipe::new(
+                wasmopts.input.clone().unwrap_or_default(),
+            ))
+            .stdout(stdout.clone())
+            .stderr(stderr.clone());
+
+        for (key, value) in wasmopts.env.iter() {
+            builder.env(key, value);
+        }
+
+        for dir in wasmopts.dirs.iter() {
+            builder
+                .preopened_dir(dir, dir, DirPerms::READ, FilePerms::READ)
+                .map_err(|err| format!("Could not open directory `{}`: {}", dir, err))?;
+        }
+
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(&engine);
+        preview1::add_to_linker_async(&mut linker, |ctx| ctx).map_err(|err| format!("{}", err))?;
+
+        let mut store = Store::new(&engine, builder.build_p1());
+        store.set_fuel(u64::MAX).map_err(|err| format!("{}", err))?;
+        store
+            .fuel_async_yield_interval(Some(YIELD_INTERVAL))
+            .map_err(|err| format!("{}", err))?;
+
+        let run = async {
+            let instance = linker
+                .instantiate_async(&mut store, &module)
+                .await
+                .map_err(|err| format!("Could not instantiate module: {}", err))?;
+
+            let start = instance
+                .get_typed_func::<(), ()>(&mut store, "_start")
+                .map_err(|err| format!("Module is not a WASI command: {}", err))?;
+
+            match start.call_async(&mut store, ()).await {
+                Ok(()) => Ok(0),
+                Err(err) => match err.downcast_ref::<I32Exit>() {
+                    Some(exit) => Ok(exit.0),
+                    None => Err(format!("Module trapped: {}", err)),
+                },
+            }
+        };
+
+        let exit_code = tokio::time::timeout(Duration::from_millis(wasmopts.timeout_ms), run)
+            .await
+            .map_err(|_| format!("Module timed out after {}ms", wasmopts.timeout_ms))??;
+
+        let output = String::from_utf8_lossy(&stdout.contents()).to_string();
+
+        if exit_code != 0 {
+            let error = String::from_utf8_lossy(&stderr.contents()).to_string();
+
+            return Err(if error.is_empty() {
+                format!("Module exited with code {}", exit_code)
+            } else {
+                error
+            });
+        }
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,120 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::process::Command;
+
+use crate::audit_log;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum WmiVariant {
+    QueryOnly(String),
+    Options(WmiOptions),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WmiOptions {
+    //A WQL query, i.e, `SELECT * FROM Win32_Service WHERE Name = 'W3SVC'`
+    #[serde(default)]
+    query: Option<String>,
+    //A performance counter path, i.e, `\Processor(_Total)\% Processor Time`
+    #[serde(default)]
+    counter: Option<String>,
+    #[serde(default = "default_namespace")]
+    namespace: String,
+    #[serde(default)]
+    computer: Option<String>,
+}
+
+fn default_namespace() -> String {
+    "root/cimv2".into()
+}
+
+impl WmiVariant {
+    // Only querying a remote computer needs the network
+    pub(crate) fn needs_network(&self) -> bool {
+        matches!(self, WmiVariant::Options(opts) if opts.computer.is_some())
+    }
+
+    pub async fn run(&self) -> Result<String, String> {
+        let wmiopts = match *self {
+            WmiVariant::QueryOnly(ref query) => WmiOptions {
+                query: Some(query.clone()),
+                counter: None,
+                namespace: default_namespace(),
+                computer: None,
+            },
+            WmiVariant::Options(ref opts) => opts.clone(),
+        };
+
+        if !cfg!(windows) {
+            return Err("Not Implemented Yet".into());
+        }
+
+        let script = wmiopts.script()?;
+
+        audit_log::record("powershell", &script);
+
+        tokio::task::spawn_blocking(move || run_powershell(&script))
+            .await
+            .map_err(|err| format!("{}", err))?
+    }
+}
+
+impl WmiOptions {
+    // The powershell to run, which outputs the results as json
+    fn script(&self) -> Result<String, String> {
+        let computer = match self.computer {
+            Some(ref computer) => format!(" -ComputerName {}", quote(computer)),
+            None => String::new(),
+        };
+
+        match (&self.query, &self.counter) {
+            (Some(query), None) => Ok(format!(
+                "ConvertTo-Json -Compress -Depth 3 -InputObject @(Get-CimInstance -Namespace {} -Query {}{} | Select-Object -Property * -ExcludeProperty Cim*)",
+                quote(&self.namespace),
+                quote(query),
+                computer
+            )),
+            (None, Some(counter)) => Ok(format!(
+                "ConvertTo-Json -Compress -InputObject @((Get-Counter -Counter {}{}).CounterSamples | Select-Object @{{n='path';e={{$_.Path}}}}, @{{n='instance';e={{$_.InstanceName}}}}, @{{n='value';e={{$_.CookedValue}}}})",
+                quote(counter),
+                computer
+            )),
+            _ => Err("Specify one of either `query` or `counter`".into()),
+        }
+    }
+}
+
+// Quotes a value as a powershell single quoted string
+pub(crate) fn quote(val: &str) -> String {
+    format!("'{}'", val.replace('\'', "''"))
+}
+
+fn run_powershell(script: &str) -> Result<String, String> {
+    let value = powershell_json(script)?;
+
+    serde_json::to_string_pretty(&value).map_err(|err| format!("{}", err))
+}
+
+// Runs a powershell script that outputs json, returning the parsed output
+pub(crate) fn powershell_json(script: &str) -> Result<Value, String> {
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .map_err(|err| format!("Could not run powershell: {}", err))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    //Re-encode so the output is consistent for jmespath filters
+    match stdout.trim() {
+        "" => Ok(Value::Array(vec![])),
+        json => serde_json::from_str(json)
+            .map_err(|err| format!("Could not parse powershell output: {}", err)),
+    }
+}
@@ -1,11 +1,23 @@
 use colored::*;
-use reqwest::IntoUrl;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::multipart::{Form, Part};
+use reqwest::{IntoUrl, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+use std::borrow::Cow;
 use std::convert::From;
+use std::fs::{create_dir_all, write};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
 
-use crate::step::Step;
+use crate::runner::workdir_name;
+use crate::signing::{Signing, KEY_ID_HEADER, SIGNATURE_HEADER};
+use crate::step::{Attachment, ErrorCode, Step, Timing};
+use crate::timezone::format_time;
+use linked_hash_map::LinkedHashMap;
+use log::debug;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct StepResult {
@@ -17,12 +29,145 @@ pub struct StepResult {
     pub on_fail_output: Option<String>,
     pub on_fail_error: Option<String>,
     pub duration: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    #[serde(default)]
+    pub attempts: usize,
+    #[serde(default)]
+    pub max_attempts: usize,
+    #[serde(default, skip_serializing_if = "LinkedHashMap::is_empty")]
+    pub meta: LinkedHashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    //A hex dump of the start of a binary output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    //A stable code for why the step failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    //Files attached to the outcome, i.e, screenshots
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(skip)]
+    pub timing: Timing,
+    #[serde(skip)]
+    pub require: Vec<String>,
+    #[serde(skip)]
+    pub required_by: Vec<String>,
+    #[serde(skip_serializing, default = "default_report")]
+    pub report: bool,
+}
+
+fn default_report() -> bool {
+    true
+}
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationUnit {
+    #[default]
+    Ms,
+    S,
+}
+
+impl FromStr for DurationUnit {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "ms" => Ok(DurationUnit::Ms),
+            "s" => Ok(DurationUnit::S),
+            other => Err(format!(
+                "Unknown duration unit `{}`, expected `ms` or `s`",
+                other
+            )),
+        }
+    }
+}
+
+// How durations are rounded & displayed in reports.  The default matches the raw millisecond values
+#[derive(Default, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DurationFormat {
+    pub unit: DurationUnit,
+    pub precision: Option<usize>,
+    pub thousands_separator: bool,
+}
+
+impl DurationFormat {
+    // Rounds a millisecond duration to the configured precision of the unit, still returning milliseconds
+    pub fn round(&self, ms: f32) -> f32 {
+        match self.precision {
+            Some(precision) => {
+                let scale = match self.unit {
+                    DurationUnit::Ms => 1f64,
+                    DurationUnit::S => 1000f64,
+                };
+                let factor = 10f64.powi(precision as i32);
+                ((ms as f64 / scale * factor).round() / factor * scale) as f32
+            }
+            None => ms,
+        }
+    }
+
+    pub fn format(&self, ms: f32) -> String {
+        let (value, suffix) = match self.unit {
+            DurationUnit::Ms => (ms, "ms"),
+            DurationUnit::S => (ms / 1000f32, "s"),
+        };
+
+        let number = match self.precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => value.to_string(),
+        };
+
+        if self.thousands_separator {
+            format!("{}{}", separate_thousands(&number), suffix)
+        } else {
+            format!("{}{}", number, suffix)
+        }
+    }
+
+    // Formats a millisecond duration as seconds, for reports that require seconds such as JUnit
+    pub fn format_secs(&self, ms: f32) -> String {
+        let secs = ms as f64 / 1000f64;
+        match self.precision {
+            Some(precision) if self.unit == DurationUnit::S => format!("{:.*}", precision, secs),
+            Some(precision) => format!("{:.*}", precision + 3, secs),
+            None => (ms / 1000f32).to_string(),
+        }
+    }
+}
+
+fn separate_thousands(number: &str) -> String {
+    let (sign, number) = match number.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", number),
+    };
+
+    let (integer, fraction) = match number.find('.') {
+        Some(idx) => number.split_at(idx),
+        None => (number, ""),
+    };
+
+    let mut grouped = String::new();
+
+    for (i, ch) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(ch);
+    }
+
+    format!("{}{}{}", sign, grouped, fraction)
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct WebHook {
     hostname: String,
     has_errors: bool,
+    #[serde(default, skip_serializing_if = "LinkedHashMap::is_empty")]
+    meta: LinkedHashMap<String, String>,
+    total_retries: usize,
     tests: Vec<StepResult>,
 }
 
@@ -30,7 +175,15 @@ pub async fn submit_slack<U: IntoUrl, I: Into<String>>(
     results: &[StepResult],
     url: U,
     hostname: I,
+    meta: &LinkedHashMap<String, String>,
+    format: &DurationFormat,
 ) -> Result<(), reqwest::Error> {
+    //Slack has always shown 2 decimal places
+    let format = DurationFormat {
+        precision: format.precision.or(Some(2)),
+        ..*format
+    };
+
     let num_errors = results.iter().filter(|result| !result.pass).count();
 
     if num_errors == 0 {
@@ -55,6 +208,24 @@ pub async fn submit_slack<U: IntoUrl, I: Into<String>>(
         }
     }));
 
+    if !meta.is_empty() {
+        let elements: Vec<_> = meta
+            .iter()
+            .map(|(key, value)| {
+                json!({
+                    "type": "mrkdwn",
+                    "text": format!("*{}*: {}", key, value)
+                })
+            })
+            .collect();
+
+        //Context blocks are limited to 10 elements
+        blocks.push(json!({
+            "type": "context",
+            "elements": elements.into_iter().take(10).collect::<Vec<_>>()
+        }));
+    }
+
     for result in results.iter().filter(|result| !result.pass) {
         let mut text = format!("*Name*: {}", result.name);
 
@@ -68,10 +239,24 @@ pub async fn submit_slack<U: IntoUrl, I: Into<String>>(
             text.push_str(&format!("*Error*: {}\n\n", val));
         }
 
+        if let Some(ref code) = result.code {
+            text.push_str(&format!("*Code*: `{}`\n\n", code));
+        }
+
+        if result.max_attempts > 1 {
+            text.push_str(&format!("*Attempts*: {}\n\n", result.attempts_display()));
+        }
+
         if result.output.is_empty() {
-            text.push_str(&format!("*Duration*: ({:.2}ms)\n\n", result.duration));
+            text.push_str(&format!(
+                "*Duration*: ({})\n\n",
+                format.format(result.duration)
+            ));
         } else {
-            text.push_str(&format!("*Output*: ({:.2}ms)\n\n", result.duration));
+            text.push_str(&format!(
+                "*Output*: ({})\n\n",
+                format.format(result.duration)
+            ));
         }
 
         blocks.push(json!({
@@ -126,24 +311,127 @@ pub async fn submit_slack<U: IntoUrl, I: Into<String>>(
     Ok(())
 }
 
-pub async fn submit_webhook<U: IntoUrl, I: Into<String>>(
+// The results of a run in the shape sent to webhooks
+pub fn webhook_payload<I: Into<String>>(
     results: &[StepResult],
-    url: U,
     hostname: I,
-) -> Result<(), reqwest::Error> {
-    let has_errors = results.iter().any(|result| !result.pass);
-
-    let payload = WebHook {
+    meta: &LinkedHashMap<String, String>,
+) -> WebHook {
+    WebHook {
         hostname: hostname.into(),
-        has_errors,
+        has_errors: results.iter().any(|result| !result.pass),
+        meta: meta.clone(),
+        total_retries: total_retries(results),
         tests: results.to_vec(),
-    };
+    }
+}
+
+// The `Content-Encoding` to compress webhook payloads with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!(
+                "Unknown compression `{}`, expected `gzip` or `zstd`",
+                other
+            )),
+        }
+    }
+}
+
+impl Compression {
+    fn content_encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+}
+
+// Compresses webhook payloads at least `min_bytes` long, as small payloads don't gain anything
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WebhookCompression {
+    pub compression: Compression,
+    pub min_bytes: usize,
+}
+
+pub async fn submit_webhook<U: IntoUrl, I: Into<String>>(
+    results: &[StepResult],
+    url: U,
+    hostname: I,
+    meta: &LinkedHashMap<String, String>,
+    signing: Option<&Signing>,
+    compression: Option<WebhookCompression>,
+    attachments: bool,
+) -> Result<(), anyhow::Error> {
+    let payload = webhook_payload(results, hostname, meta);
 
     let client = reqwest::Client::new();
 
     let builder = client.post(url);
 
-    let builder = builder.json(&payload);
+    //Attachments are uploaded alongside the payload as a multipart form, if asked for
+    let has_attachments =
+        attachments && results.iter().any(|result| !result.attachments.is_empty());
+
+    let (builder, json) = match has_attachments {
+        //Form parts can't be given their own `Content-Encoding`, so a receiver couldn't tell a compressed payload apart
+        true => {
+            if compression.is_some() {
+                debug!("Not compressing the webhook, as it has attachments");
+            }
+
+            let (form, json) = webhook_form(payload)?;
+            (builder.multipart(form), json)
+        }
+        false => {
+            let json = serde_json::to_string(&payload)?;
+            let builder = builder.header(CONTENT_TYPE, "application/json");
+
+            let builder = match compression {
+                Some(compression) if json.len() >= compression.min_bytes => builder
+                    .header(CONTENT_ENCODING, compression.compression.content_encoding())
+                    .body(compression.compression.compress(json.as_bytes())?),
+                _ => builder.body(json.clone()),
+            };
+
+            (builder, json)
+        }
+    };
+
+    //With attachments, the signature is of the `payload` part.  Compressed payloads are signed before compression
+    let builder = match signing {
+        Some(signing) => {
+            let builder = builder.header(SIGNATURE_HEADER, signing.sign(json.as_bytes())?);
+
+            match signing.key_id {
+                Some(ref key_id) => builder.header(KEY_ID_HEADER, key_id),
+                None => builder,
+            }
+        }
+        None => builder,
+    };
 
     let response = builder.send().await?;
 
@@ -157,8 +445,225 @@ pub async fn submit_webhook<U: IntoUrl, I: Into<String>>(
     Ok(())
 }
 
+// The payload as a `payload` part, with each attachment in its own part.  Step & attachment names can contain anything, so parts are numbered & the payload records which part is which
+fn webhook_form(mut payload: WebHook) -> Result<(Form, String), anyhow::Error> {
+    let mut parts = Vec::new();
+
+    for result in payload.tests.iter_mut() {
+        for attachment in result.attachments.iter_mut() {
+            let part = format!("attachment-{}", parts.len());
+
+            parts.push((
+                part.clone(),
+                Part::bytes(std::mem::take(&mut attachment.contents))
+                    .file_name(attachment.file_name.clone())
+                    .mime_str("application/octet-stream")?,
+            ));
+
+            attachment.part = Some(part);
+        }
+    }
+
+    let json = serde_json::to_string(&payload)?;
+
+    let mut form = Form::new().part(
+        "payload",
+        Part::text(json.clone()).mime_str("application/json")?,
+    );
+
+    for (name, part) in parts {
+        form = form.part(name, part);
+    }
+
+    Ok((form, json))
+}
+
+// Saves attachments to `<dir>/<index>-<step>/<file>`, recording where each was saved so reports can reference them
+pub fn save_attachments(results: &mut [StepResult], dir: &Path) -> std::io::Result<()> {
+    for (index, result) in results.iter_mut().enumerate() {
+        let step_dir = dir.join(workdir_name(index, &result.name));
+
+        for attachment in result.attachments.iter_mut() {
+            create_dir_all(&step_dir)?;
+
+            let path = step_dir.join(&attachment.file_name);
+            write(&path, &attachment.contents)?;
+
+            attachment.path = Some(path.canonicalize().unwrap_or(path));
+        }
+    }
+
+    Ok(())
+}
+
+// A Sentry DSN, i.e, `https://<key>@<host>/<project>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SentryDsn {
+    key: String,
+    secret: Option<String>,
+    store_url: Url,
+}
+
+impl FromStr for SentryDsn {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(val).map_err(|err| format!("Invalid Sentry DSN: {}", err))?;
+
+        if url.username().is_empty() {
+            return Err("Sentry DSN has no public key".into());
+        }
+
+        let (prefix, project) = url
+            .path()
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .unwrap_or_default();
+
+        if project.is_empty() {
+            return Err("Sentry DSN has no project id".into());
+        }
+
+        let mut store_url = url.clone();
+        store_url.set_username("").ok();
+        store_url.set_password(None).ok();
+        store_url.set_path(&format!("{}/api/{}/store/", prefix, project));
+
+        Ok(SentryDsn {
+            key: url.username().to_string(),
+            secret: url.password().map(String::from),
+            store_url,
+        })
+    }
+}
+
+//Sentry truncates tags longer than this
+const SENTRY_TAG_VALUE_LEN: usize = 200;
+
+// Reports each failing step as a Sentry event, tagged with the step & run metadata
+pub async fn submit_sentry<I: Into<String>>(
+    results: &[StepResult],
+    dsn: &SentryDsn,
+    hostname: I,
+    meta: &LinkedHashMap<String, String>,
+) -> Result<(), reqwest::Error> {
+    let hostname = hostname.into();
+
+    let mut auth = format!(
+        "Sentry sentry_version=7, sentry_client=lorikeet/{}, sentry_key={}",
+        env!("CARGO_PKG_VERSION"),
+        dsn.key
+    );
+
+    if let Some(ref secret) = dsn.secret {
+        auth.push_str(&format!(", sentry_secret={}", secret));
+    }
+
+    let client = reqwest::Client::new();
+
+    for result in results.iter().filter(|result| !result.pass) {
+        let mut tags: LinkedHashMap<String, String> = meta.clone();
+        tags.extend(result.meta.clone());
+        tags.insert("step".into(), result.name.clone());
+
+        if let Some(ref code) = result.code {
+            tags.insert("code".into(), code.to_string());
+        }
+
+        let tags: LinkedHashMap<String, String> = tags
+            .into_iter()
+            .map(|(key, value)| (key, truncate(&value, SENTRY_TAG_VALUE_LEN)))
+            .collect();
+
+        let event_id: String = (0..16)
+            .map(|_| format!("{:02x}", rand::random::<u8>()))
+            .collect();
+
+        let payload = json!({
+            "event_id": event_id,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "level": "error",
+            "logger": "lorikeet",
+            "platform": "other",
+            "server_name": hostname,
+            "environment": tags.get("environment"),
+            "transaction": result.name,
+            //Group events by step & code, rather than by the error message which may change between runs
+            "fingerprint": ["lorikeet", result.name, result.code.map(|code| code.to_string())],
+            "message": {
+                "formatted": format!(
+                    "{}: {}",
+                    result.name,
+                    result
+                        .error
+                        .as_deref()
+                        .filter(|error| !error.is_empty())
+                        .unwrap_or("failed")
+                ),
+            },
+            "tags": tags,
+            "extra": {
+                "description": result.description,
+                "output": truncate(&result.output, 4096),
+                "on_fail_output": result.on_fail_output,
+                "on_fail_error": result.on_fail_error,
+                "attempts": result.attempts_display(),
+                "duration_ms": result.duration,
+            },
+        });
+
+        let response = client
+            .post(dsn.store_url.clone())
+            .header("X-Sentry-Auth", &auth)
+            .json(&payload)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            eprintln!("Error submitting sentry event:");
+            eprintln!("Status: {}", response.status());
+            let val = response.text().await?;
+            eprintln!("Body: {}", val);
+        }
+    }
+
+    Ok(())
+}
+
+// The number of retries used across all steps, i.e, attempts after the first
+pub fn total_retries(results: &[StepResult]) -> usize {
+    results
+        .iter()
+        .map(|result| result.attempts.saturating_sub(1))
+        .sum()
+}
+
+// Whether any failure should fail the run.  With gating tags, only failed steps with one of the tags count,
+// along with errors loading the plan, which never carry tags
+pub fn has_gated_failures(results: &[StepResult], gate_tags: &[String]) -> bool {
+    results.iter().filter(|result| !result.pass).any(|result| {
+        gate_tags.is_empty()
+            || result.code == Some(ErrorCode::PlanError)
+            || result.tags.iter().any(|tag| gate_tags.contains(tag))
+    })
+}
+
 impl StepResult {
-    pub fn terminal_print(&self, colours: &bool) {
+    // Steps with `report: false` are hidden unless they fail, otherwise the steps depending on it fail without a cause
+    pub fn is_reported(&self) -> bool {
+        self.report || !self.pass
+    }
+
+    pub fn attempts_display(&self) -> String {
+        format!("{}/{}", self.attempts, self.max_attempts)
+    }
+
+    pub fn terminal_print(
+        &self,
+        colours: &bool,
+        format: &DurationFormat,
+        max_output_lines: Option<usize>,
+    ) {
         let mut message = format!("- name: {}\n", self.name);
 
         if let Some(ref description) = self.description {
@@ -167,23 +672,55 @@ impl StepResult {
 
         message.push_str(&format!("  pass: {}\n", self.pass));
 
+        if let Some(ref reason) = self.skipped {
+            message.push_str(&format!("  skipped: {}\n", reason));
+        }
+
         if !self.output.is_empty() {
-            if self.output.contains('\n') {
+            let output = limit_lines(&self.output, max_output_lines);
+
+            if output.contains('\n') {
                 message.push_str(&format!(
                     "  output: |\n    {}\n",
-                    self.output.replace("\n", "\n    ")
+                    output.replace("\n", "\n    ")
                 ));
             } else {
-                message.push_str(&format!("  output: {}\n", self.output));
+                message.push_str(&format!("  output: {}\n", output));
             }
         }
+
+        if let Some(ref preview) = self.preview {
+            message.push_str(&format!(
+                "  preview: |\n    {}\n",
+                preview.trim_end().replace("\n", "\n    ")
+            ));
+        }
+
+        let error_start = message.len();
+
         if let Some(ref error) = self.error {
-            message.push_str(&format!("  error: {}\n", error));
+            if error.contains('\n') {
+                message.push_str(&format!(
+                    "  error: |\n    {}\n",
+                    error.trim_end().replace("\n", "\n    ")
+                ));
+            } else {
+                message.push_str(&format!("  error: {}\n", error));
+            }
         }
 
+        if let Some(ref code) = self.code {
+            message.push_str(&format!("  code: {}\n", code));
+        }
+
+        let error_end = message.len();
+
         if let Some(ref output) = self.on_fail_output {
             if !output.trim().is_empty() {
-                message.push_str(&format!("  on_fail_output: {}\n", output));
+                message.push_str(&format!(
+                    "  on_fail_output: {}\n",
+                    limit_lines(output, max_output_lines)
+                ));
             }
         }
 
@@ -191,7 +728,22 @@ impl StepResult {
             message.push_str(&format!("  on_fail_error: {}\n", error));
         }
 
-        message.push_str(&format!("  duration: {}ms\n", self.duration));
+        if self.max_attempts > 1 {
+            message.push_str(&format!("  attempts: {}\n", self.attempts_display()));
+        }
+
+        if !self.attachments.is_empty() {
+            message.push_str("  attachments:\n");
+
+            for attachment in self.attachments.iter() {
+                message.push_str(&format!(
+                    "    {}: {} ({} bytes)\n",
+                    attachment.name, attachment.file_name, attachment.size
+                ));
+            }
+        }
+
+        message.push_str(&format!("  duration: {}\n", format.format(self.duration)));
 
         if *colours {
             match self.pass {
@@ -199,7 +751,23 @@ impl StepResult {
                     println!("{}", message.green().bold());
                 }
                 false => {
-                    println!("{}", message.red().bold());
+                    //Highlight any diff lines within the error
+                    let mut coloured = message[..error_start].red().bold().to_string();
+
+                    for line in message[error_start..error_end].split_inclusive('\n') {
+                        let line = if line.starts_with("    + ") {
+                            line.green()
+                        } else if line.starts_with("    - ") {
+                            line.yellow()
+                        } else {
+                            line.red().bold()
+                        };
+                        coloured.push_str(&line.to_string());
+                    }
+
+                    coloured.push_str(&message[error_end..].red().bold().to_string());
+
+                    println!("{}", coloured);
                 }
             }
         } else {
@@ -209,12 +777,85 @@ impl StepResult {
 }
 
 impl From<Step> for StepResult {
-    fn from(step: Step) -> Self {
+    fn from(mut step: Step) -> Self {
         let duration = step.get_duration_ms();
         let name = step.name;
         let description = step.description;
+        let mut meta = step.meta;
+        let tags = step.tags;
+
+        if let Some(last_run) = step.outcome.as_ref().and_then(|outcome| outcome.last_run) {
+            meta.insert("last_run".into(), format_time(&last_run));
+        }
+
+        let report = step.report;
+        let require = step.require;
+        let required_by = step.required_by;
+
+        let code = step.outcome.as_ref().and_then(|outcome| outcome.code);
+
+        //A sensitive step's files could hold the values it hides, so they aren't attached
+        let sensitive = step.sensitive;
+        let attachments = step
+            .outcome
+            .as_mut()
+            .filter(|_| !sensitive)
+            .map(|outcome| std::mem::take(&mut outcome.attachments))
+            .unwrap_or_default();
+
+        let (attempts, max_attempts, skipped, timing) = step
+            .outcome
+            .as_ref()
+            .map(|outcome| {
+                (
+                    outcome.attempts,
+                    outcome.max_attempts,
+                    outcome.skipped.clone(),
+                    outcome.timing,
+                )
+            })
+            .unwrap_or_default();
+
+        //The raw bytes of a sensitive output can't be masked, so it has no preview
+        let preview = match step.do_output && !step.sensitive {
+            true => step
+                .outcome
+                .as_ref()
+                .and_then(|outcome| outcome.preview.clone()),
+            false => None,
+        };
 
         let (pass, output, error, on_fail_output, on_fail_error) = match step.outcome {
+            Some(outcome) if step.sensitive => {
+                let raw = outcome.output.unwrap_or_default();
+
+                let output = match step.do_output {
+                    true => mask(&raw),
+                    false => String::new(),
+                };
+
+                //Errors can echo the output back, i.e, when an expectation fails
+                let hide = |error: String, secret: &str| match secret {
+                    "" => error,
+                    _ => error.replace(secret, &mask(secret)),
+                };
+
+                let error = outcome.error.map(|error| hide(error, &raw));
+
+                //The `on_fail` step can echo either output back too
+                let on_fail_raw = outcome.on_fail_output.as_deref().unwrap_or_default();
+                let on_fail_error = outcome
+                    .on_fail_error
+                    .map(|error| hide(hide(error, &raw), on_fail_raw));
+
+                (
+                    error.is_none(),
+                    output,
+                    error,
+                    outcome.on_fail_output.as_deref().map(mask),
+                    on_fail_error,
+                )
+            }
             Some(outcome) => {
                 let output = match step.do_output {
                     true => outcome.output.unwrap_or_default(),
@@ -247,10 +888,61 @@ impl From<Step> for StepResult {
             on_fail_output,
             on_fail_error,
             error,
+            skipped,
+            attempts,
+            max_attempts,
+            meta,
+            tags,
+            preview,
+            //Anything that fails without a more specific reason failed to run
+            code: match pass {
+                true => None,
+                false => code.or(Some(ErrorCode::RunFailed)),
+            },
+            attachments,
+            timing,
+            require,
+            required_by,
+            report,
         }
     }
 }
 
+// Hides a sensitive value, keeping enough information to tell whether it has changed
+pub fn mask(input: &str) -> String {
+    if input.is_empty() {
+        return String::new();
+    }
+
+    let digest: String = openssl::sha::sha256(input.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("<sensitive: {} bytes, sha256:{}>", input.len(), digest)
+}
+
+// Keeps the first `max` lines of the input, noting how many were left out
+pub fn limit_lines(input: &str, max: Option<usize>) -> Cow<'_, str> {
+    let max = match max {
+        Some(max) => max,
+        None => return Cow::Borrowed(input),
+    };
+
+    let total = input.lines().count();
+
+    if total <= max {
+        return Cow::Borrowed(input);
+    }
+
+    let mut kept: Vec<&str> = input.lines().take(max).collect();
+    let more = format!("... ({} more lines)", total - max);
+    kept.push(&more);
+
+    Cow::Owned(kept.join("\n"))
+}
+
 pub fn truncate(input: &str, len: usize) -> String {
     if input.len() <= len {
         return input.to_string();
@@ -270,5 +962,132 @@ pub fn truncate(input: &str, len: usize) -> String {
         end_idx = val;
     }
 
-    return format!("{}...", &input[0..end_idx]);
+    format!("{}...", &input[0..end_idx])
+}
+
+// A result with only a name & whether it passed, for tests to fill in the rest of
+#[cfg(test)]
+pub(crate) fn test_result(name: &str, pass: bool) -> StepResult {
+    serde_json::from_value(json!({
+        "name": name,
+        "pass": pass,
+        "output": "",
+        "duration": 0.0,
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn only_gating_tags_fail_the_run() {
+        let result = |pass: bool, tags: &[&str]| StepResult {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..test_result("check", pass)
+        };
+
+        let gate = vec!["critical".to_string()];
+
+        let results = vec![result(false, &["info"]), result(true, &["critical"])];
+        assert!(!has_gated_failures(&results, &gate));
+        assert!(has_gated_failures(&results, &[]));
+
+        let results = vec![result(false, &["info", "critical"])];
+        assert!(has_gated_failures(&results, &gate));
+    }
+
+    async fn run_sensitive(yaml: &str) -> StepResult {
+        let steps =
+            crate::yaml::get_steps_raw(yaml, &std::collections::HashMap::<String, String>::new())
+                .unwrap();
+
+        let mut finished: Vec<Step> = crate::runner::run_steps(steps).unwrap().collect().await;
+
+        finished.remove(0).into()
+    }
+
+    #[tokio::test]
+    async fn masks_sensitive_output() {
+        let result = run_sensitive("token:\n  value: hunter2-secret\n  sensitive: true\n").await;
+
+        assert!(result.pass);
+        assert_eq!(result.output, mask("hunter2-secret"));
+    }
+
+    #[tokio::test]
+    async fn masks_sensitive_expectation_errors() {
+        //An excerpt of the output would only be part of it, so it has to be left out rather than masked
+        let result = run_sensitive(
+            "token:\n  value: hunter2-secret\n  sensitive: true\n  matches: hunter3-public\n",
+        )
+        .await;
+
+        let error = result.error.unwrap();
+
+        assert!(!error.contains("hunter2"), "{}", error);
+        assert_eq!(error, "Not matched against `hunter3-public`");
+    }
+
+    #[tokio::test]
+    async fn masks_sensitive_on_fail_errors() {
+        let result = run_sensitive(
+            "token:\n  value: hunter2-secret\n  sensitive: true\n  matches: nope\n  on_fail:\n    bash: echo hunter2-secret >&2; exit 1\n",
+        )
+        .await;
+
+        let on_fail_error = result.on_fail_error.unwrap();
+
+        assert!(!on_fail_error.contains("hunter2"), "{}", on_fail_error);
+        assert!(on_fail_error.contains(&mask("hunter2-secret")));
+    }
+
+    #[test]
+    fn limits_output_lines() {
+        assert_eq!(limit_lines("a\nb\nc", Some(2)), "a\nb\n... (1 more lines)");
+        assert_eq!(limit_lines("a\nb", Some(2)), "a\nb");
+        assert_eq!(limit_lines("a\nb", None), "a\nb");
+    }
+
+    #[test]
+    fn parses_sentry_dsn() {
+        let dsn: SentryDsn = "https://abc123@sentry.example.com/prefix/42"
+            .parse()
+            .unwrap();
+
+        assert_eq!(dsn.key, "abc123");
+        assert_eq!(dsn.secret, None);
+        assert_eq!(
+            dsn.store_url.as_str(),
+            "https://sentry.example.com/prefix/api/42/store/"
+        );
+
+        assert!("https://sentry.example.com/42"
+            .parse::<SentryDsn>()
+            .is_err());
+        assert!("https://abc123@sentry.example.com/"
+            .parse::<SentryDsn>()
+            .is_err());
+    }
+
+    #[test]
+    fn compresses_payloads() {
+        use std::io::Read;
+
+        let json = br#"{"tests":[{"name":"a","pass":true},{"name":"b","pass":true}]}"#;
+
+        let gzip = Compression::Gzip.compress(json).unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, json);
+
+        let zstd = Compression::Zstd.compress(json).unwrap();
+        assert_eq!(zstd::decode_all(&zstd[..]).unwrap(), json);
+
+        assert!("brotli".parse::<Compression>().is_err());
+    }
 }
@@ -0,0 +1,195 @@
+use crate::submitter::{truncate, StepResult};
+use crate::timezone::format_time;
+
+use anyhow::Error;
+use chrono::Utc;
+use linked_hash_map::LinkedHashMap;
+
+use std::path::Path;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+const IDENTIFIER: &str = "lorikeet";
+
+//Keeps each entry well under the datagram size limit
+const MAX_VALUE_LEN: usize = 4096;
+
+//Facility `user`, severity `info` or `err`
+const PRIORITY_PASS: u8 = 6;
+const PRIORITY_FAIL: u8 = 3;
+
+// The structured fields for a step result, i.e, `STEP`, `PASS`, `DURATION_MS` & any meta as `META_<KEY>`
+fn fields(result: &StepResult, meta: &LinkedHashMap<String, String>) -> Vec<(String, String)> {
+    let mut fields = vec![
+        ("STEP".to_string(), result.name.clone()),
+        ("PASS".to_string(), result.pass.to_string()),
+        ("DURATION_MS".to_string(), result.duration.to_string()),
+        ("ATTEMPTS".to_string(), result.attempts.to_string()),
+    ];
+
+    let optional = [
+        ("DESCRIPTION", &result.description),
+        ("ERROR", &result.error),
+        ("SKIPPED", &result.skipped),
+    ];
+
+    if let Some(ref code) = result.code {
+        fields.push(("CODE".to_string(), code.to_string()));
+    }
+
+    for (name, value) in optional.iter() {
+        if let Some(value) = value {
+            fields.push((name.to_string(), truncate(value, MAX_VALUE_LEN)));
+        }
+    }
+
+    if !result.output.is_empty() {
+        fields.push(("OUTPUT".into(), truncate(&result.output, MAX_VALUE_LEN)));
+    }
+
+    for (key, value) in meta.iter().chain(result.meta.iter()) {
+        fields.push((format!("META_{}", field_name(key)), value.clone()));
+    }
+
+    fields
+}
+
+// Journal field names can only contain uppercase letters, digits & underscores
+fn field_name(key: &str) -> String {
+    key.chars()
+        .map(|ch| match ch.is_ascii_alphanumeric() {
+            true => ch.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+fn message(result: &StepResult) -> String {
+    match (result.pass, &result.error) {
+        (true, _) => format!("Step `{}` passed", result.name),
+        (false, Some(error)) if !error.is_empty() => {
+            format!("Step `{}` failed: {}", result.name, truncate(error, 256))
+        }
+        (false, _) => format!("Step `{}` failed", result.name),
+    }
+}
+
+// Encodes an entry in the journald native protocol.  Values with newlines are length prefixed
+fn journal_entry(priority: u8, message: &str, fields: &[(String, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+
+    let mut push = |name: &str, value: &str| {
+        entry.extend_from_slice(name.as_bytes());
+
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+
+    push("PRIORITY", &priority.to_string());
+    push("SYSLOG_IDENTIFIER", IDENTIFIER);
+    push("MESSAGE", message);
+
+    for (name, value) in fields.iter() {
+        push(&format!("LORIKEET_{}", name), value);
+    }
+
+    entry
+}
+
+// Formats an RFC 5424 message, with the fields as structured data
+fn syslog_entry(priority: u8, message: &str, fields: &[(String, String)]) -> Vec<u8> {
+    let hostname = hostname::get()
+        .map(|val| val.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "-".into());
+
+    let params: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            format!("{}=\"{}\"", name.to_lowercase(), value)
+        })
+        .collect();
+
+    format!(
+        "<{}>1 {} {} {} {} - [{}@32473 {}] {}",
+        8 + priority,
+        format_time(&Utc::now()),
+        hostname,
+        IDENTIFIER,
+        std::process::id(),
+        IDENTIFIER,
+        params.join(" "),
+        message
+    )
+    .into_bytes()
+}
+
+// Sends each step result to journald if it's running, otherwise the local syslog socket
+pub fn submit_syslog(
+    results: &[StepResult],
+    meta: &LinkedHashMap<String, String>,
+) -> Result<(), Error> {
+    let journald = Path::new(JOURNALD_SOCKET).exists();
+
+    for result in results.iter() {
+        let priority = match result.pass {
+            true => PRIORITY_PASS,
+            false => PRIORITY_FAIL,
+        };
+
+        let fields = fields(result, meta);
+        let message = message(result);
+
+        match journald {
+            true => send(&journal_entry(priority, &message, &fields), JOURNALD_SOCKET)?,
+            false => send(&syslog_entry(priority, &message, &fields), SYSLOG_SOCKET)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn send(entry: &[u8], path: &str) -> Result<(), Error> {
+    std::os::unix::net::UnixDatagram::unbound()?.send_to(entry, path)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_entry: &[u8], _path: &str) -> Result<(), Error> {
+    Err(anyhow::anyhow!("Syslog is only supported on unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_entry_length_prefixes_multiline_values() {
+        let fields = vec![
+            ("STEP".to_string(), "web".to_string()),
+            ("OUTPUT".to_string(), "a\nb".to_string()),
+        ];
+
+        let entry = journal_entry(6, "Step `web` passed", &fields);
+
+        let mut expected = b"PRIORITY=6\nSYSLOG_IDENTIFIER=lorikeet\nMESSAGE=Step `web` passed\nLORIKEET_STEP=web\nLORIKEET_OUTPUT\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+
+        assert_eq!(entry, expected);
+        assert_eq!(field_name("service-name"), "SERVICE_NAME");
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Error};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use tera::Tera;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+lazy_static! {
+    static ref TIMEZONE: RwLock<Timezone> = RwLock::new(Timezone::Utc);
+}
+
+// The timezone timestamps are rendered in, so reports, logs & junit output line up with each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timezone {
+    Utc,
+    //Whatever the host is set to
+    Local,
+    Named(Tz),
+}
+
+impl Timezone {
+    pub fn format(&self, time: &DateTime<Utc>) -> String {
+        match self {
+            Timezone::Utc => time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            Timezone::Local => time
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            Timezone::Named(tz) => time
+                .with_timezone(tz)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    // Formats a time with a `strftime` format, i.e, `%Y-%m-%d %H:%M`
+    pub fn format_with(&self, time: &DateTime<Utc>, format: &str) -> String {
+        match self {
+            Timezone::Utc => time.format(format).to_string(),
+            Timezone::Local => time.with_timezone(&Local).format(format).to_string(),
+            Timezone::Named(tz) => time.with_timezone(tz).format(format).to_string(),
+        }
+    }
+}
+
+impl FromStr for Timezone {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "utc" | "z" => Ok(Timezone::Utc),
+            "local" => Ok(Timezone::Local),
+            _ => input
+                .parse::<Tz>()
+                .map(Timezone::Named)
+                .map_err(|_| anyhow!("Unknown timezone `{}`, expected `utc`, `local` or a name like `Australia/Brisbane`", input)),
+        }
+    }
+}
+
+impl fmt::Display for Timezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timezone::Utc => write!(f, "UTC"),
+            Timezone::Local => write!(f, "local"),
+            Timezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+// Sets the timezone used for every timestamp lorikeet renders
+pub fn set_timezone(timezone: Timezone) {
+    *TIMEZONE.write().unwrap() = timezone;
+}
+
+pub fn timezone() -> Timezone {
+    *TIMEZONE.read().unwrap()
+}
+
+// Renders a timestamp as RFC 3339 in the configured timezone
+pub fn format_time(time: &DateTime<Utc>) -> String {
+    timezone().format(time)
+}
+
+// Replaces tera's `now` & `date`, which use the host's timezone, with ones using the configured timezone
+pub fn register_templates(tera: &mut Tera) {
+    tera.register_function("now", now);
+    tera.register_filter("date", date);
+}
+
+fn now(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let flag = |name: &str| match args.get(name) {
+        Some(Value::Bool(val)) => Ok(*val),
+        Some(val) => Err(tera::Error::msg(format!(
+            "Function `now` received {}={} but `{}` can only be a boolean",
+            name, val, name
+        ))),
+        None => Ok(false),
+    };
+
+    let time = Utc::now();
+
+    match (flag("timestamp")?, flag("utc")?) {
+        (true, _) => Ok(json!(time.timestamp())),
+        (false, true) => Ok(json!(Timezone::Utc.format(&time))),
+        (false, false) => Ok(json!(format_time(&time))),
+    }
+}
+
+fn date(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let format = match args.get("format") {
+        Some(Value::String(format)) => format.clone(),
+        Some(val) => {
+            return Err(tera::Error::msg(format!(
+                "Filter `date` received format={} but `format` can only be a string",
+                val
+            )))
+        }
+        None => "%Y-%m-%d".into(),
+    };
+
+    if StrftimeItems::new(&format).any(|item| item == Item::Error) {
+        return Err(tera::Error::msg(format!(
+            "Invalid date format `{}`",
+            format
+        )));
+    }
+
+    let timezone = match args.get("timezone") {
+        Some(Value::String(timezone)) => timezone
+            .parse::<Timezone>()
+            .map_err(|err| tera::Error::msg(format!("{}", err)))?,
+        Some(val) => {
+            return Err(tera::Error::msg(format!(
+                "Filter `date` received timezone={} but `timezone` can only be a string",
+                val
+            )))
+        }
+        None => timezone(),
+    };
+
+    let time = match value {
+        Value::Number(number) => number
+            .as_i64()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+        Value::String(val) => match DateTime::parse_from_rfc3339(val) {
+            Ok(time) => Some(time.with_timezone(&Utc)),
+            //Times & dates without an offset are already local to wherever they came from, so are left as is
+            Err(_) => {
+                if let Ok(time) = val.parse::<NaiveDateTime>() {
+                    return Ok(json!(time.format(&format).to_string()));
+                }
+
+                if let Ok(date) = val.parse::<NaiveDate>() {
+                    return Ok(json!(date.format(&format).to_string()));
+                }
+
+                None
+            }
+        },
+        _ => None,
+    };
+
+    match time {
+        Some(time) => Ok(json!(timezone.format_with(&time, &format))),
+        None => Err(tera::Error::msg(format!(
+            "Filter `date` received an invalid time: {}",
+            value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_in_named_timezone() {
+        let time = DateTime::parse_from_rfc3339("2021-06-01T02:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let brisbane: Timezone = "Australia/Brisbane".parse().unwrap();
+
+        assert_eq!(brisbane.format(&time), "2021-06-01T12:30:00.000+10:00");
+        assert_eq!(Timezone::Utc.format(&time), "2021-06-01T02:30:00.000Z");
+        assert!("Mars/Olympus_Mons".parse::<Timezone>().is_err());
+    }
+
+    #[test]
+    fn renders_template_dates_in_timezone() {
+        let mut tera = Tera::default();
+        register_templates(&mut tera);
+
+        let template = r#"{{ 1622514600 | date(format="%H:%M %z", timezone="Australia/Brisbane") }} {{ "2021-06-01T02:30:00Z" | date(format="%H:%M", timezone="utc") }} {{ "2021-06-01" | date(format="%d/%m") }}"#;
+
+        assert_eq!(
+            tera.render_str(template, &tera::Context::new()).unwrap(),
+            "12:30 +1000 02:30 01/06"
+        );
+    }
+}
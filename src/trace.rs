@@ -0,0 +1,85 @@
+use crate::step::Timing;
+use crate::submitter::StepResult;
+
+use anyhow::Error;
+use serde_json::{json, Value};
+
+use std::fs::{create_dir_all, File};
+use std::path::Path;
+use std::time::Duration;
+
+// Writes step timings as a chrome://tracing compatible JSON file, with each step on its own row
+pub fn create_trace(results: &[StepResult], file_path: &Path) -> Result<(), Error> {
+    if let Some(parent) = file_path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut events = Vec::new();
+
+    for (tid, result) in results.iter().enumerate() {
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": 1,
+            "tid": tid,
+            "args": { "name": result.name }
+        }));
+
+        //Steps that never ran, i.e, when a dependency failed
+        if result.timing == Timing::default() {
+            continue;
+        }
+
+        let Timing {
+            ready,
+            started,
+            finished,
+        } = result.timing;
+
+        if ready > Duration::default() {
+            events.push(span(
+                "waiting for dependencies",
+                "wait",
+                tid,
+                Duration::default(),
+                ready,
+            ));
+        }
+
+        if started > ready {
+            events.push(span("waiting for lock", "wait", tid, ready, started));
+        }
+
+        let mut event = span(&result.name, "step", tid, started, finished);
+
+        event["args"] = json!({
+            "pass": result.pass,
+            "error": result.error,
+            "skipped": result.skipped,
+            "attempts": result.attempts,
+        });
+
+        events.push(event);
+    }
+
+    let trace = json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms"
+    });
+
+    serde_json::to_writer_pretty(File::create(file_path)?, &trace)?;
+
+    Ok(())
+}
+
+fn span(name: &str, category: &str, tid: usize, start: Duration, end: Duration) -> Value {
+    json!({
+        "name": name,
+        "cat": category,
+        "ph": "X",
+        "pid": 1,
+        "tid": tid,
+        "ts": start.as_micros() as u64,
+        "dur": end.saturating_sub(start).as_micros() as u64,
+    })
+}
@@ -1,25 +1,43 @@
+use crate::include::{IncludeList, IncludeOptions, Source};
 use crate::step::FilterType;
 use crate::step::RegexVariant;
+use crate::step::WORKDIR_PLACEHOLDER;
+use crate::timezone::register_templates;
 use std::fs::File;
 
+use lazy_static::lazy_static;
 use log::debug;
+use regex::Regex;
+use schemars::gen::SchemaSettings;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use serde_yaml::{self, Value};
+use semver::{Version, VersionReq};
+use serde_yaml::{self, Mapping, Value};
 use tera::{Context, Tera};
 
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::{anyhow, Error};
 use std::io::Read;
 
 use crate::step::{
-    BashVariant, DiskVariant, ExpectType, HttpVariant, Requirement, RetryPolicy, RunType, Step,
-    SystemVariant,
+    relative_to, AwsHealthVariant, AzureVariant, BashVariant, BenchmarkVariant, BrowserVariant,
+    CertFileVariant, CloudMetadataVariant, ConsulVariant, CrawlVariant, DiskVariant, DnsVariant,
+    EtcdVariant, EventLogVariant, ExpectType, GcpVariant, GithubStatusVariant, GroupStep,
+    GroupVariant, GrpcVariant, HeadersAuditVariant, HostGuard, HttpVariant, KubernetesVariant,
+    ListeningVariant, MailflowVariant, ManualVariant, MatchesFileVariant, OAuth2Variant,
+    PermVariant, PromptVariant, ProxyVariant, RedfishVariant, Requirement, RetryPolicy, RunType,
+    SnapshotVariant, StatuspageVariant, Step, SystemVariant, TimeVariant, TracerouteVariant,
+    TransferVariant, WasmVariant, WmiVariant,
 };
 use linked_hash_map::LinkedHashMap;
 
-#[derive(Debug, PartialEq, Deserialize)]
+//Stops include cycles from recursing forever
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 struct StepYaml {
     description: Option<String>,
     value: Option<String>,
@@ -28,13 +46,56 @@ struct StepYaml {
     http: Option<HttpVariant>,
     system: Option<SystemVariant>,
     disk: Option<DiskVariant>,
+    consul: Option<ConsulVariant>,
+    etcd: Option<EtcdVariant>,
+    certfile: Option<CertFileVariant>,
+    listening: Option<ListeningVariant>,
+    time: Option<TimeVariant>,
+    perm: Option<PermVariant>,
+    crawl: Option<CrawlVariant>,
+    headers_audit: Option<HeadersAuditVariant>,
+    wmi: Option<WmiVariant>,
+    redfish: Option<RedfishVariant>,
+    azure: Option<AzureVariant>,
+    gcp: Option<GcpVariant>,
+    transfer: Option<TransferVariant>,
+    dns: Option<DnsVariant>,
+    mailflow: Option<MailflowVariant>,
+    browser: Option<BrowserVariant>,
+    snapshot: Option<SnapshotVariant>,
+    wasm: Option<WasmVariant>,
+    grpc: Option<GrpcVariant>,
+    kubernetes: Option<KubernetesVariant>,
+    prompt: Option<PromptVariant>,
+    manual: Option<ManualVariant>,
+    benchmark: Option<BenchmarkVariant>,
+    proxy: Option<ProxyVariant>,
+    traceroute: Option<TracerouteVariant>,
+    cloud_metadata: Option<CloudMetadataVariant>,
+    statuspage_io: Option<StatuspageVariant>,
+    github_status: Option<GithubStatusVariant>,
+    aws_health: Option<AwsHealthVariant>,
+    eventlog: Option<EventLogVariant>,
+    oauth2: Option<OAuth2Variant>,
+    //Steps run one after the other, by name.  Only their run type, filters & expectation are used
+    #[schemars(with = "Option<std::collections::HashMap<String, StepYaml>>")]
+    group: Option<LinkedHashMap<String, StepYaml>>,
     matches: Option<String>,
     matches_not: Option<String>,
+    equals: Option<String>,
+    matches_file: Option<MatchesFileVariant>,
+    compare_to: Option<String>,
     #[serde(default)]
     filters: Vec<FilterType>,
     jmespath: Option<String>,
     regex: Option<RegexVariant>,
     do_output: Option<bool>,
+    sensitive: Option<bool>,
+    binary: Option<bool>,
+    report: Option<bool>,
+    lock: Option<String>,
+    requires_os: Option<Requirement>,
+    requires_command: Option<Requirement>,
     less_than: Option<String>,
     greater_than: Option<String>,
     retry_count: Option<usize>,
@@ -42,7 +103,23 @@ struct StepYaml {
     delay_ms: Option<usize>,
     on_fail: Option<RunType>,
     require: Option<Requirement>,
+    //Require every step with one of these tags
+    require_tag: Option<Requirement>,
     required_by: Option<Requirement>,
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, serde_json::Value>")]
+    meta: LinkedHashMap<String, Value>,
+    tags: Option<Requirement>,
+    //Files to attach to the outcome by name, i.e, `screenshot: "{{workdir}}/screenshot.png"`
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, String>")]
+    attachments: LinkedHashMap<String, String>,
+    //Run at most this often on an interval, i.e, `10m`
+    every: Option<String>,
+    //Run once for all the identical setup steps, i.e, a login step in a loop
+    setup: Option<bool>,
+    #[serde(skip)]
+    source: Option<Value>,
 }
 
 fn get_retry_policy(step: &StepYaml) -> RetryPolicy {
@@ -78,6 +155,144 @@ fn get_runtype(step: &StepYaml) -> RunType {
         return RunType::Disk(variant.clone());
     }
 
+    if let Some(ref variant) = step.consul {
+        return RunType::Consul(variant.clone());
+    }
+
+    if let Some(ref variant) = step.etcd {
+        return RunType::Etcd(variant.clone());
+    }
+
+    if let Some(ref variant) = step.certfile {
+        return RunType::CertFile(variant.clone());
+    }
+
+    if let Some(ref variant) = step.listening {
+        return RunType::Listening(variant.clone());
+    }
+
+    if let Some(ref variant) = step.time {
+        return RunType::Time(variant.clone());
+    }
+
+    if let Some(ref variant) = step.perm {
+        return RunType::Perm(variant.clone());
+    }
+
+    if let Some(ref variant) = step.crawl {
+        return RunType::Crawl(variant.clone());
+    }
+
+    if let Some(ref variant) = step.headers_audit {
+        return RunType::HeadersAudit(variant.clone());
+    }
+
+    if let Some(ref variant) = step.wmi {
+        return RunType::Wmi(variant.clone());
+    }
+
+    if let Some(ref variant) = step.redfish {
+        return RunType::Redfish(variant.clone());
+    }
+
+    if let Some(ref variant) = step.azure {
+        return RunType::Azure(variant.clone());
+    }
+
+    if let Some(ref variant) = step.gcp {
+        return RunType::Gcp(variant.clone());
+    }
+
+    if let Some(ref variant) = step.transfer {
+        return RunType::Transfer(variant.clone());
+    }
+
+    if let Some(ref variant) = step.dns {
+        return RunType::Dns(variant.clone());
+    }
+
+    if let Some(ref variant) = step.mailflow {
+        return RunType::Mailflow(variant.clone());
+    }
+
+    if let Some(ref variant) = step.browser {
+        return RunType::Browser(variant.clone());
+    }
+
+    if let Some(ref variant) = step.snapshot {
+        return RunType::Snapshot(variant.clone());
+    }
+
+    if let Some(ref variant) = step.wasm {
+        return RunType::Wasm(variant.clone());
+    }
+
+    if let Some(ref variant) = step.grpc {
+        return RunType::Grpc(variant.clone());
+    }
+
+    if let Some(ref variant) = step.kubernetes {
+        return RunType::Kubernetes(variant.clone());
+    }
+
+    if let Some(ref variant) = step.prompt {
+        return RunType::Prompt(variant.clone());
+    }
+
+    if let Some(ref variant) = step.manual {
+        return RunType::Manual(variant.clone());
+    }
+
+    if let Some(ref variant) = step.benchmark {
+        return RunType::Benchmark(variant.clone());
+    }
+
+    if let Some(ref variant) = step.proxy {
+        return RunType::Proxy(variant.clone());
+    }
+
+    if let Some(ref variant) = step.traceroute {
+        return RunType::Traceroute(variant.clone());
+    }
+
+    if let Some(ref variant) = step.cloud_metadata {
+        return RunType::CloudMetadata(variant.clone());
+    }
+
+    if let Some(ref variant) = step.statuspage_io {
+        return RunType::Statuspage(variant.clone());
+    }
+
+    if let Some(ref variant) = step.github_status {
+        return RunType::GithubStatus(variant.clone());
+    }
+
+    if let Some(ref variant) = step.aws_health {
+        return RunType::AwsHealth(variant.clone());
+    }
+
+    if let Some(ref variant) = step.eventlog {
+        return RunType::EventLog(variant.clone());
+    }
+
+    if let Some(ref variant) = step.oauth2 {
+        return RunType::OAuth2(variant.clone());
+    }
+
+    if let Some(ref group) = step.group {
+        return RunType::Group(GroupVariant {
+            steps: group
+                .iter()
+                .map(|(name, step)| GroupStep {
+                    name: name.clone(),
+                    run: get_runtype(step),
+                    filters: get_filters(step),
+                    expect: get_expecttype(step),
+                })
+                .collect(),
+        });
+    }
+
     RunType::Value(step.value.clone().unwrap_or_default())
 }
 
@@ -90,6 +305,10 @@ fn get_expecttype(step: &StepYaml) -> ExpectType {
         return ExpectType::MatchesNot(string.clone());
     }
 
+    if let Some(ref string) = step.equals {
+        return ExpectType::Equals(string.clone());
+    }
+
     if let Some(ref string) = step.greater_than {
         return ExpectType::GreaterThan(string.parse().expect("Could not parse number"));
     }
@@ -98,9 +317,60 @@ fn get_expecttype(step: &StepYaml) -> ExpectType {
         return ExpectType::LessThan(string.parse().expect("Could not parse number"));
     }
 
+    if let Some(ref variant) = step.matches_file {
+        return ExpectType::MatchesFile(variant.clone());
+    }
+
+    if let Some(ref file) = step.compare_to {
+        return ExpectType::CompareTo(file.clone());
+    }
+
     ExpectType::Anything
 }
 
+// Meta values can be any scalar, but are always reported as strings
+fn get_meta(step: &StepYaml) -> Result<LinkedHashMap<String, String>, Error> {
+    step.meta
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(val) => val.clone(),
+                Value::Number(val) => val.to_string(),
+                Value::Bool(val) => val.to_string(),
+                Value::Null => String::new(),
+                _ => return Err(anyhow!("Meta value for `{}` must be a scalar", key)),
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
+// Parses a duration like `30s`, `10m`, `1h` or `1d`.  Plain numbers are seconds
+fn parse_duration(input: &str) -> Result<Duration, Error> {
+    let input = input.trim();
+
+    let (number, unit) = input.split_at(
+        input
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(input.len()),
+    );
+
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("Invalid duration `{}`, expected i.e, `10m`", input)),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("Invalid duration `{}`, expected i.e, `10m`", input))
+}
+
 fn get_filters(step: &StepYaml) -> Vec<FilterType> {
     let mut filters: Vec<FilterType> = step.filters.clone();
 
@@ -116,31 +386,122 @@ fn get_filters(step: &StepYaml) -> Vec<FilterType> {
 }
 
 pub fn get_steps_raw<T: Serialize>(yaml_contents: &str, context: &T) -> Result<Vec<Step>, Error> {
-    let mut tera = Tera::default();
+    get_steps_from(
+        yaml_contents,
+        context,
+        None,
+        &IncludeOptions::default(),
+        &serde_json::Value::Null,
+    )
+}
 
-    tera.add_raw_template("test_plan", yaml_contents)?;
+fn get_steps_from<T: Serialize>(
+    yaml_contents: &str,
+    context: &T,
+    source: Option<&Source>,
+    options: &IncludeOptions,
+    previous: &serde_json::Value,
+) -> Result<Vec<Step>, Error> {
+    let http_defaults = get_http_defaults(context)?;
 
-    let test_plan_yaml = tera.render("test_plan", &Context::from_serialize(context)?)?;
+    let mut context = Context::from_serialize(context)?;
 
-    debug!("YAML output:\n{}", test_plan_yaml);
+    //The workdir is unique per step, so it's substituted when each step is run
+    if !context.contains_key("workdir") {
+        context.insert("workdir", WORKDIR_PLACEHOLDER);
+    }
+
+    //The results of the previous run by step name, which is empty if there wasn't one
+    if !context.contains_key("previous") {
+        match previous {
+            serde_json::Value::Null => context.insert("previous", &serde_json::Map::new()),
+            previous => context.insert("previous", previous),
+        }
+    }
 
-    let input_steps: LinkedHashMap<String, StepYaml> = serde_yaml::from_str(&test_plan_yaml)?;
+    let input_steps = parse_plan(yaml_contents, &context, &http_defaults, source, options, 0)?;
     let mut steps: Vec<Step> = Vec::new();
 
+    //The outputs of steps within groups are shared with the whole plan, so can't clash with other steps
+    for (name, step) in input_steps.iter() {
+        for group_step in step.group.iter().flat_map(|group| group.keys()) {
+            if input_steps.contains_key(group_step) {
+                return Err(anyhow!(
+                    "Step `{}`: group step `{}` has the same name as another step",
+                    name,
+                    group_step
+                ));
+            }
+        }
+    }
+
+    //The tags each step requires, by index, which are resolved to names once all the steps are known
+    let mut require_tags = Vec::new();
+
     for (name, step) in input_steps {
         let run = get_runtype(&step);
 
+        //Checked up front, so a typo in `status` fails the plan rather than every run of the step
+        match run {
+            RunType::Http(ref http) => http.check_status(),
+            RunType::Benchmark(ref benchmark) => benchmark.check_status(),
+            _ => Ok(()),
+        }
+        .map_err(|err| anyhow!("Step `{}`: {}", name, err))?;
+
         let expect = get_expecttype(&step);
 
         let filters = get_filters(&step);
 
         let retry_policy = get_retry_policy(&step);
 
+        let meta = get_meta(&step).map_err(|err| anyhow!("Step `{}`: {}", name, err))?;
+
+        let every = step
+            .every
+            .as_deref()
+            .map(parse_duration)
+            .transpose()
+            .map_err(|err| anyhow!("Step `{}`: {}", name, err))?;
+
+        //Secret answers & access tokens are hidden unless the step says otherwise
+        let sensitive = step.sensitive.unwrap_or_else(|| {
+            step.oauth2.is_some()
+                || step
+                    .prompt
+                    .as_ref()
+                    .map(PromptVariant::is_secret)
+                    .unwrap_or(false)
+        });
+
+        require_tags.push(
+            step.require_tag
+                .as_ref()
+                .map(|tags| tags.to_vec())
+                .unwrap_or_default(),
+        );
+
         steps.push(Step {
             name,
             run,
             on_fail: step.on_fail,
             do_output: step.do_output.unwrap_or(true),
+            sensitive,
+            binary: step.binary.unwrap_or(false),
+            report: step.report.unwrap_or(true),
+            lock: step.lock.clone(),
+            guard: HostGuard {
+                os: step
+                    .requires_os
+                    .as_ref()
+                    .map(|os| os.to_vec())
+                    .unwrap_or_default(),
+                commands: step
+                    .requires_command
+                    .as_ref()
+                    .map(|command| command.to_vec())
+                    .unwrap_or_default(),
+            },
             expect,
             description: step.description,
             filters,
@@ -154,16 +515,500 @@ pub fn get_steps_raw<T: Serialize>(yaml_contents: &str, context: &T) -> Result<V
                 .required_by
                 .map(|require| require.to_vec())
                 .unwrap_or_default(),
+            meta,
+            tags: step.tags.map(|tags| tags.to_vec()).unwrap_or_default(),
+            attachments: step.attachments,
+            every,
+            setup: step.setup.unwrap_or(false),
+            source: step.source,
         });
     }
 
+    for (i, tags) in require_tags.into_iter().enumerate() {
+        for tag in tags {
+            let tagged: Vec<String> = steps
+                .iter()
+                .enumerate()
+                .filter(|(j, step)| *j != i && step.tags.contains(&tag))
+                .map(|(_, step)| step.name.clone())
+                .collect();
+
+            //Most likely a typo, which would otherwise silently run the step straight away
+            if tagged.is_empty() {
+                return Err(anyhow!(
+                    "Step `{}`: no steps have the tag `{}` from `require_tag`",
+                    steps[i].name,
+                    tag
+                ));
+            }
+
+            for name in tagged {
+                if !steps[i].require.contains(&name) {
+                    steps[i].require.push(name);
+                }
+            }
+        }
+    }
+
+    Ok(steps)
+}
+
+// The JSON schema of a test plan, generated from the types steps are parsed into
+pub fn plan_schema() -> serde_json::Value {
+    let mut generator = SchemaSettings::draft07().into_generator();
+
+    let include = generator.subschema_for::<IncludeList>();
+    let step = generator.subschema_for::<StepYaml>();
+
+    let mut schema = serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("lorikeet {} test plan", env!("CARGO_PKG_VERSION")),
+        "type": "object",
+        "properties": {
+            "include": include,
+            "lorikeet_version": {
+                "description": "The lorikeet version the plan needs, either a minimum version like `0.16` or a requirement like `>=0.16, <1`",
+                "type": "string",
+            },
+        },
+        "additionalProperties": step,
+    });
+
+    schema["definitions"] = serde_json::json!(generator.definitions());
+
+    allow_scalar_strings(&mut schema);
+
+    schema
+}
+
+//YAML scalars such as `yes` or `5` are read into string fields as is, so the schema shouldn't reject them
+fn allow_scalar_strings(schema: &mut serde_json::Value) {
+    use serde_json::Value as Json;
+
+    match schema {
+        Json::Object(object) => {
+            if !object.contains_key("enum") {
+                let types = match object.get("type") {
+                    Some(Json::String(kind)) if kind == "string" => Some(vec![]),
+                    Some(Json::Array(types)) if types.contains(&"string".into()) => {
+                        Some(types.clone())
+                    }
+                    _ => None,
+                };
+
+                if let Some(mut types) = types {
+                    for kind in ["string", "number", "boolean"] {
+                        if !types.contains(&kind.into()) {
+                            types.push(kind.into());
+                        }
+                    }
+                    object.insert("type".into(), Json::Array(types));
+                }
+            }
+
+            object.values_mut().for_each(allow_scalar_strings);
+        }
+        Json::Array(values) => values.iter_mut().for_each(allow_scalar_strings),
+        _ => (),
+    }
+}
+
+// Renders & parses a test plan, with the steps of any includes coming before its own
+fn parse_plan(
+    yaml_contents: &str,
+    context: &Context,
+    http_defaults: &HttpDefaults,
+    source: Option<&Source>,
+    options: &IncludeOptions,
+    depth: usize,
+) -> Result<LinkedHashMap<String, StepYaml>, Error> {
+    let mut tera = Tera::default();
+    register_templates(&mut tera);
+
+    tera.add_raw_template("test_plan", yaml_contents)?;
+
+    let test_plan_yaml = tera.render("test_plan", context)?;
+
+    debug!("YAML output:\n{}", test_plan_yaml);
+
+    let mut plan: Mapping = serde_yaml::from_str(&test_plan_yaml)?;
+    let mut steps = LinkedHashMap::new();
+
+    //Checked before anything else, so plans using newer options don't fail with a confusing parse error
+    if let Some(required) = plan.remove(&Value::String("lorikeet_version".into())) {
+        check_version(&required, &test_plan_yaml, env!("CARGO_PKG_VERSION"))?;
+    }
+
+    if let Some(includes) = plan.remove(&Value::String("include".into())) {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!(
+                "Includes are nested more than {} deep",
+                MAX_INCLUDE_DEPTH
+            ));
+        }
+
+        let includes: IncludeList = serde_yaml::from_value(includes)
+            .map_err(|err| anyhow!("Could not parse `include`: {}", err))?;
+
+        for include in includes.into_vec() {
+            let included = include.source(source)?;
+
+            debug!("Including steps from `{}`", included);
+
+            let contents = include.load(&included, options)?;
+
+            let included_steps = parse_plan(
+                &contents,
+                context,
+                http_defaults,
+                Some(&included),
+                options,
+                depth + 1,
+            )
+            .map_err(|err| anyhow!("Could not parse include `{}`: {}", included, err))?;
+
+            steps.extend(included_steps);
+        }
+    }
+
+    apply_http_defaults(&mut plan, http_defaults)?;
+
+    let sources = plan.clone();
+
+    //Steps in this plan override any included steps with the same name
+    let mut own_steps: LinkedHashMap<String, StepYaml> =
+        serde_yaml::from_value(Value::Mapping(plan))?;
+
+    for (name, step) in own_steps.iter_mut() {
+        step.source = sources
+            .get(&Value::String(name.clone()))
+            .filter(|source| uses_workdir(source))
+            .cloned();
+    }
+
+    //Expected files live next to the plan that references them
+    if let Some(Source::Local(path)) = source {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        for (_, step) in own_steps.iter_mut() {
+            if let Some(ref mut variant) = step.matches_file {
+                variant.relative_to(dir);
+            }
+
+            if let Some(ref mut file) = step.compare_to {
+                relative_to(file, dir);
+            }
+
+            for (_, group_step) in step.group.iter_mut().flatten() {
+                if let Some(ref mut variant) = group_step.matches_file {
+                    variant.relative_to(dir);
+                }
+
+                if let Some(ref mut file) = group_step.compare_to {
+                    relative_to(file, dir);
+                }
+            }
+        }
+    }
+
+    steps.extend(own_steps);
+
     Ok(steps)
 }
 
+// Whether any of the step's values use `{{workdir}}`
+fn uses_workdir(value: &Value) -> bool {
+    match value {
+        Value::String(string) => string.contains(WORKDIR_PLACEHOLDER),
+        Value::Sequence(values) => values.iter().any(uses_workdir),
+        Value::Mapping(mapping) => mapping.iter().any(|(_, value)| uses_workdir(value)),
+        _ => false,
+    }
+}
+
+// Substitutes the workdir into the step's values, quoting it where it's part of a bash command
+fn replace_workdir(value: &mut Value, workdir: &str, quoted: &str) {
+    match value {
+        Value::String(string) => *string = string.replace(WORKDIR_PLACEHOLDER, workdir),
+        Value::Sequence(values) => {
+            for value in values.iter_mut() {
+                replace_workdir(value, workdir, quoted);
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                match key.as_str() {
+                    Some("bash") => replace_workdir(value, quoted, quoted),
+                    _ => replace_workdir(value, workdir, quoted),
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Parses the run types of a step again from its source, with `{{workdir}}` replaced by the step's own workdir
+pub fn with_workdir(source: &Value, workdir: &Path) -> Result<(RunType, Option<RunType>), String> {
+    let workdir = workdir.to_string_lossy();
+
+    let quoted = shlex::try_quote(&workdir).map_err(|err| format!("{}", err))?;
+
+    let mut source = source.clone();
+    replace_workdir(&mut source, &workdir, &quoted);
+
+    let step: StepYaml = serde_yaml::from_value(source).map_err(|err| format!("{}", err))?;
+
+    Ok((get_runtype(&step), step.on_fail))
+}
+
+lazy_static! {
+    static ref VERSION_LINE: Regex = Regex::new(r"(?m)^lorikeet_version:\s*([0-9.]+)\s*$").unwrap();
+}
+
+// Checks this version of lorikeet meets the plan's `lorikeet_version`, either a minimum version or a requirement
+fn check_version(required: &Value, plan: &str, current: &str) -> Result<(), Error> {
+    let required = match required {
+        Value::String(required) => required.trim().to_string(),
+        //Unquoted versions like `0.20` are parsed as numbers, which would lose the `0`, so the text is used instead
+        Value::Number(_) => VERSION_LINE
+            .captures(plan)
+            .map(|captures| captures[1].to_string())
+            .ok_or_else(|| anyhow!("`lorikeet_version` must be a version, i.e, `0.16`"))?,
+        _ => return Err(anyhow!("`lorikeet_version` must be a version, i.e, `0.16`")),
+    };
+
+    //A bare version is the minimum, rather than semver's default of compatible versions
+    let requirement = match required.starts_with(|ch: char| ch.is_ascii_digit()) {
+        true => format!(">={}", required),
+        false => required.clone(),
+    };
+
+    let requirement = VersionReq::parse(&requirement)
+        .map_err(|err| anyhow!("Invalid `lorikeet_version` `{}`: {}", required, err))?;
+
+    let current = Version::parse(current)?;
+
+    if !requirement.matches(&current) {
+        return Err(anyhow!(
+            "This plan needs lorikeet `{}`, but this is lorikeet `{}`.  Upgrade lorikeet to run it",
+            required,
+            current
+        ));
+    }
+
+    Ok(())
+}
+
+// Options from the config that http steps take as defaults
+#[derive(Default)]
+struct HttpDefaults {
+    //The `hosts` section, for steps that reference a host with `host: <name>`
+    hosts: Mapping,
+    //The `proxy` for every http step
+    proxy: Option<Value>,
+    //The rate limit for every http step, per host
+    max_requests_per_second: Option<Value>,
+}
+
+impl HttpDefaults {
+    // The options that are set on every http step
+    fn step_defaults(&self) -> impl Iterator<Item = (&'static str, &Value)> {
+        vec![
+            ("proxy", &self.proxy),
+            ("max_requests_per_second", &self.max_requests_per_second),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| (key, value)))
+    }
+}
+
+fn get_http_defaults<T: Serialize>(context: &T) -> Result<HttpDefaults, Error> {
+    let mut config = match serde_yaml::to_value(context)? {
+        Value::Mapping(config) => config,
+        _ => return Ok(HttpDefaults::default()),
+    };
+
+    let hosts = match config.remove(&Value::String("hosts".into())) {
+        None | Some(Value::Null) => Mapping::new(),
+        Some(Value::Mapping(hosts)) => hosts,
+        Some(_) => return Err(anyhow!("`hosts` must be a map of host names to options")),
+    };
+
+    let mut default = |key: &str| match config.remove(&Value::String(key.into())) {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(value),
+    };
+
+    Ok(HttpDefaults {
+        hosts,
+        proxy: default("proxy"),
+        max_requests_per_second: default("max_requests_per_second"),
+    })
+}
+
+// Fills in options for http steps from the config: from the host a step references with `host: <name>`, then the
+// `proxy` & `max_requests_per_second`.  Options set on the step take precedence
+fn apply_http_defaults(plan: &mut Mapping, defaults: &HttpDefaults) -> Result<(), Error> {
+    for (name, step) in plan.iter_mut() {
+        let name = name.as_str().unwrap_or_default();
+
+        //Steps within groups can reference hosts too
+        if let Some(Value::Mapping(group)) = step.get_mut("group") {
+            apply_http_defaults(group, defaults)?;
+        }
+
+        //Steps with only a url still need the defaults
+        if let (Some(_), Value::Mapping(step)) = (defaults.step_defaults().next(), &mut *step) {
+            let key = Value::String("http".into());
+
+            if let Some(Value::String(url)) = step.get(&key) {
+                let mut http = Mapping::new();
+                http.insert(Value::String("url".into()), Value::String(url.clone()));
+                step.insert(key, Value::Mapping(http));
+            }
+        }
+
+        let http = match step {
+            Value::Mapping(step) => match step.get_mut(&Value::String("http".into())) {
+                Some(Value::Mapping(http)) => http,
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        if let Some(host) = http.remove(&Value::String("host".into())) {
+            apply_host(name, http, &host, &defaults.hosts)?;
+        }
+
+        for (key, value) in defaults.step_defaults() {
+            let key = Value::String(key.into());
+
+            if !http.contains_key(&key) {
+                http.insert(key, value.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Options that set how a request is authenticated
+const AUTH_OPTIONS: &[&str] = &["user", "pass", "bearer", "digest", "aws_auth"];
+
+// Fills in the options of the host the step references
+fn apply_host(name: &str, http: &mut Mapping, host: &Value, hosts: &Mapping) -> Result<(), Error> {
+    let defaults = match hosts.get(host) {
+        Some(Value::Mapping(defaults)) => defaults,
+        _ => {
+            return Err(anyhow!(
+                "Step `{}`: host `{}` is not defined in `hosts`",
+                name,
+                host.as_str().unwrap_or_default()
+            ))
+        }
+    };
+
+    //A step with its own credentials doesn't get any from the host, as only one kind of auth can be used
+    let step_auth = AUTH_OPTIONS
+        .iter()
+        .any(|option| http.contains_key(&Value::String(option.to_string())));
+
+    for (key, value) in defaults.iter() {
+        match key.as_str().unwrap_or_default() {
+            option if step_auth && AUTH_OPTIONS.contains(&option) => (),
+            "base_url" => {
+                let base_url = value.as_str().unwrap_or_default().trim_end_matches('/');
+
+                let url = match http.get(&Value::String("url".into())) {
+                    Some(Value::String(url)) if url.contains("://") => url.clone(),
+                    Some(Value::String(url)) => {
+                        format!("{}/{}", base_url, url.trim_start_matches('/'))
+                    }
+                    _ => base_url.to_string(),
+                };
+
+                http.insert(Value::String("url".into()), Value::String(url));
+            }
+            "headers" => {
+                let mut headers = value.as_mapping().cloned().unwrap_or_default();
+
+                if let Some(Value::Mapping(step_headers)) = http.get(key) {
+                    for (header, val) in step_headers.iter() {
+                        headers.insert(header.clone(), val.clone());
+                    }
+                }
+
+                http.insert(key.clone(), Value::Mapping(headers));
+            }
+            "user"
+            | "pass"
+            | "verify_ssl"
+            | "timeout_ms"
+            | "session"
+            | "client_cert"
+            | "client_key"
+            | "proxy"
+            | "resolve"
+            | "aws_auth"
+            | "bearer"
+            | "digest"
+            | "trace"
+            | "max_requests_per_second" => {
+                if !http.contains_key(key) {
+                    http.insert(key.clone(), value.clone());
+                }
+            }
+            other => {
+                return Err(anyhow!(
+                    "Host `{}` has an unknown option `{}`",
+                    host.as_str().unwrap_or_default(),
+                    other
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A top level section of the config file, for settings that aren't part of the plan, i.e, `notify`
+pub fn config_section<P: AsRef<Path>>(
+    config_path: Option<P>,
+    key: &str,
+) -> Result<Option<Value>, Error> {
+    let path = match config_path {
+        Some(ref path) => path.as_ref(),
+        None => return Ok(None),
+    };
+
+    let file =
+        File::open(path).map_err(|err| anyhow!("Could not open config {:?}: {}", path, err))?;
+
+    let config: Value = serde_yaml::from_reader(file)
+        .map_err(|err| anyhow!("Could not parse config {:?} as YAML: {}", path, err))?;
+
+    Ok(config.get(key).cloned())
+}
+
 //We use P & Q here so that when specialising file path and config path can be different types, i.e, a &str & Option<String> for instance..
 pub fn get_steps<P: AsRef<Path>, Q: AsRef<Path>>(
     file_path: P,
     config_path: &Option<Q>,
+) -> Result<Vec<Step>, Error> {
+    get_steps_with_options(
+        file_path,
+        config_path,
+        &IncludeOptions::default(),
+        &serde_json::Value::Null,
+    )
+}
+
+// Loads the steps of a plan, with the results of the `previous` run by step name available to templates
+pub fn get_steps_with_options<P: AsRef<Path>, Q: AsRef<Path>>(
+    file_path: P,
+    config_path: &Option<Q>,
+    options: &IncludeOptions,
+    previous: &serde_json::Value,
 ) -> Result<Vec<Step>, Error> {
     let mut file_contents = String::new();
 
@@ -174,6 +1019,8 @@ pub fn get_steps<P: AsRef<Path>, Q: AsRef<Path>>(
 
     f.read_to_string(&mut file_contents)?;
 
+    let source = Source::Local(path_ref.to_path_buf());
+
     match *config_path {
         Some(ref path) => {
             let c = File::open(path)?;
@@ -186,10 +1033,422 @@ pub fn get_steps<P: AsRef<Path>, Q: AsRef<Path>>(
                 )
             })?;
 
-            get_steps_raw(&file_contents, &value)
+            get_steps_from(&file_contents, &value, Some(&source), options, previous)
                 .map_err(|err| anyhow!("Could not parse file {:?}: {}", path_ref, err))
         }
-        None => get_steps_raw(&file_contents, &Value::Mapping(serde_yaml::Mapping::new()))
-            .map_err(|err| anyhow!("Could not parse file {:?}: {}", path_ref, err)),
+        None => get_steps_from(
+            &file_contents,
+            &Value::Mapping(serde_yaml::Mapping::new()),
+            Some(&source),
+            options,
+            previous,
+        )
+        .map_err(|err| anyhow!("Could not parse file {:?}: {}", path_ref, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_is_up_to_date() {
+        //Run with `UPDATE_SCHEMA=1` after changing the plan format
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("schema/plan.schema.json");
+        let generated = format!(
+            "{}\n",
+            serde_json::to_string_pretty(&plan_schema()).unwrap()
+        );
+
+        if std::env::var("UPDATE_SCHEMA").is_ok() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+
+        let published = std::fs::read_to_string(&path).unwrap_or_default();
+
+        assert!(
+            published == generated,
+            "`schema/plan.schema.json` is out of date, run the tests with `UPDATE_SCHEMA=1`"
+        );
+    }
+
+    #[test]
+    fn checks_lorikeet_version() {
+        let check = |plan: &str| {
+            let parsed: Value = serde_yaml::from_str(plan).unwrap();
+            check_version(&parsed["lorikeet_version"], plan, "0.20.0")
+        };
+
+        assert!(check("lorikeet_version: 0.20").is_ok());
+        assert!(check("lorikeet_version: \">=0.14, <1\"").is_ok());
+
+        assert_eq!(
+            check("lorikeet_version: 0.20.1").unwrap_err().to_string(),
+            "This plan needs lorikeet `0.20.1`, but this is lorikeet `0.20.0`.  Upgrade lorikeet to run it"
+        );
+
+        assert!(check("lorikeet_version: \"<0.20\"").is_err());
+        assert!(check("lorikeet_version: latest").is_err());
+    }
+
+    #[test]
+    fn requires_tagged_steps() {
+        let steps = get_steps_raw(
+            r#"
+migrate:
+  value: done
+  tags: database
+seed:
+  value: done
+  tags: [database, fixtures]
+cache:
+  value: done
+report:
+  value: done
+  require: cache
+  require_tag: database
+"#,
+            &Value::Mapping(Mapping::new()),
+        )
+        .unwrap();
+
+        assert_eq!(steps[3].require, vec!["cache", "migrate", "seed"]);
+
+        let missing = get_steps_raw(
+            "report:\n  value: done\n  require_tag: databse\n",
+            &Value::Mapping(Mapping::new()),
+        );
+
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "Step `report`: no steps have the tag `databse` from `require_tag`"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_statuses() {
+        let invalid = get_steps_raw(
+            "health:\n  http:\n    url: https://example.com\n    status: 2yy\n",
+            &Value::Mapping(Mapping::new()),
+        );
+
+        assert!(invalid
+            .unwrap_err()
+            .to_string()
+            .starts_with("Step `health`: Invalid status `2yy`"));
+    }
+
+    #[test]
+    fn http_host_defaults() {
+        let config: Value = serde_yaml::from_str(
+            r#"
+hosts:
+  api:
+    base_url: https://api.example.com/v1/
+    headers:
+      Authorization: Bearer abc
+      Accept: application/json
+    timeout_ms: 5000
+"#,
+        )
+        .unwrap();
+
+        let with_host = get_steps_raw(
+            r#"
+health:
+  http:
+    host: api
+    url: /health
+    headers:
+      Accept: text/plain
+"#,
+            &config,
+        )
+        .unwrap();
+
+        let expanded = get_steps_raw(
+            r#"
+health:
+  http:
+    url: https://api.example.com/v1/health
+    timeout_ms: 5000
+    headers:
+      Authorization: Bearer abc
+      Accept: text/plain
+"#,
+            &Value::Mapping(Mapping::new()),
+        )
+        .unwrap();
+
+        assert_eq!(with_host[0].run, expanded[0].run);
+    }
+
+    #[test]
+    fn http_host_resolve() {
+        let config: Value = serde_yaml::from_str(
+            r#"
+hosts:
+  api:
+    base_url: https://api.example.com
+    resolve:
+      api.example.com: 10.0.0.5
+"#,
+        )
+        .unwrap();
+
+        let with_host = get_steps_raw(
+            r#"
+health:
+  http:
+    host: api
+"#,
+            &config,
+        )
+        .unwrap();
+
+        let expanded = get_steps_raw(
+            r#"
+health:
+  http:
+    url: https://api.example.com
+    resolve:
+      api.example.com: 10.0.0.5
+"#,
+            &Value::Mapping(Mapping::new()),
+        )
+        .unwrap();
+
+        assert_eq!(with_host[0].run, expanded[0].run);
+    }
+
+    #[test]
+    fn plan_wide_rate_limit() {
+        let config: Value = serde_yaml::from_str(
+            r#"
+max_requests_per_second: 5
+hosts:
+  payments:
+    base_url: https://payments.example.com
+    max_requests_per_second: 1
+"#,
+        )
+        .unwrap();
+
+        let with_defaults = get_steps_raw(
+            r#"
+home:
+  http: https://www.example.com
+payments:
+  http:
+    host: payments
+search:
+  http:
+    url: https://search.example.com
+    max_requests_per_second: 10
+"#,
+            &config,
+        )
+        .unwrap();
+
+        let expanded = get_steps_raw(
+            r#"
+home:
+  http:
+    url: https://www.example.com
+    max_requests_per_second: 5
+payments:
+  http:
+    url: https://payments.example.com
+    max_requests_per_second: 1
+search:
+  http:
+    url: https://search.example.com
+    max_requests_per_second: 10
+"#,
+            &Value::Mapping(Mapping::new()),
+        )
+        .unwrap();
+
+        let runs = |steps: &[Step]| {
+            let mut runs: Vec<(String, RunType)> = steps
+                .iter()
+                .map(|step| (step.name.clone(), step.run.clone()))
+                .collect();
+            runs.sort_by(|a, b| a.0.cmp(&b.0));
+            runs
+        };
+
+        assert_eq!(runs(&with_defaults), runs(&expanded));
+    }
+
+    #[test]
+    fn step_auth_replaces_host_auth() {
+        let config: Value = serde_yaml::from_str(
+            r#"
+hosts:
+  api:
+    base_url: https://api.example.com
+    user: admin
+    pass: hunter2
+"#,
+        )
+        .unwrap();
+
+        let with_host = get_steps_raw(
+            r#"
+health:
+  http:
+    host: api
+    bearer: abc123
+"#,
+            &config,
+        )
+        .unwrap();
+
+        let expanded = get_steps_raw(
+            r#"
+health:
+  http:
+    url: https://api.example.com
+    bearer: abc123
+"#,
+            &Value::Mapping(Mapping::new()),
+        )
+        .unwrap();
+
+        assert_eq!(with_host[0].run, expanded[0].run);
+    }
+
+    #[test]
+    fn previous_results_in_context() {
+        let plan = "check:\n  value: {% if previous.check and not previous.check.pass %}again{% else %}first{% endif %}\n";
+        let config = Value::Mapping(Mapping::new());
+
+        let first = get_steps_raw(plan, &config).unwrap();
+        assert_eq!(first[0].run, RunType::Value("first".into()));
+
+        let previous = serde_json::json!({"check": {"pass": false}});
+        let again =
+            get_steps_from(plan, &config, None, &IncludeOptions::default(), &previous).unwrap();
+        assert_eq!(again[0].run, RunType::Value("again".into()));
+    }
+
+    fn step_names(steps: &[Step]) -> Vec<&str> {
+        steps.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    #[test]
+    fn includes_steps_relative_to_the_plan() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("shared")).unwrap();
+
+        std::fs::write(
+            dir.path().join("shared/common.yml"),
+            "include: more.yml\ncommon:\n  value: included\n",
+        )
+        .unwrap();
+
+        std::fs::write(dir.path().join("shared/more.yml"), "more:\n  value: more\n").unwrap();
+
+        let plan = dir.path().join("plan.yml");
+        std::fs::write(
+            &plan,
+            "include: shared/common.yml\ncommon:\n  value: replaced\nown:\n  value: own\n",
+        )
+        .unwrap();
+
+        let steps = get_steps(&plan, &None::<&Path>).unwrap();
+
+        let mut names = step_names(&steps);
+        names.sort_unstable();
+
+        assert_eq!(names, vec!["common", "more", "own"]);
+
+        let common = steps.iter().find(|step| step.name == "common").unwrap();
+        assert_eq!(common.run, RunType::Value("replaced".into()));
+    }
+
+    #[test]
+    fn includes_relative_to_remote_includes() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut request_line = String::new();
+                BufReader::new(&stream)
+                    .read_line(&mut request_line)
+                    .unwrap();
+
+                let body = match request_line.split_whitespace().nth(1) {
+                    Some("/plans/main.yml") => "include: common.yml\nremote:\n  value: main\n",
+                    Some("/plans/common.yml") => "common:\n  value: common\n",
+                    _ => "",
+                };
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let plan = dir.path().join("plan.yml");
+        std::fs::write(
+            &plan,
+            format!("include: http://127.0.0.1:{}/plans/main.yml\n", port),
+        )
+        .unwrap();
+
+        let options = IncludeOptions {
+            offline: false,
+            cache_dir: Some(dir.path().join("cache")),
+        };
+
+        let steps =
+            get_steps_with_options(&plan, &None::<&Path>, &options, &serde_json::Value::Null)
+                .unwrap();
+
+        assert_eq!(step_names(&steps), vec!["common", "remote"]);
+    }
+
+    #[test]
+    fn rejects_include_checksum_mismatches() {
+        let dir = tempfile::tempdir().unwrap();
+
+        std::fs::write(dir.path().join("common.yml"), "common:\n  value: changed\n").unwrap();
+
+        let plan = dir.path().join("plan.yml");
+        std::fs::write(
+            &plan,
+            format!(
+                "include:\n  url: common.yml\n  sha256: {}\n",
+                "0".repeat(64)
+            ),
+        )
+        .unwrap();
+
+        let err = get_steps(&plan, &None::<&Path>).unwrap_err().to_string();
+
+        assert!(err.contains("Checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("10 minutes").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
     }
 }